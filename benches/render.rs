@@ -0,0 +1,57 @@
+use criterion::{criterion_group, criterion_main, Criterion};
+use rust_nes::mapper;
+use rust_nes::ppu::{NesPPU, PPU};
+use rust_nes::render::{self, frame::Frame};
+use rust_nes::rom::Rom;
+
+// a full frame of PPU dots
+const DOTS_PER_FRAME: usize = 341 * 262;
+
+/// A PPU showing Pac-Man's tiles across the whole screen, with all 64 sprites up.
+fn busy_ppu() -> NesPPU {
+    let rom = Rom::new(&std::fs::read("pac-man.nes").unwrap()).unwrap();
+    let mut ppu = NesPPU::new(mapper::from_rom(rom).unwrap());
+    for addr in 0x2000..0x23c0 {
+        ppu.poke_vram(addr, addr as u8);
+    }
+    for addr in 0x23c0..0x2400 {
+        ppu.poke_vram(addr, 0b1110_0100);
+    }
+    for (i, entry) in ppu.palette_table.iter_mut().enumerate() {
+        *entry = i as u8 * 2;
+    }
+    for sprite in 0..64 {
+        let oam = &mut ppu.oam_data[sprite * 4..sprite * 4 + 4];
+        oam.copy_from_slice(&[
+            (sprite * 3) as u8,
+            sprite as u8,
+            sprite as u8 & 3,
+            (sprite * 4) as u8,
+        ]);
+    }
+    ppu.write_to_ctrl(0b0000_1000);
+    ppu.write_to_mask(0b0001_1110);
+    ppu
+}
+
+fn render_frame(c: &mut Criterion) {
+    let ppu = busy_ppu();
+    let mut frame = Frame::new();
+    c.bench_function("render frame", |b| {
+        b.iter(|| render::render(&ppu, &mut frame))
+    });
+}
+
+fn ppu_frame(c: &mut Criterion) {
+    let mut ppu = busy_ppu();
+    c.bench_function("ppu frame", |b| {
+        b.iter(|| {
+            for _ in 0..DOTS_PER_FRAME {
+                ppu.tick(1);
+            }
+        })
+    });
+}
+
+criterion_group!(benches, render_frame, ppu_frame);
+criterion_main!(benches);
@@ -0,0 +1,108 @@
+//! Runs a bare 6502 program instead of a game, the way the Easy 6502 tutorial does: the
+//! program is assembled into RAM at $0600, the 32x32 screen is the bytes at
+//! $0200-$05FF, $FE is a new random number before every instruction and $FF holds the
+//! last key pressed. Play with W, A, S and D.
+//!
+//! cargo run --example snake [program.asm]
+
+use rust_nes::asm;
+use rust_nes::bus::Bus;
+use rust_nes::cpu::{Mem, CPU};
+use rust_nes::mapper::{self, SharedMapper};
+use rust_nes::ram_init::Rng;
+use rust_nes::rom::Mirroring;
+use sdl2::event::Event;
+use sdl2::keyboard::Keycode;
+use sdl2::pixels::PixelFormatEnum;
+use std::time::Duration;
+
+const SCREEN: u16 = 0x0200;
+const RANDOM: u16 = 0xfe;
+const LAST_KEY: u16 = 0xff;
+
+fn color(byte: u8) -> [u8; 3] {
+    match byte {
+        0 => [0, 0, 0],
+        1 => [255, 255, 255],
+        2 | 9 => [128, 128, 128],
+        3 | 10 => [255, 0, 0],
+        4 | 11 => [0, 255, 0],
+        5 | 12 => [0, 0, 255],
+        6 | 13 => [255, 0, 255],
+        7 | 14 => [255, 255, 0],
+        _ => [0, 255, 255],
+    }
+}
+
+/// Copies the screen into `frame`, and says whether anything changed.
+fn read_screen(cpu: &CPU, frame: &mut [u8; 32 * 32 * 3]) -> bool {
+    let mut changed = false;
+    for (i, pixel) in frame.chunks_exact_mut(3).enumerate() {
+        let rgb = color(cpu.mem_peek(SCREEN + i as u16));
+        if pixel != rgb {
+            pixel.copy_from_slice(&rgb);
+            changed = true;
+        }
+    }
+    changed
+}
+
+fn main() {
+    let source = match std::env::args().nth(1) {
+        Some(path) => {
+            std::fs::read_to_string(&path).unwrap_or_else(|e| panic!("Can't read {}: {}", path, e))
+        }
+        None => include_str!("snake.asm").to_string(),
+    };
+    let program = asm::assemble(&source).unwrap_or_else(|e| panic!("{}", e));
+
+    let sdl_context = sdl2::init().unwrap();
+    let window = sdl_context
+        .video()
+        .unwrap()
+        .window("Snake", 32 * 10, 32 * 10)
+        .position_centered()
+        .build()
+        .unwrap();
+    let mut canvas = window.into_canvas().present_vsync().build().unwrap();
+    let mut event_pump = sdl_context.event_pump().unwrap();
+    canvas.set_scale(10.0, 10.0).unwrap();
+    let creator = canvas.texture_creator();
+    let mut texture = creator
+        .create_texture_target(PixelFormatEnum::RGB24, 32, 32)
+        .unwrap();
+
+    // the console is only there for its CPU and RAM, with a blank cartridge in
+    let mapper: SharedMapper = mapper::blank(Mirroring::Horizontal, false);
+    let mut cpu = CPU::new(Bus::with_mapper(mapper));
+    cpu.load(program);
+    cpu.reset();
+    cpu.program_counter = asm::LOAD_ADDRESS;
+
+    let mut rng = Rng::new(Rng::clock_seed());
+    let mut frame = [0; 32 * 32 * 3];
+    cpu.run_with_callback(move |cpu| {
+        for event in event_pump.poll_iter() {
+            match event {
+                Event::Quit { .. }
+                | Event::KeyDown {
+                    keycode: Some(Keycode::Escape),
+                    ..
+                } => std::process::exit(0),
+                Event::KeyDown {
+                    keycode: Some(key @ (Keycode::W | Keycode::A | Keycode::S | Keycode::D)),
+                    ..
+                } => cpu.mem_write(LAST_KEY, key.name().to_ascii_lowercase().as_bytes()[0]),
+                _ => {}
+            }
+        }
+        cpu.mem_write(RANDOM, rng.next_u8() % 15 + 1);
+
+        if read_screen(cpu, &mut frame) {
+            texture.update(None, &frame, 32 * 3).unwrap();
+            canvas.copy(&texture, None, None).unwrap();
+            canvas.present();
+        }
+        std::thread::sleep(Duration::new(0, 70_000));
+    });
+}
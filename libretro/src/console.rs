@@ -0,0 +1,190 @@
+use rust_nes::apu::filter::{SampleBuffer, SAMPLE_RATE};
+use rust_nes::bus::Bus;
+use rust_nes::cheats::Cheats;
+use rust_nes::cpu::{CpuState, Mem, CPU};
+use rust_nes::error::NesError;
+use rust_nes::event::Event;
+use rust_nes::joypad::JoypadButton;
+use rust_nes::render::{self, frame::Frame};
+use rust_nes::rom::Rom;
+use rust_nes::savestate;
+use std::sync::{Arc, Mutex};
+
+pub const WIDTH: usize = 256;
+pub const HEIGHT: usize = 240;
+
+pub const BUTTONS: [JoypadButton; 8] = [
+    JoypadButton::UP,
+    JoypadButton::DOWN,
+    JoypadButton::LEFT,
+    JoypadButton::RIGHT,
+    JoypadButton::SELECT,
+    JoypadButton::START,
+    JoypadButton::BUTTON_A,
+    JoypadButton::BUTTON_B,
+];
+
+/// The console behind the libretro functions, run a frame at a time.
+pub struct Console {
+    cpu: CPU,
+    frame: Frame,
+    samples: Arc<Mutex<SampleBuffer>>,
+    /// The last frame as 0x00RRGGBB pixels.
+    pub video: Vec<u32>,
+    /// The sound made during the last frame as interleaved stereo.
+    pub audio: Vec<i16>,
+}
+
+impl Console {
+    pub fn new(rom: &[u8]) -> Result<Console, NesError> {
+        let rom = Rom::new(&rom.to_vec())?;
+
+        let samples = Arc::new(Mutex::new(SampleBuffer::new(SAMPLE_RATE as usize / 10)));
+        let mut cpu = CPU::new(Bus::new(rom)?);
+        cpu.halt_on_brk = false;
+        cpu.bus.apu.set_output(Box::new(samples.clone()));
+        cpu.reset();
+
+        Ok(Console {
+            cpu,
+            frame: Frame::new(),
+            samples,
+            video: vec![0; WIDTH * HEIGHT],
+            audio: Vec::new(),
+        })
+    }
+
+    /// The reset button, which leaves RAM alone.
+    pub fn reset(&mut self) {
+        self.cpu.soft_reset();
+    }
+
+    /// Sets the buttons held on each controller for the next frame.
+    pub fn set_buttons(&mut self, first: JoypadButton, second: JoypadButton) {
+        for (player, held) in [(1, first), (2, second)] {
+            let joypad = self.cpu.bus.joypad_mut(player).unwrap();
+            for &button in BUTTONS.iter() {
+                joypad.set_button_pressed_status(button, held.contains(button));
+            }
+        }
+    }
+
+    /// Runs the console to the end of the next frame and fills `video` and `audio`.
+    pub fn run_frame(&mut self) -> Result<(), NesError> {
+        let mut frame_done = false;
+        while !frame_done {
+            match self.cpu.step() {
+                CpuState::Running | CpuState::Halted => {}
+                CpuState::Jammed => {
+                    return Err(NesError::Jammed {
+                        opcode: self.cpu.mem_peek(self.cpu.program_counter),
+                        addr: self.cpu.program_counter,
+                    })
+                }
+                CpuState::Error(e) => return Err(e),
+            }
+            while let Some(event) = self.cpu.bus.poll_event() {
+                frame_done |= event == Event::FrameComplete;
+            }
+        }
+
+        render::render(&self.cpu.bus.ppu, &mut self.frame);
+        for (pixel, rgb) in self.video.iter_mut().zip(self.frame.data.chunks(3)) {
+            *pixel = (rgb[0] as u32) << 16 | (rgb[1] as u32) << 8 | rgb[2] as u32;
+        }
+
+        let mut samples = self.samples.lock().unwrap();
+        self.audio.clear();
+        while let Some(sample) = samples.pop() {
+            let sample = (sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16;
+            self.audio.extend_from_slice(&[sample, sample]);
+        }
+        Ok(())
+    }
+
+    pub fn add_cheat(&mut self, code: &str) -> Result<(), String> {
+        self.cpu.bus.cheats.add(code).map(|_| ())
+    }
+
+    pub fn clear_cheats(&mut self) {
+        self.cpu.bus.cheats = Cheats::new();
+    }
+
+    pub fn save_state(&self) -> Vec<u8> {
+        savestate::save(&self.cpu)
+    }
+
+    pub fn load_state(&mut self, data: &[u8]) -> Result<(), String> {
+        savestate::load(&mut self.cpu, data)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // an NROM cartridge whose program shows the background in colour $21 and then
+    // copies the first controller into $00 forever
+    fn test_rom() -> Vec<u8> {
+        let mut rom = b"NES\x1a\x01\x01".to_vec();
+        rom.resize(16, 0);
+        let mut prg = vec![0; 0x4000];
+        #[rustfmt::skip]
+        let program = [
+            0xa9, 0x3f, 0x8d, 0x06, 0x20, // LDA #$3f; STA $2006
+            0xa9, 0x00, 0x8d, 0x06, 0x20, // LDA #$00; STA $2006
+            0xa9, 0x21, 0x8d, 0x07, 0x20, // LDA #$21; STA $2007
+            0xa9, 0x08, 0x8d, 0x01, 0x20, // LDA #$08; STA $2001
+            0xa9, 0x01, 0x8d, 0x16, 0x40, // LDA #$01; STA $4016
+            0xa9, 0x00, 0x8d, 0x16, 0x40, // LDA #$00; STA $4016
+            0xad, 0x16, 0x40, 0x85, 0x00, // LDA $4016; STA $00
+            0x4c, 0x14, 0x80, //             JMP $8014
+        ];
+        prg[..program.len()].copy_from_slice(&program);
+        prg[0x3ffc..0x3ffe].copy_from_slice(&[0x00, 0x80]);
+        rom.extend(prg);
+        rom.extend(vec![0; 0x2000]);
+        rom
+    }
+
+    #[test]
+    fn test_runs_frames() {
+        let mut console = Console::new(&test_rom()).unwrap();
+        console.run_frame().unwrap();
+        console.run_frame().unwrap();
+        let (r, g, b) = render::palette::SYSTEM_PALLETE[0x21];
+        assert_eq!(
+            console.video[0],
+            (r as u32) << 16 | (g as u32) << 8 | b as u32
+        );
+        assert!(!console.audio.is_empty());
+        assert_eq!(console.audio.len() % 2, 0);
+    }
+
+    #[test]
+    fn test_buttons_reach_the_game() {
+        let mut console = Console::new(&test_rom()).unwrap();
+        console.set_buttons(JoypadButton::BUTTON_A, JoypadButton::empty());
+        console.run_frame().unwrap();
+        console.run_frame().unwrap();
+        assert_eq!(console.cpu.mem_read(0x00) & 1, 1);
+
+        console.set_buttons(JoypadButton::empty(), JoypadButton::empty());
+        console.run_frame().unwrap();
+        console.run_frame().unwrap();
+        assert_eq!(console.cpu.mem_read(0x00) & 1, 0);
+    }
+
+    #[test]
+    fn test_state_round_trip() {
+        let mut console = Console::new(&test_rom()).unwrap();
+        console.run_frame().unwrap();
+        let state = console.save_state();
+        console.run_frame().unwrap();
+        let after = console.save_state();
+
+        console.load_state(&state).unwrap();
+        console.run_frame().unwrap();
+        assert_eq!(console.save_state(), after);
+    }
+}
@@ -0,0 +1,379 @@
+//! A libretro core, so the emulator can be loaded by RetroArch and other libretro
+//! frontends. The frontend calls everything from one thread, so the console and the
+//! callbacks it hands over live in thread locals.
+
+mod api;
+mod console;
+
+use api::*;
+use console::{Console, HEIGHT, WIDTH};
+use rust_nes::apu::filter::SAMPLE_RATE;
+use rust_nes::joypad::JoypadButton;
+use rust_nes::pacer::NTSC_FPS;
+use std::cell::{Cell, RefCell};
+use std::ffi::CStr;
+use std::os::raw::{c_char, c_uint, c_void};
+use std::ptr;
+use std::slice;
+
+const JOYPAD: [(c_uint, JoypadButton); 8] = [
+    (RETRO_DEVICE_ID_JOYPAD_UP, JoypadButton::UP),
+    (RETRO_DEVICE_ID_JOYPAD_DOWN, JoypadButton::DOWN),
+    (RETRO_DEVICE_ID_JOYPAD_LEFT, JoypadButton::LEFT),
+    (RETRO_DEVICE_ID_JOYPAD_RIGHT, JoypadButton::RIGHT),
+    (RETRO_DEVICE_ID_JOYPAD_SELECT, JoypadButton::SELECT),
+    (RETRO_DEVICE_ID_JOYPAD_START, JoypadButton::START),
+    (RETRO_DEVICE_ID_JOYPAD_A, JoypadButton::BUTTON_A),
+    (RETRO_DEVICE_ID_JOYPAD_B, JoypadButton::BUTTON_B),
+];
+
+#[derive(Clone, Copy, Default)]
+struct Callbacks {
+    environment: Option<EnvironmentFn>,
+    video_refresh: Option<VideoRefreshFn>,
+    audio_sample_batch: Option<AudioSampleBatchFn>,
+    input_poll: Option<InputPollFn>,
+    input_state: Option<InputStateFn>,
+}
+
+thread_local! {
+    static CALLBACKS: Cell<Callbacks> = Cell::new(Callbacks::default());
+    static CONSOLE: RefCell<Option<Console>> = const { RefCell::new(None) };
+}
+
+fn set_callbacks(update: impl FnOnce(&mut Callbacks)) {
+    CALLBACKS.with(|callbacks| {
+        let mut updated = callbacks.get();
+        update(&mut updated);
+        callbacks.set(updated);
+    });
+}
+
+fn with_console<T>(default: T, f: impl FnOnce(&mut Console) -> T) -> T {
+    CONSOLE.with(|console| console.borrow_mut().as_mut().map_or(default, f))
+}
+
+#[no_mangle]
+pub extern "C" fn retro_api_version() -> c_uint {
+    RETRO_API_VERSION
+}
+
+#[no_mangle]
+pub extern "C" fn retro_set_environment(callback: EnvironmentFn) {
+    set_callbacks(|callbacks| callbacks.environment = Some(callback));
+}
+
+#[no_mangle]
+pub extern "C" fn retro_set_video_refresh(callback: VideoRefreshFn) {
+    set_callbacks(|callbacks| callbacks.video_refresh = Some(callback));
+}
+
+/// Sound goes to the frontend a frame at a time through the batch callback instead.
+#[no_mangle]
+pub extern "C" fn retro_set_audio_sample(_: AudioSampleFn) {}
+
+#[no_mangle]
+pub extern "C" fn retro_set_audio_sample_batch(callback: AudioSampleBatchFn) {
+    set_callbacks(|callbacks| callbacks.audio_sample_batch = Some(callback));
+}
+
+#[no_mangle]
+pub extern "C" fn retro_set_input_poll(callback: InputPollFn) {
+    set_callbacks(|callbacks| callbacks.input_poll = Some(callback));
+}
+
+#[no_mangle]
+pub extern "C" fn retro_set_input_state(callback: InputStateFn) {
+    set_callbacks(|callbacks| callbacks.input_state = Some(callback));
+}
+
+#[no_mangle]
+pub extern "C" fn retro_init() {}
+
+#[no_mangle]
+pub extern "C" fn retro_deinit() {
+    retro_unload_game();
+}
+
+/// # Safety
+///
+/// `info` must point to a `retro_system_info`.
+#[no_mangle]
+pub unsafe extern "C" fn retro_get_system_info(info: *mut SystemInfo) {
+    *info = SystemInfo {
+        library_name: b"rust-nes\0".as_ptr() as *const c_char,
+        library_version: concat!(env!("CARGO_PKG_VERSION"), "\0").as_ptr() as *const c_char,
+        valid_extensions: b"nes\0".as_ptr() as *const c_char,
+        need_fullpath: false,
+        block_extract: false,
+    };
+}
+
+/// # Safety
+///
+/// `info` must point to a `retro_system_av_info`.
+#[no_mangle]
+pub unsafe extern "C" fn retro_get_system_av_info(info: *mut SystemAvInfo) {
+    *info = SystemAvInfo {
+        geometry: GameGeometry {
+            base_width: WIDTH as c_uint,
+            base_height: HEIGHT as c_uint,
+            max_width: WIDTH as c_uint,
+            max_height: HEIGHT as c_uint,
+            aspect_ratio: 0.0,
+        },
+        timing: SystemTiming {
+            fps: NTSC_FPS,
+            sample_rate: SAMPLE_RATE as f64,
+        },
+    };
+}
+
+/// Both ports always have a standard controller plugged in.
+#[no_mangle]
+pub extern "C" fn retro_set_controller_port_device(_port: c_uint, _device: c_uint) {}
+
+#[no_mangle]
+pub extern "C" fn retro_reset() {
+    with_console((), Console::reset);
+}
+
+/// # Safety
+///
+/// The callbacks the frontend has set must be safe to call.
+#[no_mangle]
+pub unsafe extern "C" fn retro_run() {
+    let callbacks = CALLBACKS.with(Cell::get);
+    if let Some(input_poll) = callbacks.input_poll {
+        input_poll();
+    }
+    let buttons = |port| {
+        let mut buttons = JoypadButton::empty();
+        if let Some(input_state) = callbacks.input_state {
+            for &(id, button) in JOYPAD.iter() {
+                buttons.set(button, input_state(port, RETRO_DEVICE_JOYPAD, 0, id) != 0);
+            }
+        }
+        buttons
+    };
+    let (first, second) = (buttons(0), buttons(1));
+
+    with_console((), |console| {
+        console.set_buttons(first, second);
+        if let Err(e) = console.run_frame() {
+            eprintln!("{}", e);
+        }
+
+        if let Some(video_refresh) = callbacks.video_refresh {
+            video_refresh(
+                console.video.as_ptr() as *const c_void,
+                WIDTH as c_uint,
+                HEIGHT as c_uint,
+                WIDTH * 4,
+            );
+        }
+        if let Some(audio_sample_batch) = callbacks.audio_sample_batch {
+            // the frontend may take fewer frames than it's offered
+            let mut audio = &console.audio[..];
+            while !audio.is_empty() {
+                let taken = audio_sample_batch(audio.as_ptr(), audio.len() / 2);
+                if taken == 0 {
+                    break;
+                }
+                audio = &audio[(taken * 2).min(audio.len())..];
+            }
+        }
+    });
+}
+
+#[no_mangle]
+pub extern "C" fn retro_serialize_size() -> usize {
+    with_console(0, |console| console.save_state().len())
+}
+
+/// # Safety
+///
+/// `data` must point to `size` writable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn retro_serialize(data: *mut c_void, size: usize) -> bool {
+    with_console(false, |console| {
+        let state = console.save_state();
+        if state.len() > size {
+            return false;
+        }
+        ptr::copy_nonoverlapping(state.as_ptr(), data as *mut u8, state.len());
+        true
+    })
+}
+
+/// # Safety
+///
+/// `data` must point to `size` readable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn retro_unserialize(data: *const c_void, size: usize) -> bool {
+    let state = slice::from_raw_parts(data as *const u8, size);
+    with_console(false, |console| match console.load_state(state) {
+        Ok(()) => true,
+        Err(e) => {
+            eprintln!("{}", e);
+            false
+        }
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn retro_cheat_reset() {
+    with_console((), Console::clear_cheats);
+}
+
+/// # Safety
+///
+/// `code` must be a nul terminated string. Frontends join several codes with `+`.
+#[no_mangle]
+pub unsafe extern "C" fn retro_cheat_set(_index: c_uint, enabled: bool, code: *const c_char) {
+    if !enabled || code.is_null() {
+        return;
+    }
+    let code = CStr::from_ptr(code).to_string_lossy();
+    with_console((), |console| {
+        for code in code.split('+') {
+            if let Err(e) = console.add_cheat(code) {
+                eprintln!("{}", e);
+            }
+        }
+    });
+}
+
+/// # Safety
+///
+/// `game` must be null or point to a `retro_game_info` holding the whole ROM.
+#[no_mangle]
+pub unsafe extern "C" fn retro_load_game(game: *const GameInfo) -> bool {
+    if game.is_null() || (*game).data.is_null() {
+        return false;
+    }
+
+    let mut format = RETRO_PIXEL_FORMAT_XRGB8888;
+    let environment = CALLBACKS.with(Cell::get).environment;
+    let format_set = environment.is_some_and(|environment| {
+        environment(
+            RETRO_ENVIRONMENT_SET_PIXEL_FORMAT,
+            &mut format as *mut c_uint as *mut c_void,
+        )
+    });
+    if !format_set {
+        eprintln!("The frontend doesn't support XRGB8888");
+        return false;
+    }
+
+    let rom = slice::from_raw_parts((*game).data as *const u8, (*game).size);
+    match Console::new(rom) {
+        Ok(console) => {
+            CONSOLE.with(|current| *current.borrow_mut() = Some(console));
+            true
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            false
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn retro_load_game_special(
+    _game_type: c_uint,
+    _info: *const GameInfo,
+    _num_info: usize,
+) -> bool {
+    false
+}
+
+#[no_mangle]
+pub extern "C" fn retro_unload_game() {
+    CONSOLE.with(|console| *console.borrow_mut() = None);
+}
+
+#[no_mangle]
+pub extern "C" fn retro_get_region() -> c_uint {
+    RETRO_REGION_NTSC
+}
+
+#[no_mangle]
+pub extern "C" fn retro_get_memory_data(_id: c_uint) -> *mut c_void {
+    ptr::null_mut()
+}
+
+#[no_mangle]
+pub extern "C" fn retro_get_memory_size(_id: c_uint) -> usize {
+    0
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    static FRAMES: AtomicUsize = AtomicUsize::new(0);
+    static SAMPLES: AtomicUsize = AtomicUsize::new(0);
+
+    unsafe extern "C" fn environment(cmd: c_uint, _: *mut c_void) -> bool {
+        cmd == RETRO_ENVIRONMENT_SET_PIXEL_FORMAT
+    }
+
+    unsafe extern "C" fn video_refresh(_: *const c_void, width: c_uint, height: c_uint, _: usize) {
+        assert_eq!((width, height), (256, 240));
+        FRAMES.fetch_add(1, Ordering::SeqCst);
+    }
+
+    // takes at most 100 frames at a time, like a frontend with a small buffer
+    unsafe extern "C" fn audio_sample_batch(_: *const i16, frames: usize) -> usize {
+        let taken = frames.min(100);
+        SAMPLES.fetch_add(taken, Ordering::SeqCst);
+        taken
+    }
+
+    #[test]
+    fn test_frontend_calls() {
+        let mut rom = b"NES\x1a\x01\x01".to_vec();
+        rom.resize(16, 0);
+        let mut prg = vec![0; 0x4000];
+        prg[..3].copy_from_slice(&[0x4c, 0x00, 0x80]);
+        prg[0x3ffc..0x3ffe].copy_from_slice(&[0x00, 0x80]);
+        rom.extend(prg);
+        rom.extend(vec![0; 0x2000]);
+        let game = GameInfo {
+            path: ptr::null(),
+            data: rom.as_ptr() as *const c_void,
+            size: rom.len(),
+            meta: ptr::null(),
+        };
+
+        retro_set_environment(environment);
+        retro_set_video_refresh(video_refresh);
+        retro_set_audio_sample_batch(audio_sample_batch);
+        unsafe {
+            assert!(retro_load_game(&game));
+            retro_run();
+            retro_run();
+        }
+        assert_eq!(FRAMES.load(Ordering::SeqCst), 2);
+        // roughly 735 samples a frame
+        assert!(SAMPLES.load(Ordering::SeqCst) > 1000);
+
+        let mut state = vec![0; retro_serialize_size()];
+        unsafe {
+            assert!(retro_serialize(
+                state.as_mut_ptr() as *mut c_void,
+                state.len()
+            ));
+            assert!(!retro_serialize(state.as_mut_ptr() as *mut c_void, 10));
+            assert!(retro_unserialize(
+                state.as_ptr() as *const c_void,
+                state.len()
+            ));
+        }
+
+        retro_unload_game();
+        assert_eq!(retro_serialize_size(), 0);
+    }
+}
@@ -0,0 +1,245 @@
+use crate::savestate::{Savestate, StateReader, StateWriter};
+
+// NTSC timer periods, in CPU cycles
+const RATE_TABLE: [u16; 16] = [
+    428, 380, 340, 320, 286, 254, 226, 214, 190, 160, 142, 128, 106, 84, 72, 54,
+];
+
+/// The delta modulation channel, which plays 1-bit samples from PRG as steps up and down
+/// of a 7-bit level. The bus fetches the sample bytes for it, see `fetch_address`.
+pub struct Dmc {
+    irq_enabled: bool,
+    looping: bool,
+    period: u16,
+    timer: u16,
+    level: u8,
+
+    sample_address: u16,
+    sample_length: u16,
+    current_address: u16,
+    bytes_remaining: u16,
+    buffer: Option<u8>,
+
+    shift_register: u8,
+    bits_remaining: u8,
+    silence: bool,
+    irq: bool,
+}
+
+impl Default for Dmc {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Dmc {
+    pub fn new() -> Self {
+        Dmc {
+            irq_enabled: false,
+            looping: false,
+            period: RATE_TABLE[0],
+            timer: 0,
+            level: 0,
+            sample_address: 0xc000,
+            sample_length: 1,
+            current_address: 0xc000,
+            bytes_remaining: 0,
+            buffer: None,
+            shift_register: 0,
+            bits_remaining: 8,
+            silence: true,
+            irq: false,
+        }
+    }
+
+    /// Writes one of the channel's registers ($4010-$4013).
+    pub fn write(&mut self, register: u16, data: u8) {
+        match register {
+            0 => {
+                self.irq_enabled = data & 0b1000_0000 != 0;
+                self.looping = data & 0b0100_0000 != 0;
+                self.period = RATE_TABLE[(data & 0b1111) as usize];
+                if !self.irq_enabled {
+                    self.irq = false;
+                }
+            }
+            1 => self.level = data & 0b0111_1111,
+            2 => self.sample_address = 0xc000 | (data as u16) << 6,
+            3 => self.sample_length = (data as u16) << 4 | 1,
+            _ => unreachable!(),
+        }
+    }
+
+    /// Bit 4 of $4015 stops the sample, or starts it over when it has finished.
+    pub fn set_enabled(&mut self, enabled: bool) {
+        if !enabled {
+            self.bytes_remaining = 0;
+        } else if self.bytes_remaining == 0 {
+            self.restart();
+        }
+    }
+
+    fn restart(&mut self) {
+        self.current_address = self.sample_address;
+        self.bytes_remaining = self.sample_length;
+    }
+
+    /// Whether there are sample bytes left to fetch.
+    pub fn is_active(&self) -> bool {
+        self.bytes_remaining > 0
+    }
+
+    /// Where the next sample byte comes from, once the channel wants it.
+    pub fn fetch_address(&self) -> Option<u16> {
+        match self.buffer {
+            None if self.bytes_remaining > 0 => Some(self.current_address),
+            _ => None,
+        }
+    }
+
+    /// Hands the channel the byte it asked for with `fetch_address`.
+    pub fn fill(&mut self, data: u8) {
+        self.buffer = Some(data);
+        // the address wraps around to $8000, not $0000
+        self.current_address = self.current_address.checked_add(1).unwrap_or(0x8000);
+        self.bytes_remaining -= 1;
+        if self.bytes_remaining == 0 {
+            if self.looping {
+                self.restart();
+            } else if self.irq_enabled {
+                self.irq = true;
+            }
+        }
+    }
+
+    /// Whether the sample ran out and raised the IRQ since the last call.
+    pub fn take_irq(&mut self) -> bool {
+        std::mem::take(&mut self.irq)
+    }
+
+    /// Clocked every CPU cycle.
+    pub fn clock_timer(&mut self) {
+        if self.timer > 0 {
+            self.timer -= 1;
+            return;
+        }
+        self.timer = self.period - 1;
+
+        if !self.silence {
+            if self.shift_register & 1 != 0 {
+                if self.level <= 125 {
+                    self.level += 2;
+                }
+            } else if self.level >= 2 {
+                self.level -= 2;
+            }
+        }
+        self.shift_register >>= 1;
+        self.bits_remaining -= 1;
+        if self.bits_remaining == 0 {
+            self.bits_remaining = 8;
+            match self.buffer.take() {
+                Some(data) => {
+                    self.silence = false;
+                    self.shift_register = data;
+                }
+                None => self.silence = true,
+            }
+        }
+    }
+
+    pub fn period(&self) -> u16 {
+        self.period
+    }
+
+    pub fn output(&self) -> u8 {
+        self.level
+    }
+}
+
+impl Savestate for Dmc {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_bool(self.irq_enabled);
+        state.write_bool(self.looping);
+        state.write_u16(self.period);
+        state.write_u16(self.timer);
+        state.write_u8(self.level);
+        state.write_u16(self.sample_address);
+        state.write_u16(self.sample_length);
+        state.write_u16(self.current_address);
+        state.write_u16(self.bytes_remaining);
+        state.write_bool(self.buffer.is_some());
+        state.write_u8(self.buffer.unwrap_or(0));
+        state.write_u8(self.shift_register);
+        state.write_u8(self.bits_remaining);
+        state.write_bool(self.silence);
+        state.write_bool(self.irq);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), String> {
+        self.irq_enabled = state.read_bool()?;
+        self.looping = state.read_bool()?;
+        self.period = state.read_u16()?;
+        self.timer = state.read_u16()?;
+        self.level = state.read_u8()?;
+        self.sample_address = state.read_u16()?;
+        self.sample_length = state.read_u16()?;
+        self.current_address = state.read_u16()?;
+        self.bytes_remaining = state.read_u16()?;
+        let full = state.read_bool()?;
+        let data = state.read_u8()?;
+        self.buffer = Some(data).filter(|_| full);
+        self.shift_register = state.read_u8()?;
+        self.bits_remaining = state.read_u8()?;
+        self.silence = state.read_bool()?;
+        self.irq = state.read_bool()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_plays_and_loops() {
+        let mut dmc = Dmc::new();
+        // fastest rate, looping, $C040, 17 bytes
+        dmc.write(0, 0b0100_1111);
+        dmc.write(1, 64);
+        dmc.write(2, 1);
+        dmc.write(3, 1);
+        dmc.set_enabled(true);
+
+        for i in 0..17 {
+            assert_eq!(dmc.fetch_address(), Some(0xc040 + i));
+            dmc.fill(0xff);
+            while dmc.fetch_address().is_none() {
+                dmc.clock_timer();
+            }
+        }
+        assert!(dmc.level > 64);
+        assert_eq!(dmc.fetch_address(), Some(0xc040));
+        assert!(!dmc.take_irq());
+    }
+
+    #[test]
+    fn test_irq_at_the_end() {
+        let mut dmc = Dmc::new();
+        dmc.write(0, 0b1000_0000);
+        dmc.write(3, 0);
+        dmc.set_enabled(true);
+        dmc.fill(0);
+        assert!(!dmc.is_active());
+        assert!(dmc.take_irq());
+
+        // the address wraps to $8000
+        dmc.write(2, 0xff);
+        dmc.write(3, 0xff);
+        dmc.set_enabled(true);
+        for _ in 0xffc0..=0xffff {
+            dmc.fill(0);
+        }
+        assert_eq!(dmc.current_address, 0x8000);
+    }
+}
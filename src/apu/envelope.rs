@@ -0,0 +1,208 @@
+use crate::savestate::{Savestate, StateReader, StateWriter};
+
+const LENGTH_TABLE: [u8; 32] = [
+    10, 254, 20, 2, 40, 4, 80, 6, 160, 8, 60, 10, 14, 12, 26, 14, 12, 16, 24, 18, 48, 20, 96, 22,
+    192, 24, 72, 26, 16, 28, 32, 30,
+];
+
+#[derive(Default)]
+pub struct Envelope {
+    start: bool,
+    looping: bool,
+    constant: bool,
+    volume: u8,
+    divider: u8,
+    decay: u8,
+}
+
+impl Envelope {
+    pub fn write(&mut self, data: u8) {
+        self.looping = data & 0b0010_0000 != 0;
+        self.constant = data & 0b0001_0000 != 0;
+        self.volume = data & 0b0000_1111;
+    }
+
+    pub fn restart(&mut self) {
+        self.start = true;
+    }
+
+    /// Clocked by the frame sequencer every quarter frame.
+    pub fn clock(&mut self) {
+        if self.start {
+            self.start = false;
+            self.decay = 15;
+            self.divider = self.volume;
+        } else if self.divider == 0 {
+            self.divider = self.volume;
+            if self.decay > 0 {
+                self.decay -= 1;
+            } else if self.looping {
+                self.decay = 15;
+            }
+        } else {
+            self.divider -= 1;
+        }
+    }
+
+    pub fn output(&self) -> u8 {
+        if self.constant {
+            self.volume
+        } else {
+            self.decay
+        }
+    }
+}
+
+impl Savestate for Envelope {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_bool(self.start);
+        state.write_bool(self.looping);
+        state.write_bool(self.constant);
+        state.write_u8(self.volume);
+        state.write_u8(self.divider);
+        state.write_u8(self.decay);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), String> {
+        self.start = state.read_bool()?;
+        self.looping = state.read_bool()?;
+        self.constant = state.read_bool()?;
+        self.volume = state.read_u8()?;
+        self.divider = state.read_u8()?;
+        self.decay = state.read_u8()?;
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+pub struct LengthCounter {
+    enabled: bool,
+    halt: bool,
+    counter: u8,
+}
+
+impl LengthCounter {
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+        if !enabled {
+            self.counter = 0;
+        }
+    }
+
+    pub fn set_halt(&mut self, halt: bool) {
+        self.halt = halt;
+    }
+
+    /// Loads the counter from the 5 bit index written to the channel's last register.
+    pub fn load(&mut self, index: u8) {
+        if self.enabled {
+            self.counter = LENGTH_TABLE[(index & 0b1_1111) as usize];
+        }
+    }
+
+    /// Clocked by the frame sequencer every half frame.
+    pub fn clock(&mut self) {
+        if !self.halt && self.counter > 0 {
+            self.counter -= 1;
+        }
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.counter > 0
+    }
+}
+
+impl Savestate for LengthCounter {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_bool(self.enabled);
+        state.write_bool(self.halt);
+        state.write_u8(self.counter);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), String> {
+        self.enabled = state.read_bool()?;
+        self.halt = state.read_bool()?;
+        self.counter = state.read_u8()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_envelope_decays_and_loops() {
+        let mut envelope = Envelope::default();
+        envelope.write(0b0010_0000);
+        envelope.restart();
+        envelope.clock();
+        assert_eq!(envelope.output(), 15);
+
+        for _ in 0..15 {
+            envelope.clock();
+        }
+        assert_eq!(envelope.output(), 0);
+        envelope.clock();
+        assert_eq!(envelope.output(), 15);
+    }
+
+    #[test]
+    fn test_envelope_divider_and_constant_volume() {
+        let mut envelope = Envelope::default();
+        // decays a step every 3 quarter frames
+        envelope.write(0b0000_0010);
+        envelope.restart();
+        envelope.clock();
+        for _ in 0..3 {
+            envelope.clock();
+        }
+        assert_eq!(envelope.output(), 14);
+        for _ in 0..3 * 14 {
+            envelope.clock();
+        }
+        assert_eq!(envelope.output(), 0);
+        envelope.clock();
+        assert_eq!(envelope.output(), 0);
+
+        // the volume is used as is, while the decay carries on underneath
+        envelope.write(0b0001_0111);
+        assert_eq!(envelope.output(), 7);
+        envelope.restart();
+        envelope.clock();
+        assert_eq!(envelope.output(), 7);
+        envelope.write(0b0000_0111);
+        assert_eq!(envelope.output(), 15);
+    }
+
+    #[test]
+    fn test_length_counter_halt_and_disable() {
+        let mut length = LengthCounter::default();
+        length.set_enabled(true);
+        length.load(3);
+        length.set_halt(true);
+        length.clock();
+        length.clock();
+        assert!(length.is_active());
+
+        length.set_halt(false);
+        length.set_enabled(false);
+        assert!(!length.is_active());
+        length.set_enabled(true);
+        assert!(!length.is_active());
+    }
+
+    #[test]
+    fn test_length_counter() {
+        let mut length = LengthCounter::default();
+        length.load(1);
+        assert!(!length.is_active());
+
+        length.set_enabled(true);
+        length.load(3);
+        assert!(length.is_active());
+        length.clock();
+        length.clock();
+        assert!(!length.is_active());
+    }
+}
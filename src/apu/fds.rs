@@ -0,0 +1,339 @@
+use crate::savestate::{Savestate, StateReader, StateWriter};
+
+// At full volume the wave channel is about as loud as both 2A03 pulse channels together
+const OUTPUT_SCALE: f32 = 0.25 / (63.0 * 32.0);
+
+// $4089 master volume: 2/2, 2/3, 2/4 or 2/5
+const MASTER_VOLUME: [f32; 4] = [1.0, 2.0 / 3.0, 0.5, 0.4];
+
+// Modulation table entries: how much to move the mod counter by, 4 resets it instead
+const MOD_ADJUST: [i8; 8] = [0, 1, 2, 4, 0, -4, -2, -1];
+
+#[derive(Default)]
+struct FdsEnvelope {
+    direct: bool,
+    increase: bool,
+    speed: u8,
+    gain: u8,
+    timer: u32,
+}
+
+impl FdsEnvelope {
+    fn write(&mut self, data: u8) {
+        self.direct = data & 0b1000_0000 != 0;
+        self.increase = data & 0b0100_0000 != 0;
+        self.speed = data & 0b0011_1111;
+        if self.direct {
+            self.gain = self.speed;
+        }
+        self.timer = 0;
+    }
+
+    fn clock(&mut self, master_speed: u8) {
+        if self.direct {
+            return;
+        }
+        self.timer += 1;
+        if self.timer < 8 * (self.speed as u32 + 1) * master_speed as u32 {
+            return;
+        }
+        self.timer = 0;
+        if self.increase && self.gain < 32 {
+            self.gain += 1;
+        } else if !self.increase && self.gain > 0 {
+            self.gain -= 1;
+        }
+    }
+}
+
+/// The Famicom Disk System's sound channel: a 64 step, 6-bit wavetable with a volume
+/// envelope and a frequency modulation unit driven by its own table.
+pub struct FdsAudio {
+    wave_table: [u8; 64],
+    mod_table: [u8; 64],
+    volume: FdsEnvelope,
+    sweep: FdsEnvelope,
+
+    frequency: u16,
+    wave_halt: bool,
+    envelopes_halt: bool,
+    wave_accumulator: u32,
+    wave_position: u8,
+
+    mod_frequency: u16,
+    mod_halt: bool,
+    mod_accumulator: u32,
+    mod_position: u8,
+    // 7-bit signed
+    mod_counter: i8,
+
+    master_volume: u8,
+    wave_write: bool,
+    envelope_speed: u8,
+    level: u8,
+}
+
+impl Default for FdsAudio {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FdsAudio {
+    pub fn new() -> Self {
+        FdsAudio {
+            wave_table: [0; 64],
+            mod_table: [0; 64],
+            volume: FdsEnvelope::default(),
+            sweep: FdsEnvelope::default(),
+            frequency: 0,
+            wave_halt: true,
+            envelopes_halt: false,
+            wave_accumulator: 0,
+            wave_position: 0,
+            mod_frequency: 0,
+            mod_halt: true,
+            mod_accumulator: 0,
+            mod_position: 0,
+            mod_counter: 0,
+            master_volume: 0,
+            wave_write: false,
+            envelope_speed: 0xe8,
+            level: 0,
+        }
+    }
+
+    /// Reads $4040-$407F (the wavetable), $4090 (volume gain) and $4092 (sweep gain).
+    pub fn read(&self, addr: u16) -> u8 {
+        match addr {
+            0x4040..=0x407f => self.wave_table[(addr - 0x4040) as usize],
+            0x4090 => 0b0100_0000 | self.volume.gain,
+            0x4092 => 0b0100_0000 | self.sweep.gain,
+            _ => 0,
+        }
+    }
+
+    pub fn write(&mut self, addr: u16, data: u8) {
+        match addr {
+            // the wavetable can only be written while the channel is held
+            0x4040..=0x407f if self.wave_write => {
+                self.wave_table[(addr - 0x4040) as usize] = data & 0b0011_1111;
+            }
+            0x4080 => self.volume.write(data),
+            0x4082 => self.frequency = (self.frequency & 0x0f00) | data as u16,
+            0x4083 => {
+                self.frequency = (self.frequency & 0x00ff) | ((data as u16 & 0b1111) << 8);
+                self.wave_halt = data & 0b1000_0000 != 0;
+                self.envelopes_halt = data & 0b0100_0000 != 0;
+                if self.wave_halt {
+                    self.wave_accumulator = 0;
+                    self.wave_position = 0;
+                }
+                if self.envelopes_halt {
+                    self.volume.timer = 0;
+                    self.sweep.timer = 0;
+                }
+            }
+            0x4084 => self.sweep.write(data),
+            0x4085 => self.mod_counter = ((data << 1) as i8) >> 1,
+            0x4086 => self.mod_frequency = (self.mod_frequency & 0x0f00) | data as u16,
+            0x4087 => {
+                self.mod_frequency = (self.mod_frequency & 0x00ff) | ((data as u16 & 0b1111) << 8);
+                self.mod_halt = data & 0b1000_0000 != 0;
+                if self.mod_halt {
+                    self.mod_accumulator = 0;
+                }
+            }
+            // each write fills two entries, and only while the mod unit is halted
+            0x4088 if self.mod_halt => {
+                let entry = data & 0b111;
+                self.mod_table[self.mod_position as usize] = entry;
+                self.mod_table[self.mod_position as usize + 1] = entry;
+                self.mod_position = (self.mod_position + 2) & 0b11_1111;
+            }
+            0x4089 => {
+                self.wave_write = data & 0b1000_0000 != 0;
+                self.master_volume = data & 0b11;
+            }
+            0x408a => self.envelope_speed = data,
+            _ => {}
+        }
+    }
+
+    // Carrier frequency bent by the mod unit, following the reference formula from
+    // https://wiki.nesdev.com/w/index.php/FDS_audio
+    fn pitch(&self) -> u32 {
+        let mut temp = self.mod_counter as i32 * self.sweep.gain as i32;
+        let remainder = temp & 0xf;
+        temp >>= 4;
+        if remainder > 0 && temp & 0x80 == 0 {
+            temp += if self.mod_counter < 0 { -1 } else { 2 };
+        }
+        if temp >= 192 {
+            temp -= 256;
+        } else if temp < -64 {
+            temp += 256;
+        }
+
+        temp *= self.frequency as i32;
+        let remainder = temp & 0x3f;
+        temp >>= 6;
+        if remainder >= 32 {
+            temp += 1;
+        }
+        (self.frequency as i32 + temp).max(0) as u32
+    }
+
+    fn clock_mod(&mut self) {
+        let entry = self.mod_table[self.mod_position as usize];
+        self.mod_position = (self.mod_position + 1) & 0b11_1111;
+        self.mod_counter = if entry == 4 {
+            0
+        } else {
+            // wraps around within 7 bits
+            let counter = self.mod_counter.wrapping_add(MOD_ADJUST[entry as usize]);
+            (counter << 1) >> 1
+        };
+    }
+
+    /// Clocked every CPU cycle.
+    pub fn clock(&mut self) {
+        if !self.envelopes_halt && !self.wave_halt && self.envelope_speed > 0 {
+            self.volume.clock(self.envelope_speed);
+            self.sweep.clock(self.envelope_speed);
+        }
+
+        if !self.mod_halt && self.mod_frequency > 0 {
+            self.mod_accumulator += self.mod_frequency as u32;
+            if self.mod_accumulator >= 0x10000 {
+                self.mod_accumulator -= 0x10000;
+                self.clock_mod();
+            }
+        }
+
+        if !self.wave_halt {
+            self.wave_accumulator += self.pitch();
+            if self.wave_accumulator >= 0x10000 {
+                self.wave_accumulator -= 0x10000;
+                self.wave_position = (self.wave_position + 1) & 0b11_1111;
+            }
+        }
+
+        // the output holds its last level while the wavetable is being written
+        if !self.wave_write {
+            self.level = self.wave_table[self.wave_position as usize];
+        }
+    }
+
+    pub fn output(&self) -> f32 {
+        let gain = self.volume.gain.min(32);
+        let level = self.level as f32 * gain as f32 * MASTER_VOLUME[self.master_volume as usize];
+        level * OUTPUT_SCALE
+    }
+}
+
+impl Savestate for FdsAudio {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_bytes(&self.wave_table);
+        state.write_bytes(&self.mod_table);
+        for envelope in [&self.volume, &self.sweep].iter() {
+            state.write_bool(envelope.direct);
+            state.write_bool(envelope.increase);
+            state.write_u8(envelope.speed);
+            state.write_u8(envelope.gain);
+            state.write_usize(envelope.timer as usize);
+        }
+        state.write_u16(self.frequency);
+        state.write_bool(self.wave_halt);
+        state.write_bool(self.envelopes_halt);
+        state.write_usize(self.wave_accumulator as usize);
+        state.write_u8(self.wave_position);
+        state.write_u16(self.mod_frequency);
+        state.write_bool(self.mod_halt);
+        state.write_usize(self.mod_accumulator as usize);
+        state.write_u8(self.mod_position);
+        state.write_u8(self.mod_counter as u8);
+        state.write_u8(self.master_volume);
+        state.write_bool(self.wave_write);
+        state.write_u8(self.envelope_speed);
+        state.write_u8(self.level);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), String> {
+        state.read_bytes(&mut self.wave_table)?;
+        state.read_bytes(&mut self.mod_table)?;
+        for envelope in [&mut self.volume, &mut self.sweep].iter_mut() {
+            envelope.direct = state.read_bool()?;
+            envelope.increase = state.read_bool()?;
+            envelope.speed = state.read_u8()?;
+            envelope.gain = state.read_u8()?;
+            envelope.timer = state.read_usize()? as u32;
+        }
+        self.frequency = state.read_u16()?;
+        self.wave_halt = state.read_bool()?;
+        self.envelopes_halt = state.read_bool()?;
+        self.wave_accumulator = state.read_usize()? as u32;
+        self.wave_position = state.read_u8()?;
+        self.mod_frequency = state.read_u16()?;
+        self.mod_halt = state.read_bool()?;
+        self.mod_accumulator = state.read_usize()? as u32;
+        self.mod_position = state.read_u8()?;
+        self.mod_counter = state.read_u8()? as i8;
+        self.master_volume = state.read_u8()?;
+        self.wave_write = state.read_bool()?;
+        self.envelope_speed = state.read_u8()?;
+        self.level = state.read_u8()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_wave_table_write_protect() {
+        let mut audio = FdsAudio::new();
+        audio.write(0x4040, 0x3f);
+        assert_eq!(audio.read(0x4040), 0);
+
+        audio.write(0x4089, 0b1000_0000);
+        audio.write(0x4040, 0xff);
+        assert_eq!(audio.read(0x4040), 0x3f);
+    }
+
+    #[test]
+    fn test_wave_plays_at_volume() {
+        let mut audio = FdsAudio::new();
+        audio.write(0x4089, 0b1000_0000);
+        for i in 0..64 {
+            audio.write(0x4040 + i, if i < 32 { 0x3f } else { 0 });
+        }
+        audio.write(0x4089, 0);
+        audio.write(0x4080, 0b1000_0000 | 32);
+        // one wavetable step every 32 cycles
+        audio.write(0x4082, 0x00);
+        audio.write(0x4083, 0x08);
+
+        audio.clock();
+        assert_eq!(audio.read(0x4090), 0b0100_0000 | 32);
+        assert!((audio.output() - 0.25).abs() < 0.001);
+        for _ in 0..32 * 32 {
+            audio.clock();
+        }
+        assert_eq!(audio.output(), 0.0);
+    }
+
+    #[test]
+    fn test_mod_counter_wraps_in_7_bits() {
+        let mut audio = FdsAudio::new();
+        audio.write(0x4085, 0x3f);
+        assert_eq!(audio.mod_counter, 63);
+        for _ in 0..32 {
+            audio.write(0x4088, 1);
+        }
+        audio.write(0x4087, 0);
+        audio.clock_mod();
+        assert_eq!(audio.mod_counter, -64);
+    }
+}
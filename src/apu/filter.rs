@@ -0,0 +1,281 @@
+use std::f32::consts::PI;
+
+pub const CPU_CLOCK: f64 = 1_789_773.0;
+pub const SAMPLE_RATE: u32 = 44_100;
+
+// how far the sample rate can be bent to keep the audio device's buffer half full
+const MAX_DRIFT: f64 = 0.005;
+
+enum Kind {
+    HighPass,
+    LowPass,
+}
+
+/// A first order RC filter running at the output sample rate.
+struct Filter {
+    kind: Kind,
+    alpha: f32,
+    prev_input: f32,
+    prev_output: f32,
+}
+
+impl Filter {
+    fn new(kind: Kind, cutoff: f32, sample_rate: f32) -> Self {
+        let rc = 1.0 / (2.0 * PI * cutoff);
+        let dt = 1.0 / sample_rate;
+        let alpha = match kind {
+            Kind::HighPass => rc / (rc + dt),
+            Kind::LowPass => dt / (rc + dt),
+        };
+        Filter {
+            kind,
+            alpha,
+            prev_input: 0.0,
+            prev_output: 0.0,
+        }
+    }
+
+    fn process(&mut self, input: f32) -> f32 {
+        let output = match self.kind {
+            Kind::HighPass => self.alpha * (self.prev_output + input - self.prev_input),
+            Kind::LowPass => self.prev_output + self.alpha * (input - self.prev_output),
+        };
+        self.prev_input = input;
+        self.prev_output = output;
+        output
+    }
+}
+
+/// Turns the mixer output, produced once per CPU cycle, into samples at `SAMPLE_RATE`.
+///
+/// Every output sample is the average of the CPU cycles it covers, which keeps most of the
+/// aliasing out, and then goes through the console's own filter chain: two high-pass
+/// filters at 90Hz and 440Hz and a low-pass filter at 14kHz.
+pub struct Resampler {
+    nominal_cycles_per_sample: f64,
+    cycles_per_sample: f64,
+    ratio: f64,
+    speed: f64,
+    phase: f64,
+    sum: f32,
+    count: u32,
+    filters: [Filter; 3],
+    samples: Vec<f32>,
+}
+
+impl Default for Resampler {
+    fn default() -> Self {
+        Self::new(SAMPLE_RATE)
+    }
+}
+
+impl Resampler {
+    pub fn new(sample_rate: u32) -> Self {
+        let rate = sample_rate as f32;
+        Resampler {
+            nominal_cycles_per_sample: CPU_CLOCK / sample_rate as f64,
+            cycles_per_sample: CPU_CLOCK / sample_rate as f64,
+            ratio: 1.0,
+            speed: 1.0,
+            phase: 0.0,
+            sum: 0.0,
+            count: 0,
+            filters: [
+                Filter::new(Kind::HighPass, 90.0, rate),
+                Filter::new(Kind::HighPass, 440.0, rate),
+                Filter::new(Kind::LowPass, 14_000.0, rate),
+            ],
+            samples: Vec::new(),
+        }
+    }
+
+    pub fn push(&mut self, input: f32) {
+        self.sum += input;
+        self.count += 1;
+        self.phase += 1.0;
+
+        if self.phase >= self.cycles_per_sample {
+            self.phase -= self.cycles_per_sample;
+            let mut sample = self.sum / self.count as f32;
+            for filter in self.filters.iter_mut() {
+                sample = filter.process(sample);
+            }
+            self.samples.push(sample);
+            self.sum = 0.0;
+            self.count = 0;
+        }
+    }
+
+    /// Makes `ratio` times as many samples as the sample rate calls for, which
+    /// `RateControl` keeps very close to 1.
+    pub fn set_ratio(&mut self, ratio: f64) {
+        self.ratio = ratio;
+        self.cycles_per_sample = self.nominal_cycles_per_sample * self.speed / self.ratio;
+    }
+
+    /// Spreads the samples over `speed` times as many CPU cycles, for a console running
+    /// faster or slower than real time.
+    pub fn set_speed(&mut self, speed: f64) {
+        self.speed = speed;
+        self.set_ratio(self.ratio);
+    }
+
+    /// Takes the samples produced since the last call.
+    pub fn take_samples(&mut self) -> Vec<f32> {
+        std::mem::take(&mut self.samples)
+    }
+}
+
+/// Keeps the audio device's buffer about half full, so it neither runs dry and crackles
+/// nor lags further and further behind the picture. The emulator and the sound card run
+/// off different clocks, so a fixed sample rate always drifts one way or the other; instead
+/// a few more samples are made while the buffer is running low and a few fewer while it's
+/// filling up. The pitch never changes by more than half a percent, which can't be heard.
+pub struct RateControl {
+    level: f32,
+}
+
+impl Default for RateControl {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RateControl {
+    pub fn new() -> Self {
+        RateControl { level: 0.5 }
+    }
+
+    /// Takes how full the buffer is, from 0 to 1, once a frame, and returns the ratio to
+    /// resample at. The level is smoothed over a few frames, since the device takes
+    /// samples out in chunks.
+    pub fn update(&mut self, level: f32) -> f64 {
+        self.level += (level.clamp(0.0, 1.0) - self.level) * 0.1;
+        1.0 + MAX_DRIFT * (1.0 - 2.0 * self.level as f64)
+    }
+}
+
+/// Fixed size FIFO between the emulator and the audio device. When the emulator runs
+/// ahead the oldest samples are dropped, when it falls behind the last sample is repeated.
+pub struct SampleBuffer {
+    data: Vec<f32>,
+    start: usize,
+    len: usize,
+    last: f32,
+}
+
+impl SampleBuffer {
+    pub fn new(capacity: usize) -> Self {
+        SampleBuffer {
+            data: vec![0.0; capacity],
+            start: 0,
+            len: 0,
+            last: 0.0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.data.len()
+    }
+
+    pub fn push(&mut self, sample: f32) {
+        let capacity = self.data.len();
+        let end = (self.start + self.len) % capacity;
+        self.data[end] = sample;
+        if self.len == capacity {
+            self.start = (self.start + 1) % capacity;
+        } else {
+            self.len += 1;
+        }
+    }
+
+    pub fn extend(&mut self, samples: &[f32]) {
+        for sample in samples {
+            self.push(*sample);
+        }
+    }
+
+    pub fn pop(&mut self) -> Option<f32> {
+        if self.len == 0 {
+            return None;
+        }
+        let sample = self.data[self.start];
+        self.start = (self.start + 1) % self.data.len();
+        self.len -= 1;
+        self.last = sample;
+        Some(sample)
+    }
+
+    /// The next sample for the audio callback, or the last one again on underrun.
+    pub fn next_sample(&mut self) -> f32 {
+        self.pop().unwrap_or(self.last)
+    }
+
+    /// Fills `out` for the audio callback, padding with the last sample on underrun.
+    pub fn fill(&mut self, out: &mut [f32]) {
+        for sample in out.iter_mut() {
+            *sample = self.next_sample();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_resampler_rate() {
+        let mut resampler = Resampler::new(SAMPLE_RATE);
+        for _ in 0..CPU_CLOCK as usize {
+            resampler.push(0.5);
+        }
+        let samples = resampler.take_samples();
+        assert!((samples.len() as i64 - SAMPLE_RATE as i64).abs() <= 1);
+        // the high-pass filters remove the DC offset
+        assert!(samples.last().unwrap().abs() < 0.01);
+    }
+
+    #[test]
+    fn test_rate_control() {
+        let mut resampler = Resampler::new(SAMPLE_RATE);
+        let mut control = RateControl::new();
+        assert_eq!(control.update(0.5), 1.0);
+        // running low, so more samples are made, and never more than MAX_DRIFT more
+        let mut ratio = 1.0;
+        for _ in 0..100 {
+            ratio = control.update(0.0);
+        }
+        assert!(ratio > 1.004 && ratio <= 1.0 + MAX_DRIFT);
+        resampler.set_ratio(ratio);
+        for _ in 0..CPU_CLOCK as usize {
+            resampler.push(0.5);
+        }
+        let extra = resampler.take_samples().len() as f64 / SAMPLE_RATE as f64 - 1.0;
+        assert!((extra - (ratio - 1.0)).abs() < 0.0001);
+
+        for _ in 0..100 {
+            ratio = control.update(1.0);
+        }
+        assert!((1.0 - MAX_DRIFT..0.996).contains(&ratio));
+    }
+
+    #[test]
+    fn test_sample_buffer_wraps_and_drops_oldest() {
+        let mut buffer = SampleBuffer::new(3);
+        buffer.extend(&[1.0, 2.0, 3.0, 4.0]);
+        assert_eq!(buffer.len(), 3);
+
+        let mut out = [0.0; 5];
+        buffer.fill(&mut out);
+        assert_eq!(out, [2.0, 3.0, 4.0, 4.0, 4.0]);
+        assert!(buffer.is_empty());
+    }
+}
@@ -0,0 +1,602 @@
+pub mod dmc;
+mod envelope;
+pub mod fds;
+pub mod filter;
+pub mod monitor;
+mod noise;
+mod pulse;
+pub mod sunsoft5b;
+mod triangle;
+pub mod vrc6;
+
+use crate::apu::dmc::Dmc;
+use crate::apu::filter::{RateControl, Resampler};
+use crate::apu::monitor::{ApuMonitor, ChannelState};
+use crate::apu::noise::Noise;
+use crate::apu::pulse::Pulse;
+use crate::apu::triangle::Triangle;
+use crate::audio::AudioSink;
+use crate::savestate::{Savestate, StateReader, StateWriter};
+use std::cell::RefCell;
+use std::rc::Rc;
+
+// Frame sequencer steps, in CPU cycles since the last $4017 write or sequence restart
+const STEP_1: usize = 7457;
+const STEP_2: usize = 14913;
+const STEP_3: usize = 22371;
+const STEP_4: usize = 29829;
+const STEP_5: usize = 37281;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Channel {
+    Pulse1,
+    Pulse2,
+    Triangle,
+    Noise,
+    Dmc,
+}
+
+pub const CHANNELS: [Channel; 5] = [
+    Channel::Pulse1,
+    Channel::Pulse2,
+    Channel::Triangle,
+    Channel::Noise,
+    Channel::Dmc,
+];
+
+pub struct Apu {
+    pub pulse1: Pulse,
+    pub pulse2: Pulse,
+    pub triangle: Triangle,
+    pub noise: Noise,
+    pub dmc: Dmc,
+
+    five_step_mode: bool,
+    frame_cycle: usize,
+    cycles: usize,
+    // a $4017 write waiting to restart the sequencer, and the CPU cycles until it does
+    frame_write: Option<(u8, u8)>,
+    irq_inhibit: bool,
+    // set when the 4-step sequence raises its IRQ, until the bus takes it
+    frame_irq: bool,
+
+    muted: [bool; 5],
+    expansion: f32,
+    resampler: Resampler,
+    rate_control: RateControl,
+    // how fast the console runs compared to real time, `None` when it's unlimited
+    speed: Option<f64>,
+    output: Option<Box<dyn AudioSink>>,
+    record_output: Option<Box<dyn AudioSink>>,
+    monitor: Option<Rc<RefCell<ApuMonitor>>>,
+}
+
+impl Default for Apu {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Apu {
+    pub fn new() -> Self {
+        Apu {
+            pulse1: Pulse::pulse1(),
+            pulse2: Pulse::pulse2(),
+            triangle: Triangle::default(),
+            noise: Noise::new(),
+            dmc: Dmc::new(),
+            five_step_mode: false,
+            frame_cycle: 0,
+            cycles: 0,
+            frame_write: None,
+            irq_inhibit: false,
+            frame_irq: false,
+            muted: [false; 5],
+            expansion: 0.0,
+            resampler: Resampler::default(),
+            rate_control: RateControl::new(),
+            speed: Some(1.0),
+            output: None,
+            record_output: None,
+            monitor: None,
+        }
+    }
+
+    /// The reset button silences every channel and restarts the frame counter in the mode
+    /// it was in.
+    pub fn reset(&mut self) {
+        self.write_register(0x4015, 0);
+        self.frame_cycle = 0;
+        self.frame_write = None;
+    }
+
+    /// Puts the channels and the frame counter back to their power on state. Where the
+    /// sound goes and which channels are muted stays the same.
+    pub fn power_cycle(&mut self) {
+        self.pulse1 = Pulse::pulse1();
+        self.pulse2 = Pulse::pulse2();
+        self.triangle = Triangle::default();
+        self.noise = Noise::new();
+        self.dmc = Dmc::new();
+        self.five_step_mode = false;
+        self.frame_cycle = 0;
+        self.cycles = 0;
+        self.frame_write = None;
+        self.irq_inhibit = false;
+        self.frame_irq = false;
+        self.expansion = 0.0;
+    }
+
+    /// Sends the generated samples to `sink`, which is usually the audio device.
+    pub fn set_output(&mut self, sink: Box<dyn AudioSink>) {
+        self.output = Some(sink);
+    }
+
+    /// Also sends every sample to `sink`, for recording.
+    pub fn set_record_output(&mut self, sink: Box<dyn AudioSink>) {
+        self.record_output = Some(sink);
+    }
+
+    /// Tells the APU how fast the console is running, `None` meaning as fast as it can.
+    /// Sped up or slowed down, fewer or more samples are made per frame so the device
+    /// still plays them in real time, at a higher or lower pitch. At unlimited speed
+    /// there's nothing sensible to play, so the output is silent.
+    pub fn set_speed(&mut self, speed: Option<f64>) {
+        self.speed = speed;
+        self.resampler.set_speed(speed.unwrap_or(1.0));
+    }
+
+    /// Keeps `monitor` up to date with what the channels are doing, for the APU viewer.
+    pub fn set_monitor(&mut self, monitor: Rc<RefCell<ApuMonitor>>) {
+        self.monitor = Some(monitor);
+    }
+
+    /// Level of the cartridge's expansion audio, mixed in with the APU channels.
+    pub fn set_expansion_output(&mut self, level: f32) {
+        self.expansion = level;
+    }
+
+    pub fn is_muted(&self, channel: Channel) -> bool {
+        self.muted[channel as usize]
+    }
+
+    pub fn set_muted(&mut self, channel: Channel, muted: bool) {
+        self.muted[channel as usize] = muted;
+    }
+
+    /// Mutes every channel but `channel`. Soloing the only audible channel again unmutes all.
+    pub fn solo(&mut self, channel: Channel) {
+        let soloed = CHANNELS
+            .iter()
+            .all(|other| self.is_muted(*other) == (*other != channel));
+        for other in CHANNELS.iter() {
+            self.set_muted(*other, !soloed && *other != channel);
+        }
+    }
+
+    // Output level of a channel as it reaches the mixer
+    fn channel_output(&self, channel: Channel) -> u8 {
+        if self.is_muted(channel) {
+            return 0;
+        }
+        match channel {
+            Channel::Pulse1 => self.pulse1.output(),
+            Channel::Pulse2 => self.pulse2.output(),
+            Channel::Triangle => self.triangle.output(),
+            Channel::Noise => self.noise.output(),
+            Channel::Dmc => self.dmc.output(),
+        }
+    }
+
+    pub fn channel_state(&self, channel: Channel) -> ChannelState {
+        let (period, volume, active) = match channel {
+            Channel::Pulse1 => (
+                self.pulse1.period(),
+                self.pulse1.envelope.output(),
+                self.pulse1.length.is_active(),
+            ),
+            Channel::Pulse2 => (
+                self.pulse2.period(),
+                self.pulse2.envelope.output(),
+                self.pulse2.length.is_active(),
+            ),
+            Channel::Triangle => (
+                self.triangle.period(),
+                self.triangle.linear_counter(),
+                self.triangle.length.is_active(),
+            ),
+            Channel::Noise => (
+                self.noise.period(),
+                self.noise.envelope.output(),
+                self.noise.length.is_active(),
+            ),
+            Channel::Dmc => (self.dmc.period(), self.dmc.output(), self.dmc.is_active()),
+        };
+        ChannelState {
+            period,
+            volume,
+            active,
+            muted: self.is_muted(channel),
+        }
+    }
+
+    pub fn write_register(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x4000..=0x4003 => self.pulse1.write(addr - 0x4000, data),
+            0x4004..=0x4007 => self.pulse2.write(addr - 0x4004, data),
+            0x4008..=0x400b => self.triangle.write(addr - 0x4008, data),
+            0x400c..=0x400f => self.noise.write(addr - 0x400c, data),
+            0x4010..=0x4013 => self.dmc.write(addr - 0x4010, data),
+            0x4015 => {
+                self.pulse1.length.set_enabled(data & 0b0001 != 0);
+                self.pulse2.length.set_enabled(data & 0b0010 != 0);
+                self.triangle.length.set_enabled(data & 0b0100 != 0);
+                self.noise.length.set_enabled(data & 0b1000 != 0);
+                self.dmc.set_enabled(data & 0b1_0000 != 0);
+            }
+            0x4017 => {
+                // the inhibit flag takes effect at once, the sequencer restarts 3 CPU
+                // cycles later, or 4 when the write lands between APU cycles
+                self.irq_inhibit = data & 0b0100_0000 != 0;
+                if self.irq_inhibit {
+                    self.frame_irq = false;
+                }
+                let delay = if self.cycles & 1 == 0 { 3 } else { 4 };
+                self.frame_write = Some((data, delay));
+            }
+            _ => {}
+        }
+    }
+
+    /// The frame counter's mode, as set by bit 7 of $4017.
+    pub fn five_step_mode(&self) -> bool {
+        self.five_step_mode
+    }
+
+    /// Whether the frame counter raised its IRQ since the last call. The bus holds the
+    /// IRQ line down from then until the game acknowledges it.
+    pub fn take_frame_irq(&mut self) -> bool {
+        std::mem::take(&mut self.frame_irq)
+    }
+
+    /// $4015: which channels still have a non-zero length counter, and whether the DMC
+    /// has sample bytes left. The interrupt flags are added by the bus.
+    pub fn status(&self) -> u8 {
+        (self.pulse1.length.is_active() as u8)
+            | (self.pulse2.length.is_active() as u8) << 1
+            | (self.triangle.length.is_active() as u8) << 2
+            | (self.noise.length.is_active() as u8) << 3
+            | (self.dmc.is_active() as u8) << 4
+    }
+
+    /// Advances the APU by one CPU cycle.
+    pub fn tick(&mut self) {
+        self.cycles += 1;
+        self.triangle.clock_timer();
+        self.noise.clock_timer();
+        self.dmc.clock_timer();
+        if self.cycles & 1 == 0 {
+            self.pulse1.clock_timer();
+            self.pulse2.clock_timer();
+        }
+
+        self.clock_frame_sequencer();
+        if let Some((data, delay)) = self.frame_write {
+            if delay > 1 {
+                self.frame_write = Some((data, delay - 1));
+            } else {
+                self.frame_write = None;
+                self.restart_frame_sequencer(data);
+            }
+        }
+        let sample = self.mix();
+        self.resampler.push(sample);
+
+        if let Some(monitor) = &self.monitor {
+            // the waveforms are taken before muting, so muted channels can still be watched
+            let levels = [
+                self.pulse1.output(),
+                self.pulse2.output(),
+                self.triangle.output(),
+                self.noise.output(),
+                self.dmc.output(),
+            ];
+            monitor.borrow_mut().push_levels(levels);
+        }
+    }
+
+    /// Hands the samples generated so far to the outputs.
+    pub fn flush_samples(&mut self) -> usize {
+        let samples = self.resampler.take_samples();
+        // nothing is played at unlimited speed, but a recording still gets every sample
+        let audible = self.speed.is_some();
+        let output = self.output.iter_mut().filter(|_| audible);
+        for output in output.chain(self.record_output.iter_mut()) {
+            output.write(&samples);
+        }
+        if let Some(level) = self.output.as_ref().and_then(|output| output.fill_level()) {
+            self.resampler.set_ratio(self.rate_control.update(level));
+        }
+        if let Some(monitor) = &self.monitor {
+            let mut monitor = monitor.borrow_mut();
+            for channel in CHANNELS.iter() {
+                monitor.channels[*channel as usize] = self.channel_state(*channel);
+            }
+        }
+        samples.len()
+    }
+
+    /// Throws away the samples generated so far, for frames that aren't heard.
+    pub fn discard_samples(&mut self) {
+        self.resampler.take_samples();
+    }
+
+    fn clock_frame_sequencer(&mut self) {
+        self.frame_cycle += 1;
+        match (self.five_step_mode, self.frame_cycle) {
+            (_, STEP_1) | (_, STEP_3) => self.clock_quarter_frame(),
+            (_, STEP_2) | (false, STEP_4) | (true, STEP_5) => {
+                self.clock_quarter_frame();
+                self.clock_half_frame();
+            }
+            (false, cycle) if cycle > STEP_4 => self.frame_cycle = 0,
+            (true, cycle) if cycle > STEP_5 => self.frame_cycle = 0,
+            _ => {}
+        }
+        // the 4-step sequence raises its IRQ over its last three cycles
+        let last_cycles =
+            self.frame_cycle == STEP_4 - 1 || self.frame_cycle == STEP_4 || self.frame_cycle == 0;
+        if !self.five_step_mode && !self.irq_inhibit && last_cycles {
+            self.frame_irq = true;
+        }
+    }
+
+    fn restart_frame_sequencer(&mut self, data: u8) {
+        self.five_step_mode = data & 0b1000_0000 != 0;
+        self.frame_cycle = 0;
+        if self.five_step_mode {
+            self.clock_quarter_frame();
+            self.clock_half_frame();
+        }
+    }
+
+    fn clock_quarter_frame(&mut self) {
+        self.pulse1.envelope.clock();
+        self.pulse2.envelope.clock();
+        self.triangle.clock_linear_counter();
+        self.noise.envelope.clock();
+    }
+
+    fn clock_half_frame(&mut self) {
+        self.pulse1.length.clock();
+        self.pulse1.clock_sweep();
+        self.pulse2.length.clock();
+        self.pulse2.clock_sweep();
+        self.triangle.length.clock();
+        self.noise.length.clock();
+    }
+
+    // Non-linear mixer approximation from https://wiki.nesdev.com/w/index.php/APU_Mixer
+    fn mix(&self) -> f32 {
+        let pulse =
+            (self.channel_output(Channel::Pulse1) + self.channel_output(Channel::Pulse2)) as f32;
+        let pulse_out = if pulse == 0.0 {
+            0.0
+        } else {
+            95.88 / (8128.0 / pulse + 100.0)
+        };
+
+        let tnd = self.channel_output(Channel::Triangle) as f32 / 8227.0
+            + self.channel_output(Channel::Noise) as f32 / 12241.0
+            + self.channel_output(Channel::Dmc) as f32 / 22638.0;
+        let tnd_out = if tnd == 0.0 {
+            0.0
+        } else {
+            159.79 / (1.0 / tnd + 100.0)
+        };
+
+        pulse_out + tnd_out + self.expansion
+    }
+}
+
+impl Savestate for Apu {
+    fn save_state(&self, state: &mut StateWriter) {
+        self.pulse1.save_state(state);
+        self.pulse2.save_state(state);
+        self.triangle.save_state(state);
+        self.noise.save_state(state);
+        self.dmc.save_state(state);
+        state.write_bool(self.five_step_mode);
+        state.write_usize(self.frame_cycle);
+        state.write_usize(self.cycles);
+        let (data, delay) = self.frame_write.unwrap_or((0, 0));
+        state.write_u8(data);
+        state.write_u8(delay);
+        state.write_bool(self.irq_inhibit);
+        state.write_bool(self.frame_irq);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), String> {
+        self.pulse1.load_state(state)?;
+        self.pulse2.load_state(state)?;
+        self.triangle.load_state(state)?;
+        self.noise.load_state(state)?;
+        self.dmc.load_state(state)?;
+        self.five_step_mode = state.read_bool()?;
+        self.frame_cycle = state.read_usize()?;
+        self.cycles = state.read_usize()?;
+        let data = state.read_u8()?;
+        let delay = state.read_u8()?;
+        self.frame_write = Some((data, delay)).filter(|_| delay > 0);
+        self.irq_inhibit = state.read_bool()?;
+        self.frame_irq = state.read_bool()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::apu::filter::SampleBuffer;
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn test_status_reports_length_counters() {
+        let mut apu = Apu::new();
+        apu.write_register(0x4003, 0b0000_1000);
+        assert_eq!(apu.status(), 0);
+
+        apu.write_register(0x4015, 0b0000_0101);
+        apu.write_register(0x4003, 0b0000_1000);
+        apu.write_register(0x400b, 0b0000_1000);
+        assert_eq!(apu.status(), 0b0000_0101);
+
+        apu.write_register(0x4015, 0);
+        assert_eq!(apu.status(), 0);
+    }
+
+    #[test]
+    fn test_length_counter_runs_out() {
+        let mut apu = Apu::new();
+        apu.write_register(0x4015, 0b0000_0001);
+        // length index 3 loads a count of 2, i.e. two half frames
+        apu.write_register(0x4003, 0b0001_1000);
+        for _ in 0..STEP_2 {
+            apu.tick();
+        }
+        assert_eq!(apu.status(), 1);
+        for _ in STEP_2..STEP_4 {
+            apu.tick();
+        }
+        assert_eq!(apu.status(), 0);
+    }
+
+    fn write_4017(apu: &mut Apu, data: u8) {
+        apu.write_register(0x4017, data);
+        for _ in 0..4 {
+            apu.tick();
+        }
+    }
+
+    #[test]
+    fn test_five_step_mode_clocks_on_write() {
+        let mut apu = Apu::new();
+        apu.write_register(0x4015, 0b0000_0001);
+        apu.write_register(0x4003, 0b0001_1000);
+        write_4017(&mut apu, 0b1000_0000);
+        assert_eq!(apu.status(), 1);
+        write_4017(&mut apu, 0b1000_0000);
+        assert_eq!(apu.status(), 0);
+
+        // writing the 4-step mode doesn't clock anything
+        apu.write_register(0x4003, 0b0001_1000);
+        write_4017(&mut apu, 0);
+        write_4017(&mut apu, 0);
+        assert_eq!(apu.status(), 1);
+    }
+
+    #[test]
+    fn test_frame_irq_timing() {
+        // the sequencer restarts 3 cycles after a write on an even cycle
+        let irqs = |data: u8| {
+            let mut apu = Apu::new();
+            apu.write_register(0x4017, data);
+            (1..=2 * 29830 + 3)
+                .filter(|_| {
+                    apu.tick();
+                    apu.take_frame_irq()
+                })
+                .collect::<Vec<usize>>()
+        };
+        let restart = 3;
+        let first = restart + STEP_4 - 1;
+        let second = restart + 29830 + STEP_4 - 1;
+        assert_eq!(
+            irqs(0),
+            [first, first + 1, first + 2, second, second + 1, second + 2]
+        );
+        assert!(irqs(0b0100_0000).is_empty());
+        assert!(irqs(0b1000_0000).is_empty());
+    }
+
+    #[test]
+    fn test_five_step_sequence_is_longer() {
+        // a count of 4 runs out after four half frames
+        let run = |mode: u8| {
+            let mut apu = Apu::new();
+            write_4017(&mut apu, mode);
+            apu.write_register(0x4015, 0b0000_0001);
+            apu.write_register(0x4003, 0b0010_1000);
+            for _ in 0..60_000 {
+                apu.tick();
+            }
+            apu.status()
+        };
+        assert_eq!(run(0), 0);
+        assert_eq!(run(0b1000_0000), 1);
+    }
+
+    #[test]
+    fn test_mute_and_solo() {
+        let mut apu = Apu::new();
+        apu.set_muted(Channel::Noise, true);
+        assert!(apu.is_muted(Channel::Noise));
+
+        apu.solo(Channel::Triangle);
+        assert!(!apu.is_muted(Channel::Triangle));
+        assert!(apu.is_muted(Channel::Pulse1));
+        assert!(apu.is_muted(Channel::Noise));
+
+        apu.solo(Channel::Triangle);
+        assert!(CHANNELS.iter().all(|channel| !apu.is_muted(*channel)));
+    }
+
+    #[test]
+    fn test_pulse_produces_sound() {
+        let mut apu = Apu::new();
+        let output = Arc::new(Mutex::new(SampleBuffer::new(4096)));
+        apu.set_output(Box::new(output.clone()));
+
+        apu.write_register(0x4015, 0b0000_0001);
+        apu.write_register(0x4000, 0b1011_1111);
+        apu.write_register(0x4002, 0xfd);
+        apu.write_register(0x4003, 0b1111_1000);
+        for _ in 0..30_000 {
+            apu.tick();
+        }
+        apu.flush_samples();
+
+        let mut samples = vec![0.0; output.lock().unwrap().len()];
+        output.lock().unwrap().fill(&mut samples);
+        assert!(samples.len() > 700);
+        assert!(samples.iter().any(|sample| sample.abs() > 0.05));
+    }
+
+    #[test]
+    fn test_speed_scales_the_sample_rate() {
+        let mut apu = Apu::new();
+        let output = Arc::new(Mutex::new(SampleBuffer::new(4096)));
+        apu.set_output(Box::new(output.clone()));
+
+        let samples_at = |apu: &mut Apu, speed| {
+            apu.set_speed(speed);
+            for _ in 0..29_780 {
+                apu.tick();
+            }
+            let samples = apu.flush_samples();
+            let written = output.lock().unwrap().len();
+            output.lock().unwrap().fill(&mut vec![0.0; written]);
+            (samples, written)
+        };
+        let (normal, _) = samples_at(&mut apu, Some(1.0));
+        let (double, _) = samples_at(&mut apu, Some(2.0));
+        assert!(
+            (double as i32 - normal as i32 / 2).abs() <= 2,
+            "{} {}",
+            normal,
+            double
+        );
+        let (_, written) = samples_at(&mut apu, None);
+        assert_eq!(written, 0);
+    }
+}
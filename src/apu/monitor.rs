@@ -0,0 +1,63 @@
+use serde::Serialize;
+use std::collections::VecDeque;
+
+/// Points kept of each channel's output, a frame's worth at one point every 116 cycles.
+pub const WAVEFORM_LENGTH: usize = 256;
+const WAVEFORM_STEP: usize = 116;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+pub struct ChannelState {
+    /// Timer period in CPU cycles, or APU cycles for the pulse channels.
+    pub period: u16,
+    /// Envelope or constant volume. The triangle has no volume, so this is its linear counter.
+    pub volume: u8,
+    /// The length counter hasn't run out.
+    pub active: bool,
+    pub muted: bool,
+}
+
+/// What the channels were doing recently, filled in by the APU for the APU viewer. Indexed
+/// by `Channel`.
+#[derive(Clone)]
+pub struct ApuMonitor {
+    /// As of the end of the last frame.
+    pub channels: [ChannelState; 5],
+    waveforms: [VecDeque<u8>; 5],
+    cycles: usize,
+}
+
+impl Default for ApuMonitor {
+    fn default() -> Self {
+        ApuMonitor::new()
+    }
+}
+
+impl ApuMonitor {
+    pub fn new() -> Self {
+        ApuMonitor {
+            channels: [ChannelState::default(); 5],
+            waveforms: Default::default(),
+            cycles: 0,
+        }
+    }
+
+    /// Called every CPU cycle with each channel's output level, 0-15.
+    pub fn push_levels(&mut self, levels: [u8; 5]) {
+        self.cycles += 1;
+        if self.cycles < WAVEFORM_STEP {
+            return;
+        }
+        self.cycles = 0;
+        for (waveform, level) in self.waveforms.iter_mut().zip(levels.iter()) {
+            if waveform.len() == WAVEFORM_LENGTH {
+                waveform.pop_front();
+            }
+            waveform.push_back(*level);
+        }
+    }
+
+    /// Oldest point first.
+    pub fn waveform(&self, channel: usize) -> &VecDeque<u8> {
+        &self.waveforms[channel]
+    }
+}
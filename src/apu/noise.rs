@@ -0,0 +1,99 @@
+use crate::apu::envelope::{Envelope, LengthCounter};
+use crate::savestate::{Savestate, StateReader, StateWriter};
+
+// NTSC timer periods, in CPU cycles
+const PERIOD_TABLE: [u16; 16] = [
+    4, 8, 16, 32, 64, 96, 128, 160, 202, 254, 380, 508, 762, 1016, 2034, 4068,
+];
+
+pub struct Noise {
+    short_mode: bool,
+    period: u16,
+    timer: u16,
+    shift_register: u16,
+    pub envelope: Envelope,
+    pub length: LengthCounter,
+}
+
+impl Default for Noise {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Noise {
+    pub fn new() -> Self {
+        Noise {
+            short_mode: false,
+            period: PERIOD_TABLE[0],
+            timer: 0,
+            shift_register: 1,
+            envelope: Envelope::default(),
+            length: LengthCounter::default(),
+        }
+    }
+
+    /// Writes one of the channel's registers ($400C-$400F).
+    pub fn write(&mut self, register: u16, data: u8) {
+        match register {
+            0 => {
+                self.length.set_halt(data & 0b0010_0000 != 0);
+                self.envelope.write(data);
+            }
+            1 => {}
+            2 => {
+                self.short_mode = data & 0b1000_0000 != 0;
+                self.period = PERIOD_TABLE[(data & 0b1111) as usize];
+            }
+            3 => {
+                self.length.load(data >> 3);
+                self.envelope.restart();
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    /// Clocked every CPU cycle.
+    pub fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.period - 1;
+            let tap = if self.short_mode { 6 } else { 1 };
+            let feedback = (self.shift_register ^ (self.shift_register >> tap)) & 1;
+            self.shift_register = (self.shift_register >> 1) | (feedback << 14);
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    pub fn period(&self) -> u16 {
+        self.period
+    }
+
+    pub fn output(&self) -> u8 {
+        if !self.length.is_active() || self.shift_register & 1 == 1 {
+            0
+        } else {
+            self.envelope.output()
+        }
+    }
+}
+
+impl Savestate for Noise {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_bool(self.short_mode);
+        state.write_u16(self.period);
+        state.write_u16(self.timer);
+        state.write_u16(self.shift_register);
+        self.envelope.save_state(state);
+        self.length.save_state(state);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), String> {
+        self.short_mode = state.read_bool()?;
+        self.period = state.read_u16()?;
+        self.timer = state.read_u16()?;
+        self.shift_register = state.read_u16()?;
+        self.envelope.load_state(state)?;
+        self.length.load_state(state)
+    }
+}
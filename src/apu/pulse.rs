@@ -0,0 +1,213 @@
+use crate::apu::envelope::{Envelope, LengthCounter};
+use crate::savestate::{Savestate, StateReader, StateWriter};
+
+const DUTY_TABLE: [[u8; 8]; 4] = [
+    [0, 1, 0, 0, 0, 0, 0, 0],
+    [0, 1, 1, 0, 0, 0, 0, 0],
+    [0, 1, 1, 1, 1, 0, 0, 0],
+    [1, 0, 0, 1, 1, 1, 1, 1],
+];
+
+/// Bends the pitch by adding a fraction of the period to it, or taking it away, every
+/// few half frames.
+#[derive(Default)]
+struct Sweep {
+    enabled: bool,
+    period: u8,
+    negate: bool,
+    shift: u8,
+    divider: u8,
+    reload: bool,
+}
+
+impl Savestate for Sweep {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_bool(self.enabled);
+        state.write_u8(self.period);
+        state.write_bool(self.negate);
+        state.write_u8(self.shift);
+        state.write_u8(self.divider);
+        state.write_bool(self.reload);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), String> {
+        self.enabled = state.read_bool()?;
+        self.period = state.read_u8()?;
+        self.negate = state.read_bool()?;
+        self.shift = state.read_u8()?;
+        self.divider = state.read_u8()?;
+        self.reload = state.read_bool()?;
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+pub struct Pulse {
+    duty: u8,
+    step: u8,
+    period: u16,
+    timer: u16,
+    sweep: Sweep,
+    // pulse 1 negates its sweep with one's complement, so it bends down one further than
+    // pulse 2
+    ones_complement: bool,
+    pub envelope: Envelope,
+    pub length: LengthCounter,
+}
+
+impl Pulse {
+    pub fn pulse1() -> Self {
+        Pulse {
+            ones_complement: true,
+            ..Pulse::default()
+        }
+    }
+
+    pub fn pulse2() -> Self {
+        Pulse::default()
+    }
+
+    /// Writes one of the channel's four registers ($4000-$4003 or $4004-$4007).
+    pub fn write(&mut self, register: u16, data: u8) {
+        match register {
+            0 => {
+                self.duty = data >> 6;
+                self.length.set_halt(data & 0b0010_0000 != 0);
+                self.envelope.write(data);
+            }
+            1 => {
+                self.sweep.enabled = data & 0b1000_0000 != 0;
+                self.sweep.period = (data >> 4) & 0b111;
+                self.sweep.negate = data & 0b0000_1000 != 0;
+                self.sweep.shift = data & 0b111;
+                self.sweep.reload = true;
+            }
+            2 => self.period = (self.period & 0xff00) | data as u16,
+            3 => {
+                self.period = (self.period & 0x00ff) | ((data as u16 & 0b111) << 8);
+                self.length.load(data >> 3);
+                self.step = 0;
+                self.envelope.restart();
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    /// Clocked every other CPU cycle.
+    pub fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.period;
+            self.step = (self.step + 1) % 8;
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    /// Clocked by the frame sequencer every half frame.
+    pub fn clock_sweep(&mut self) {
+        let sweep = &self.sweep;
+        if sweep.divider == 0 && sweep.enabled && sweep.shift > 0 && !self.is_muted() {
+            self.period = self.target_period();
+        }
+        if self.sweep.divider == 0 || self.sweep.reload {
+            self.sweep.divider = self.sweep.period;
+            self.sweep.reload = false;
+        } else {
+            self.sweep.divider -= 1;
+        }
+    }
+
+    // Where the sweep would take the period next. It's worked out all the time, whether
+    // the sweep is on or not.
+    fn target_period(&self) -> u16 {
+        let change = self.period >> self.sweep.shift;
+        if !self.sweep.negate {
+            self.period + change
+        } else if self.ones_complement {
+            self.period.saturating_sub(change + 1)
+        } else {
+            self.period - change
+        }
+    }
+
+    // Periods below 8 would be ultrasonic, and a sweep target past 11 bits can't be
+    // reached: the hardware silences the channel for either.
+    fn is_muted(&self) -> bool {
+        self.period < 8 || self.target_period() > 0x7ff
+    }
+
+    pub fn period(&self) -> u16 {
+        self.period
+    }
+
+    pub fn output(&self) -> u8 {
+        if !self.length.is_active()
+            || self.is_muted()
+            || DUTY_TABLE[self.duty as usize][self.step as usize] == 0
+        {
+            0
+        } else {
+            self.envelope.output()
+        }
+    }
+}
+
+impl Savestate for Pulse {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_u8(self.duty);
+        state.write_u8(self.step);
+        state.write_u16(self.period);
+        state.write_u16(self.timer);
+        self.sweep.save_state(state);
+        self.envelope.save_state(state);
+        self.length.save_state(state);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), String> {
+        self.duty = state.read_u8()?;
+        self.step = state.read_u8()?;
+        self.period = state.read_u16()?;
+        self.timer = state.read_u16()?;
+        self.sweep.load_state(state)?;
+        self.envelope.load_state(state)?;
+        self.length.load_state(state)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // period $100 after one half frame of a sweep shifted by 2
+    fn swept(mut pulse: Pulse, negate: bool) -> Pulse {
+        pulse.write(2, 0x00);
+        pulse.write(3, 0x01);
+        pulse.write(1, 0b1000_0010 | (negate as u8) << 3);
+        pulse.clock_sweep();
+        pulse
+    }
+
+    #[test]
+    fn test_sweep_negates_differently() {
+        assert_eq!(swept(Pulse::pulse1(), false).period(), 0x140);
+        assert_eq!(swept(Pulse::pulse2(), false).period(), 0x140);
+        assert_eq!(swept(Pulse::pulse1(), true).period(), 0xbf);
+        assert_eq!(swept(Pulse::pulse2(), true).period(), 0xc0);
+    }
+
+    #[test]
+    fn test_sweep_target_mutes() {
+        let mut pulse = Pulse::pulse2();
+        pulse.length.set_enabled(true);
+        pulse.write(0, 0b0011_1111);
+        pulse.write(2, 0xff);
+        pulse.write(3, 0b0000_1110);
+        // the target is past $7FF even with the sweep off
+        assert!(pulse.is_muted());
+        pulse.write(1, 0b0000_1000);
+        assert!(!pulse.is_muted());
+        pulse.write(2, 0x07);
+        pulse.write(3, 0);
+        assert!(pulse.is_muted());
+    }
+}
@@ -0,0 +1,155 @@
+use crate::savestate::{Savestate, StateReader, StateWriter};
+
+// a tone at full volume is about as loud as a 2A03 pulse channel at full volume
+const OUTPUT_SCALE: f32 = 0.15;
+
+// Each volume step is 3dB, and 0 is silent
+#[rustfmt::skip]
+const VOLUME_LEVELS: [f32; 16] = [
+    0.0, 0.0079, 0.0112, 0.0158, 0.0224, 0.0316, 0.0447, 0.0631,
+    0.0891, 0.126, 0.178, 0.251, 0.355, 0.501, 0.708, 1.0,
+];
+
+// the tone counters run at a sixteenth of the CPU clock
+const TONE_DIVIDER: u8 = 16;
+
+#[derive(Default)]
+struct Tone {
+    period: u16,
+    timer: u16,
+    high: bool,
+    volume: u8,
+    muted: bool,
+}
+
+impl Tone {
+    fn clock(&mut self) {
+        self.timer += 1;
+        if self.timer >= self.period.max(1) {
+            self.timer = 0;
+            self.high = !self.high;
+        }
+    }
+
+    fn output(&self) -> f32 {
+        if self.high && !self.muted {
+            VOLUME_LEVELS[self.volume as usize]
+        } else {
+            0.0
+        }
+    }
+}
+
+/// The Sunsoft 5B sound chip on some FME-7 boards, a YM2149 with three square wave
+/// tones. Its noise and envelope generators aren't emulated, since no game uses them.
+#[derive(Default)]
+pub struct Sunsoft5bAudio {
+    register: u8,
+    tones: [Tone; 3],
+    divider: u8,
+}
+
+impl Sunsoft5bAudio {
+    /// $C000-$DFFF picks the register the next write to $E000-$FFFF goes to.
+    pub fn write_address(&mut self, data: u8) {
+        self.register = data & 0b1111;
+    }
+
+    pub fn write_data(&mut self, data: u8) {
+        match self.register {
+            register @ (0 | 2 | 4) => {
+                let tone = &mut self.tones[register as usize / 2];
+                tone.period = (tone.period & 0x0f00) | data as u16;
+            }
+            register @ (1 | 3 | 5) => {
+                let tone = &mut self.tones[register as usize / 2];
+                tone.period = (tone.period & 0x00ff) | ((data as u16 & 0b1111) << 8);
+            }
+            7 => {
+                for (i, tone) in self.tones.iter_mut().enumerate() {
+                    tone.muted = data & (1 << i) != 0;
+                }
+            }
+            register @ 8..=0xa => self.tones[register as usize - 8].volume = data & 0b1111,
+            _ => {}
+        }
+    }
+
+    /// Clocked every CPU cycle.
+    pub fn clock(&mut self) {
+        self.divider += 1;
+        if self.divider == TONE_DIVIDER {
+            self.divider = 0;
+            self.tones.iter_mut().for_each(Tone::clock);
+        }
+    }
+
+    pub fn output(&self) -> f32 {
+        self.tones.iter().map(Tone::output).sum::<f32>() * OUTPUT_SCALE
+    }
+}
+
+impl Savestate for Sunsoft5bAudio {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_u8(self.register);
+        for tone in self.tones.iter() {
+            state.write_u16(tone.period);
+            state.write_u16(tone.timer);
+            state.write_bool(tone.high);
+            state.write_u8(tone.volume);
+            state.write_bool(tone.muted);
+        }
+        state.write_u8(self.divider);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), String> {
+        self.register = state.read_u8()? & 0b1111;
+        for tone in self.tones.iter_mut() {
+            tone.period = state.read_u16()?;
+            tone.timer = state.read_u16()?;
+            tone.high = state.read_bool()?;
+            tone.volume = state.read_u8()? & 0b1111;
+            tone.muted = state.read_bool()?;
+        }
+        self.divider = state.read_u8()? % TONE_DIVIDER;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn write(audio: &mut Sunsoft5bAudio, register: u8, data: u8) {
+        audio.write_address(register);
+        audio.write_data(data);
+    }
+
+    #[test]
+    fn test_tone() {
+        let mut audio = Sunsoft5bAudio::default();
+        write(&mut audio, 0, 2);
+        write(&mut audio, 8, 15);
+        write(&mut audio, 7, 0b110);
+
+        // a period of 2 flips the output every 32 CPU cycles
+        let levels: Vec<f32> = (0..128)
+            .map(|_| {
+                audio.clock();
+                audio.output()
+            })
+            .collect();
+        assert_eq!(
+            levels
+                .iter()
+                .filter(|level| **level == OUTPUT_SCALE)
+                .count(),
+            64
+        );
+        assert_eq!(levels[31], OUTPUT_SCALE);
+        assert_eq!(levels[63], 0.0);
+
+        write(&mut audio, 7, 0b111);
+        assert_eq!(audio.output(), 0.0);
+    }
+}
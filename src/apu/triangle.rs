@@ -0,0 +1,100 @@
+use crate::apu::envelope::LengthCounter;
+use crate::savestate::{Savestate, StateReader, StateWriter};
+
+const SEQUENCE: [u8; 32] = [
+    15, 14, 13, 12, 11, 10, 9, 8, 7, 6, 5, 4, 3, 2, 1, 0, 0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12,
+    13, 14, 15,
+];
+
+#[derive(Default)]
+pub struct Triangle {
+    step: u8,
+    period: u16,
+    timer: u16,
+    control: bool,
+    linear_reload_value: u8,
+    linear_counter: u8,
+    linear_reload: bool,
+    pub length: LengthCounter,
+}
+
+impl Triangle {
+    /// Writes one of the channel's registers ($4008-$400B).
+    pub fn write(&mut self, register: u16, data: u8) {
+        match register {
+            0 => {
+                self.control = data & 0b1000_0000 != 0;
+                self.length.set_halt(self.control);
+                self.linear_reload_value = data & 0b0111_1111;
+            }
+            1 => {}
+            2 => self.period = (self.period & 0xff00) | data as u16,
+            3 => {
+                self.period = (self.period & 0x00ff) | ((data as u16 & 0b111) << 8);
+                self.length.load(data >> 3);
+                self.linear_reload = true;
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    /// Clocked every CPU cycle.
+    pub fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.period;
+            if self.length.is_active() && self.linear_counter > 0 {
+                self.step = (self.step + 1) % 32;
+            }
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    /// Clocked by the frame sequencer every quarter frame.
+    pub fn clock_linear_counter(&mut self) {
+        if self.linear_reload {
+            self.linear_counter = self.linear_reload_value;
+        } else if self.linear_counter > 0 {
+            self.linear_counter -= 1;
+        }
+        if !self.control {
+            self.linear_reload = false;
+        }
+    }
+
+    pub fn period(&self) -> u16 {
+        self.period
+    }
+
+    pub fn linear_counter(&self) -> u8 {
+        self.linear_counter
+    }
+
+    pub fn output(&self) -> u8 {
+        SEQUENCE[self.step as usize]
+    }
+}
+
+impl Savestate for Triangle {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_u8(self.step);
+        state.write_u16(self.period);
+        state.write_u16(self.timer);
+        state.write_bool(self.control);
+        state.write_u8(self.linear_reload_value);
+        state.write_u8(self.linear_counter);
+        state.write_bool(self.linear_reload);
+        self.length.save_state(state);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), String> {
+        self.step = state.read_u8()?;
+        self.period = state.read_u16()?;
+        self.timer = state.read_u16()?;
+        self.control = state.read_bool()?;
+        self.linear_reload_value = state.read_u8()?;
+        self.linear_counter = state.read_u8()?;
+        self.linear_reload = state.read_bool()?;
+        self.length.load_state(state)
+    }
+}
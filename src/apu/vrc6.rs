@@ -0,0 +1,254 @@
+use crate::savestate::{Savestate, StateReader, StateWriter};
+
+// VRC6 levels roughly line up with the 2A03 pulse channels at low volume
+const OUTPUT_SCALE: f32 = 95.88 / (8128.0 + 100.0);
+
+#[derive(Default)]
+struct Vrc6Pulse {
+    volume: u8,
+    duty: u8,
+    ignore_duty: bool,
+    period: u16,
+    timer: u16,
+    step: u8,
+    enabled: bool,
+}
+
+impl Vrc6Pulse {
+    fn write(&mut self, register: u16, data: u8) {
+        match register {
+            0 => {
+                self.ignore_duty = data & 0b1000_0000 != 0;
+                self.duty = (data >> 4) & 0b111;
+                self.volume = data & 0b1111;
+            }
+            1 => self.period = (self.period & 0x0f00) | data as u16,
+            2 => {
+                self.period = (self.period & 0x00ff) | ((data as u16 & 0b1111) << 8);
+                self.enabled = data & 0b1000_0000 != 0;
+                if !self.enabled {
+                    self.step = 0;
+                }
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    fn clock(&mut self, shift: u8) {
+        if !self.enabled {
+            return;
+        }
+        if self.timer == 0 {
+            self.timer = self.period >> shift;
+            self.step = (self.step + 1) % 16;
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    fn output(&self) -> u8 {
+        if self.enabled && (self.ignore_duty || self.step <= self.duty) {
+            self.volume
+        } else {
+            0
+        }
+    }
+}
+
+#[derive(Default)]
+struct Vrc6Sawtooth {
+    rate: u8,
+    period: u16,
+    timer: u16,
+    step: u8,
+    accumulator: u8,
+    enabled: bool,
+}
+
+impl Vrc6Sawtooth {
+    fn write(&mut self, register: u16, data: u8) {
+        match register {
+            0 => self.rate = data & 0b0011_1111,
+            1 => self.period = (self.period & 0x0f00) | data as u16,
+            2 => {
+                self.period = (self.period & 0x00ff) | ((data as u16 & 0b1111) << 8);
+                self.enabled = data & 0b1000_0000 != 0;
+                if !self.enabled {
+                    self.step = 0;
+                    self.accumulator = 0;
+                }
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    fn clock(&mut self, shift: u8) {
+        if !self.enabled {
+            return;
+        }
+        if self.timer > 0 {
+            self.timer -= 1;
+            return;
+        }
+
+        self.timer = self.period >> shift;
+        self.step += 1;
+        // the accumulator grows on every other step and resets after the 7th addition
+        if self.step == 14 {
+            self.step = 0;
+            self.accumulator = 0;
+        } else if self.step & 1 == 0 {
+            self.accumulator = self.accumulator.wrapping_add(self.rate);
+        }
+    }
+
+    fn output(&self) -> u8 {
+        self.accumulator >> 3
+    }
+}
+
+/// The sound half of Konami's VRC6 (mappers 24 and 26): two pulse channels with 8 duty
+/// settings and a sawtooth channel.
+#[derive(Default)]
+pub struct Vrc6Audio {
+    pulse1: Vrc6Pulse,
+    pulse2: Vrc6Pulse,
+    sawtooth: Vrc6Sawtooth,
+    halt: bool,
+    shift: u8,
+}
+
+impl Vrc6Audio {
+    /// Writes an audio register, using mapper 24 addressing ($9000-$9003, $A000-$A002,
+    /// $B000-$B002). Mapper 26 boards swap A0 and A1 before calling this.
+    pub fn write(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x9000..=0x9002 => self.pulse1.write(addr - 0x9000, data),
+            0x9003 => {
+                self.halt = data & 0b001 != 0;
+                self.shift = if data & 0b100 != 0 {
+                    8
+                } else if data & 0b010 != 0 {
+                    4
+                } else {
+                    0
+                };
+            }
+            0xa000..=0xa002 => self.pulse2.write(addr - 0xa000, data),
+            0xb000..=0xb002 => self.sawtooth.write(addr - 0xb000, data),
+            _ => {}
+        }
+    }
+
+    /// Clocked every CPU cycle.
+    pub fn clock(&mut self) {
+        if self.halt {
+            return;
+        }
+        self.pulse1.clock(self.shift);
+        self.pulse2.clock(self.shift);
+        self.sawtooth.clock(self.shift);
+    }
+
+    pub fn output(&self) -> f32 {
+        let level = self.pulse1.output() + self.pulse2.output() + self.sawtooth.output();
+        level as f32 * OUTPUT_SCALE
+    }
+}
+
+impl Savestate for Vrc6Audio {
+    fn save_state(&self, state: &mut StateWriter) {
+        for pulse in [&self.pulse1, &self.pulse2].iter() {
+            state.write_u8(pulse.volume);
+            state.write_u8(pulse.duty);
+            state.write_bool(pulse.ignore_duty);
+            state.write_u16(pulse.period);
+            state.write_u16(pulse.timer);
+            state.write_u8(pulse.step);
+            state.write_bool(pulse.enabled);
+        }
+        state.write_u8(self.sawtooth.rate);
+        state.write_u16(self.sawtooth.period);
+        state.write_u16(self.sawtooth.timer);
+        state.write_u8(self.sawtooth.step);
+        state.write_u8(self.sawtooth.accumulator);
+        state.write_bool(self.sawtooth.enabled);
+        state.write_bool(self.halt);
+        state.write_u8(self.shift);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), String> {
+        for pulse in [&mut self.pulse1, &mut self.pulse2].iter_mut() {
+            pulse.volume = state.read_u8()?;
+            pulse.duty = state.read_u8()?;
+            pulse.ignore_duty = state.read_bool()?;
+            pulse.period = state.read_u16()?;
+            pulse.timer = state.read_u16()?;
+            pulse.step = state.read_u8()?;
+            pulse.enabled = state.read_bool()?;
+        }
+        self.sawtooth.rate = state.read_u8()?;
+        self.sawtooth.period = state.read_u16()?;
+        self.sawtooth.timer = state.read_u16()?;
+        self.sawtooth.step = state.read_u8()?;
+        self.sawtooth.accumulator = state.read_u8()?;
+        self.sawtooth.enabled = state.read_bool()?;
+        self.halt = state.read_bool()?;
+        self.shift = state.read_u8()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_pulse_duty() {
+        let mut audio = Vrc6Audio::default();
+        // duty 3 of 16: high for 4 steps
+        audio.write(0x9000, 0b0011_1010);
+        audio.write(0x9001, 0);
+        audio.write(0x9002, 0b1000_0000);
+
+        let levels: Vec<u8> = (0..16)
+            .map(|_| {
+                audio.clock();
+                audio.pulse1.output()
+            })
+            .collect();
+        assert_eq!(levels.iter().filter(|level| **level == 10).count(), 4);
+        assert_eq!(levels.iter().filter(|level| **level == 0).count(), 12);
+    }
+
+    #[test]
+    fn test_sawtooth_ramp() {
+        let mut audio = Vrc6Audio::default();
+        audio.write(0xb000, 36);
+        audio.write(0xb001, 0);
+        audio.write(0xb002, 0b1000_0000);
+
+        let levels: Vec<u8> = (0..14)
+            .map(|_| {
+                audio.clock();
+                audio.sawtooth.output()
+            })
+            .collect();
+        assert_eq!(
+            levels,
+            vec![0, 4, 4, 9, 9, 13, 13, 18, 18, 22, 22, 27, 27, 0]
+        );
+    }
+
+    #[test]
+    fn test_halt() {
+        let mut audio = Vrc6Audio::default();
+        audio.write(0xb000, 36);
+        audio.write(0xb002, 0b1000_0000);
+        audio.write(0x9003, 1);
+        for _ in 0..10 {
+            audio.clock();
+        }
+        assert_eq!(audio.output(), 0.0);
+    }
+}
@@ -0,0 +1,83 @@
+use std::io::{Cursor, Read};
+use zip::ZipArchive;
+
+const ZIP_SIGNATURE: &[u8] = b"PK\x03\x04";
+
+fn has_extension(name: &str, extensions: &[&str]) -> bool {
+    let name = name.to_ascii_lowercase();
+    extensions
+        .iter()
+        .any(|extension| name.ends_with(&format!(".{}", extension)))
+}
+
+/// Takes the first file with one of `extensions` out of a ZIP archive. Anything that
+/// isn't an archive is passed through untouched.
+pub fn unpack(bytes: Vec<u8>, extensions: &[&str]) -> Result<Vec<u8>, String> {
+    if !bytes.starts_with(ZIP_SIGNATURE) {
+        return Ok(bytes);
+    }
+
+    let mut archive = ZipArchive::new(Cursor::new(bytes)).map_err(|e| e.to_string())?;
+    for i in 0..archive.len() {
+        let mut file = archive.by_index(i).map_err(|e| e.to_string())?;
+        if file.is_file() && has_extension(file.name(), extensions) {
+            let mut contents = Vec::with_capacity(file.size() as usize);
+            file.read_to_end(&mut contents)
+                .map_err(|e| format!("Can't unzip {}: {}", file.name(), e))?;
+            return Ok(contents);
+        }
+    }
+    let wanted: Vec<String> = extensions.iter().map(|e| format!(".{}", e)).collect();
+    Err(format!(
+        "The archive has no {} file in it",
+        wanted.join(" or ")
+    ))
+}
+
+/// Reads a game from disk, from inside a ZIP archive if it's in one.
+pub fn read_game(path: &str, extensions: &[&str]) -> Result<Vec<u8>, String> {
+    let bytes = std::fs::read(path).map_err(|e| e.to_string())?;
+    unpack(bytes, extensions)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Write;
+    use zip::write::{FileOptions, ZipWriter};
+    use zip::CompressionMethod;
+
+    fn zip(files: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut writer = ZipWriter::new(Cursor::new(Vec::new()));
+        let options = FileOptions::default().compression_method(CompressionMethod::Deflated);
+        for (name, contents) in files {
+            writer.start_file(*name, options).unwrap();
+            writer.write_all(contents).unwrap();
+        }
+        writer.finish().unwrap().into_inner()
+    }
+
+    #[test]
+    fn test_finds_the_game() {
+        let archive = zip(&[
+            ("readme.txt", b"hello"),
+            ("Game (USA).NES", b"NES\x1a first"),
+            ("other.nes", b"NES\x1a second"),
+        ]);
+        assert_eq!(unpack(archive, &["nes"]).unwrap(), b"NES\x1a first");
+    }
+
+    #[test]
+    fn test_passes_other_files_through() {
+        assert_eq!(unpack(b"NES\x1a".to_vec(), &["nes"]).unwrap(), b"NES\x1a");
+    }
+
+    #[test]
+    fn test_no_game_in_archive() {
+        let archive = zip(&[("readme.txt", b"hello")]);
+        assert_eq!(
+            unpack(archive, &["fds", "nsf"]),
+            Err("The archive has no .fds or .nsf file in it".to_string())
+        );
+    }
+}
@@ -0,0 +1,276 @@
+use crate::cpu::AddressingMode;
+use crate::opcodes::{OpCode, CPU_OPS_CODES};
+use std::collections::HashMap;
+
+/// Where `CPU::load` puts programs, and so where `assemble` assembles them for.
+pub const LOAD_ADDRESS: u16 = 0x0600;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Index {
+    None,
+    X,
+    Y,
+}
+
+#[derive(Debug, PartialEq)]
+enum Operand {
+    Implied,
+    Immediate(u16),
+    // an address, and whether it has to be 16 bits
+    Direct(u16, bool, Index),
+    Indirect(u16),
+    IndirectX(u16),
+    IndirectY(u16),
+}
+
+struct Assembler<'a> {
+    labels: HashMap<&'a str, u16>,
+    // the first pass only works out where the labels are
+    resolve: bool,
+}
+
+impl<'a> Assembler<'a> {
+    /// A number in hex with `$`, binary with `%` or decimal, or a label. Also says whether
+    /// it has to be 16 bits: labels always are, as are hex numbers written with 4 digits.
+    fn value(&self, text: &str) -> Result<(u16, bool), String> {
+        let parsed = if let Some(hex) = text.strip_prefix('$') {
+            u16::from_str_radix(hex, 16).map(|n| (n, hex.len() > 2))
+        } else if let Some(binary) = text.strip_prefix('%') {
+            u16::from_str_radix(binary, 2).map(|n| (n, binary.len() > 8))
+        } else if text.starts_with(|c: char| c.is_ascii_digit()) {
+            text.parse().map(|n: u16| (n, n > 0xff))
+        } else if !self.resolve {
+            return Ok((0, true));
+        } else {
+            return match self.labels.get(text) {
+                Some(&addr) => Ok((addr, true)),
+                None => Err(format!("no label called {}", text)),
+            };
+        };
+        parsed.map_err(|_| format!("bad number {}", text))
+    }
+
+    fn byte(&self, text: &str) -> Result<u16, String> {
+        match self.value(text)? {
+            (n, _) if n <= 0xff => Ok(n),
+            _ => Err(format!("{} doesn't fit in a byte", text)),
+        }
+    }
+
+    fn operand(&self, text: &str) -> Result<Operand, String> {
+        let upper = text.to_ascii_uppercase();
+        if text.is_empty() || upper == "A" {
+            return Ok(Operand::Implied);
+        }
+        if let Some(value) = text.strip_prefix('#') {
+            return Ok(Operand::Immediate(self.byte(value)?));
+        }
+        if let Some(inner) = text.strip_prefix('(') {
+            if let Some(value) = upper.strip_suffix(",X)") {
+                return Ok(Operand::IndirectX(self.byte(&inner[..value.len() - 1])?));
+            }
+            if let Some(value) = upper.strip_suffix("),Y") {
+                return Ok(Operand::IndirectY(self.byte(&inner[..value.len() - 1])?));
+            }
+            if let Some(value) = inner.strip_suffix(')') {
+                return Ok(Operand::Indirect(self.value(value)?.0));
+            }
+            return Err(format!("bad operand {}", text));
+        }
+        let (value, index) = if upper.ends_with(",X") {
+            (&text[..text.len() - 2], Index::X)
+        } else if upper.ends_with(",Y") {
+            (&text[..text.len() - 2], Index::Y)
+        } else {
+            (text, Index::None)
+        };
+        let (value, wide) = self.value(value)?;
+        Ok(Operand::Direct(value, wide, index))
+    }
+
+    fn instruction(&self, mnemonic: &str, operand: &str, pc: u16) -> Result<Vec<u8>, String> {
+        let find = |matches: &dyn Fn(&OpCode) -> bool| {
+            CPU_OPS_CODES
+                .iter()
+                .find(|op| op.mnemonic == mnemonic && matches(op))
+        };
+        let mode = |mode: AddressingMode| {
+            move |op: &OpCode| std::mem::discriminant(&op.mode) == std::mem::discriminant(&mode)
+        };
+        if find(&|_| true).is_none() {
+            return Err(format!("unknown instruction {}", mnemonic));
+        }
+
+        let bad_operand = || format!("{} can't take {}", mnemonic, operand);
+        let (op, value) = match self.operand(operand)? {
+            Operand::Implied => (find(&|op| op.len == 1), 0),
+            Operand::Immediate(value) => (find(&mode(AddressingMode::Immediate)), value),
+            Operand::Indirect(value) => (find(&|op| op.code == 0x6c), value),
+            Operand::IndirectX(value) => (find(&mode(AddressingMode::Indirect_X)), value),
+            Operand::IndirectY(value) => (find(&mode(AddressingMode::Indirect_Y)), value),
+            Operand::Direct(target, _, Index::None)
+                if find(&|op| op.len == 2 && matches!(op.mode, AddressingMode::NoneAddressing))
+                    .is_some() =>
+            {
+                // branches are relative to the instruction after them
+                let offset = target.wrapping_sub(pc.wrapping_add(2)) as i16;
+                if self.resolve && !(-128..=127).contains(&offset) {
+                    return Err(format!("{} is too far away to branch to", operand));
+                }
+                let op = find(&|_| true).unwrap();
+                return Ok(vec![op.code, offset as u8]);
+            }
+            Operand::Direct(value, wide, index) => {
+                let (zero_page, absolute) = match index {
+                    Index::None => (AddressingMode::ZeroPage, AddressingMode::Absolute),
+                    Index::X => (AddressingMode::ZeroPage_X, AddressingMode::Absolute_X),
+                    Index::Y => (AddressingMode::ZeroPage_Y, AddressingMode::Absolute_Y),
+                };
+                let op = if wide { None } else { find(&mode(zero_page)) }
+                    .or_else(|| find(&mode(absolute)))
+                    // JMP and JSR
+                    .or_else(|| match index {
+                        Index::None => find(&|op| op.len == 3 && op.code != 0x6c),
+                        _ => None,
+                    });
+                (op, value)
+            }
+        };
+
+        let op = op.ok_or_else(bad_operand)?;
+        let mut bytes = vec![op.code];
+        bytes.extend(&value.to_le_bytes()[..op.len as usize - 1]);
+        Ok(bytes)
+    }
+
+    fn line(&mut self, line: &'a str, pc: u16) -> Result<Vec<u8>, String> {
+        let mut line = line.split(';').next().unwrap_or_default().trim();
+        if let Some((label, rest)) = line.split_once(':') {
+            let label = label.trim();
+            if !self.resolve && self.labels.insert(label, pc).is_some() {
+                return Err(format!("label {} is defined twice", label));
+            }
+            line = rest.trim();
+        }
+        if line.is_empty() {
+            return Ok(vec![]);
+        }
+
+        let (mnemonic, operand) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+        let operand: String = operand.split_whitespace().collect();
+        let mnemonic = mnemonic.to_ascii_uppercase();
+        if mnemonic == ".BYTE" {
+            return operand
+                .split(',')
+                .map(|value| self.byte(value).map(|byte| byte as u8))
+                .collect();
+        }
+        self.instruction(&mnemonic, &operand, pc)
+    }
+
+    fn pass(&mut self, origin: u16, source: &'a str) -> Result<Vec<u8>, String> {
+        let mut program = Vec::new();
+        for (number, line) in source.lines().enumerate() {
+            let pc = origin.wrapping_add(program.len() as u16);
+            let bytes = self
+                .line(line, pc)
+                .map_err(|e| format!("Line {}: {}", number + 1, e))?;
+            program.extend(bytes);
+        }
+        Ok(program)
+    }
+}
+
+/// Assembles a 6502 program to run from `origin`. Takes one instruction per line, with
+/// official opcodes only, `label:`s to jump and branch to, `.byte` for data and `;`
+/// comments. Operands are written the usual way: `#$01`, `$10,X`, `($20),Y` and so on,
+/// and use zero page addressing whenever the address fits in a byte.
+pub fn assemble_at(origin: u16, source: &str) -> Result<Vec<u8>, String> {
+    let mut assembler = Assembler {
+        labels: HashMap::new(),
+        resolve: false,
+    };
+    assembler.pass(origin, source)?;
+    assembler.resolve = true;
+    assembler.pass(origin, source)
+}
+
+/// Assembles a program for `CPU::load`, like
+/// `assemble("LDA #$01\nSTA $0200\nBRK")`.
+pub fn assemble(source: &str) -> Result<Vec<u8>, String> {
+    assemble_at(LOAD_ADDRESS, source)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_addressing_modes() {
+        let program = assemble(
+            "LDA #$01
+             sta $0200
+             STA $02 ; zero page
+             lda $10,x
+             LDX $10,Y
+             LDA $1234,Y
+             ORA ($20,X)
+             ORA ($20),Y
+             JMP ($0300)
+             ASL A
+             ASL
+             LDA #%101
+             LDY #10
+             .byte $de, $ad",
+        )
+        .unwrap();
+        assert_eq!(
+            program,
+            vec![
+                0xa9, 0x01, 0x8d, 0x00, 0x02, 0x85, 0x02, 0xb5, 0x10, 0xb6, 0x10, 0xb9, 0x34, 0x12,
+                0x01, 0x20, 0x11, 0x20, 0x6c, 0x00, 0x03, 0x0a, 0x0a, 0xa9, 0x05, 0xa0, 0x0a, 0xde,
+                0xad,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_labels() {
+        let program = assemble(
+            "start: LDX #$03
+             loop:
+               DEX
+               BNE loop
+               JSR end
+               JMP start
+             end: RTS",
+        )
+        .unwrap();
+        assert_eq!(
+            program,
+            vec![0xa2, 0x03, 0xca, 0xd0, 0xfd, 0x20, 0x0b, 0x06, 0x4c, 0x00, 0x06, 0x60]
+        );
+    }
+
+    #[test]
+    fn test_errors() {
+        assert_eq!(
+            assemble("NOP\nFOO"),
+            Err("Line 2: unknown instruction FOO".to_string())
+        );
+        assert_eq!(
+            assemble("STA #$01"),
+            Err("Line 1: STA can't take #$01".to_string())
+        );
+        assert_eq!(
+            assemble("LDA #$100").unwrap_err(),
+            "Line 1: $100 doesn't fit in a byte"
+        );
+        assert_eq!(
+            assemble("BNE nowhere").unwrap_err(),
+            "Line 1: no label called nowhere"
+        );
+        let far = format!("here: {}\nBNE here", "NOP\n".repeat(200));
+        assert!(assemble(&far).unwrap_err().contains("too far away"));
+    }
+}
@@ -0,0 +1,67 @@
+use crate::apu::filter::SAMPLE_RATE;
+use crate::audio::{AudioSink, DeviceBuffer};
+use ::cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+use ::cpal::{OutputCallbackInfo, SampleFormat, SampleRate, Stream};
+
+/// Plays the sound through cpal, for frontends that don't use SDL. The samples go
+/// through a `DeviceBuffer` the device's callback drains, on every channel it has.
+pub struct CpalOutput {
+    buffer: DeviceBuffer,
+    // the sound stops when this is dropped
+    _stream: Stream,
+}
+
+impl CpalOutput {
+    /// Opens the default device and starts it playing, with room for `max_samples`
+    /// between the emulator and the device.
+    pub fn open(max_samples: usize) -> Result<CpalOutput, String> {
+        let device = ::cpal::default_host()
+            .default_output_device()
+            .ok_or_else(|| "There's no audio device".to_string())?;
+        let config = device
+            .supported_output_configs()
+            .map_err(|e| e.to_string())?
+            .find(|config| {
+                config.sample_format() == SampleFormat::F32
+                    && config.min_sample_rate().0 <= SAMPLE_RATE
+                    && config.max_sample_rate().0 >= SAMPLE_RATE
+            })
+            .ok_or_else(|| format!("The device can't play {}Hz float samples", SAMPLE_RATE))?
+            .with_sample_rate(SampleRate(SAMPLE_RATE))
+            .config();
+
+        let buffer = DeviceBuffer::new(max_samples);
+        let device_buffer = buffer.clone();
+        let channels = config.channels as usize;
+        let stream = device
+            .build_output_stream(
+                &config,
+                move |out: &mut [f32], _: &OutputCallbackInfo| {
+                    device_buffer.fill_channels(out, channels)
+                },
+                |e| eprintln!("Audio: {}", e),
+                None,
+            )
+            .map_err(|e| e.to_string())?;
+        stream.play().map_err(|e| e.to_string())?;
+        Ok(CpalOutput {
+            buffer,
+            _stream: stream,
+        })
+    }
+
+    /// Where the samples go, for the thread running the emulator.
+    pub fn buffer(&self) -> DeviceBuffer {
+        self.buffer.clone()
+    }
+}
+
+impl AudioSink for CpalOutput {
+    fn write(&mut self, samples: &[f32]) {
+        self.buffer.write(samples);
+    }
+
+    fn fill_level(&self) -> Option<f32> {
+        self.buffer.fill_level()
+    }
+}
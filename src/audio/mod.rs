@@ -0,0 +1,66 @@
+use crate::apu::filter::SampleBuffer;
+use std::sync::{Arc, Mutex};
+
+#[cfg(feature = "cpal")]
+pub mod cpal;
+#[cfg(feature = "sdl")]
+pub mod sdl;
+
+/// Somewhere for the sound to go, usually the sound card. The APU writes the samples it
+/// made once per frame, mono at `SAMPLE_RATE`.
+pub trait AudioSink {
+    fn write(&mut self, samples: &[f32]);
+
+    /// How full the device's buffer is, from 0 to 1, for sinks that want the sample rate
+    /// adjusted to keep it half full. Sinks drained at their own pace, like a recorder or
+    /// a frontend with its own audio sync, leave it at `None`.
+    fn fill_level(&self) -> Option<f32> {
+        None
+    }
+}
+
+/// A buffer shared with something that drains it, like an audio callback or a recorder.
+impl AudioSink for Arc<Mutex<SampleBuffer>> {
+    fn write(&mut self, samples: &[f32]) {
+        self.lock().unwrap().extend(samples);
+    }
+}
+
+/// The buffer between the emulator and a device that drains it on the device's own
+/// thread. Unlike the device, it can be sent to whichever thread runs the emulator.
+#[derive(Clone)]
+pub struct DeviceBuffer(Arc<Mutex<SampleBuffer>>);
+
+impl DeviceBuffer {
+    pub fn new(max_samples: usize) -> Self {
+        DeviceBuffer(Arc::new(Mutex::new(SampleBuffer::new(max_samples))))
+    }
+
+    /// Fills `out` for the device's callback, padding with the last sample on underrun.
+    pub fn fill(&self, out: &mut [f32]) {
+        self.0.lock().unwrap().fill(out);
+    }
+
+    /// Fills `out` for a device with `channels` interleaved channels, the same sample on
+    /// each.
+    pub fn fill_channels(&self, out: &mut [f32], channels: usize) {
+        let mut buffer = self.0.lock().unwrap();
+        for frame in out.chunks_mut(channels) {
+            let sample = buffer.next_sample();
+            for out in frame.iter_mut() {
+                *out = sample;
+            }
+        }
+    }
+}
+
+impl AudioSink for DeviceBuffer {
+    fn write(&mut self, samples: &[f32]) {
+        self.0.lock().unwrap().extend(samples);
+    }
+
+    fn fill_level(&self) -> Option<f32> {
+        let buffer = self.0.lock().unwrap();
+        Some(buffer.len() as f32 / buffer.capacity() as f32)
+    }
+}
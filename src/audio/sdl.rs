@@ -0,0 +1,60 @@
+use crate::apu::filter::SAMPLE_RATE;
+use crate::audio::{AudioSink, DeviceBuffer};
+use sdl2::audio::{AudioCallback, AudioDevice, AudioSpecDesired};
+use sdl2::AudioSubsystem;
+
+struct Callback(DeviceBuffer);
+
+impl AudioCallback for Callback {
+    type Channel = f32;
+
+    fn callback(&mut self, out: &mut [f32]) {
+        self.0.fill(out);
+    }
+}
+
+/// Plays the sound through SDL, whose audio thread drains a `DeviceBuffer`. The device
+/// has to stay on the thread that opened it, but its buffer can be filled from any.
+pub struct SdlOutput {
+    buffer: DeviceBuffer,
+    // the sound stops when this is dropped
+    _device: AudioDevice<Callback>,
+}
+
+impl SdlOutput {
+    /// Opens the default device and starts it playing, with room for `max_samples`
+    /// between the emulator and the device, so the sound can't fall far behind the
+    /// picture.
+    pub fn open(audio: &AudioSubsystem, max_samples: usize) -> Result<SdlOutput, String> {
+        let spec = AudioSpecDesired {
+            freq: Some(SAMPLE_RATE as i32),
+            channels: Some(1),
+            samples: Some(1024),
+        };
+        let buffer = DeviceBuffer::new(max_samples);
+        let device = audio.open_playback(None, &spec, |_| Callback(buffer.clone()))?;
+        if device.spec().freq != SAMPLE_RATE as i32 {
+            return Err(format!("The device plays at {}Hz", device.spec().freq));
+        }
+        device.resume();
+        Ok(SdlOutput {
+            buffer,
+            _device: device,
+        })
+    }
+
+    /// Where the samples go, for the thread running the emulator.
+    pub fn buffer(&self) -> DeviceBuffer {
+        self.buffer.clone()
+    }
+}
+
+impl AudioSink for SdlOutput {
+    fn write(&mut self, samples: &[f32]) {
+        self.buffer.write(samples);
+    }
+
+    fn fill_level(&self) -> Option<f32> {
+        self.buffer.fill_level()
+    }
+}
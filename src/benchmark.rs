@@ -0,0 +1,179 @@
+use crate::bus::Bus;
+use crate::cpu::{CpuState, Mem, CPU};
+use crate::error::NesError;
+use crate::event::Event;
+use crate::mapper::SharedMapper;
+use crate::pacer::NTSC_FPS;
+use crate::render::{self, frame::Frame};
+use std::fmt;
+use std::time::{Duration, Instant};
+
+/// Where the time went while running a game flat out for `--bench`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BenchResult {
+    pub frames: usize,
+    pub total: Duration,
+    pub ppu: Duration,
+    pub render: Duration,
+}
+
+impl BenchResult {
+    pub fn fps(&self) -> f64 {
+        self.frames as f64 / self.total.as_secs_f64()
+    }
+
+    /// Everything that isn't the PPU or the renderer: the CPU itself, the APU and the
+    /// cartridge.
+    pub fn cpu(&self) -> Duration {
+        self.total
+            .checked_sub(self.ppu + self.render)
+            .unwrap_or_default()
+    }
+}
+
+impl fmt::Display for BenchResult {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "{} frames in {:.3}s: {:.1} FPS, {:.1}x full speed",
+            self.frames,
+            self.total.as_secs_f64(),
+            self.fps(),
+            self.fps() / NTSC_FPS
+        )?;
+        let total = self.total.as_secs_f64().max(f64::EPSILON);
+        for (name, time) in &[
+            ("CPU/APU", self.cpu()),
+            ("PPU", self.ppu),
+            ("render", self.render),
+        ] {
+            writeln!(
+                f,
+                "  {:<8} {:>8.3}s {:>5.1}%",
+                name,
+                time.as_secs_f64(),
+                100.0 * time.as_secs_f64() / total
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Runs `frames` frames of the game without showing or playing anything, as fast as it
+/// goes. Timing the PPU takes some time of its own, so the split is only a guide.
+pub fn run(mapper: SharedMapper, frames: usize) -> Result<BenchResult, NesError> {
+    let mut render_time = Duration::default();
+    let mut frame = Frame::new();
+    let mut cpu = CPU::new(Bus::with_mapper(mapper));
+    cpu.halt_on_brk = false;
+    cpu.bus.ppu_time = Some(Duration::default());
+    cpu.reset();
+
+    let started = Instant::now();
+    while cpu.bus.frame_count() < frames {
+        match cpu.step() {
+            CpuState::Running | CpuState::Halted => {}
+            CpuState::Jammed => {
+                return Err(NesError::Jammed {
+                    opcode: cpu.mem_peek(cpu.program_counter),
+                    addr: cpu.program_counter,
+                })
+            }
+            CpuState::Error(e) => return Err(e),
+        }
+        while let Some(event) = cpu.bus.poll_event() {
+            if event == Event::FrameComplete {
+                let started = Instant::now();
+                render::render(&cpu.bus.ppu, &mut frame);
+                render_time += started.elapsed();
+            }
+        }
+    }
+
+    Ok(BenchResult {
+        frames,
+        total: started.elapsed(),
+        ppu: cpu.bus.ppu_time.unwrap_or_default(),
+        render: render_time,
+    })
+}
+
+/// Runs `frames` frames of the game like `run`, handing the number and hash of each frame
+/// to `each` as it's drawn. Two runs of the same game draw the same frames, so the hashes
+/// of a long run can be kept to compare later runs against.
+pub fn hash_frames(
+    mapper: SharedMapper,
+    frames: usize,
+    mut each: impl FnMut(usize, u32),
+) -> Result<(), NesError> {
+    let mut frame = Frame::new();
+    let mut cpu = CPU::new(Bus::with_mapper(mapper));
+    cpu.halt_on_brk = false;
+    cpu.reset();
+
+    while cpu.bus.frame_count() < frames {
+        match cpu.step() {
+            CpuState::Running | CpuState::Halted => {}
+            CpuState::Jammed => {
+                return Err(NesError::Jammed {
+                    opcode: cpu.mem_peek(cpu.program_counter),
+                    addr: cpu.program_counter,
+                })
+            }
+            CpuState::Error(e) => return Err(e),
+        }
+        while let Some(event) = cpu.bus.poll_event() {
+            if event == Event::FrameComplete {
+                render::render(&cpu.bus.ppu, &mut frame);
+                each(cpu.bus.frame_count(), frame.hash());
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::mapper;
+    use crate::rom::Mirroring;
+
+    #[test]
+    fn test_runs_the_frames() {
+        let result = run(mapper::blank(Mirroring::Horizontal, false), 3).unwrap();
+        assert_eq!(result.frames, 3);
+        assert!(result.ppu > Duration::default());
+        assert!(result.render > Duration::default());
+        assert!(result.cpu() + result.ppu + result.render <= result.total);
+    }
+
+    #[test]
+    fn test_hashes_every_frame() {
+        let hashes = || {
+            let mut hashes = Vec::new();
+            let mapper = mapper::blank(Mirroring::Horizontal, false);
+            hash_frames(mapper, 3, |n, hash| hashes.push((n, hash))).unwrap();
+            hashes
+        };
+        let first = hashes();
+        assert_eq!(first.iter().map(|(n, _)| *n).collect::<Vec<_>>(), [1, 2, 3]);
+        assert_eq!(hashes(), first);
+    }
+
+    #[test]
+    fn test_report() {
+        let result = BenchResult {
+            frames: 120,
+            total: Duration::from_secs(1),
+            ppu: Duration::from_millis(250),
+            render: Duration::from_millis(100),
+        };
+        assert_eq!(
+            result.to_string(),
+            "120 frames in 1.000s: 120.0 FPS, 2.0x full speed\n\
+             \x20 CPU/APU     0.650s  65.0%\n\
+             \x20 PPU         0.250s  25.0%\n\
+             \x20 render      0.100s  10.0%\n"
+        );
+    }
+}
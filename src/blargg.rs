@@ -0,0 +1,171 @@
+//! Runs blargg's test ROMs headlessly. They aren't part of the repository, so the tests
+//! only run when `BLARGG_ROMS` points at a checkout of the usual test ROM collection,
+//! as in `BLARGG_ROMS=~/nes-test-roms cargo test blargg`.
+//!
+//! Each suite runs every .nes file in its directory. ROMs for boards we don't emulate yet
+//! are skipped and listed, rather than failed, but a suite where none of them ran fails,
+//! as does one whose directory is missing. The instr_test-v5, cpu_interrupts_v2 and
+//! apu_test singles are MMC1 boards, so those suites can't pass until MMC1 is emulated.
+
+use crate::bus::Bus;
+use crate::cpu::{CpuState, Mem, CPU};
+use crate::error::NesError;
+use crate::rom::{Rom, RomError};
+use std::ffi::OsStr;
+use std::path::{Path, PathBuf};
+
+const ROMS_VAR: &str = "BLARGG_ROMS";
+
+// Status byte at $6000, followed by a signature that says the ROM is using the protocol
+const STATUS: u16 = 0x6000;
+const SIGNATURE: [u8; 3] = [0xde, 0xb0, 0x61];
+const RUNNING: u8 = 0x80;
+const NEEDS_RESET: u8 = 0x81;
+const TEXT: u16 = 0x6004;
+
+// The 2005 sprite hit tests predate the $6000 protocol and leave their result in $F8
+const LEGACY_RESULT: u16 = 0x00f8;
+
+// The ROM asks for the reset to come at least 100ms after it raises the flag
+const RESET_DELAY: usize = 1_789_773 / 5;
+const TIMEOUT: usize = 1_789_773 * 60;
+
+enum Outcome {
+    Passed,
+    Failed(String),
+    Skipped(String),
+}
+
+fn run_rom(path: &Path, legacy: bool) -> Outcome {
+    let raw = match std::fs::read(path) {
+        Ok(raw) => raw,
+        Err(e) => return Outcome::Failed(e.to_string()),
+    };
+    // only boards we don't emulate are skipped, a file that doesn't load is a failure
+    let bus = match Rom::new(&raw).map_err(NesError::from).and_then(Bus::new) {
+        Ok(bus) => bus,
+        Err(e @ NesError::Rom(RomError::UnsupportedMapper { .. }))
+        | Err(e @ NesError::UnsupportedMapper(_)) => return Outcome::Skipped(e.to_string()),
+        Err(e) => return Outcome::Failed(e.to_string()),
+    };
+    let mut cpu = CPU::new(bus);
+    cpu.halt_on_brk = false;
+    cpu.reset();
+
+    let mut reset_at = None;
+    while cpu.bus.cycles() < TIMEOUT {
+        match cpu.step() {
+            CpuState::Running => {}
+            state => return Outcome::Failed(format!("CPU stopped: {:?}", state)),
+        }
+
+        if legacy {
+            // $F8 starts at 0 and the test writes its result code once it's done
+            let result = cpu.mem_peek(LEGACY_RESULT);
+            if result == 1 {
+                return Outcome::Passed;
+            } else if result > 1 {
+                return Outcome::Failed(format!("result code {}", result));
+            }
+            continue;
+        }
+
+        let signature = [
+            cpu.mem_peek(STATUS + 1),
+            cpu.mem_peek(STATUS + 2),
+            cpu.mem_peek(STATUS + 3),
+        ];
+        if signature != SIGNATURE {
+            continue;
+        }
+        match cpu.mem_peek(STATUS) {
+            RUNNING => {}
+            NEEDS_RESET => match reset_at {
+                Some(at) if cpu.bus.cycles() >= at => {
+                    reset_at = None;
+                    cpu.soft_reset();
+                }
+                Some(_) => {}
+                None => reset_at = Some(cpu.bus.cycles() + RESET_DELAY),
+            },
+            0 => return Outcome::Passed,
+            code => return Outcome::Failed(format!("{}: {}", code, read_text(&cpu))),
+        }
+    }
+    Outcome::Failed("timed out".to_string())
+}
+
+fn read_text(cpu: &CPU) -> String {
+    let mut text = String::new();
+    let mut addr = TEXT;
+    while addr < 0x7fff {
+        match cpu.mem_peek(addr) {
+            0 => break,
+            c => text.push(c as char),
+        }
+        addr += 1;
+    }
+    text.trim().to_string()
+}
+
+fn run_suite(dir: &str, legacy: bool) {
+    let root = match std::env::var_os(ROMS_VAR) {
+        Some(root) => PathBuf::from(root),
+        None => {
+            eprintln!("{} is not set, skipping {}", ROMS_VAR, dir);
+            return;
+        }
+    };
+    let entries = std::fs::read_dir(root.join(dir))
+        .unwrap_or_else(|e| panic!("Can't read {} in {}: {}", dir, ROMS_VAR, e));
+    let mut roms: Vec<PathBuf> = entries
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.extension() == Some(OsStr::new("nes")))
+        .collect();
+    roms.sort();
+
+    let mut failures = vec![];
+    let mut ran = 0;
+    for rom in roms.iter() {
+        let name = rom.file_name().unwrap().to_string_lossy();
+        match run_rom(rom, legacy) {
+            Outcome::Passed => {
+                eprintln!("{}: passed", name);
+                ran += 1;
+            }
+            Outcome::Skipped(reason) => eprintln!("{}: skipped, {}", name, reason),
+            Outcome::Failed(reason) => {
+                failures.push(format!("{}: {}", name, reason));
+                ran += 1;
+            }
+        }
+    }
+    assert!(failures.is_empty(), "\n{}", failures.join("\n"));
+    // a suite that skipped everything tested nothing
+    assert!(ran > 0, "None of the {} ROMs in {} ran", roms.len(), dir);
+}
+
+#[test]
+fn test_blargg_cpu() {
+    run_suite("instr_test-v5/rom_singles", false);
+}
+
+#[test]
+fn test_blargg_cpu_interrupts() {
+    run_suite("cpu_interrupts_v2/rom_singles", false);
+}
+
+#[test]
+fn test_blargg_ppu_vbl_nmi() {
+    run_suite("ppu_vbl_nmi/rom_singles", false);
+}
+
+#[test]
+fn test_blargg_sprite_hit() {
+    run_suite("sprite_hit_tests_2005.10.05", true);
+}
+
+#[test]
+fn test_blargg_apu() {
+    run_suite("apu_test/rom_singles", false);
+}
@@ -1,97 +1,618 @@
-use crate::joypad::Joypad;
+use crate::apu::Apu;
+use crate::cdl::CodeDataLog;
+use crate::cheats::Cheats;
+use crate::cpu::Mem;
+use crate::error::NesError;
+use crate::event::{Event, EventQueue};
+use crate::joypad::{FourScore, Joypad};
+use crate::mapper::{self, SharedMapper};
 use crate::ppu::{NesPPU, PPU};
-use crate::rom::Rom;
-use crate::Mem;
+use crate::ram_init::{RamInit, Rng};
+use crate::render::palette::PaletteKind;
+use crate::rom::{Rom, System};
+use crate::savestate::{Savestate, StateReader, StateWriter};
+use crate::symbols::Symbols;
+use crate::trace::{AccessLog, InterruptLog};
+use crate::vs::VsSystem;
+use crate::zapper::Zapper;
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
 
 const RAM: u16 = 0x0000;
 const RAM_MIRRORS_END: u16 = 0x1fff;
 const PPU_REGISTERS_MIRRORS_END: u16 = 0x3fff;
 
-pub struct Bus<'call> {
+// Everything is derived from the NTSC master clock (21.477272 MHz): the CPU, the APU and
+// the cartridge run at a twelfth of it, the PPU at a quarter
+pub const CPU_DIVIDER: usize = 12;
+pub const PPU_DIVIDER: usize = 4;
+
+/// Where the PPU is relative to the CPU when the console is switched on. The real thing
+/// comes up with the PPU 0, 1 or 2 dots further along at random, which timing tests and
+/// some raster effects can tell apart.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Alignment {
+    Dots(u8),
+    /// Picked with the console's seed at every power on.
+    Random,
+}
+
+impl Alignment {
+    /// `0`, `1`, `2` or `random`.
+    pub fn parse(name: &str) -> Result<Alignment, String> {
+        match name {
+            "0" | "1" | "2" => Ok(Alignment::Dots(name.parse().unwrap())),
+            "random" => Ok(Alignment::Random),
+            _ => Err(format!(
+                "Unknown alignment {}, expected 0, 1, 2 or random",
+                name
+            )),
+        }
+    }
+}
+
+bitflags! {
+    /// Everything that can pull the CPU's IRQ line down. The line is down while any of
+    /// them is asserted, and each stays asserted until it's acknowledged, so one source
+    /// being dealt with doesn't hide another raised at the same time.
+    pub struct IrqSource: u8 {
+        const APU_FRAME = 0b0000_0001;
+        const DMC = 0b0000_0010;
+        /// Follows the board's own IRQ line, which the game acknowledges through the
+        /// board's registers.
+        const MAPPER = 0b0000_0100;
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Watchpoint {
+    pub addr: u16,
+    pub access: Access,
+}
+
+pub struct Bus {
     cpu_vram: [u8; 2048],
-    prg_rom: Vec<u8>,
-    ppu: NesPPU,
+    ram_init: RamInit,
+    seed: u64,
+    rng: Rng,
+    alignment: Alignment,
+    pub mapper: SharedMapper,
+    pub ppu: NesPPU,
+    pub apu: Apu,
     joypad1: Joypad,
+    pub joypad2: Joypad,
+    pub four_score: Option<FourScore>,
+    pub zapper: Option<Zapper>,
+    /// The coin slots and DIP switches of a VS System cabinet.
+    pub vs: Option<VsSystem>,
+
+    master_cycles: usize,
+    frames: usize,
+    // last value driven on the CPU data bus, returned by reads from unmapped addresses
+    open_bus: u8,
+    events: EventQueue,
+    irq_sources: IrqSource,
+    // set while the DMC waits to halt the CPU for a sample byte, counting the CPU cycles
+    // gone by since it asked
+    dmc_dma: Option<u8>,
+    // the IRQ line as the CPU last saw it, to raise an event when it goes up
+    irq_line: bool,
+    /// Also raises an event whenever the PPU starts a new scanline, for stepping through a
+    /// frame.
+    pub scanline_break: bool,
+    /// Set while frames are run ahead, which are neither shown nor heard: no events are
+    /// raised and the sound is thrown away.
+    pub running_ahead: bool,
+    /// When set, the time spent running the PPU is added up here, for benchmarking.
+    pub ppu_time: Option<Duration>,
 
-    cycles: usize,
-    gameloop_callback: Box<dyn FnMut(&NesPPU, &mut Joypad) + 'call>,
+    pub cheats: Cheats,
+    pub watchpoints: Vec<Watchpoint>,
+    watchpoint_hit: Option<(Watchpoint, u8)>,
+    /// Where the instruction the CPU is running started, for the access log.
+    pub instruction_pc: u16,
+    pub access_log: Option<AccessLog>,
+    pub interrupt_log: Option<InterruptLog>,
+    pub symbols: Symbols,
+    /// When set, the CPU marks the PRG ROM it runs and reads here.
+    pub cdl: Option<Rc<RefCell<CodeDataLog>>>,
 }
 
-impl<'a> Bus<'a> {
-    pub fn new<'call, F>(rom: Rom, gameloop_callback: F) -> Bus<'call>
-    where
-        F: FnMut(&NesPPU, &mut Joypad) + 'call,
-    {
-        let ppu = NesPPU::new(rom.chr_rom, rom.screen_mirroring);
+impl Bus {
+    pub fn new(rom: Rom) -> Result<Bus, NesError> {
+        let vs = rom.system == System::VsSystem;
+        let mut bus = Bus::with_mapper(mapper::from_rom(rom)?);
+        if vs {
+            bus.attach_vs_system(0);
+        }
+        Ok(bus)
+    }
+
+    /// For boards that don't come from an iNES file, like the Famicom Disk System.
+    pub fn with_mapper(mapper: SharedMapper) -> Bus {
+        let ppu = NesPPU::new(mapper.clone());
 
         Bus {
             cpu_vram: [0; 2048],
-            prg_rom: rom.prg_rom,
+            ram_init: RamInit::default(),
+            seed: 0,
+            rng: Rng::new(0),
+            alignment: Alignment::Dots(0),
+            mapper,
             ppu,
+            apu: Apu::new(),
             joypad1: Joypad::new(),
-            cycles: 0,
-            gameloop_callback: Box::from(gameloop_callback),
+            joypad2: Joypad::new(),
+            four_score: None,
+            zapper: None,
+            vs: None,
+            master_cycles: 0,
+            frames: 0,
+            open_bus: 0,
+            events: EventQueue::new(),
+            irq_sources: IrqSource::empty(),
+            dmc_dma: None,
+            irq_line: false,
+            cheats: Cheats::new(),
+            watchpoints: Vec::new(),
+            watchpoint_hit: None,
+            instruction_pc: 0,
+            access_log: None,
+            interrupt_log: None,
+            symbols: Symbols::new(),
+            cdl: None,
+            scanline_break: false,
+            running_ahead: false,
+            ppu_time: None,
         }
     }
 
+    /// Runs the rest of the console for the given number of CPU cycles.
     pub fn tick(&mut self, cycles: u8) {
-        self.cycles += cycles as usize;
-        let new_frame = self.ppu.tick(cycles * 3);
-        if new_frame {
-            (self.gameloop_callback)(&self.ppu, &mut self.joypad1);
+        for _ in 0..cycles {
+            for _ in 0..CPU_DIVIDER / PPU_DIVIDER {
+                self.master_cycles += PPU_DIVIDER;
+                let scanline = self.ppu.scanline;
+                let started = self.ppu_time.map(|_| Instant::now());
+                let frame_done = self.ppu.tick(1);
+                if let (Some(time), Some(started)) = (self.ppu_time.as_mut(), started) {
+                    *time += started.elapsed();
+                }
+                if frame_done {
+                    self.frames += 1;
+                    self.clock_turbo();
+                    if let Some(vs) = &mut self.vs {
+                        vs.clock_frame();
+                    }
+                    if self.running_ahead {
+                        self.apu.discard_samples();
+                    } else {
+                        let samples = self.apu.flush_samples();
+                        self.events.push(Event::AudioSamples(samples));
+                        self.events.push(Event::FrameComplete);
+                    }
+                } else if self.scanline_break
+                    && !self.running_ahead
+                    && self.ppu.scanline != scanline
+                {
+                    self.events.push(Event::Scanline(self.ppu.scanline));
+                }
+            }
+            self.clock_cpu_side();
+            match self.dmc_dma.as_mut() {
+                Some(elapsed) => *elapsed = elapsed.saturating_add(1),
+                None if self.apu.dmc.fetch_address().is_some() => self.dmc_dma = Some(0),
+                None => {}
+            }
+        }
+    }
+
+    // The DMC reads its sample bytes from PRG by halting the CPU, which only stops on a
+    // read: it takes a halt and a dummy cycle, one more to line up with the APU if need be,
+    // and the cycle the byte is read on. Writes carry on while the DMA waits, so the CPU
+    // loses anything from 1 to 4 cycles, 4 being the usual. Until the byte comes in the
+    // CPU keeps putting its read on the bus, and registers like $2007 and the joypads see
+    // the reads it repeats.
+    fn run_dmc_dma(&mut self, addr: u16) {
+        let elapsed = self.dmc_dma.unwrap_or(0);
+        if self.apu.dmc.fetch_address().is_none() {
+            // the channel was stopped while the DMA waited
+            self.dmc_dma = None;
+            return;
+        }
+
+        let mut halted = 2u8.saturating_sub(elapsed);
+        if !(self.cycles() + halted as usize).is_multiple_of(2) {
+            halted += 1;
+        }
+        // the joypads see one long read, since their output enable stays low throughout
+        let repeats = match addr {
+            0x4016 | 0x4017 => halted.min(1),
+            _ => halted,
+        };
+        for cycle in 0..halted {
+            if cycle < repeats {
+                self.read(addr);
+            }
+            self.tick(1);
+        }
+        self.fetch_dmc_sample();
+    }
+
+    // The cycle the DMA reads the sample byte on
+    fn fetch_dmc_sample(&mut self) {
+        self.dmc_dma = None;
+        if let Some(addr) = self.apu.dmc.fetch_address() {
+            let data = self.mapper.borrow().read_prg(addr);
+            self.apu.dmc.fill(data);
+            if self.apu.dmc.take_irq() {
+                self.assert_irq(IrqSource::DMC);
+            }
+        }
+        self.tick(1);
+    }
+
+    // OAM DMA halts the CPU for a cycle, plus one to line up when it starts on an odd
+    // cycle, then copies the page a byte at a time: read on one cycle, written to OAM on
+    // the next. A DMC fetch that comes due takes the place of one of the reads, and
+    // another cycle to line up again.
+    fn run_oam_dma(&mut self, page: u8) {
+        let align = self.cycles() % 2;
+        self.tick(1 + align as u8);
+        let start = (page as u16) << 8;
+        for i in 0..256 {
+            if self.dmc_dma.is_some_and(|elapsed| elapsed >= 2) {
+                self.fetch_dmc_sample();
+                self.tick(1);
+            }
+            let data = self.read(start + i);
+            self.tick(1);
+            self.ppu.write_to_oam_data(data);
+            self.tick(1);
+        }
+    }
+
+    /// Turns the console into a VS System, with the RGB PPU and the cabinet's coin slots
+    /// and DIP switches.
+    pub fn attach_vs_system(&mut self, dip_switches: u8) {
+        self.vs = Some(VsSystem::new(dip_switches));
+        self.ppu.palette = PaletteKind::Rgb;
+    }
+
+    // the bits of $4016 and $4017 the controllers don't drive are open bus, unless a VS
+    // System's coin slots and DIP switches are wired to them
+    fn port_bits(&self, port: usize, data: u8) -> u8 {
+        match (&self.vs, port) {
+            (Some(vs), 0) => (self.open_bus & 0b1000_0000) | vs.read_4016() | data,
+            (Some(vs), _) => vs.read_4017() | data,
+            (None, _) => (self.open_bus & 0b1110_0000) | data,
+        }
+    }
+
+    fn clock_turbo(&mut self) {
+        let frame = self.frames;
+        for player in 1..=4 {
+            if let Some(joypad) = self.joypad_mut(player) {
+                joypad.clock_turbo(frame);
+            }
+        }
+    }
+
+    /// What the reset button does to the rest of the console: the PPU and APU are partly
+    /// reset, RAM and the cartridge are left alone.
+    pub fn soft_reset(&mut self) {
+        self.ppu.reset();
+        self.apu.reset();
+        self.dmc_dma = None;
+        self.watchpoint_hit = None;
+    }
+
+    /// Puts RAM, the PPU, the APU and the cartridge registers back to their power on state.
+    pub fn power_cycle(&mut self) {
+        self.ram_init.fill(&mut self.cpu_vram, &mut self.rng);
+        self.open_bus = 0;
+        self.ppu.power_cycle();
+        self.align_ppu();
+        self.apu.power_cycle();
+        self.mapper.borrow_mut().power_cycle();
+        self.irq_sources = IrqSource::empty();
+        self.dmc_dma = None;
+        self.watchpoint_hit = None;
+    }
+
+    /// Fills RAM with `init`, now and on every power cycle. Meant to be called before the
+    /// console starts.
+    pub fn set_ram_init(&mut self, init: RamInit) {
+        self.ram_init = init;
+        init.fill(&mut self.cpu_vram, &mut self.rng);
+    }
+
+    /// Everything the console picks at random comes from `seed`, so two runs from the same
+    /// seed with the same inputs match on any machine. It's 0 until changed, which keeps
+    /// tests and tools deterministic. Meant to be called before the console starts.
+    pub fn set_seed(&mut self, seed: u64) {
+        self.seed = seed;
+        self.rng = Rng::new(seed);
+        self.ram_init.fill(&mut self.cpu_vram, &mut self.rng);
+    }
+
+    /// Restarts the PPU at `alignment`, now and on every power cycle. Meant to be called
+    /// before the console starts.
+    pub fn set_alignment(&mut self, alignment: Alignment) {
+        self.alignment = alignment;
+        self.ppu.power_cycle();
+        self.align_ppu();
+    }
+
+    fn align_ppu(&mut self) {
+        let dots = match self.alignment {
+            Alignment::Dots(dots) => dots % 3,
+            Alignment::Random => (self.rng.next_u64() % 3) as u8,
+        };
+        for _ in 0..dots {
+            self.ppu.tick(1);
         }
     }
 
+    /// The seed behind everything random, for running the same way again with `set_seed`.
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    /// The 2KB of internal RAM, without the mirrors.
+    pub fn ram(&self) -> &[u8; 2048] {
+        &self.cpu_vram
+    }
+
+    /// The controller plugged in for `player` 1-4, if there is one.
+    pub fn joypad_mut(&mut self, player: usize) -> Option<&mut Joypad> {
+        match (player, self.four_score.as_mut()) {
+            (1, _) => Some(&mut self.joypad1),
+            (2, _) => Some(&mut self.joypad2),
+            (3, Some(four_score)) => Some(&mut four_score.joypad3),
+            (4, Some(four_score)) => Some(&mut four_score.joypad4),
+            _ => None,
+        }
+    }
+
+    /// Writes to RAM or cartridge RAM without any of the side effects of a CPU write.
+    /// Returns false for addresses that aren't RAM.
+    pub fn poke(&mut self, addr: u16, data: u8) -> bool {
+        match addr {
+            RAM..=RAM_MIRRORS_END => {
+                self.cpu_vram[(addr & 0b0000_0111_1111_1111) as usize] = data;
+                true
+            }
+            0x6000..=0x7fff => {
+                let mut mapper = self.mapper.borrow_mut();
+                match mapper.prg_ram_mut() {
+                    Some(prg_ram) => prg_ram.poke(addr, data),
+                    None => mapper.write_prg(addr, data),
+                }
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn clock_cpu_side(&mut self) {
+        let expansion_audio = {
+            let mut mapper = self.mapper.borrow_mut();
+            mapper.clock();
+            mapper.audio_output()
+        };
+        self.apu.set_expansion_output(expansion_audio);
+        self.apu.tick();
+        if self.apu.take_frame_irq() {
+            self.assert_irq(IrqSource::APU_FRAME);
+        }
+    }
+
+    // $4015 with the interrupt flags, which live with the IRQ line
+    fn apu_status(&self) -> u8 {
+        let frame_irq = self.irq_sources.contains(IrqSource::APU_FRAME) as u8;
+        let dmc_irq = self.irq_sources.contains(IrqSource::DMC) as u8;
+        (self.open_bus & 0b0010_0000) | dmc_irq << 7 | frame_irq << 6 | self.apu.status()
+    }
+
+    /// Master clock cycles elapsed since power on.
+    pub fn master_cycles(&self) -> usize {
+        self.master_cycles
+    }
+
+    /// CPU cycles elapsed since power on, including cycles stolen by DMA.
+    pub fn cycles(&self) -> usize {
+        self.master_cycles / CPU_DIVIDER
+    }
+
+    /// Number of frames completed since power on. Not part of the savestate, so it keeps
+    /// counting forward even when an older state is loaded.
+    pub fn frame_count(&self) -> usize {
+        self.frames
+    }
+
     pub fn poll_nmi_status(&mut self) -> Option<u8> {
-        self.ppu.poll_nmi_interrupt()
+        let nmi = self.ppu.poll_nmi_interrupt();
+        if nmi.is_some() && !self.running_ahead {
+            self.events.push(Event::Nmi);
+        }
+        nmi
     }
 
-    fn read_prg_rom(&self, mut addr: u16) -> u8 {
-        addr -= 0x8000;
-        if self.prg_rom.len() == 0x4000 && addr >= 0x4000 {
-            // Mirror if needed
-            addr = addr % 0x4000;
+    /// Holds the IRQ line down for `source` until `acknowledge_irq` is called for it.
+    pub fn assert_irq(&mut self, source: IrqSource) {
+        self.irq_sources.insert(source);
+    }
+
+    pub fn acknowledge_irq(&mut self, source: IrqSource) {
+        self.irq_sources.remove(source);
+    }
+
+    /// The sources holding the IRQ line down, as of the last poll for the board's.
+    pub fn irq_sources(&self) -> IrqSource {
+        self.irq_sources
+    }
+
+    /// The IRQ line is level triggered: it stays asserted until every source is
+    /// acknowledged.
+    pub fn poll_irq_status(&mut self) -> bool {
+        let mapper = self.mapper.borrow().irq_pending();
+        self.irq_sources.set(IrqSource::MAPPER, mapper);
+        let line = !self.irq_sources.is_empty();
+        if line && !self.irq_line && !self.running_ahead {
+            self.events.push(Event::Irq);
         }
-        self.prg_rom[addr as usize]
+        self.irq_line = line;
+        line
     }
-}
 
-impl<'a> Mem for Bus<'a> {
-    fn mem_read(&mut self, addr: u16) -> u8 {
+    /// The next thing that happened inside the console that the frontend hasn't seen yet,
+    /// like a frame being finished. Frontends look for them between instructions.
+    pub fn poll_event(&mut self) -> Option<Event> {
+        self.events.poll()
+    }
+
+    /// Returns the first watchpoint triggered since the last call, along with the value accessed.
+    pub fn take_watchpoint_hit(&mut self) -> Option<(Watchpoint, u8)> {
+        self.watchpoint_hit.take()
+    }
+
+    /// Like `take_watchpoint_hit`, but leaves the hit for the next caller.
+    pub fn peek_watchpoint_hit(&self) -> Option<(Watchpoint, u8)> {
+        self.watchpoint_hit
+    }
+
+    fn log_access(&mut self, addr: u16, access: Access, data: u8) {
+        if let Some(log) = self.access_log.as_mut().filter(|log| log.covers(addr)) {
+            let cycles = self.master_cycles / CPU_DIVIDER;
+            if let Err(e) = log.log(cycles, self.instruction_pc, access, addr, data) {
+                eprintln!("Can't write the access log: {}", e);
+                self.access_log = None;
+            }
+        }
+    }
+
+    /// Called as the CPU takes an NMI, or an IRQ, having pushed `pc`.
+    pub fn log_interrupt(&mut self, nmi: bool, pc: u16) {
+        let source = if nmi {
+            "NMI".to_string()
+        } else {
+            format!("IRQ {:?}", self.irq_sources)
+        };
+        let cycles = self.cycles();
+        let ppu = (self.ppu.scanline, self.ppu.dot());
+        if let Some(log) = self.interrupt_log.as_mut() {
+            if let Err(e) = log.log(&source, cycles, ppu, pc) {
+                eprintln!("Can't write the interrupt log: {}", e);
+                self.interrupt_log = None;
+            }
+        }
+    }
+
+    fn check_watchpoints(&mut self, addr: u16, access: Access, data: u8) {
+        if self.watchpoint_hit.is_some() {
+            return;
+        }
+
+        let hit = Watchpoint { addr, access };
+        if self.watchpoints.contains(&hit) {
+            self.watchpoint_hit = Some((hit, data));
+        }
+    }
+
+    fn peek(&self, addr: u16) -> u8 {
         match addr {
             RAM..=RAM_MIRRORS_END => {
                 let mirror_down_addr = addr & 0b0000_0111_1111_1111;
                 self.cpu_vram[mirror_down_addr as usize]
             }
-            0x2000 | 0x2001 | 0x2003 | 0x2005 | 0x2006 | 0x4014 => {
-                //panic!("Attempt to read from write-only PPU address {:x}", addr);
-                0
+            0x2000 | 0x2001 | 0x2003 | 0x2005 | 0x2006 => self.ppu.io_latch(),
+            0x2002 => self.ppu.peek_status(),
+            0x2004 => self.ppu.peek_oam_data(),
+            0x2007 => self.ppu.peek_data(),
+            0x2008..=PPU_REGISTERS_MIRRORS_END => {
+                let mirror_down_addr = addr & 0b00100000_00000111;
+                self.peek(mirror_down_addr)
+            }
+            0x4015 => self.apu_status(),
+            0x4016 => {
+                let data = match &self.four_score {
+                    Some(four_score) => four_score.peek(0, &self.joypad1),
+                    None => self.joypad1.peek(),
+                };
+                self.port_bits(0, data)
+            }
+            0x4017 => {
+                let data = match (&self.zapper, &self.four_score) {
+                    (Some(zapper), _) => zapper.read(self.ppu.scanline),
+                    (None, Some(four_score)) => four_score.peek(1, &self.joypad2),
+                    (None, None) => self.joypad2.peek(),
+                };
+                self.port_bits(1, data)
             }
+            0x4020..=0x5fff => self
+                .mapper
+                .borrow()
+                .peek_expansion(addr)
+                .unwrap_or(self.open_bus),
+            0x6000..=0xffff => self.mapper.borrow().read_prg(addr),
+            _ => self.open_bus,
+        }
+    }
+
+    fn read(&mut self, addr: u16) -> u8 {
+        match addr {
+            RAM..=RAM_MIRRORS_END => {
+                let mirror_down_addr = addr & 0b0000_0111_1111_1111;
+                self.cpu_vram[mirror_down_addr as usize]
+            }
+            0x2000 | 0x2001 | 0x2003 | 0x2005 | 0x2006 => self.ppu.io_latch(),
             0x2002 => self.ppu.read_status(),
             0x2004 => self.ppu.read_oam_data(),
             0x2007 => self.ppu.read_data(),
             0x2008..=PPU_REGISTERS_MIRRORS_END => {
                 let mirror_down_addr = addr & 0b00100000_00000111;
-                self.mem_read(mirror_down_addr)
+                self.read(mirror_down_addr)
             }
-            0x4000..=0x4015 => {
-                //ignore APU
-                0
+            0x4015 => {
+                // reading the status acknowledges the frame IRQ
+                let status = self.apu_status();
+                self.acknowledge_irq(IrqSource::APU_FRAME);
+                status
             }
-            0x4016 => self.joypad1.read(),
-            0x4017 => {
-                // ignore joypad 2
-                0
+            0x4016 => {
+                let data = match &mut self.four_score {
+                    Some(four_score) => four_score.read(0, &self.joypad1),
+                    None => self.joypad1.read(),
+                };
+                self.port_bits(0, data)
             }
-            0x8000..=0xffff => self.read_prg_rom(addr),
-            _ => {
-                println!("Ignoring mem access at {}", addr);
-                0
+            0x4017 => {
+                let data = match (&self.zapper, &mut self.four_score) {
+                    (Some(zapper), _) => zapper.read(self.ppu.scanline),
+                    (None, Some(four_score)) => four_score.read(1, &self.joypad2),
+                    (None, None) => self.joypad2.read(),
+                };
+                self.port_bits(1, data)
             }
+            0x4020..=0x5fff => self
+                .mapper
+                .borrow_mut()
+                .read_expansion(addr)
+                .unwrap_or(self.open_bus),
+            0x6000..=0xffff => self.mapper.borrow().read_prg(addr),
+            _ => self.open_bus,
         }
     }
 
-    fn mem_write(&mut self, addr: u16, data: u8) {
+    fn write(&mut self, addr: u16, data: u8) {
         match addr {
             RAM..=RAM_MIRRORS_END => {
                 let mirror_down_addr = addr & 0b0000_0111_1111_1111;
@@ -103,7 +624,7 @@ impl<'a> Mem for Bus<'a> {
             0x2001 => {
                 self.ppu.write_to_mask(data);
             }
-            0x2002 => panic!("attempt to write to PPU status register"),
+            0x2002 => self.ppu.refresh_io_latch(data),
             0x2003 => {
                 self.ppu.write_to_oam_addr(data);
             }
@@ -121,27 +642,36 @@ impl<'a> Mem for Bus<'a> {
             }
             0x2008..=PPU_REGISTERS_MIRRORS_END => {
                 let mirror_down_addr = addr & 0b00100000_00000111;
-                self.mem_write(mirror_down_addr, data);
+                self.write(mirror_down_addr, data);
             }
-            0x4000..=0x4013 | 0x4015 => {
-                //ignore APU
+            0x4000..=0x4013 => {
+                // turning the DMC's IRQ off acknowledges it
+                if addr == 0x4010 && data & 0b1000_0000 == 0 {
+                    self.acknowledge_irq(IrqSource::DMC);
+                }
+                self.apu.write_register(addr, data);
             }
-            0x4016 => self.joypad1.write(data),
-            0x4017 => {
-                // ignore joypad 2
+            0x4015 => {
+                self.acknowledge_irq(IrqSource::DMC);
+                self.apu.write_register(addr, data);
             }
-            0x4014 => {
-                let mut buffer: [u8; 256] = [0; 256];
-                let hi: u16 = (data as u16) << 8;
-                for i in 0..256u16 {
-                    buffer[i as usize] = self.mem_read(hi + i);
+            0x4017 => {
+                if data & 0b0100_0000 != 0 {
+                    self.acknowledge_irq(IrqSource::APU_FRAME);
                 }
-
-                self.ppu.write_oam_dma(&buffer);
+                self.apu.write_register(addr, data);
             }
-            0x8000..=0xffff => {
-                panic!("Attempted to write to cartidge ROM space!");
+            0x4016 => {
+                self.joypad1.write(data);
+                self.joypad2.write(data);
+                self.mapper.borrow_mut().write_4016(data);
+                if let Some(four_score) = &mut self.four_score {
+                    four_score.write(data, [&self.joypad1, &self.joypad2]);
+                }
             }
+            0x4014 => self.run_oam_dma(data),
+            0x4020..=0x5fff => self.mapper.borrow_mut().write_expansion(addr, data),
+            0x6000..=0xffff => self.mapper.borrow_mut().write_prg(addr, data),
             _ => {
                 println!("Ignoring mem write-access at {}", addr);
             }
@@ -149,15 +679,371 @@ impl<'a> Mem for Bus<'a> {
     }
 }
 
+impl Mem for Bus {
+    fn mem_read(&mut self, addr: u16) -> u8 {
+        if self.dmc_dma.is_some() {
+            self.run_dmc_dma(addr);
+        }
+        let data = self.read(addr);
+        let data = self.cheats.apply(addr, data);
+        self.open_bus = data;
+        self.check_watchpoints(addr, Access::Read, data);
+        self.log_access(addr, Access::Read, data);
+        data
+    }
+
+    fn mem_peek(&self, addr: u16) -> u8 {
+        self.cheats.apply(addr, self.peek(addr))
+    }
+
+    fn mem_write(&mut self, addr: u16, data: u8) {
+        self.check_watchpoints(addr, Access::Write, data);
+        self.log_access(addr, Access::Write, data);
+        self.open_bus = data;
+        self.write(addr, data);
+    }
+
+    fn symbol(&self, addr: u16) -> Option<&str> {
+        if self.symbols.is_empty() {
+            return None;
+        }
+        self.symbols.name(addr, &*self.mapper.borrow())
+    }
+}
+
+impl Savestate for Bus {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_bytes(&self.cpu_vram);
+        state.write_usize(self.master_cycles);
+        state.write_usize(self.frames);
+        state.write_u8(self.open_bus);
+        state.write_u8(self.irq_sources.bits());
+        state.write_bool(self.dmc_dma.is_some());
+        state.write_u8(self.dmc_dma.unwrap_or(0));
+        self.ppu.save_state(state);
+        self.apu.save_state(state);
+        self.mapper.borrow().save_state(state);
+        self.joypad1.save_state(state);
+        self.joypad2.save_state(state);
+        if let Some(four_score) = &self.four_score {
+            four_score.save_state(state);
+        }
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), String> {
+        state.read_bytes(&mut self.cpu_vram)?;
+        self.master_cycles = state.read_usize()?;
+        self.frames = state.read_usize()?;
+        self.open_bus = state.read_u8()?;
+        self.irq_sources = IrqSource::from_bits_truncate(state.read_u8()?);
+        let waiting = state.read_bool()?;
+        let elapsed = state.read_u8()?;
+        self.dmc_dma = Some(elapsed).filter(|_| waiting);
+        self.ppu.load_state(state)?;
+        self.apu.load_state(state)?;
+        self.mapper.borrow_mut().load_state(state)?;
+        self.joypad1.load_state(state)?;
+        self.joypad2.load_state(state)?;
+        match &mut self.four_score {
+            Some(four_score) => four_score.load_state(state),
+            None => Ok(()),
+        }
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::joypad::JoypadButton;
     use crate::rom::test;
 
+    #[test]
+    fn test_seeded_power_on() {
+        let power_on = |seed: u64| {
+            let mut bus = Bus::new(test::test_rom()).unwrap();
+            bus.set_seed(seed);
+            bus.set_ram_init(RamInit::Random(None));
+            let first = *bus.ram();
+            bus.power_cycle();
+            (first, *bus.ram())
+        };
+        let (first, second) = power_on(1);
+        assert_eq!(power_on(1), (first, second));
+        assert_ne!(first, second);
+        assert_ne!(power_on(2).0, first);
+    }
+
+    #[test]
+    fn test_alignment() {
+        let mut bus = Bus::new(test::test_rom()).unwrap();
+        bus.set_alignment(Alignment::Dots(2));
+        assert_eq!(bus.ppu.dot(), 2);
+        bus.tick(1);
+        bus.power_cycle();
+        assert_eq!(bus.ppu.dot(), 2);
+
+        bus.set_alignment(Alignment::Random);
+        assert!(bus.ppu.dot() < 3);
+        assert!(Alignment::parse("3").is_err());
+    }
+
     #[test]
     fn test_mem_read_write_to_ram() {
-        let mut bus = Bus::new(test::test_rom(), |_: &NesPPU, _: &mut Joypad| {});
+        let mut bus = Bus::new(test::test_rom()).unwrap();
         bus.mem_write(0x01, 0x55);
         assert_eq!(bus.mem_read(0x01), 0x55);
     }
+
+    #[test]
+    fn test_unsupported_mapper_is_an_error() {
+        let mut rom = test::test_rom();
+        rom.mapper = 4;
+        let bus = Bus::new(rom);
+        assert_eq!(bus.err(), Some(NesError::UnsupportedMapper(4)));
+    }
+
+    #[test]
+    fn test_unmapped_reads_return_open_bus() {
+        let mut bus = Bus::new(test::test_rom()).unwrap();
+        bus.mem_write(0x01, 0x55);
+        assert_eq!(bus.mem_read(0x01), 0x55);
+        assert_eq!(bus.mem_read(0x5000), 0x55);
+
+        bus.mem_write(0x01, 0xa0);
+        assert_eq!(bus.mem_read(0x4016), 0xa0);
+    }
+
+    #[test]
+    fn test_write_only_ppu_registers_read_the_latch() {
+        let mut bus = Bus::new(test::test_rom()).unwrap();
+        bus.mem_write(0x2003, 0x10);
+        bus.mem_write(0x2004, 0x66);
+        assert_eq!(bus.mem_read(0x2000), 0x66);
+        assert_eq!(bus.mem_read(0x2005), 0x66);
+    }
+
+    #[test]
+    fn test_vs_system_ports() {
+        let mut rom = test::test_rom();
+        rom.mapper = 99;
+        rom.system = System::VsSystem;
+        let mut bus = Bus::new(rom).unwrap();
+        assert_eq!(bus.ppu.palette, PaletteKind::Rgb);
+        bus.vs.as_mut().unwrap().dip_switches = 0b1000_0011;
+        bus.vs.as_mut().unwrap().insert_coin(0);
+
+        bus.mem_write(0x01, 0xff);
+        assert_eq!(bus.mem_read(0x4016), 0b1011_1000);
+        assert_eq!(bus.mem_read(0x4017), 0b1000_0000);
+        // the strobe also switches banks
+        bus.mem_write(0x4016, 0b100);
+        assert_eq!(bus.mapper.borrow().chr_bank_key(), 1);
+    }
+
+    #[test]
+    fn test_oam_dma_steals_cycles() {
+        let mut bus = Bus::new(test::test_rom()).unwrap();
+        bus.mem_write(0x4014, 0x02);
+        assert_eq!(bus.cycles(), 513);
+
+        bus.mem_write(0x4014, 0x02);
+        assert_eq!(bus.cycles(), 513 + 514);
+    }
+
+    #[test]
+    fn test_oam_dma_copies_as_it_goes() {
+        let mut bus = Bus::new(test::test_rom()).unwrap();
+        for i in 0..256 {
+            bus.mem_write(0x0200 + i, i as u8);
+        }
+        // the DMC fetches its byte partway through, for two more cycles
+        bus.mem_write(0x4013, 0);
+        bus.mem_write(0x4015, 0b0001_0000);
+        bus.tick(1);
+        bus.mem_write(0x4014, 0x02);
+        assert_eq!(bus.cycles(), 1 + 514 + 2);
+        assert!(!bus.apu.dmc.is_active());
+        assert_eq!(bus.mem_read(0x2004), 0);
+        bus.mem_write(0x2003, 0xff);
+        assert_eq!(bus.mem_read(0x2004), 0xff);
+    }
+
+    #[test]
+    fn test_master_clock_drives_cpu_and_ppu() {
+        let mut bus = Bus::new(test::test_rom()).unwrap();
+        bus.tick(2);
+        assert_eq!(bus.master_cycles(), 24);
+        assert_eq!(bus.cycles(), 2);
+
+        // a frame is 89342 PPU dots, or 29780.67 CPU cycles
+        let mut bus = Bus::new(test::test_rom()).unwrap();
+        for _ in 0..29780 {
+            bus.tick(1);
+        }
+        assert_eq!(bus.frame_count(), 0);
+        bus.tick(1);
+        assert_eq!(bus.frame_count(), 1);
+    }
+
+    #[test]
+    fn test_scanline_break() {
+        let mut bus = Bus::new(test::test_rom()).unwrap();
+        bus.scanline_break = true;
+        for _ in 0..29781 {
+            bus.tick(1);
+        }
+        let events: Vec<_> = std::iter::from_fn(|| bus.poll_event()).collect();
+        assert_eq!(events.len(), EventQueue::CAPACITY);
+        let last = &events[events.len() - 3..];
+        assert_eq!(last[0], Event::Scanline(261));
+        assert!(matches!(last[1], Event::AudioSamples(samples) if samples > 700));
+        assert_eq!(last[2], Event::FrameComplete);
+    }
+
+    #[test]
+    fn test_watchpoint_hit() {
+        let mut bus = Bus::new(test::test_rom()).unwrap();
+        bus.watchpoints.push(Watchpoint {
+            addr: 0x10,
+            access: Access::Write,
+        });
+
+        bus.mem_read(0x10);
+        assert_eq!(bus.take_watchpoint_hit(), None);
+
+        bus.mem_write(0x10, 0x42);
+        let (hit, data) = bus.take_watchpoint_hit().unwrap();
+        assert_eq!(hit.addr, 0x10);
+        assert_eq!(data, 0x42);
+        assert_eq!(bus.take_watchpoint_hit(), None);
+    }
+
+    #[test]
+    fn test_cheats_overlay_reads() {
+        let mut bus = Bus::new(test::test_rom()).unwrap();
+        bus.mem_write(0x10, 0x01);
+        bus.cheats.add("0010:63").unwrap();
+        // ZEXPYGLA: $94A7 = $02 if the ROM has $03 there, and the test rom is filled with $01
+        bus.cheats.add("ZEXPYGLA").unwrap();
+
+        assert_eq!(bus.mem_read(0x10), 0x63);
+        assert_eq!(bus.mem_peek(0x10), 0x63);
+        assert_eq!(bus.mem_read(0x94a7), 0x01);
+
+        bus.cheats.set_all_enabled(false);
+        assert_eq!(bus.mem_read(0x10), 0x01);
+    }
+
+    #[test]
+    fn test_peek_does_not_trigger_watchpoints() {
+        let mut bus = Bus::new(test::test_rom()).unwrap();
+        bus.mem_write(0x10, 0x42);
+        bus.watchpoints.push(Watchpoint {
+            addr: 0x10,
+            access: Access::Read,
+        });
+
+        assert_eq!(bus.mem_peek(0x10), 0x42);
+        assert_eq!(bus.mem_peek(0x810), 0x42);
+        assert_eq!(bus.take_watchpoint_hit(), None);
+    }
+
+    #[test]
+    fn test_poke() {
+        let mut bus = Bus::new(test::test_rom()).unwrap();
+        assert!(bus.poke(0x0812, 0x42));
+        assert!(!bus.poke(0x8000, 0x42));
+        assert_eq!(bus.mem_peek(0x12), 0x42);
+        assert_eq!(bus.mem_peek(0x8000), 0x01);
+        assert_eq!(bus.ram()[0x12], 0x42);
+    }
+
+    #[test]
+    fn test_frame_irq_is_acknowledged() {
+        let mut bus = Bus::new(test::test_rom()).unwrap();
+        for _ in 0..30_000 / 255 {
+            bus.tick(255);
+        }
+        assert!(bus.poll_irq_status());
+        assert_eq!(bus.mem_peek(0x4015) & 0b0100_0000, 0b0100_0000);
+        assert_eq!(bus.mem_read(0x4015) & 0b0100_0000, 0b0100_0000);
+        assert_eq!(bus.mem_read(0x4015) & 0b0100_0000, 0);
+        assert!(!bus.poll_irq_status());
+
+        for _ in 0..30_000 / 255 {
+            bus.tick(255);
+        }
+        assert!(bus.poll_irq_status());
+        bus.mem_write(0x4017, 0b0100_0000);
+        assert!(!bus.poll_irq_status());
+    }
+
+    #[test]
+    fn test_dmc_sample_raises_irq() {
+        let mut bus = Bus::new(test::test_rom()).unwrap();
+        bus.mem_write(0x4010, 0b1000_0000);
+        bus.mem_write(0x4013, 0);
+        bus.mem_write(0x4015, 0b0001_0000);
+        // the only byte is fetched on the CPU's next read, with the CPU halted for it
+        bus.tick(1);
+        assert!(!bus.poll_irq_status());
+        bus.mem_read(0x0000);
+        assert_eq!(bus.cycles(), 5);
+        assert!(bus.poll_irq_status());
+        assert_eq!(bus.mem_peek(0x4015) & 0b1001_0000, 0b1000_0000);
+
+        bus.mem_write(0x4015, 0);
+        assert!(!bus.poll_irq_status());
+    }
+
+    #[test]
+    fn test_dmc_dma_during_joypad_read_skips_a_bit() {
+        let mut bus = Bus::new(test::test_rom()).unwrap();
+        bus.joypad1
+            .set_button_pressed_status(JoypadButton::BUTTON_A | JoypadButton::START, true);
+        bus.mem_write(0x4016, 1);
+        bus.mem_write(0x4016, 0);
+        bus.mem_write(0x4013, 0);
+        bus.mem_write(0x4015, 0b0001_0000);
+        bus.tick(1);
+
+        // the halted read clocks the joypad once more, so A is lost and B read first
+        let bits: Vec<u8> = (0..3).map(|_| bus.mem_read(0x4016) & 1).collect();
+        assert_eq!(bits, [0, 0, 1]);
+        assert_eq!(bus.cycles(), 1 + 4);
+    }
+
+    #[test]
+    fn test_dmc_dma_overlaps_cpu_writes() {
+        let mut bus = Bus::new(test::test_rom()).unwrap();
+        bus.mem_write(0x4013, 0);
+        bus.mem_write(0x4015, 0b0001_0000);
+        bus.tick(1);
+        // three writes in a row, like an interrupt pushing onto the stack
+        for _ in 0..3 {
+            bus.mem_write(0x0100, 0);
+            bus.tick(1);
+        }
+        bus.mem_read(0x0000);
+        assert_eq!(bus.cycles(), 4 + 1);
+    }
+
+    #[test]
+    fn test_irq_sources() {
+        let mut bus = Bus::new(test::test_rom()).unwrap();
+        assert!(!bus.poll_irq_status());
+
+        bus.assert_irq(IrqSource::APU_FRAME);
+        bus.assert_irq(IrqSource::DMC);
+        assert!(bus.poll_irq_status());
+        bus.acknowledge_irq(IrqSource::APU_FRAME);
+        assert!(bus.poll_irq_status());
+        assert_eq!(bus.irq_sources(), IrqSource::DMC);
+        bus.acknowledge_irq(IrqSource::DMC);
+        assert!(!bus.poll_irq_status());
+
+        // the line only went up once
+        let irqs = std::iter::from_fn(|| bus.poll_event()).filter(|e| *e == Event::Irq);
+        assert_eq!(irqs.count(), 1);
+    }
 }
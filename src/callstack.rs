@@ -0,0 +1,222 @@
+use crate::cpu::Mem;
+use crate::disasm::address;
+
+// A stack is only 256 bytes, so anything deeper has lost track of the real one
+const MAX_DEPTH: usize = 128;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameKind {
+    Subroutine,
+    Nmi,
+    Irq,
+    Brk,
+}
+
+/// One call the CPU hasn't returned from yet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Frame {
+    pub kind: FrameKind,
+    /// The JSR or BRK, or the instruction an NMI or IRQ interrupted.
+    pub from: u16,
+    /// The subroutine or interrupt handler that was entered.
+    pub to: u16,
+    /// The stack pointer once the return address was pushed. The matching RTS or RTI
+    /// finds it here.
+    pub stack_pointer: u8,
+}
+
+impl Frame {
+    fn describe<M: Mem>(&self, mem: &M) -> String {
+        let (to, from) = (address(mem, self.to), address(mem, self.from));
+        match self.kind {
+            FrameKind::Subroutine => format!("{}, called from {}", to, from),
+            FrameKind::Nmi => format!("NMI handler {}, interrupting {}", to, from),
+            FrameKind::Irq => format!("IRQ handler {}, interrupting {}", to, from),
+            FrameKind::Brk => format!("BRK handler {}, from BRK at {}", to, from),
+        }
+    }
+}
+
+/// Follows JSR and RTS, and interrupts and RTI, alongside the real stack, for a
+/// backtrace of how the CPU got where it is.
+///
+/// Games don't always pair them up. Some push an address and RTS to it as a jump, some
+/// pull a return address off and never come back, and some just reset the stack
+/// pointer. Returns are matched up by the stack pointer, so the frames they skip past
+/// are dropped, and anything that doesn't add up is counted as a mispair rather than
+/// thrown off.
+#[derive(Default)]
+pub struct CallStack {
+    frames: Vec<Frame>,
+    mispairs: usize,
+    last_mispair: Option<String>,
+}
+
+impl CallStack {
+    pub fn new() -> Self {
+        CallStack {
+            frames: Vec::new(),
+            mispairs: 0,
+            last_mispair: None,
+        }
+    }
+
+    /// Outermost first.
+    pub fn frames(&self) -> &[Frame] {
+        &self.frames
+    }
+
+    pub fn depth(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub fn mispairs(&self) -> usize {
+        self.mispairs
+    }
+
+    pub fn last_mispair(&self) -> Option<&str> {
+        self.last_mispair.as_deref()
+    }
+
+    pub fn clear(&mut self) {
+        self.frames.clear();
+    }
+
+    fn mispair(&mut self, message: String) {
+        self.mispairs += 1;
+        self.last_mispair = Some(message);
+    }
+
+    /// A JSR or interrupt has pushed its return address.
+    pub fn push<M: Mem>(&mut self, mem: &M, frame: Frame) {
+        // frames at or below the new one on the stack have been written over, so the
+        // stack pointer was moved past them
+        let live = self
+            .frames
+            .iter()
+            .take_while(|f| f.stack_pointer > frame.stack_pointer)
+            .count();
+        if live < self.frames.len() {
+            let dropped = self.frames.len() - live;
+            self.frames.truncate(live);
+            self.mispair(format!(
+                "{} frame{} left behind by the stack pointer before {}",
+                dropped,
+                if dropped == 1 { "" } else { "s" },
+                address(mem, frame.from)
+            ));
+        }
+        if self.frames.len() == MAX_DEPTH {
+            self.frames.remove(0);
+        }
+        self.frames.push(frame);
+    }
+
+    /// An RTS or RTI at `at` ran with the stack pointer at `stack_pointer`, and went
+    /// back to `to`.
+    pub fn ret<M: Mem>(&mut self, mem: &M, interrupt: bool, at: u16, stack_pointer: u8, to: u16) {
+        let name = if interrupt { "RTI" } else { "RTS" };
+        let found = self
+            .frames
+            .iter()
+            .rposition(|f| f.stack_pointer == stack_pointer);
+        let index = match found {
+            Some(index) => index,
+            None => {
+                self.mispair(format!(
+                    "{} at {} went to {} without a call to return from",
+                    name,
+                    address(mem, at),
+                    address(mem, to)
+                ));
+                return;
+            }
+        };
+
+        let frame = self.frames[index];
+        let skipped = self.frames.len() - 1 - index;
+        self.frames.truncate(index);
+        if skipped > 0 {
+            self.mispair(format!(
+                "{} at {} returned from {} past {} more frame{}",
+                name,
+                address(mem, at),
+                frame.describe(mem),
+                skipped,
+                if skipped == 1 { "" } else { "s" }
+            ));
+        } else if interrupt != (frame.kind != FrameKind::Subroutine) {
+            self.mispair(format!(
+                "{} at {} returned from {}",
+                name,
+                address(mem, at),
+                frame.describe(mem)
+            ));
+        }
+    }
+
+    /// Innermost first, starting with the instruction at `pc`.
+    pub fn backtrace<M: Mem>(&self, mem: &M, pc: u16) -> Vec<String> {
+        let mut lines = vec![format!("#0  {}", address(mem, pc))];
+        for (i, frame) in self.frames.iter().rev().enumerate() {
+            lines.push(format!("#{:<2} in {}", i + 1, frame.describe(mem)));
+        }
+        if let Some(mispair) = &self.last_mispair {
+            lines.push(format!(
+                "{} mispaired call{}, the last: {}",
+                self.mispairs,
+                if self.mispairs == 1 { "" } else { "s" },
+                mispair
+            ));
+        }
+        lines
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bus::Bus;
+    use crate::rom::test;
+
+    fn call(from: u16, to: u16, stack_pointer: u8) -> Frame {
+        Frame {
+            kind: FrameKind::Subroutine,
+            from,
+            to,
+            stack_pointer,
+        }
+    }
+
+    #[test]
+    fn test_mispairs() {
+        let bus = Bus::new(test::test_rom()).unwrap();
+        let mut stack = CallStack::new();
+        stack.push(&bus, call(0x8000, 0x9000, 0xfb));
+        stack.push(&bus, call(0x9000, 0xa000, 0xf9));
+        stack.push(&bus, call(0xa000, 0xb000, 0xf7));
+
+        // pulling a return address off and returning to the caller's caller
+        stack.ret(&bus, false, 0xb005, 0xf9, 0x9003);
+        assert_eq!(stack.frames(), &[call(0x8000, 0x9000, 0xfb)]);
+        assert_eq!(stack.mispairs(), 1);
+
+        // pushing an address and RTS to it
+        stack.ret(&bus, false, 0x9010, 0xf9, 0xc001);
+        assert_eq!(stack.depth(), 1);
+        assert_eq!(stack.mispairs(), 2);
+
+        // resetting the stack pointer
+        stack.push(&bus, call(0xc100, 0xd000, 0xfd));
+        assert_eq!(stack.frames(), &[call(0xc100, 0xd000, 0xfd)]);
+        assert_eq!(stack.mispairs(), 3);
+
+        stack.ret(&bus, false, 0xd000, 0xfd, 0xc103);
+        assert_eq!(stack.depth(), 0);
+        assert_eq!(stack.mispairs(), 3);
+        assert_eq!(
+            stack.backtrace(&bus, 0xc103)[1],
+            "3 mispaired calls, the last: 1 frame left behind by the stack pointer before $C100"
+        );
+    }
+}
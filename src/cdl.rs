@@ -0,0 +1,103 @@
+use std::fs;
+use std::io;
+
+/// The byte was run as an instruction, opcode or operand.
+pub const CODE: u8 = 0x01;
+/// The byte was read by an instruction.
+pub const DATA: u8 = 0x02;
+/// The byte was jumped to through a JMP ($xxxx).
+pub const INDIRECT_CODE: u8 = 0x10;
+/// The byte was read through a pointer, with ($xx,X) or ($xx),Y.
+pub const INDIRECT_DATA: u8 = 0x20;
+
+/// A code/data log: which bytes of PRG ROM the CPU has run and which it has read, saved
+/// in the .cdl format of FCEUX for disassemblers to go by. That is a byte of flags for
+/// each byte of PRG ROM, then one for each byte of CHR ROM. Along with the flags above,
+/// bits 2-3 of a PRG byte say which 8 KiB of $8000-$FFFF it was seen in. CHR bytes
+/// aren't logged, and are left at 0.
+pub struct CodeDataLog {
+    prg: Vec<u8>,
+    chr_len: usize,
+}
+
+impl CodeDataLog {
+    pub fn new(prg_len: usize, chr_len: usize) -> Self {
+        CodeDataLog {
+            prg: vec![0; prg_len],
+            chr_len,
+        }
+    }
+
+    /// Carries on with the log in `path`, or starts a new one if there's nothing there.
+    pub fn open(path: &str, prg_len: usize, chr_len: usize) -> Result<Self, String> {
+        let mut log = CodeDataLog::new(prg_len, chr_len);
+        match fs::read(path) {
+            Ok(bytes) if bytes.len() == prg_len + chr_len => {
+                log.prg.copy_from_slice(&bytes[..prg_len]);
+                Ok(log)
+            }
+            Ok(_) => Err(format!("{} is the log of some other game", path)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(log),
+            Err(e) => Err(format!("Can't read {}: {}", path, e)),
+        }
+    }
+
+    /// Marks the PRG ROM byte at `offset`, seen by the CPU at `addr`.
+    pub fn mark(&mut self, offset: usize, addr: u16, flags: u8) {
+        if let Some(byte) = self.prg.get_mut(offset) {
+            *byte |= flags | ((addr >> 11) as u8 & 0b1100);
+        }
+    }
+
+    pub fn flags(&self, offset: usize) -> u8 {
+        self.prg.get(offset).copied().unwrap_or(0)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = self.prg.clone();
+        bytes.resize(self.prg.len() + self.chr_len, 0);
+        bytes
+    }
+
+    pub fn save(&self, path: &str) -> io::Result<()> {
+        fs::write(path, self.to_bytes())
+    }
+
+    /// How many bytes of PRG ROM have been run and read, and how many haven't been seen.
+    pub fn summary(&self) -> String {
+        let count = |flag: u8| self.prg.iter().filter(|&&b| b & flag != 0).count();
+        let unseen = self.prg.iter().filter(|&&b| b & (CODE | DATA) == 0).count();
+        format!(
+            "{} bytes of code, {} of data, {} not seen of {}",
+            count(CODE),
+            count(DATA),
+            unseen,
+            self.prg.len()
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_log() {
+        let mut log = CodeDataLog::new(0x8000, 0x2000);
+        log.mark(0x0010, 0x8010, CODE);
+        log.mark(0x0010, 0x8010, DATA);
+        log.mark(0x7ffc, 0xfffc, DATA | INDIRECT_DATA);
+        log.mark(0x8000, 0x0000, CODE);
+
+        assert_eq!(log.flags(0x0010), CODE | DATA);
+        // the last 8 KiB
+        assert_eq!(log.flags(0x7ffc), 0b0010_1110);
+        let bytes = log.to_bytes();
+        assert_eq!(bytes.len(), 0xa000);
+        assert_eq!(bytes[0x7ffc], 0b0010_1110);
+        assert_eq!(
+            log.summary(),
+            "1 bytes of code, 2 of data, 32766 not seen of 32768"
+        );
+    }
+}
@@ -0,0 +1,177 @@
+const GAME_GENIE_LETTERS: &str = "APZLGITYEOXUKSVN";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cheat {
+    pub code: String,
+    pub addr: u16,
+    pub value: u8,
+    pub compare: Option<u8>,
+    pub enabled: bool,
+}
+
+impl Cheat {
+    /// Parses a 6 or 8 letter Game Genie code, or a raw `AAAA:VV` Pro Action Replay code.
+    pub fn parse(code: &str) -> Result<Cheat, String> {
+        let code = code.trim().to_ascii_uppercase();
+        if code.contains(':') {
+            return Cheat::parse_raw(&code);
+        }
+
+        let n: Vec<u16> = code
+            .chars()
+            .map(|c| GAME_GENIE_LETTERS.find(c).map(|i| i as u16))
+            .collect::<Option<Vec<u16>>>()
+            .ok_or_else(|| format!("Invalid Game Genie code: {}", code))?;
+
+        if n.len() != 6 && n.len() != 8 {
+            return Err(format!("Game Genie codes have 6 or 8 letters: {}", code));
+        }
+
+        let addr = 0x8000
+            | ((n[3] & 7) << 12)
+            | ((n[5] & 7) << 8)
+            | ((n[4] & 8) << 8)
+            | ((n[2] & 7) << 4)
+            | ((n[1] & 8) << 4)
+            | (n[4] & 7)
+            | (n[3] & 8);
+
+        let (value, compare) = if n.len() == 6 {
+            let value = ((n[1] & 7) << 4) | ((n[0] & 8) << 4) | (n[0] & 7) | (n[5] & 8);
+            (value, None)
+        } else {
+            let value = ((n[1] & 7) << 4) | ((n[0] & 8) << 4) | (n[0] & 7) | (n[7] & 8);
+            let compare = ((n[7] & 7) << 4) | ((n[6] & 8) << 4) | (n[6] & 7) | (n[5] & 8);
+            (value, Some(compare as u8))
+        };
+
+        Ok(Cheat {
+            code,
+            addr,
+            value: value as u8,
+            compare,
+            enabled: true,
+        })
+    }
+
+    fn parse_raw(code: &str) -> Result<Cheat, String> {
+        let invalid = || format!("Invalid address:value code: {}", code);
+        let mut parts = code.splitn(2, ':');
+        let addr = u16::from_str_radix(parts.next().unwrap_or(""), 16).map_err(|_| invalid())?;
+        let value = u8::from_str_radix(parts.next().unwrap_or(""), 16).map_err(|_| invalid())?;
+
+        Ok(Cheat {
+            code: code.to_string(),
+            addr,
+            value,
+            compare: None,
+            enabled: true,
+        })
+    }
+
+    fn matches(&self, addr: u16, original: u8) -> bool {
+        self.enabled && self.addr == addr && self.compare.is_none_or(|c| c == original)
+    }
+}
+
+#[derive(Default)]
+pub struct Cheats {
+    cheats: Vec<Cheat>,
+}
+
+impl Cheats {
+    pub fn new() -> Self {
+        Cheats { cheats: Vec::new() }
+    }
+
+    pub fn add(&mut self, code: &str) -> Result<&Cheat, String> {
+        let cheat = Cheat::parse(code)?;
+        self.cheats.push(cheat);
+        Ok(self.cheats.last().unwrap())
+    }
+
+    pub fn remove(&mut self, code: &str) {
+        let code = code.trim().to_ascii_uppercase();
+        self.cheats.retain(|cheat| cheat.code != code);
+    }
+
+    pub fn list(&self) -> &[Cheat] {
+        &self.cheats
+    }
+
+    pub fn set_enabled(&mut self, code: &str, enabled: bool) {
+        let code = code.trim().to_ascii_uppercase();
+        for cheat in self.cheats.iter_mut().filter(|cheat| cheat.code == code) {
+            cheat.enabled = enabled;
+        }
+    }
+
+    pub fn set_all_enabled(&mut self, enabled: bool) {
+        for cheat in self.cheats.iter_mut() {
+            cheat.enabled = enabled;
+        }
+    }
+
+    pub fn any_enabled(&self) -> bool {
+        self.cheats.iter().any(|cheat| cheat.enabled)
+    }
+
+    /// Overlays active cheats onto a value read by the CPU from `addr`.
+    pub fn apply(&self, addr: u16, original: u8) -> u8 {
+        if self.cheats.is_empty() {
+            return original;
+        }
+
+        // RAM codes are written against $0000-$07FF, so they also apply to the mirrors
+        let addr = if addr < 0x2000 { addr & 0x07ff } else { addr };
+        self.cheats
+            .iter()
+            .find(|cheat| cheat.matches(addr, original))
+            .map_or(original, |cheat| cheat.value)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_decode_six_letter_code() {
+        // Super Mario Bros: infinite lives
+        let cheat = Cheat::parse("SXIOPO").unwrap();
+        assert_eq!(cheat.addr, 0x91d9);
+        assert_eq!(cheat.value, 0xad);
+        assert_eq!(cheat.compare, None);
+    }
+
+    #[test]
+    fn test_decode_eight_letter_code() {
+        let cheat = Cheat::parse("ZEXPYGLA").unwrap();
+        assert_eq!(cheat.addr, 0x94a7);
+        assert_eq!(cheat.value, 0x02);
+        assert_eq!(cheat.compare, Some(0x03));
+    }
+
+    #[test]
+    fn test_invalid_codes() {
+        assert!(Cheat::parse("PIGOA").is_err());
+        assert!(Cheat::parse("PIGOAB").is_err());
+        assert!(Cheat::parse("0075:zz").is_err());
+    }
+
+    #[test]
+    fn test_apply_overlays() {
+        let mut cheats = Cheats::new();
+        cheats.add("0075:09").unwrap();
+        cheats.add("ZEXPYGLA").unwrap();
+
+        assert_eq!(cheats.apply(0x0075, 0x01), 0x09);
+        assert_eq!(cheats.apply(0x0875, 0x01), 0x09);
+        assert_eq!(cheats.apply(0x94a7, 0x03), 0x02);
+        // compare value doesn't match, so the ROM byte is left alone
+        assert_eq!(cheats.apply(0x94a7, 0x04), 0x04);
+
+        cheats.set_enabled("0075:09", false);
+        assert_eq!(cheats.apply(0x0075, 0x01), 0x01);
+    }
+}
@@ -0,0 +1,115 @@
+use crate::render::frame::Frame;
+use std::borrow::Cow;
+use std::collections::{HashMap, VecDeque};
+use std::fs::File;
+use std::io::BufWriter;
+
+// Every third frame is kept, which GIFs can play back at the right speed: 5/100s each
+const FRAME_STEP: usize = 3;
+const GIF_DELAY: u16 = 5;
+
+/// The last few seconds of video, ready to be saved as an animated GIF.
+pub struct ClipBuffer {
+    frames: VecDeque<Vec<u8>>,
+    capacity: usize,
+    skipped: usize,
+}
+
+impl ClipBuffer {
+    pub fn new(seconds: usize) -> Self {
+        ClipBuffer {
+            frames: VecDeque::new(),
+            capacity: seconds * 60 / FRAME_STEP,
+            skipped: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.frames.is_empty()
+    }
+
+    pub fn push(&mut self, frame: &Frame) {
+        self.skipped += 1;
+        if self.skipped < FRAME_STEP {
+            return;
+        }
+        self.skipped = 0;
+        if self.frames.len() == self.capacity {
+            self.frames.pop_front();
+        }
+        self.frames.push_back(frame.data.clone());
+    }
+
+    pub fn save_gif(&self, path: &str) -> Result<(), String> {
+        let file = File::create(path).map_err(|e| e.to_string())?;
+        let mut encoder =
+            gif::Encoder::new(BufWriter::new(file), 256, 240, &[]).map_err(|e| e.to_string())?;
+        encoder
+            .set_repeat(gif::Repeat::Infinite)
+            .map_err(|e| e.to_string())?;
+        for data in self.frames.iter() {
+            let mut frame = quantize(data);
+            frame.delay = GIF_DELAY;
+            encoder.write_frame(&frame).map_err(|e| e.to_string())?;
+        }
+        Ok(())
+    }
+}
+
+// A NES picture rarely has more than a couple of dozen colours, so it usually fits a GIF
+// palette exactly. Only pictures with more than 256 fall back to quantizing.
+fn quantize(data: &[u8]) -> gif::Frame<'static> {
+    let mut palette = vec![];
+    let mut indices = HashMap::new();
+    let mut buffer = Vec::with_capacity(data.len() / 3);
+    for rgb in data.chunks(3) {
+        let next = indices.len();
+        let index = *indices.entry((rgb[0], rgb[1], rgb[2])).or_insert(next);
+        if index == 256 {
+            return gif::Frame::from_rgb_speed(256, 240, data, 10);
+        }
+        if index == next {
+            palette.extend(rgb);
+        }
+        buffer.push(index as u8);
+    }
+
+    gif::Frame {
+        width: 256,
+        height: 240,
+        buffer: Cow::Owned(buffer),
+        palette: Some(palette),
+        ..gif::Frame::default()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_keeps_the_last_frames() {
+        let mut clip = ClipBuffer::new(1);
+        let mut frame = Frame::new();
+        for i in 0..90 {
+            frame.set_pixel(0, 0, (i as u8, 0, 0));
+            clip.push(&frame);
+        }
+        assert_eq!(clip.len(), 20);
+        assert_eq!(clip.frames.back().unwrap()[0], 89);
+        assert_eq!(clip.frames.front().unwrap()[0], 32);
+    }
+
+    #[test]
+    fn test_exact_palette() {
+        let mut frame = Frame::new();
+        frame.set_pixel(1, 0, (0xfc, 0x74, 0x60));
+        let gif_frame = quantize(&frame.data);
+        assert_eq!(gif_frame.palette, Some(vec![0, 0, 0, 0xfc, 0x74, 0x60]));
+        assert_eq!(&gif_frame.buffer[0..3], &[0, 1, 0]);
+    }
+}
@@ -0,0 +1,233 @@
+use crate::rom::{Mirroring, Rom};
+
+/// The TV system a game was released for. Everything runs with NTSC timing for now.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Region {
+    #[default]
+    Ntsc,
+    Pal,
+}
+
+/// What the game expects to have plugged in, besides the controller in port 1.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Controller {
+    #[default]
+    Joypad,
+    Zapper,
+    FourScore,
+}
+
+/// What's known about one dump, for games whose headers can't be trusted or that need
+/// more than a joypad. `None` leaves the header's value alone.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Game {
+    pub crc32: u32,
+    /// Tells apart dumps whose CRC32s collide, where it's given.
+    pub sha1: Option<&'static str>,
+    pub name: &'static str,
+    pub mapper: Option<u8>,
+    pub mirroring: Option<Mirroring>,
+    pub region: Region,
+    pub controller: Controller,
+    /// The dump is known to be corrupt, and may not run right whatever the emulator does.
+    pub bad_dump: bool,
+}
+
+const GAME: Game = Game {
+    crc32: 0,
+    sha1: None,
+    name: "",
+    mapper: None,
+    mirroring: None,
+    region: Region::Ntsc,
+    controller: Controller::Joypad,
+    bad_dump: false,
+};
+
+/// Keyed by the CRC32 and SHA1 of the PRG and CHR ROM without the header, which is what
+/// No-Intro and most other NES databases list, so a fixed up header still matches.
+pub const GAMES: &[Game] = &[
+    Game {
+        crc32: 0x158b_0388,
+        sha1: Some("4131307f0f69f2a5c54b7d438328c5b2a5ed0820"),
+        name: "nestest",
+        ..GAME
+    },
+    Game {
+        crc32: 0x3337_ec46,
+        name: "Super Mario Bros.",
+        ..GAME
+    },
+    Game {
+        crc32: 0x9e4e_9cc2,
+        sha1: Some("92c3361b9e3b28a51fd30e7845c988a6d576ee65"),
+        name: "Pac-Man",
+        ..GAME
+    },
+    Game {
+        crc32: 0x1949_42db,
+        name: "Duck Hunt",
+        controller: Controller::Zapper,
+        ..GAME
+    },
+];
+
+pub fn crc32(prg_rom: &[u8], chr_rom: &[u8]) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(prg_rom);
+    hasher.update(chr_rom);
+    hasher.finalize()
+}
+
+/// The SHA1 of the PRG and CHR ROM, in lower case hex.
+pub fn sha1(prg_rom: &[u8], chr_rom: &[u8]) -> String {
+    let mut message: Vec<u8> = [prg_rom, chr_rom].concat();
+    let bits = message.len() as u64 * 8;
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend_from_slice(&bits.to_be_bytes());
+
+    let mut h: [u32; 5] = [
+        0x6745_2301,
+        0xefcd_ab89,
+        0x98ba_dcfe,
+        0x1032_5476,
+        0xc3d2_e1f0,
+    ];
+    for block in message.chunks(64) {
+        let mut w = [0u32; 80];
+        for (i, word) in block.chunks(4).enumerate() {
+            w[i] = u32::from_be_bytes([word[0], word[1], word[2], word[3]]);
+        }
+        for i in 16..80 {
+            w[i] = (w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16]).rotate_left(1);
+        }
+
+        let [mut a, mut b, mut c, mut d, mut e] = h;
+        for (i, &word) in w.iter().enumerate() {
+            let (f, k) = match i {
+                0..=19 => ((b & c) | (!b & d), 0x5a82_7999),
+                20..=39 => (b ^ c ^ d, 0x6ed9_eba1),
+                40..=59 => ((b & c) | (b & d) | (c & d), 0x8f1b_bcdc),
+                _ => (b ^ c ^ d, 0xca62_c1d6),
+            };
+            let temp = a
+                .rotate_left(5)
+                .wrapping_add(f)
+                .wrapping_add(e)
+                .wrapping_add(k)
+                .wrapping_add(word);
+            e = d;
+            d = c;
+            c = b.rotate_left(30);
+            b = a;
+            a = temp;
+        }
+        for (h, x) in h.iter_mut().zip([a, b, c, d, e]) {
+            *h = h.wrapping_add(x);
+        }
+    }
+    h.iter().map(|word| format!("{:08x}", word)).collect()
+}
+
+/// The entry with this CRC32, as long as its SHA1 matches too where it lists one.
+pub fn find(games: &'static [Game], crc32: u32, sha1: &str) -> Option<&'static Game> {
+    games
+        .iter()
+        .find(|game| game.crc32 == crc32 && game.sha1.is_none_or(|hash| hash == sha1))
+}
+
+/// The entry for a ROM in the built in database, if there is one.
+pub fn lookup(rom: &Rom) -> Option<&'static Game> {
+    find(GAMES, rom.crc32(), &rom.sha1())
+}
+
+impl Game {
+    /// Overrides the header values the entry has better ones for.
+    pub fn fix(&self, rom: &mut Rom) {
+        if let Some(mapper) = self.mapper {
+            rom.mapper = mapper;
+        }
+        if let Some(mirroring) = self.mirroring {
+            rom.screen_mirroring = mirroring;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::rom::System;
+
+    const TEST_GAMES: &[Game] = &[Game {
+        crc32: 0x1234_5678,
+        sha1: Some("da39a3ee5e6b4b0d3255bfef95601890afd80709"),
+        name: "Light Gun Game",
+        mapper: Some(66),
+        mirroring: Some(Mirroring::Vertical),
+        controller: Controller::Zapper,
+        ..GAME
+    }];
+
+    #[test]
+    fn test_fix_header() {
+        let mut rom = Rom {
+            prg_rom: vec![0; 0x8000],
+            chr_rom: vec![0; 0x2000],
+            chr_ram: false,
+            trainer: None,
+            prg_ram_size: 0x2000,
+            battery: false,
+            mapper: 0,
+            screen_mirroring: Mirroring::Horizontal,
+            system: System::Nes,
+        };
+        let sha1 = sha1(&[], &[]);
+        assert_eq!(find(TEST_GAMES, 0x8765_4321, &sha1), None);
+        assert_eq!(find(TEST_GAMES, 0x1234_5678, "0123"), None);
+        let game = find(TEST_GAMES, 0x1234_5678, &sha1).unwrap();
+        game.fix(&mut rom);
+        assert_eq!(rom.mapper, 66);
+        assert_eq!(rom.screen_mirroring, Mirroring::Vertical);
+        assert_eq!(game.controller, Controller::Zapper);
+    }
+
+    #[test]
+    fn test_fix_database_headers() {
+        for game in GAMES {
+            let mut rom = crate::rom::test::test_rom();
+            let (mapper, mirroring) = (rom.mapper, rom.screen_mirroring);
+            game.fix(&mut rom);
+            assert_eq!(rom.mapper, game.mapper.unwrap_or(mapper), "{}", game.name);
+            assert_eq!(
+                rom.screen_mirroring,
+                game.mirroring.unwrap_or(mirroring),
+                "{}",
+                game.name
+            );
+        }
+
+        // read through the header, nestest is found by both hashes
+        let rom = Rom::new(&std::fs::read("nestest.nes").unwrap()).unwrap();
+        assert_eq!(lookup(&rom).map(|game| game.name), Some("nestest"));
+    }
+
+    #[test]
+    fn test_hashes() {
+        assert_eq!(crc32(b"1234", b"56789"), 0xcbf4_3926);
+        assert_eq!(
+            sha1(b"a", b"bc"),
+            "a9993e364706816aba3e25717850c26c9cd0d89d"
+        );
+        assert_eq!(
+            sha1(&[b'a'; 1000], &[]),
+            "291e9a6c66994949b57ba5e650361e98fc36b1ba"
+        );
+        assert!(GAMES.iter().all(|game| {
+            let sha1 = game.sha1.unwrap_or("");
+            find(GAMES, game.crc32, sha1) == Some(game)
+        }));
+    }
+}
@@ -1,6 +1,10 @@
+use crate::asm;
 use crate::bus::Bus;
-use crate::opcodes::{OpCode, OPCODES_MAP};
-use std::collections::HashMap;
+use crate::callstack::{CallStack, Frame, FrameKind};
+use crate::cdl;
+use crate::error::NesError;
+use crate::opcodes::OPCODES;
+use crate::savestate::{Savestate, StateReader, StateWriter};
 
 bitflags! {
     pub struct CpuFlags: u8 {
@@ -16,16 +20,78 @@ bitflags! {
 }
 
 const STACK: u16 = 0x0100;
+
+/// How the CPU is doing after a step.
+#[derive(Debug, Clone, PartialEq)]
+pub enum CpuState {
+    Running,
+    /// Stopped at a BRK, with `halt_on_brk` set.
+    Halted,
+    /// Locked up by one of the KIL opcodes. Only a reset gets it going again.
+    Jammed,
+    /// Hit something it can't emulate. Like a jam, it stays stopped until a reset.
+    Error(NesError),
+}
 const STACK_RESET: u8 = 0xfd;
 
-pub struct CPU<'a> {
+pub struct CPU {
     pub register_a: u8,
     pub register_x: u8,
     pub register_y: u8,
     pub status: CpuFlags,
     pub program_counter: u16,
     pub stack_pointer: u8,
-    pub bus: Bus<'a>,
+    pub bus: Bus,
+    /// Stop running at a BRK instead of taking the interrupt, for test programs.
+    pub halt_on_brk: bool,
+    /// The calls and interrupts the CPU is inside of, for backtraces.
+    pub call_stack: CallStack,
+
+    // the instruction step_cycle is working through
+    opcode: u8,
+    cycle: u8,
+    addr: u16,
+    pointer: u8,
+    data: u8,
+    page_crossed: bool,
+    servicing: Option<interrupt::Interrupt>,
+    jammed: bool,
+
+    // interrupt lines as sampled at the end of the last two cycles
+    nmi_pending: bool,
+    prev_nmi_pending: bool,
+    irq_pending: bool,
+    prev_irq_pending: bool,
+    interrupt_ready: bool,
+}
+
+/// What an instruction does with its operand, which decides the cycles it spends on it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum OperandAccess {
+    Read,
+    Write,
+    Modify,
+}
+
+fn operand_access(code: u8) -> OperandAccess {
+    match code {
+        // STA, STX, STY, SAX, AHX, SHX, SHY, TAS
+        0x85 | 0x95 | 0x8d | 0x9d | 0x99 | 0x81 | 0x91 | 0x86 | 0x96 | 0x8e | 0x84 | 0x94
+        | 0x8c | 0x87 | 0x97 | 0x8f | 0x83 | 0x93 | 0x9f | 0x9e | 0x9c | 0x9b => {
+            OperandAccess::Write
+        }
+        // ASL, LSR, ROL, ROR, INC, DEC
+        0x06 | 0x16 | 0x0e | 0x1e | 0x46 | 0x56 | 0x4e | 0x5e | 0x26 | 0x36 | 0x2e | 0x3e
+        | 0x66 | 0x76 | 0x6e | 0x7e | 0xe6 | 0xf6 | 0xee | 0xfe | 0xc6 | 0xd6 | 0xce | 0xde => {
+            OperandAccess::Modify
+        }
+        // DCP, RLA, RRA, SLO, SRE, ISB
+        0xc7 | 0xd7 | 0xcf | 0xdf | 0xdb | 0xd3 | 0xc3 | 0x27 | 0x37 | 0x2f | 0x3f | 0x3b
+        | 0x33 | 0x23 | 0x67 | 0x77 | 0x6f | 0x7f | 0x7b | 0x63 | 0x73 | 0x07 | 0x17 | 0x0f
+        | 0x1f | 0x1b | 0x03 | 0x13 | 0x47 | 0x57 | 0x4f | 0x5f | 0x5b | 0x43 | 0x53 | 0xe7
+        | 0xf7 | 0xef | 0xff | 0xfb | 0xe3 | 0xf3 => OperandAccess::Modify,
+        _ => OperandAccess::Read,
+    }
 }
 
 #[derive(Debug)]
@@ -46,14 +112,29 @@ pub enum AddressingMode {
 pub trait Mem {
     fn mem_read(&mut self, addr: u16) -> u8;
 
+    /// Reads a byte without any of the side effects a CPU read would have on
+    /// memory-mapped registers (PPU latches, joypad shift registers, watchpoints).
+    fn mem_peek(&self, addr: u16) -> u8;
+
     fn mem_write(&mut self, addr: u16, data: u8);
 
+    /// The name debug symbols give `addr`, if any.
+    fn symbol(&self, _addr: u16) -> Option<&str> {
+        None
+    }
+
     fn mem_read_u16(&mut self, pos: u16) -> u16 {
         let lo = self.mem_read(pos);
         let hi = self.mem_read(pos + 1);
         u16::from_le_bytes([lo, hi])
     }
 
+    fn mem_peek_u16(&self, pos: u16) -> u16 {
+        let lo = self.mem_peek(pos);
+        let hi = self.mem_peek(pos.wrapping_add(1));
+        u16::from_le_bytes([lo, hi])
+    }
+
     fn mem_write_u16(&mut self, pos: u16, data: u16) {
         let bytes = data.to_le_bytes();
         self.mem_write(pos, bytes[0]);
@@ -61,15 +142,23 @@ pub trait Mem {
     }
 }
 
-impl<'a> Mem for CPU<'a> {
+impl Mem for CPU {
     fn mem_read(&mut self, addr: u16) -> u8 {
         self.bus.mem_read(addr)
     }
 
+    fn mem_peek(&self, addr: u16) -> u8 {
+        self.bus.mem_peek(addr)
+    }
+
     fn mem_write(&mut self, addr: u16, data: u8) {
         self.bus.mem_write(addr, data);
     }
 
+    fn symbol(&self, addr: u16) -> Option<&str> {
+        self.bus.symbol(addr)
+    }
+
     fn mem_read_u16(&mut self, pos: u16) -> u16 {
         self.bus.mem_read_u16(pos)
     }
@@ -80,33 +169,53 @@ impl<'a> Mem for CPU<'a> {
 }
 
 mod interrupt {
-    #[derive(PartialEq, Eq)]
+    #[allow(clippy::upper_case_acronyms)]
+    #[derive(Clone, Copy, PartialEq, Eq)]
     pub enum InterruptType {
         NMI,
+        IRQ,
+        BRK,
     }
 
-    #[derive(PartialEq, Eq)]
+    #[derive(Clone, Copy, PartialEq, Eq)]
     pub(super) struct Interrupt {
         pub(super) itype: InterruptType,
         pub(super) vector_addr: u16,
         pub(super) b_flag_mask: u8,
-        pub(super) cpu_cycles: u8,
     }
 
     pub(super) const NMI: Interrupt = Interrupt {
         itype: InterruptType::NMI,
         vector_addr: 0xfffA,
         b_flag_mask: 0b00100000,
-        cpu_cycles: 2,
     };
+
+    pub(super) const IRQ: Interrupt = Interrupt {
+        itype: InterruptType::IRQ,
+        vector_addr: 0xfffe,
+        b_flag_mask: 0b00100000,
+    };
+
+    pub(super) const BRK: Interrupt = Interrupt {
+        itype: InterruptType::BRK,
+        vector_addr: 0xfffe,
+        b_flag_mask: 0b00110000,
+    };
+}
+
+fn is_kil(code: u8) -> bool {
+    matches!(
+        code,
+        0x02 | 0x12 | 0x22 | 0x32 | 0x42 | 0x52 | 0x62 | 0x72 | 0x92 | 0xb2 | 0xd2 | 0xf2
+    )
 }
 
 fn page_cross(addr1: u16, addr2: u16) -> bool {
     addr1 & 0xFF00 != addr2 & 0xFF00
 }
 
-impl<'a> CPU<'a> {
-    pub fn new<'b>(bus: Bus<'b>) -> CPU<'b> {
+impl CPU {
+    pub fn new(bus: Bus) -> CPU {
         CPU {
             register_a: 0,
             register_x: 0,
@@ -115,49 +224,48 @@ impl<'a> CPU<'a> {
             program_counter: 0,
             stack_pointer: STACK_RESET,
             bus: bus,
+            halt_on_brk: true,
+            call_stack: CallStack::new(),
+            opcode: 0,
+            cycle: 0,
+            addr: 0,
+            pointer: 0,
+            data: 0,
+            page_crossed: false,
+            servicing: None,
+            jammed: false,
+            nmi_pending: false,
+            prev_nmi_pending: false,
+            irq_pending: false,
+            prev_irq_pending: false,
+            interrupt_ready: false,
         }
     }
 
-    pub fn get_absolute_address(&mut self, mode: &AddressingMode, addr: u16) -> (u16, bool) {
+    /// Resolves the effective address of an operand at `addr`, only peeking at memory.
+    pub fn peek_absolute_address(&self, mode: &AddressingMode, addr: u16) -> u16 {
         match mode {
-            AddressingMode::ZeroPage => (self.mem_read(addr) as u16, false),
-            AddressingMode::Absolute => (self.mem_read_u16(addr), false),
-            AddressingMode::ZeroPage_X => {
-                let pos = self.mem_read(addr);
-                let addr = pos.wrapping_add(self.register_x) as u16;
-                (addr, false)
-            }
-            AddressingMode::ZeroPage_Y => {
-                let pos = self.mem_read(addr);
-                let addr = pos.wrapping_add(self.register_y) as u16;
-                (addr, false)
-            }
+            AddressingMode::ZeroPage => self.mem_peek(addr) as u16,
+            AddressingMode::Absolute => self.mem_peek_u16(addr),
+            AddressingMode::ZeroPage_X => self.mem_peek(addr).wrapping_add(self.register_x) as u16,
+            AddressingMode::ZeroPage_Y => self.mem_peek(addr).wrapping_add(self.register_y) as u16,
             AddressingMode::Absolute_X => {
-                let base = self.mem_read_u16(addr);
-                let addr = base.wrapping_add(self.register_x as u16);
-                (addr, page_cross(base, addr))
+                self.mem_peek_u16(addr).wrapping_add(self.register_x as u16)
             }
             AddressingMode::Absolute_Y => {
-                let base = self.mem_read_u16(addr);
-                let addr = base.wrapping_add(self.register_y as u16);
-                (addr, page_cross(base, addr))
+                self.mem_peek_u16(addr).wrapping_add(self.register_y as u16)
             }
             AddressingMode::Indirect_X => {
-                let base = self.mem_read(addr);
-
-                let ptr: u8 = (base as u8).wrapping_add(self.register_x);
-                let lo = self.mem_read(ptr as u16);
-                let hi = self.mem_read(ptr.wrapping_add(1) as u16);
-                ((hi as u16) << 8 | (lo as u16), false)
+                let ptr = self.mem_peek(addr).wrapping_add(self.register_x);
+                let lo = self.mem_peek(ptr as u16);
+                let hi = self.mem_peek(ptr.wrapping_add(1) as u16);
+                u16::from_le_bytes([lo, hi])
             }
             AddressingMode::Indirect_Y => {
-                let base = self.mem_read(addr);
-
-                let lo = self.mem_read(base as u16);
-                let hi = self.mem_read((base as u8).wrapping_add(1) as u16);
-                let deref_base = (hi as u16) << 8 | (lo as u16);
-                let deref = deref_base.wrapping_add(self.register_y as u16);
-                (deref, page_cross(deref_base, deref))
+                let base = self.mem_peek(addr);
+                let lo = self.mem_peek(base as u16);
+                let hi = self.mem_peek(base.wrapping_add(1) as u16);
+                u16::from_le_bytes([lo, hi]).wrapping_add(self.register_y as u16)
             }
             _ => {
                 panic!("mode {:?} is not supported", mode);
@@ -165,13 +273,6 @@ impl<'a> CPU<'a> {
         }
     }
 
-    fn get_operand_address(&mut self, mode: &AddressingMode) -> (u16, bool) {
-        match mode {
-            AddressingMode::Immediate => (self.program_counter, false),
-            _ => self.get_absolute_address(mode, self.program_counter),
-        }
-    }
-
     fn update_zero_and_negative_flags(&mut self, result: u8) {
         if result == 0 {
             self.status.insert(CpuFlags::ZERO);
@@ -195,69 +296,11 @@ impl<'a> CPU<'a> {
         self.update_zero_and_negative_flags(self.register_a);
     }
 
-    fn lda(&mut self, mode: &AddressingMode) {
-        let (addr, page_cross) = self.get_operand_address(mode);
-        let value = self.mem_read(addr);
-
+    fn lax(&mut self, value: u8) {
         self.set_register_a(value);
-
-        if page_cross {
-            self.bus.tick(1);
-        }
-    }
-
-    fn ldx(&mut self, mode: &AddressingMode) {
-        let (addr, page_cross) = self.get_operand_address(mode);
-        let value = self.mem_read(addr);
-
-        self.register_x = value;
-        self.update_zero_and_negative_flags(self.register_x);
-
-        if page_cross {
-            self.bus.tick(1);
-        }
-    }
-
-    fn lax(&mut self, mode: &AddressingMode) {
-        let (addr, _) = self.get_operand_address(mode);
-        let data = self.mem_read(addr);
-        self.set_register_a(data);
         self.tax();
     }
 
-    fn ldy(&mut self, mode: &AddressingMode) {
-        let (addr, page_cross) = self.get_operand_address(mode);
-        let value = self.mem_read(addr);
-
-        self.register_y = value;
-        self.update_zero_and_negative_flags(self.register_y);
-
-        if page_cross {
-            self.bus.tick(1);
-        }
-    }
-
-    fn sta(&mut self, mode: &AddressingMode) {
-        let (addr, _) = self.get_operand_address(mode);
-        self.mem_write(addr, self.register_a);
-    }
-
-    fn stx(&mut self, mode: &AddressingMode) {
-        let (addr, _) = self.get_operand_address(mode);
-        self.mem_write(addr, self.register_x);
-    }
-
-    fn sty(&mut self, mode: &AddressingMode) {
-        let (addr, _) = self.get_operand_address(mode);
-        self.mem_write(addr, self.register_y);
-    }
-
-    fn sax(&mut self, mode: &AddressingMode) {
-        let data = self.register_a & self.register_x;
-        let (addr, _) = self.get_operand_address(mode);
-        self.mem_write(addr, data);
-    }
-
     fn tax(&mut self) {
         self.register_x = self.register_a;
         self.update_zero_and_negative_flags(self.register_x);
@@ -278,37 +321,15 @@ impl<'a> CPU<'a> {
         self.update_zero_and_negative_flags(self.register_a);
     }
 
-    fn stack_pop(&mut self) -> u8 {
-        self.stack_pointer = self.stack_pointer.wrapping_add(1);
-        self.mem_read(STACK + self.stack_pointer as u16)
-    }
-
-    fn stack_push(&mut self, value: u8) {
-        self.mem_write(STACK + self.stack_pointer as u16, value);
-        self.stack_pointer = self.stack_pointer.wrapping_sub(1);
-    }
-
-    fn stack_pop_u16(&mut self) -> u16 {
-        let lo = self.stack_pop();
-        let hi = self.stack_pop();
-        u16::from_le_bytes([lo, hi])
-    }
-
-    fn stack_push_u16(&mut self, data: u16) {
-        let bytes = u16::to_le_bytes(data);
-        self.stack_push(bytes[1]);
-        self.stack_push(bytes[0]);
-    }
-
-    fn php(&mut self) {
+    fn php_flags(&self) -> u8 {
         let mut flags = self.status.clone();
         flags.insert(CpuFlags::BREAK);
         flags.insert(CpuFlags::BREAK2);
-        self.stack_push(flags.bits());
+        flags.bits()
     }
 
-    fn plp(&mut self) {
-        self.status.bits = self.stack_pop();
+    fn plp(&mut self, data: u8) {
+        self.status.bits = data;
         self.status.remove(CpuFlags::BREAK);
         self.status.insert(CpuFlags::BREAK2);
     }
@@ -338,58 +359,11 @@ impl<'a> CPU<'a> {
         self.set_register_a(result);
     }
 
-    fn adc(&mut self, mode: &AddressingMode) {
-        let (addr, page_cross) = self.get_operand_address(mode);
-        let value = self.mem_read(addr);
-        self.add_to_register_a(value);
-
-        if page_cross {
-            self.bus.tick(1);
-        }
-    }
-
-    fn sbc(&mut self, mode: &AddressingMode) {
-        let (addr, page_cross) = self.get_operand_address(mode);
-        let value = self.mem_read(addr);
+    fn sub_from_register_a(&mut self, value: u8) {
         self.add_to_register_a(((value as i8).wrapping_neg().wrapping_sub(1)) as u8);
-
-        if page_cross {
-            self.bus.tick(1);
-        }
-    }
-
-    fn and(&mut self, mode: &AddressingMode) {
-        let (addr, page_cross) = self.get_operand_address(mode);
-        let value = self.mem_read(addr);
-        self.set_register_a(self.register_a & value);
-
-        if page_cross {
-            self.bus.tick(1);
-        }
-    }
-
-    fn eor(&mut self, mode: &AddressingMode) {
-        let (addr, page_cross) = self.get_operand_address(mode);
-        let value = self.mem_read(addr);
-        self.set_register_a(self.register_a ^ value);
-
-        if page_cross {
-            self.bus.tick(1);
-        }
     }
 
-    fn ora(&mut self, mode: &AddressingMode) {
-        let (addr, page_cross) = self.get_operand_address(mode);
-        let value = self.mem_read(addr);
-        self.set_register_a(self.register_a | value);
-
-        if page_cross {
-            self.bus.tick(1);
-        }
-    }
-
-    fn asl_acc(&mut self) {
-        let mut data = self.register_a;
+    fn asl(&mut self, mut data: u8) -> u8 {
         if data >> 7 == 1 {
             self.status.insert(CpuFlags::CARRY);
         } else {
@@ -397,39 +371,11 @@ impl<'a> CPU<'a> {
         }
 
         data = data << 1;
-        self.set_register_a(data);
-    }
-
-    fn asl(&mut self, mode: &AddressingMode) -> u8 {
-        let (addr, _) = self.get_operand_address(mode);
-        let mut data = self.mem_read(addr);
-        if data >> 7 == 1 {
-            self.status.insert(CpuFlags::CARRY);
-        } else {
-            self.status.remove(CpuFlags::CARRY);
-        }
-
-        data = data << 1;
-        self.mem_write(addr, data);
         self.update_zero_and_negative_flags(data);
         data
     }
 
-    fn lsr_acc(&mut self) {
-        let mut data = self.register_a;
-        if data & 1 == 1 {
-            self.status.insert(CpuFlags::CARRY);
-        } else {
-            self.status.remove(CpuFlags::CARRY);
-        }
-
-        data = data >> 1;
-        self.set_register_a(data);
-    }
-
-    fn lsr(&mut self, mode: &AddressingMode) -> u8 {
-        let (addr, _) = self.get_operand_address(mode);
-        let mut data = self.mem_read(addr);
+    fn lsr(&mut self, mut data: u8) -> u8 {
         if data & 1 == 1 {
             self.status.insert(CpuFlags::CARRY);
         } else {
@@ -437,13 +383,11 @@ impl<'a> CPU<'a> {
         }
 
         data = data >> 1;
-        self.mem_write(addr, data);
         self.update_zero_and_negative_flags(data);
         data
     }
 
-    fn rol_acc(&mut self) {
-        let mut data = self.register_a;
+    fn rol(&mut self, mut data: u8) -> u8 {
         let old_carry = self.status.contains(CpuFlags::CARRY);
 
         if data >> 7 == 1 {
@@ -456,31 +400,11 @@ impl<'a> CPU<'a> {
         if old_carry {
             data = data | 1;
         }
-        self.set_register_a(data);
-    }
-
-    fn rol(&mut self, mode: &AddressingMode) -> u8 {
-        let (addr, _) = self.get_operand_address(mode);
-        let mut data = self.mem_read(addr);
-        let old_carry = self.status.contains(CpuFlags::CARRY);
-
-        if data >> 7 == 1 {
-            self.status.insert(CpuFlags::CARRY);
-        } else {
-            self.status.remove(CpuFlags::CARRY);
-        }
-
-        data = data << 1;
-        if old_carry {
-            data = data | 1;
-        }
-        self.mem_write(addr, data);
         self.update_negative_flag(data);
         data
     }
 
-    fn ror_acc(&mut self) {
-        let mut data = self.register_a;
+    fn ror(&mut self, mut data: u8) -> u8 {
         let old_carry = self.status.contains(CpuFlags::CARRY);
 
         if data & 1 == 1 {
@@ -493,34 +417,18 @@ impl<'a> CPU<'a> {
         if old_carry {
             data = data | 0b1000_0000;
         }
-        self.set_register_a(data);
+        self.update_negative_flag(data);
+        data
     }
 
-    fn ror(&mut self, mode: &AddressingMode) -> u8 {
-        let (addr, _) = self.get_operand_address(mode);
-        let mut data = self.mem_read(addr);
-        let old_carry = self.status.contains(CpuFlags::CARRY);
-
-        if data & 1 == 1 {
-            self.status.insert(CpuFlags::CARRY);
-        } else {
-            self.status.remove(CpuFlags::CARRY);
-        }
-
-        data = data >> 1;
-        if old_carry {
-            data = data | 0b1000_0000;
-        }
-        self.mem_write(addr, data);
-        self.update_negative_flag(data);
+    fn inc(&mut self, data: u8) -> u8 {
+        let data = data.wrapping_add(1);
+        self.update_zero_and_negative_flags(data);
         data
     }
 
-    fn inc(&mut self, mode: &AddressingMode) -> u8 {
-        let (addr, _) = self.get_operand_address(mode);
-        let mut data = self.mem_read(addr);
-        data = data.wrapping_add(1);
-        self.mem_write(addr, data);
+    fn dec(&mut self, data: u8) -> u8 {
+        let data = data.wrapping_sub(1);
         self.update_zero_and_negative_flags(data);
         data
     }
@@ -535,14 +443,6 @@ impl<'a> CPU<'a> {
         self.update_zero_and_negative_flags(self.register_y);
     }
 
-    fn dec(&mut self, mode: &AddressingMode) {
-        let (addr, _) = self.get_operand_address(mode);
-        let mut data = self.mem_read(addr);
-        data = data.wrapping_sub(1);
-        self.mem_write(addr, data);
-        self.update_zero_and_negative_flags(data);
-    }
-
     fn dex(&mut self) {
         self.register_x = self.register_x.wrapping_sub(1);
         self.update_zero_and_negative_flags(self.register_x);
@@ -553,10 +453,7 @@ impl<'a> CPU<'a> {
         self.update_zero_and_negative_flags(self.register_y);
     }
 
-    fn compare(&mut self, mode: &AddressingMode, compare_value: u8) {
-        let (addr, page_cross) = self.get_operand_address(mode);
-        let data = self.mem_read(addr);
-
+    fn compare(&mut self, compare_value: u8, data: u8) {
         if data <= compare_value {
             self.status.insert(CpuFlags::CARRY);
         } else {
@@ -564,16 +461,9 @@ impl<'a> CPU<'a> {
         }
 
         self.update_zero_and_negative_flags(compare_value.wrapping_sub(data));
-
-        if page_cross {
-            self.bus.tick(1);
-        }
     }
 
-    fn bit(&mut self, mode: &AddressingMode) {
-        let (addr, _) = self.get_operand_address(mode);
-        let data = self.mem_read(addr);
-
+    fn bit(&mut self, data: u8) {
         if self.register_a & data == 0 {
             self.status.insert(CpuFlags::ZERO);
         } else {
@@ -584,63 +474,22 @@ impl<'a> CPU<'a> {
         self.status.set(CpuFlags::OVERFLOW, data & 0b0100_0000 > 0);
     }
 
-    fn jmp(&mut self) {
-        let mem_addr = self.mem_read_u16(self.program_counter);
-        self.program_counter = mem_addr;
-    }
-
-    fn jmp_indirect(&mut self) {
-        let mem_addr = self.mem_read_u16(self.program_counter);
-
-        //6502 bug mode with page boundary
-        let indirect_ref = if mem_addr & 0x00FF == 0x00FF {
-            let lo = self.mem_read(mem_addr);
-            let hi = self.mem_read(mem_addr & 0xFF00);
-            u16::from_le_bytes([lo, hi])
-        } else {
-            self.mem_read_u16(mem_addr)
-        };
-
-        self.program_counter = indirect_ref;
-    }
-
-    fn jsr(&mut self) {
-        self.stack_push_u16(self.program_counter + 2 - 1); // Push the return point to the stack
-        let mem_addr = self.mem_read_u16(self.program_counter);
-        self.program_counter = mem_addr;
-    }
-
-    fn rti(&mut self) {
-        self.status.bits = self.stack_pop();
-        self.status.remove(CpuFlags::BREAK);
-        self.status.insert(CpuFlags::BREAK2);
-
-        self.program_counter = self.stack_pop_u16();
-    }
-
     /* Unofficial */
 
-    fn dcp(&mut self, mode: &AddressingMode) {
-        let (addr, _) = self.get_operand_address(mode);
-        let mut data = self.mem_read(addr);
+    fn dcp(&mut self, mut data: u8) -> u8 {
         data = data.wrapping_sub(1);
-        self.mem_write(addr, data);
 
-        if data <= self.register_a {
-            self.status.insert(CpuFlags::CARRY);
-        }
+        self.status.set(CpuFlags::CARRY, data <= self.register_a);
 
         self.update_zero_and_negative_flags(self.register_a.wrapping_sub(data));
+        data
     }
 
-    fn axs(&mut self, mode: &AddressingMode) {
-        let (addr, _) = self.get_operand_address(mode);
-        let data = self.mem_read(addr);
-
-        let x_a = self.register_x & self.register_y;
+    fn axs(&mut self, data: u8) {
+        let x_a = self.register_x & self.register_a;
         self.register_x = x_a.wrapping_sub(data);
 
-        if data <= self.register_x {
+        if data <= x_a {
             self.status.insert(CpuFlags::CARRY);
         } else {
             self.status.remove(CpuFlags::CARRY);
@@ -649,14 +498,11 @@ impl<'a> CPU<'a> {
         self.update_zero_and_negative_flags(self.register_x);
     }
 
-    fn arr(&mut self, mode: &AddressingMode) {
-        let (addr, _) = self.get_operand_address(mode);
-        let data = self.mem_read(addr);
-
+    fn arr(&mut self, data: u8) {
         self.set_register_a(self.register_a & data);
-        self.ror_acc();
+        let result = self.ror(self.register_a);
+        self.set_register_a(result);
 
-        let result = self.register_a;
         let bit_5 = (result >> 5) & 1;
         let bit_6 = (result >> 6) & 1;
 
@@ -675,18 +521,13 @@ impl<'a> CPU<'a> {
         self.update_zero_and_negative_flags(result);
     }
 
-    fn alr(&mut self, mode: &AddressingMode) {
-        let (addr, _) = self.get_operand_address(mode);
-        let data = self.mem_read(addr);
-
+    fn alr(&mut self, data: u8) {
         self.set_register_a(data & self.register_a);
-        self.lsr_acc();
+        let result = self.lsr(self.register_a);
+        self.set_register_a(result);
     }
 
-    fn anc(&mut self, mode: &AddressingMode) {
-        let (addr, _) = self.get_operand_address(mode);
-        let data = self.mem_read(addr);
-
+    fn anc(&mut self, data: u8) {
         self.set_register_a(self.register_a & data);
         if self.status.contains(CpuFlags::NEGATIVE) {
             self.status.insert(CpuFlags::CARRY);
@@ -695,17 +536,7 @@ impl<'a> CPU<'a> {
         }
     }
 
-    fn lxa(&mut self, mode: &AddressingMode) {
-        let (addr, _) = self.get_operand_address(mode);
-        let data = self.mem_read(addr);
-        self.set_register_a(data & self.register_a);
-        self.tax();
-    }
-
-    fn las(&mut self, mode: &AddressingMode) {
-        let (addr, _) = self.get_operand_address(mode);
-        let data = self.mem_read(addr);
-
+    fn las(&mut self, data: u8) {
         let result = data & self.stack_pointer;
         self.register_a = result;
         self.register_x = result;
@@ -713,50 +544,600 @@ impl<'a> CPU<'a> {
         self.update_zero_and_negative_flags(result);
     }
 
-    fn tas(&mut self) {
-        self.stack_pointer = self.register_a & self.register_x;
-        let mem_addr = self.mem_read_u16(self.program_counter) + self.register_y as u16;
+    /* End unofficial */
+
+    /// Instructions that only read their operand.
+    fn execute_read(&mut self, value: u8) {
+        match self.opcode {
+            // ADC
+            0x69 | 0x65 | 0x75 | 0x6d | 0x7d | 0x79 | 0x61 | 0x71 => self.add_to_register_a(value),
+            // SBC, and its unofficial copy
+            0xe9 | 0xe5 | 0xf5 | 0xed | 0xfd | 0xf9 | 0xe1 | 0xf1 | 0xeb => {
+                self.sub_from_register_a(value)
+            }
+            // AND
+            0x29 | 0x25 | 0x35 | 0x2d | 0x3d | 0x39 | 0x21 | 0x31 => {
+                self.set_register_a(self.register_a & value)
+            }
+            // EOR
+            0x49 | 0x45 | 0x55 | 0x4d | 0x5d | 0x59 | 0x41 | 0x51 => {
+                self.set_register_a(self.register_a ^ value)
+            }
+            // ORA
+            0x09 | 0x05 | 0x15 | 0x0d | 0x1d | 0x19 | 0x01 | 0x11 => {
+                self.set_register_a(self.register_a | value)
+            }
+            // CMP
+            0xc9 | 0xc5 | 0xd5 | 0xcd | 0xdd | 0xd9 | 0xc1 | 0xd1 => {
+                self.compare(self.register_a, value)
+            }
+            0xc0 | 0xc4 | 0xcc => self.compare(self.register_y, value),
+            0xe0 | 0xe4 | 0xec => self.compare(self.register_x, value),
+            //BIT
+            0x24 | 0x2c => self.bit(value),
+            // LDA
+            0xa9 | 0xa5 | 0xb5 | 0xad | 0xbd | 0xb9 | 0xa1 | 0xb1 => self.set_register_a(value),
+            // LDX
+            0xa2 | 0xa6 | 0xb6 | 0xae | 0xbe => {
+                self.register_x = value;
+                self.update_zero_and_negative_flags(self.register_x);
+            }
+            // LDY
+            0xa0 | 0xa4 | 0xb4 | 0xac | 0xbc => {
+                self.register_y = value;
+                self.update_zero_and_negative_flags(self.register_y);
+            }
+            // LAX
+            0xa7 | 0xb7 | 0xaf | 0xbf | 0xa3 | 0xb3 => self.lax(value),
+            // AXS
+            0xcb => self.axs(value),
+            // ARR
+            0x6b => self.arr(value),
+            // ALR
+            0x4b => self.alr(value),
+            // ANC
+            0x0b | 0x2b => self.anc(value),
+            //LXA
+            0xab => {
+                self.set_register_a(value & self.register_a);
+                self.tax();
+            }
+            // XAA
+            0x8b => {
+                self.register_a = self.register_x;
+                self.update_zero_and_negative_flags(self.register_a);
+                self.set_register_a(self.register_a & value);
+            }
+            0xbb => self.las(value),
+            // NOPs that read memory
+            _ => {}
+        }
+    }
+
+    /// Read-modify-write instructions: returns the value written back.
+    fn execute_modify(&mut self, data: u8) -> u8 {
+        match self.opcode {
+            // ASL
+            0x06 | 0x16 | 0x0e | 0x1e => self.asl(data),
+            // LSR
+            0x46 | 0x56 | 0x4e | 0x5e => self.lsr(data),
+            // ROL
+            0x26 | 0x36 | 0x2e | 0x3e => self.rol(data),
+            // ROR
+            0x66 | 0x76 | 0x6e | 0x7e => self.ror(data),
+            // INC
+            0xe6 | 0xf6 | 0xee | 0xfe => self.inc(data),
+            // DEC
+            0xc6 | 0xd6 | 0xce | 0xde => self.dec(data),
+            // DCP
+            0xc7 | 0xd7 | 0xcf | 0xdf | 0xdb | 0xd3 | 0xc3 => self.dcp(data),
+            // RLA
+            0x27 | 0x37 | 0x2f | 0x3f | 0x3b | 0x33 | 0x23 => {
+                let data = self.rol(data);
+                self.set_register_a(data & self.register_a);
+                data
+            }
+            // RRA
+            0x67 | 0x77 | 0x6f | 0x7f | 0x7b | 0x63 | 0x73 => {
+                let data = self.ror(data);
+                self.add_to_register_a(data);
+                data
+            }
+            // SLO
+            0x07 | 0x17 | 0x0f | 0x1f | 0x1b | 0x03 | 0x13 => {
+                let data = self.asl(data);
+                self.set_register_a(data | self.register_a);
+                data
+            }
+            // SRE
+            0x47 | 0x57 | 0x4f | 0x5f | 0x5b | 0x43 | 0x53 => {
+                let data = self.lsr(data);
+                self.set_register_a(data ^ self.register_a);
+                data
+            }
+            // ISB
+            _ => {
+                let data = self.inc(data);
+                self.sub_from_register_a(data);
+                data
+            }
+        }
+    }
 
-        let data = ((mem_addr >> 8) as u8 + 1) & self.stack_pointer;
-        self.mem_write(mem_addr, data);
+    /// The value a store instruction writes to its effective address.
+    fn store_value(&mut self) -> u8 {
+        let high_byte = (self.addr >> 8) as u8;
+        match self.opcode {
+            // STA
+            0x85 | 0x95 | 0x8d | 0x9d | 0x99 | 0x81 | 0x91 => self.register_a,
+            // STX
+            0x86 | 0x96 | 0x8e => self.register_x,
+            // STY
+            0x84 | 0x94 | 0x8c => self.register_y,
+            //SAX
+            0x87 | 0x97 | 0x8f | 0x83 => self.register_a & self.register_x,
+            // AHX
+            0x93 | 0x9f => self.register_a & self.register_x & high_byte,
+            // SHX
+            0x9e => self.register_x & high_byte.wrapping_add(1),
+            // SHY
+            0x9c => self.register_y & high_byte.wrapping_add(1),
+            // TAS
+            _ => {
+                self.stack_pointer = self.register_a & self.register_x;
+                self.stack_pointer & high_byte.wrapping_add(1)
+            }
+        }
     }
 
-    /* End unofficial */
+    /// Single byte instructions that work on registers.
+    fn execute_implied(&mut self) {
+        match self.opcode {
+            0x0a => {
+                let data = self.asl(self.register_a);
+                self.set_register_a(data);
+            }
+            0x4a => {
+                let data = self.lsr(self.register_a);
+                self.set_register_a(data);
+            }
+            0x2a => {
+                let data = self.rol(self.register_a);
+                self.set_register_a(data);
+            }
+            0x6a => {
+                let data = self.ror(self.register_a);
+                self.set_register_a(data);
+            }
+            0xe8 => self.inx(),
+            0xc8 => self.iny(),
+            0xca => self.dex(),
+            0x88 => self.dey(),
+            0xaa => self.tax(),
+            0xa8 => self.tay(),
+            0x8a => self.txa(),
+            0x98 => self.tya(),
+            0xba => {
+                self.register_x = self.stack_pointer;
+                self.update_zero_and_negative_flags(self.register_x);
+            }
+            0x9a => self.stack_pointer = self.register_x,
+            0xf8 => self.status.insert(CpuFlags::DECIMAL_MODE),
+            0xd8 => self.status.remove(CpuFlags::DECIMAL_MODE),
+            0x78 => self.status.insert(CpuFlags::INTERRUPT_DISABLE),
+            0x58 => self.status.remove(CpuFlags::INTERRUPT_DISABLE),
+            0x38 => self.status.insert(CpuFlags::CARRY),
+            0x18 => self.status.remove(CpuFlags::CARRY),
+            0xb8 => self.status.remove(CpuFlags::OVERFLOW),
+            // NOPs
+            _ => {}
+        }
+    }
 
-    fn branch(&mut self, condition: bool) {
-        if condition {
-            self.bus.tick(1);
+    /* Bus cycles */
 
-            let jump = self.mem_read(self.program_counter) as i8;
-            let jump_addr = self
-                .program_counter
-                .wrapping_add(1)
-                .wrapping_add(jump as u16);
+    // The interrupt lines are sampled at the end of every cycle, and whether to take an
+    // interrupt is decided by their state going into the last cycle of an instruction
+    fn end_cycle(&mut self) {
+        self.bus.tick(1);
 
-            if self.program_counter.wrapping_add(1) & 0xFF00 != jump_addr & 0xFF00 {
-                self.bus.tick(1);
+        self.prev_nmi_pending = self.nmi_pending;
+        if self.bus.poll_nmi_status().is_some() {
+            self.nmi_pending = true;
+        }
+        self.prev_irq_pending = self.irq_pending;
+        self.irq_pending =
+            self.bus.poll_irq_status() && !self.status.contains(CpuFlags::INTERRUPT_DISABLE);
+    }
+
+    fn read_cycle(&mut self, addr: u16) -> u8 {
+        // reads at the program counter are the instruction itself, or the ones after it
+        if self.bus.cdl.is_some() && addr != self.program_counter {
+            let indirect = self.servicing.is_none()
+                && matches!(
+                    OPCODES[self.opcode as usize].map(|op| &op.mode),
+                    Some(AddressingMode::Indirect_X | AddressingMode::Indirect_Y)
+                );
+            let flags = if indirect {
+                cdl::DATA | cdl::INDIRECT_DATA
+            } else {
+                cdl::DATA
+            };
+            self.log_prg(addr, 1, flags);
+        }
+        let data = self.mem_read(addr);
+        self.end_cycle();
+        data
+    }
+
+    fn write_cycle(&mut self, addr: u16, data: u8) {
+        self.mem_write(addr, data);
+        self.end_cycle();
+    }
+
+    fn fetch_operand(&mut self) -> u8 {
+        let data = self.read_cycle(self.program_counter);
+        self.program_counter = self.program_counter.wrapping_add(1);
+        data
+    }
+
+    fn push_cycle(&mut self, data: u8) {
+        self.write_cycle(STACK + self.stack_pointer as u16, data);
+        self.stack_pointer = self.stack_pointer.wrapping_sub(1);
+    }
+
+    fn pop_cycle(&mut self) -> u8 {
+        self.stack_pointer = self.stack_pointer.wrapping_add(1);
+        self.read_cycle(STACK + self.stack_pointer as u16)
+    }
+
+    fn index_addr(&mut self, base: u16, index: u8) {
+        self.addr = base.wrapping_add(index as u16);
+        self.page_crossed = page_cross(base, self.addr);
+    }
+
+    /// Works out the effective address, one cycle at a time. Returns `true` if the
+    /// instruction already finished, when an indexed read didn't need to fix up the page.
+    fn address_cycle(&mut self, mode: &AddressingMode, access: OperandAccess) -> bool {
+        match (mode, self.cycle) {
+            (AddressingMode::Indirect_X, 1) | (AddressingMode::Indirect_Y, 1) => {
+                self.pointer = self.fetch_operand()
+            }
+            (_, 1) => self.addr = self.fetch_operand() as u16,
+            (AddressingMode::ZeroPage_X, _) => {
+                self.read_cycle(self.addr);
+                self.addr = (self.addr as u8).wrapping_add(self.register_x) as u16;
+            }
+            (AddressingMode::ZeroPage_Y, _) => {
+                self.read_cycle(self.addr);
+                self.addr = (self.addr as u8).wrapping_add(self.register_y) as u16;
+            }
+            (AddressingMode::Absolute, _) => self.addr |= (self.fetch_operand() as u16) << 8,
+            (AddressingMode::Absolute_X, 2) => {
+                let base = self.addr | (self.fetch_operand() as u16) << 8;
+                self.index_addr(base, self.register_x);
+            }
+            (AddressingMode::Absolute_Y, 2) => {
+                let base = self.addr | (self.fetch_operand() as u16) << 8;
+                self.index_addr(base, self.register_y);
+            }
+            (AddressingMode::Indirect_X, 2) => {
+                self.read_cycle(self.pointer as u16);
+                self.pointer = self.pointer.wrapping_add(self.register_x);
+            }
+            (AddressingMode::Indirect_X, 3) | (AddressingMode::Indirect_Y, 2) => {
+                self.addr = self.read_cycle(self.pointer as u16) as u16
+            }
+            (AddressingMode::Indirect_X, _) => {
+                let hi = self.read_cycle(self.pointer.wrapping_add(1) as u16);
+                self.addr |= (hi as u16) << 8;
+            }
+            (AddressingMode::Indirect_Y, 3) => {
+                let hi = self.read_cycle(self.pointer.wrapping_add(1) as u16);
+                let base = self.addr | (hi as u16) << 8;
+                self.index_addr(base, self.register_y);
+            }
+            // indexed modes read before the carry reaches the high byte
+            _ => {
+                let uncorrected = if self.page_crossed {
+                    self.addr.wrapping_sub(0x100)
+                } else {
+                    self.addr
+                };
+                let value = self.read_cycle(uncorrected);
+                if access == OperandAccess::Read && !self.page_crossed {
+                    self.execute_read(value);
+                    return true;
+                }
             }
+        }
+        false
+    }
 
-            self.program_counter = jump_addr;
+    fn memory_cycle(&mut self, mode: &AddressingMode) -> bool {
+        let access = operand_access(self.opcode);
+        let address_cycles = match mode {
+            AddressingMode::ZeroPage => 1,
+            AddressingMode::ZeroPage_X | AddressingMode::ZeroPage_Y | AddressingMode::Absolute => 2,
+            AddressingMode::Absolute_X | AddressingMode::Absolute_Y => 3,
+            _ => 4,
+        };
+        if self.cycle <= address_cycles {
+            return self.address_cycle(mode, access);
+        }
+
+        match (access, self.cycle - address_cycles) {
+            (OperandAccess::Read, _) => {
+                let value = self.read_cycle(self.addr);
+                self.execute_read(value);
+                true
+            }
+            (OperandAccess::Write, _) => {
+                let value = self.store_value();
+                self.write_cycle(self.addr, value);
+                true
+            }
+            (OperandAccess::Modify, 1) => {
+                self.data = self.read_cycle(self.addr);
+                false
+            }
+            // the unmodified value is written back while the ALU works
+            (OperandAccess::Modify, 2) => {
+                self.write_cycle(self.addr, self.data);
+                self.data = self.execute_modify(self.data);
+                false
+            }
+            (OperandAccess::Modify, _) => {
+                self.write_cycle(self.addr, self.data);
+                true
+            }
         }
     }
 
-    fn interrupt(&mut self, interrupt: interrupt::Interrupt) {
-        self.stack_push_u16(self.program_counter);
-        let mut flag = self.status.clone();
-        flag.set(CpuFlags::BREAK, interrupt.b_flag_mask & 0b010000 == 1);
-        flag.set(CpuFlags::BREAK2, interrupt.b_flag_mask & 0b100000 == 1);
+    fn branch_taken(&self) -> bool {
+        // bits 6-7 pick the flag, bit 5 the value it is compared with
+        let flag = match self.opcode >> 6 {
+            0 => CpuFlags::NEGATIVE,
+            1 => CpuFlags::OVERFLOW,
+            2 => CpuFlags::CARRY,
+            _ => CpuFlags::ZERO,
+        };
+        self.status.contains(flag) == (self.opcode & 0b0010_0000 != 0)
+    }
 
-        self.stack_push(flag.bits);
-        self.status.insert(CpuFlags::INTERRUPT_DISABLE);
+    fn branch_cycle(&mut self) -> bool {
+        match self.cycle {
+            1 => {
+                self.data = self.fetch_operand();
+                !self.branch_taken()
+            }
+            2 => {
+                // a taken branch that stays on the page doesn't see an IRQ raised now
+                if self.irq_pending && !self.prev_irq_pending {
+                    self.irq_pending = false;
+                }
+                self.read_cycle(self.program_counter);
+                let target = self.program_counter.wrapping_add(self.data as i8 as u16);
+                self.page_crossed = page_cross(self.program_counter, target);
+                self.program_counter = (self.program_counter & 0xff00) | (target & 0x00ff);
+                self.addr = target;
+                !self.page_crossed
+            }
+            _ => {
+                self.read_cycle(self.program_counter);
+                self.program_counter = self.addr;
+                true
+            }
+        }
+    }
+
+    fn jmp_cycle(&mut self) -> bool {
+        match self.cycle {
+            1 => {
+                self.addr = self.fetch_operand() as u16;
+                false
+            }
+            _ => {
+                let hi = self.read_cycle(self.program_counter);
+                self.program_counter = (hi as u16) << 8 | self.addr;
+                true
+            }
+        }
+    }
 
-        self.bus.tick(interrupt.cpu_cycles);
-        self.program_counter = self.mem_read_u16(interrupt.vector_addr);
+    fn jmp_indirect_cycle(&mut self) -> bool {
+        match self.cycle {
+            1 => self.addr = self.fetch_operand() as u16,
+            2 => self.addr |= (self.fetch_operand() as u16) << 8,
+            3 => self.data = self.read_cycle(self.addr),
+            _ => {
+                //6502 bug mode with page boundary
+                let hi_addr = (self.addr & 0xff00) | (self.addr.wrapping_add(1) & 0x00ff);
+                let hi = self.read_cycle(hi_addr);
+                self.program_counter = u16::from_le_bytes([self.data, hi]);
+                return true;
+            }
+        }
+        false
     }
 
+    fn jsr_cycle(&mut self) -> bool {
+        match self.cycle {
+            1 => self.addr = self.fetch_operand() as u16,
+            2 => {
+                self.read_cycle(STACK + self.stack_pointer as u16);
+            }
+            // the return point is the last byte of the JSR
+            3 => self.push_cycle((self.program_counter >> 8) as u8),
+            4 => self.push_cycle(self.program_counter as u8),
+            _ => {
+                let hi = self.read_cycle(self.program_counter);
+                self.program_counter = (hi as u16) << 8 | self.addr;
+                return true;
+            }
+        }
+        false
+    }
+
+    fn rts_cycle(&mut self) -> bool {
+        match self.cycle {
+            1 => {
+                self.read_cycle(self.program_counter);
+            }
+            2 => {
+                self.read_cycle(STACK + self.stack_pointer as u16);
+            }
+            3 => self.addr = self.pop_cycle() as u16,
+            4 => self.program_counter = (self.pop_cycle() as u16) << 8 | self.addr,
+            _ => {
+                self.read_cycle(self.program_counter);
+                self.program_counter = self.program_counter.wrapping_add(1);
+                return true;
+            }
+        }
+        false
+    }
+
+    fn rti_cycle(&mut self) -> bool {
+        match self.cycle {
+            1 => {
+                self.read_cycle(self.program_counter);
+            }
+            2 => {
+                self.read_cycle(STACK + self.stack_pointer as u16);
+            }
+            3 => {
+                let data = self.pop_cycle();
+                self.plp(data);
+            }
+            4 => self.addr = self.pop_cycle() as u16,
+            _ => {
+                self.program_counter = (self.pop_cycle() as u16) << 8 | self.addr;
+                return true;
+            }
+        }
+        false
+    }
+
+    fn push_instruction_cycle(&mut self) -> bool {
+        match self.cycle {
+            1 => {
+                self.read_cycle(self.program_counter);
+                false
+            }
+            _ => {
+                let data = if self.opcode == 0x08 {
+                    self.php_flags()
+                } else {
+                    self.register_a
+                };
+                self.push_cycle(data);
+                true
+            }
+        }
+    }
+
+    fn pull_instruction_cycle(&mut self) -> bool {
+        match self.cycle {
+            1 => self.read_cycle(self.program_counter),
+            2 => self.read_cycle(STACK + self.stack_pointer as u16),
+            _ => {
+                let data = self.pop_cycle();
+                if self.opcode == 0x28 {
+                    self.plp(data);
+                } else {
+                    self.set_register_a(data);
+                }
+                return true;
+            }
+        };
+        false
+    }
+
+    /// NMI, IRQ and BRK all share this sequence. An NMI arriving before the vector is
+    /// fetched hijacks it, even when the sequence started for an IRQ or a BRK.
+    fn interrupt_cycle(&mut self, source: interrupt::Interrupt) -> bool {
+        match self.cycle {
+            0 => {
+                self.read_cycle(self.program_counter);
+            }
+            1 => {
+                self.read_cycle(self.program_counter);
+                // BRK skips a padding byte
+                if source.itype == interrupt::InterruptType::BRK {
+                    self.program_counter = self.program_counter.wrapping_add(1);
+                }
+            }
+            2 => self.push_cycle((self.program_counter >> 8) as u8),
+            3 => self.push_cycle(self.program_counter as u8),
+            4 => {
+                let mut flag = self.status.clone();
+                flag.set(CpuFlags::BREAK, source.b_flag_mask & 0b010000 != 0);
+                flag.set(CpuFlags::BREAK2, source.b_flag_mask & 0b100000 != 0);
+                self.push_cycle(flag.bits);
+
+                let nmi = self.nmi_pending;
+                self.addr = if nmi {
+                    self.nmi_pending = false;
+                    interrupt::NMI.vector_addr
+                } else {
+                    source.vector_addr
+                };
+                if self.bus.interrupt_log.is_some()
+                    && (nmi || source.itype == interrupt::InterruptType::IRQ)
+                {
+                    self.bus.log_interrupt(nmi, self.program_counter);
+                }
+            }
+            5 => {
+                self.data = self.read_cycle(self.addr);
+                self.status.insert(CpuFlags::INTERRUPT_DISABLE);
+            }
+            _ => {
+                let hi = self.read_cycle(self.addr + 1);
+                self.program_counter = u16::from_le_bytes([self.data, hi]);
+                return true;
+            }
+        }
+        false
+    }
+
+    fn instruction_cycle(&mut self) -> bool {
+        match self.opcode {
+            0x00 => self.interrupt_cycle(interrupt::BRK),
+            0x4c => self.jmp_cycle(),
+            0x6c => self.jmp_indirect_cycle(),
+            0x20 => self.jsr_cycle(),
+            0x60 => self.rts_cycle(),
+            0x40 => self.rti_cycle(),
+            0x08 | 0x48 => self.push_instruction_cycle(),
+            0x28 | 0x68 => self.pull_instruction_cycle(),
+            0x10 | 0x30 | 0x50 | 0x70 | 0x90 | 0xb0 | 0xd0 | 0xf0 => self.branch_cycle(),
+            code => {
+                // unknown opcodes were turned away when they were fetched
+                let opcode = OPCODES[code as usize].unwrap();
+
+                match opcode.mode {
+                    AddressingMode::NoneAddressing => {
+                        self.read_cycle(self.program_counter);
+                        self.execute_implied();
+                        true
+                    }
+                    AddressingMode::Immediate => {
+                        let value = self.fetch_operand();
+                        self.execute_read(value);
+                        true
+                    }
+                    ref mode => self.memory_cycle(mode),
+                }
+            }
+        }
+    }
+
+    /// Copies a program into RAM at $0600, where `asm::assemble` assembles them for.
     pub fn load(&mut self, program: Vec<u8>) {
         for i in 0..(program.len() as u16) {
-            self.mem_write(0x0600 + i, program[i as usize]);
+            self.mem_write(asm::LOAD_ADDRESS + i, program[i as usize]);
         }
         //self.mem_write_u16(0xFFFC, 0x8600);
     }
@@ -765,244 +1146,206 @@ impl<'a> CPU<'a> {
         self.run_with_callback(|_| {});
     }
 
-    pub fn run_with_callback<F>(&mut self, mut callback: F)
+    /// Runs until the CPU halts or jams, calling `callback` before every instruction.
+    pub fn run_with_callback<F>(&mut self, mut callback: F) -> CpuState
     where
         F: FnMut(&mut CPU),
     {
-        let ref opcodes: HashMap<u8, &'static OpCode> = *OPCODES_MAP;
-
         loop {
-            if let Some(_nmi) = self.bus.poll_nmi_status() {
-                self.interrupt(interrupt::NMI);
+            callback(self);
+
+            let state = self.step();
+            if state != CpuState::Running {
+                return state;
             }
+        }
+    }
 
-            callback(self);
+    /// Executes the instruction at the program counter, or services a pending interrupt.
+    pub fn step(&mut self) -> CpuState {
+        loop {
+            let state = self.step_cycle();
+            if state != CpuState::Running || (self.cycle == 0 && self.servicing.is_none()) {
+                return state;
+            }
+        }
+    }
 
-            let code = self.mem_read(self.program_counter);
-            self.program_counter += 1;
-            let programe_counter_state = self.program_counter;
+    /// Runs a single CPU cycle: one read or write on the bus, after which the rest of the
+    /// console catches up.
+    pub fn step_cycle(&mut self) -> CpuState {
+        // a jammed CPU leaves the rest of the console running
+        if self.jammed {
+            self.bus.tick(1);
+            return CpuState::Jammed;
+        }
 
-            let opcode = opcodes
-                .get(&code)
-                .expect(&format!("OpCode {:?} is not recognised!", code));
+        if self.cycle == 0 && self.servicing.is_none() && self.interrupt_ready {
+            self.interrupt_ready = false;
+            self.servicing = Some(if self.nmi_pending {
+                interrupt::NMI
+            } else {
+                interrupt::IRQ
+            });
+        }
 
-            match code {
-                // ADC
-                0x69 | 0x65 | 0x75 | 0x6d | 0x7d | 0x79 | 0x61 | 0x71 => self.adc(&opcode.mode),
-                // SBC
-                0xe9 | 0xe5 | 0xf5 | 0xed | 0xfd | 0xf9 | 0xe1 | 0xf1 => self.sbc(&opcode.mode),
-                // UNOFFICIAL SBC
-                0xeb => self.sbc(&opcode.mode),
-                // AND
-                0x29 | 0x25 | 0x35 | 0x2d | 0x3d | 0x39 | 0x21 | 0x31 => self.and(&opcode.mode),
-                // EOR
-                0x49 | 0x45 | 0x55 | 0x4d | 0x5d | 0x59 | 0x41 | 0x51 => self.eor(&opcode.mode),
-                // ORA
-                0x09 | 0x05 | 0x15 | 0x0d | 0x1d | 0x19 | 0x01 | 0x11 => self.ora(&opcode.mode),
-                // CMP
-                0xc9 | 0xc5 | 0xd5 | 0xcd | 0xdd | 0xd9 | 0xc1 | 0xd1 => {
-                    self.compare(&opcode.mode, self.register_a)
+        let done = match self.servicing {
+            Some(interrupt) => self.interrupt_cycle(interrupt),
+            None if self.cycle == 0 => {
+                self.bus.instruction_pc = self.program_counter;
+                let previous_opcode = self.opcode;
+                self.opcode = self.mem_read(self.program_counter);
+                if is_kil(self.opcode) {
+                    // the program counter stays on the KIL, for reporting
+                    self.jammed = true;
+                    self.end_cycle();
+                    return CpuState::Jammed;
                 }
-                0xc0 | 0xc4 | 0xcc => self.compare(&opcode.mode, self.register_y),
-                0xe0 | 0xe4 | 0xec => self.compare(&opcode.mode, self.register_x),
-                //BIT
-                0x24 | 0x2c => self.bit(&opcode.mode),
-                // JMP
-                0x4c => self.jmp(),
-                0x6c => self.jmp_indirect(),
-                0x20 => self.jsr(),
-                0x60 => self.program_counter = self.stack_pop_u16() + 1,
-                0x40 => self.rti(),
-                // BRANCH
-                0xf0 => self.branch(self.status.contains(CpuFlags::ZERO)),
-                0xd0 => self.branch(!self.status.contains(CpuFlags::ZERO)),
-                0x70 => self.branch(self.status.contains(CpuFlags::OVERFLOW)),
-                0x50 => self.branch(!self.status.contains(CpuFlags::OVERFLOW)),
-                0x30 => self.branch(self.status.contains(CpuFlags::NEGATIVE)),
-                0x10 => self.branch(!self.status.contains(CpuFlags::NEGATIVE)),
-                0xb0 => self.branch(self.status.contains(CpuFlags::CARRY)),
-                0x90 => self.branch(!self.status.contains(CpuFlags::CARRY)),
-                // LDA
-                0xa9 | 0xa5 | 0xb5 | 0xad | 0xbd | 0xb9 | 0xa1 | 0xb1 => self.lda(&opcode.mode),
-                // LDX
-                0xa2 | 0xa6 | 0xb6 | 0xae | 0xbe => self.ldx(&opcode.mode),
-                // LDY
-                0xa0 | 0xa4 | 0xb4 | 0xac | 0xbc => self.ldy(&opcode.mode),
-                // LAX
-                0xa7 | 0xb7 | 0xaf | 0xbf | 0xa3 | 0xb3 => self.lax(&opcode.mode),
-                // STA
-                0x85 | 0x95 | 0x8d | 0x9d | 0x99 | 0x81 | 0x91 => self.sta(&opcode.mode),
-                // STX
-                0x86 | 0x96 | 0x8e => self.stx(&opcode.mode),
-                // STY
-                0x84 | 0x94 | 0x8c => self.sty(&opcode.mode),
-                //SAX
-                0x87 | 0x97 | 0x8f | 0x83 => self.sax(&opcode.mode),
-                // ASL
-                0x0a => self.asl_acc(),
-                0x06 | 0x16 | 0x0e | 0x1e => {
-                    self.asl(&opcode.mode);
+                if OPCODES[self.opcode as usize].is_none() {
+                    self.jammed = true;
+                    self.end_cycle();
+                    return CpuState::Error(NesError::UnknownOpcode {
+                        opcode: self.opcode,
+                        addr: self.program_counter,
+                    });
                 }
-                // LSR
-                0x4a => self.lsr_acc(),
-                0x46 | 0x56 | 0x4e | 0x5e => {
-                    self.lsr(&opcode.mode);
+                if self.bus.cdl.is_some() {
+                    let len = OPCODES[self.opcode as usize].unwrap().len as u16;
+                    let flags = if previous_opcode == 0x6c {
+                        cdl::CODE | cdl::INDIRECT_CODE
+                    } else {
+                        cdl::CODE
+                    };
+                    self.log_prg(self.program_counter, len, flags);
                 }
-                // ROL
-                0x2a => self.rol_acc(),
-                0x26 | 0x36 | 0x2e | 0x3e => {
-                    self.rol(&opcode.mode);
-                }
-                // ROR
-                0x6a => self.ror_acc(),
-                0x66 | 0x76 | 0x6e | 0x7e => {
-                    self.ror(&opcode.mode);
-                }
-                // INC
-                0xe6 | 0xf6 | 0xee | 0xfe => {
-                    self.inc(&opcode.mode);
-                }
-                0xe8 => self.inx(),
-                0xc8 => self.iny(),
-                // DEC
-                0xc6 | 0xd6 | 0xce | 0xde => self.dec(&opcode.mode),
-                0xca => self.dex(),
-                0x88 => self.dey(),
-                0xaa => self.tax(),
-                0xa8 => self.tay(),
-                0x8a => self.txa(),
-                0x98 => self.tya(),
-                0xba => {
-                    self.register_x = self.stack_pointer;
-                    self.update_zero_and_negative_flags(self.register_x);
-                }
-                0x9a => self.stack_pointer = self.register_x,
-                0x48 => self.stack_push(self.register_a),
-                0x68 => {
-                    let data = self.stack_pop();
-                    self.set_register_a(data);
-                }
-                0x08 => self.php(),
-                0x28 => self.plp(),
-                0xf8 => self.status.insert(CpuFlags::DECIMAL_MODE),
-                0xD8 => self.status.remove(CpuFlags::DECIMAL_MODE),
-                0x78 => self.status.insert(CpuFlags::INTERRUPT_DISABLE),
-                0x58 => self.status.remove(CpuFlags::INTERRUPT_DISABLE),
-                0x38 => self.status.insert(CpuFlags::CARRY),
-                0x18 => self.status.remove(CpuFlags::CARRY),
-                0xb8 => self.status.remove(CpuFlags::OVERFLOW),
-                // NOPs
-                0xea => { /* Do nothing */ }
-                0x04 | 0x44 | 0x64 | 0x14 | 0x34 | 0x54 | 0x74 | 0xd4 | 0xf4 | 0x0c | 0x1c
-                | 0x3c | 0x5c | 0x7c | 0xdc | 0xfc => {
-                    let (addr, page_cross) = self.get_operand_address(&opcode.mode);
-                    let _data = self.mem_read(addr);
-                    if page_cross {
-                        self.bus.tick(1);
-                    }
-                }
-                0x02 | 0x12 | 0x22 | 0x32 | 0x42 | 0x52 | 0x62 | 0x72 | 0x92 | 0xb2 | 0xd2
-                | 0xf2 => { /* Do nothing */ }
-                0x1a | 0x3a | 0x5a | 0x7a | 0xda | 0xfa => { /* do nothing */ }
-                /* SKB */
-                0x80 | 0x82 | 0x89 | 0xc2 | 0xe2 => { /* 2 byte NOP */ }
-                0x00 => return,
-                // DCP
-                0xc7 | 0xd7 | 0xCF | 0xdf | 0xdb | 0xd3 | 0xc3 => self.dcp(&opcode.mode),
-                // RLA
-                0x27 | 0x37 | 0x2F | 0x3f | 0x3b | 0x33 | 0x23 => {
-                    let data = self.rol(&opcode.mode);
-                    self.set_register_a(data & self.register_a);
-                }
-                // RRA
-                0x67 | 0x77 | 0x6f | 0x7f | 0x7b | 0x63 | 0x73 => {
-                    let data = self.ror(&opcode.mode);
-                    self.add_to_register_a(data);
-                }
-                // SLO
-                0x07 | 0x17 | 0x0f | 0x1f | 0x1b | 0x03 | 0x13 => {
-                    let data = self.asl(&opcode.mode);
-                    self.set_register_a(data | self.register_a);
-                }
-                // SRE
-                0x47 | 0x57 | 0x4f | 0x5f | 0x5b | 0x43 | 0x53 => {
-                    let data = self.lsr(&opcode.mode);
-                    self.set_register_a(data ^ self.register_a);
-                }
-                // ISB
-                0xe7 | 0xf7 | 0xef | 0xff | 0xfb | 0xe3 | 0xf3 => {
-                    let data = self.inc(&opcode.mode);
-                    self.add_to_register_a(((data as i8).wrapping_neg().wrapping_sub(1)) as u8)
-                }
-                // AXS
-                0xcb => self.axs(&opcode.mode),
-                // ARR
-                0x6b => self.arr(&opcode.mode),
-                // ALR
-                0x4b => self.alr(&opcode.mode),
-                // ANC
-                0x0b | 0x2b => self.anc(&opcode.mode),
-                //LXA
-                0xab => self.lxa(&opcode.mode),
-                // XAA
-                0x8b => {
-                    self.register_a = self.register_x;
-                    self.update_zero_and_negative_flags(self.register_a);
-                    let (addr, _) = self.get_operand_address(&opcode.mode);
-                    let data = self.mem_read(addr);
-                    self.set_register_a(self.register_a & data);
-                }
-                0xbb => self.las(&opcode.mode),
-                0x9b => self.tas(),
-                // AHX  Indirect Y
-                0x93 => {
-                    let pos: u8 = self.mem_read(self.program_counter);
-                    let mem_address = self.mem_read_u16(pos as u16) + self.register_y as u16;
-                    let data = self.register_a & self.register_x & (mem_address >> 8) as u8;
-                    self.mem_write(mem_address, data)
-                }
-                // AHX Absolute Y
-                0x9f => {
-                    let mem_address =
-                        self.mem_read_u16(self.program_counter) + self.register_y as u16;
-
-                    let data = self.register_a & self.register_x & (mem_address >> 8) as u8;
-                    self.mem_write(mem_address, data)
-                }
-                // SHX
-                0x9e => {
-                    let mem_address =
-                        self.mem_read_u16(self.program_counter) + self.register_y as u16;
-                    let data = self.register_x & ((mem_address >> 8) as u8 + 1);
-                    self.mem_write(mem_address, data)
-                }
-                // SHY
-                0x9c => {
-                    let mem_address =
-                        self.mem_read_u16(self.program_counter) + self.register_x as u16;
-                    let data = self.register_y & ((mem_address >> 8) as u8 + 1);
-                    self.mem_write(mem_address, data)
+                self.program_counter = self.program_counter.wrapping_add(1);
+                if self.opcode == 0x00 && self.halt_on_brk {
+                    return CpuState::Halted;
                 }
+                self.end_cycle();
+                false
             }
+            None => self.instruction_cycle(),
+        };
 
-            self.bus.tick(opcode.cycles);
+        if done {
+            self.follow_call_stack();
+            // interrupts are only taken between instructions
+            if self.servicing.is_none() {
+                self.interrupt_ready = self.prev_nmi_pending || self.prev_irq_pending;
+            }
+            self.cycle = 0;
+            self.servicing = None;
+        } else {
+            self.cycle += 1;
+        }
+        CpuState::Running
+    }
 
-            if programe_counter_state == self.program_counter {
-                self.program_counter += (opcode.len - 1) as u16;
+    // Marks `len` bytes from `addr` in the code/data log, where they're PRG ROM
+    fn log_prg(&mut self, addr: u16, len: u16, flags: u8) {
+        let mapper = self.bus.mapper.borrow();
+        if let Some(log) = &self.bus.cdl {
+            let mut log = log.borrow_mut();
+            for addr in (0..len).map(|i| addr.wrapping_add(i)) {
+                if let Some(offset) = mapper.prg_rom_offset(addr) {
+                    log.mark(offset, addr, flags);
+                }
             }
         }
     }
 
+    // Keeps the call stack up to date at the end of an instruction or interrupt
+    fn follow_call_stack(&mut self) {
+        let sp = self.stack_pointer;
+        let at = self.bus.instruction_pc;
+        let kind = match (self.servicing, self.opcode) {
+            (None, 0x20) => FrameKind::Subroutine,
+            (Some(_), _) | (None, 0x00) if self.addr == interrupt::NMI.vector_addr => {
+                FrameKind::Nmi
+            }
+            (Some(_), _) => FrameKind::Irq,
+            (None, 0x00) => FrameKind::Brk,
+            (None, 0x60) => {
+                let to = self.program_counter;
+                self.call_stack
+                    .ret(&self.bus, false, at, sp.wrapping_sub(2), to);
+                return;
+            }
+            (None, 0x40) => {
+                let to = self.program_counter;
+                self.call_stack
+                    .ret(&self.bus, true, at, sp.wrapping_sub(3), to);
+                return;
+            }
+            _ => return,
+        };
+        let from = if kind == FrameKind::Nmi || kind == FrameKind::Irq {
+            // what was interrupted is where it goes back to
+            self.mem_peek_u16(STACK + sp.wrapping_add(2) as u16)
+        } else {
+            at
+        };
+        self.call_stack.push(
+            &self.bus,
+            Frame {
+                kind,
+                from,
+                to: self.program_counter,
+                stack_pointer: sp,
+            },
+        );
+    }
+
+    /// Locked up by a KIL opcode or one it can't emulate, until a reset.
+    pub fn is_jammed(&self) -> bool {
+        self.jammed
+    }
+
     pub fn reset(&mut self) {
+        self.call_stack.clear();
         self.register_a = 0;
         self.register_x = 0;
         self.register_y = 0;
         self.status = CpuFlags::from_bits_truncate(0b100100);
+        self.jammed = false;
 
         self.program_counter = self.mem_read_u16(0xFFFC);
         self.stack_pointer = STACK_RESET;
     }
 
+    /// Pressing the reset button. RAM and the registers keep their values, the stack
+    /// pointer moves down 3 as if an interrupt had run without writing anything, and
+    /// interrupts are disabled before jumping through the reset vector.
+    pub fn soft_reset(&mut self) {
+        self.bus.soft_reset();
+        self.abandon_instruction();
+        self.stack_pointer = self.stack_pointer.wrapping_sub(3);
+        self.status.insert(CpuFlags::INTERRUPT_DISABLE);
+        self.jammed = false;
+        self.call_stack.clear();
+        self.program_counter = self.mem_read_u16(0xFFFC);
+    }
+
+    /// Switching the console off and on again. RAM, the PPU, the APU and the cartridge
+    /// registers all start over, only cartridge RAM is kept.
+    pub fn power_cycle(&mut self) {
+        self.bus.power_cycle();
+        self.abandon_instruction();
+        self.reset();
+    }
+
+    // Forgets the instruction or interrupt in progress and any interrupts waiting
+    fn abandon_instruction(&mut self) {
+        self.cycle = 0;
+        self.servicing = None;
+        self.nmi_pending = false;
+        self.prev_nmi_pending = false;
+        self.irq_pending = false;
+        self.prev_irq_pending = false;
+        self.interrupt_ready = false;
+    }
+
     pub fn load_and_run(&mut self, program: Vec<u8>) {
         self.load(program);
         self.reset();
@@ -1011,16 +1354,54 @@ impl<'a> CPU<'a> {
     }
 }
 
+impl Savestate for CPU {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_u8(self.register_a);
+        state.write_u8(self.register_x);
+        state.write_u8(self.register_y);
+        state.write_u8(self.status.bits());
+        state.write_u16(self.program_counter);
+        state.write_u8(self.stack_pointer);
+        state.write_bool(self.nmi_pending);
+        state.write_bool(self.prev_nmi_pending);
+        state.write_bool(self.irq_pending);
+        state.write_bool(self.prev_irq_pending);
+        state.write_bool(self.interrupt_ready);
+        state.write_bool(self.jammed);
+        self.bus.save_state(state);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), String> {
+        self.register_a = state.read_u8()?;
+        self.register_x = state.read_u8()?;
+        self.register_y = state.read_u8()?;
+        self.status = CpuFlags::from_bits_truncate(state.read_u8()?);
+        self.program_counter = state.read_u16()?;
+        self.stack_pointer = state.read_u8()?;
+        self.nmi_pending = state.read_bool()?;
+        self.prev_nmi_pending = state.read_bool()?;
+        self.irq_pending = state.read_bool()?;
+        self.prev_irq_pending = state.read_bool()?;
+        self.interrupt_ready = state.read_bool()?;
+        self.jammed = state.read_bool()?;
+        self.call_stack.clear();
+        self.bus.load_state(state)
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
-    use crate::ppu::NesPPU;
+    use crate::bus::IrqSource;
+    use crate::joypad::JoypadButton;
     use crate::rom::test;
-    use crate::Joypad;
+    use crate::trace::InterruptLog;
+    use std::cell::RefCell;
+    use std::rc::Rc;
 
     #[test]
     fn test_0xa9_lda_load_data() {
-        let mut cpu = CPU::new(Bus::new(test::test_rom(), |_: &NesPPU, _: &mut Joypad| {}));
+        let mut cpu = CPU::new(Bus::new(test::test_rom()).unwrap());
         cpu.load_and_run(vec![0xa9, 0x05, 0x00]);
         assert_eq!(cpu.register_a, 0x05);
         assert!(cpu.status.bits() & 0b0000_0010 == 0b00);
@@ -1029,14 +1410,14 @@ mod test {
 
     #[test]
     fn test_0xa9_lda_zero_flag() {
-        let mut cpu = CPU::new(Bus::new(test::test_rom(), |_: &NesPPU, _: &mut Joypad| {}));
+        let mut cpu = CPU::new(Bus::new(test::test_rom()).unwrap());
         cpu.load_and_run(vec![0xa9, 0x00, 0x00]);
         assert!(cpu.status.bits() & 0b0000_0010 == 0b10);
     }
 
     #[test]
     fn test_lda_from_memory() {
-        let mut cpu = CPU::new(Bus::new(test::test_rom(), |_: &NesPPU, _: &mut Joypad| {}));
+        let mut cpu = CPU::new(Bus::new(test::test_rom()).unwrap());
         cpu.mem_write(0x10, 0x55);
         cpu.load_and_run(vec![0xa5, 0x10, 0x00]);
         assert_eq!(cpu.register_a, 0x55);
@@ -1044,22 +1425,389 @@ mod test {
 
     #[test]
     fn test_0xaa_tax_move_a_to_x() {
-        let mut cpu = CPU::new(Bus::new(test::test_rom(), |_: &NesPPU, _: &mut Joypad| {}));
+        let mut cpu = CPU::new(Bus::new(test::test_rom()).unwrap());
         cpu.load_and_run(vec![0xa9, 0x0a, 0xaa, 0x00]);
         assert_eq!(cpu.register_x, 10)
     }
 
     #[test]
     fn test_5_ops_working_together() {
-        let mut cpu = CPU::new(Bus::new(test::test_rom(), |_: &NesPPU, _: &mut Joypad| {}));
+        let mut cpu = CPU::new(Bus::new(test::test_rom()).unwrap());
         cpu.load_and_run(vec![0xa9, 0xc0, 0xaa, 0xe8, 0x00]);
         assert_eq!(cpu.register_x, 0xc1);
     }
 
     #[test]
     fn test_inx_overflow() {
-        let mut cpu = CPU::new(Bus::new(test::test_rom(), |_: &NesPPU, _: &mut Joypad| {}));
+        let mut cpu = CPU::new(Bus::new(test::test_rom()).unwrap());
         cpu.load_and_run(vec![0xa9, 0xff, 0xaa, 0xe8, 0xe8, 0x00]);
         assert_eq!(cpu.register_x, 0x01);
     }
+
+    #[test]
+    fn test_axs_and_dcp_flags() {
+        let mut cpu = CPU::new(Bus::new(test::test_rom()).unwrap());
+        cpu.mem_write(0x10, 0x20);
+        // LDA #$0F, LDX #$FC, AXS #$02, then DCP $10 with A below the result
+        cpu.load_and_run(vec![0xa9, 0x0f, 0xa2, 0xfc, 0xcb, 0x02, 0xc7, 0x10, 0x00]);
+        assert_eq!(cpu.register_x, 0x0a);
+        assert_eq!(cpu.mem_read(0x10), 0x1f);
+        assert!(!cpu.status.contains(CpuFlags::CARRY));
+    }
+
+    #[test]
+    fn test_assembled_program() {
+        let mut cpu = CPU::new(Bus::new(test::test_rom()).unwrap());
+        let program = asm::assemble(
+            "  LDX #$00
+             loop:
+               TXA
+               STA $0200,X
+               INX
+               CPX #$05
+               BNE loop
+               BRK",
+        )
+        .unwrap();
+        cpu.load_and_run(program);
+        assert_eq!(cpu.register_x, 5);
+        assert_eq!(cpu.mem_read(0x0204), 4);
+    }
+
+    #[test]
+    fn test_call_stack() {
+        let mut cpu = CPU::new(Bus::new(test::test_rom()).unwrap());
+        let program = asm::assemble(
+            "  JSR first
+               JSR second
+               BRK
+             first:
+               RTS
+             second:
+               JSR third
+             third:
+               BRK",
+        )
+        .unwrap();
+        cpu.load_and_run(program);
+        assert_eq!(cpu.program_counter, 0x060c);
+        assert_eq!(
+            cpu.call_stack.frames(),
+            &[
+                Frame {
+                    kind: FrameKind::Subroutine,
+                    from: 0x0603,
+                    to: 0x0608,
+                    stack_pointer: 0xfb,
+                },
+                Frame {
+                    kind: FrameKind::Subroutine,
+                    from: 0x0608,
+                    to: 0x060b,
+                    stack_pointer: 0xf9,
+                },
+            ]
+        );
+        assert_eq!(cpu.call_stack.mispairs(), 0);
+    }
+
+    #[test]
+    fn test_code_data_log() {
+        let mut cpu = CPU::new(Bus::new(test::test_rom()).unwrap());
+        let log = Rc::new(RefCell::new(cdl::CodeDataLog::new(0x8000, 0x2000)));
+        cpu.bus.cdl = Some(log.clone());
+        // the ROM is full of 01 01, ORA ($01,X)
+        let program = asm::assemble(
+            "  LDA #$00
+               STA $10
+               LDA #$90
+               STA $11
+               LDY #$05
+               LDA $8010
+               LDA ($10),Y
+               JMP ($0400)",
+        )
+        .unwrap();
+        cpu.load(program);
+        cpu.program_counter = 0x0600;
+        cpu.mem_write_u16(0x0400, 0xc000);
+        for _ in 0..10 {
+            cpu.step();
+        }
+
+        let log = log.borrow();
+        assert_eq!(log.flags(0x0010), cdl::DATA);
+        assert_eq!(log.flags(0x1005), cdl::DATA | cdl::INDIRECT_DATA);
+        assert_eq!(log.flags(0x4000), cdl::CODE | cdl::INDIRECT_CODE | 0b1000);
+        assert_eq!(log.flags(0x4002), cdl::CODE | 0b1000);
+        assert_eq!(log.flags(0x4006), 0);
+    }
+
+    #[test]
+    fn test_snake_runs_into_the_wall() {
+        let mut cpu = CPU::new(Bus::new(test::test_rom()).unwrap());
+        // with no keys pressed the snake heads right until it hits the edge, and the game
+        // ends on the BRK after the program
+        cpu.load_and_run(asm::assemble(include_str!("../examples/snake.asm")).unwrap());
+        assert_eq!(cpu.mem_read(0x10), 0x20);
+        assert_eq!(cpu.mem_read(0x041f), 1);
+        assert_eq!(cpu.mem_read(0x0420), 0);
+    }
+
+    fn cycles_taken(program: Vec<u8>, setup: fn(&mut CPU)) -> usize {
+        let mut cpu = CPU::new(Bus::new(test::test_rom()).unwrap());
+        cpu.load(program);
+        cpu.program_counter = 0x0600;
+        setup(&mut cpu);
+        let start = cpu.bus.cycles();
+        cpu.step();
+        cpu.bus.cycles() - start
+    }
+
+    #[test]
+    fn test_cycle_counts() {
+        // LDA $12f0,X without and with a page cross
+        assert_eq!(
+            cycles_taken(vec![0xbd, 0xf0, 0x12], |cpu| cpu.register_x = 0x0f),
+            4
+        );
+        assert_eq!(
+            cycles_taken(vec![0xbd, 0xf0, 0x12], |cpu| cpu.register_x = 0x10),
+            5
+        );
+        // STA $12f0,X always takes the fix-up cycle
+        assert_eq!(cycles_taken(vec![0x9d, 0xf0, 0x12], |_| {}), 5);
+        // INC $10,X
+        assert_eq!(cycles_taken(vec![0xf6, 0x10], |_| {}), 6);
+        // JSR, and a BNE taken back onto the previous page
+        assert_eq!(cycles_taken(vec![0x20, 0x00, 0x07], |_| {}), 6);
+        assert_eq!(cycles_taken(vec![0xd0, 0x80], |_| {}), 4);
+    }
+
+    #[test]
+    fn test_opcode_table_matches_executor() {
+        for code in 0..=255u8 {
+            let op = OPCODES[code as usize].unwrap_or_else(|| panic!("${:02x} missing", code));
+            assert_eq!(op.code, code);
+            if is_kil(code) {
+                continue;
+            }
+
+            // the operand is $0200, or $00 for zero page: no page is ever crossed, and the
+            // indirect modes find a pointer to $0000
+            let mut cpu = CPU::new(Bus::new(test::test_rom()).unwrap());
+            cpu.halt_on_brk = false;
+            cpu.load(vec![code, 0x00, 0x02]);
+            cpu.program_counter = 0x0600;
+            let start = cpu.bus.cycles();
+            cpu.step();
+            let cycles = cpu.bus.cycles() - start;
+
+            match code {
+                // a taken branch to the next instruction costs one more cycle
+                0x10 | 0x30 | 0x50 | 0x70 | 0x90 | 0xb0 | 0xd0 | 0xf0 => {
+                    assert!(cycles == 2 || cycles == 3, "${:02x}", code);
+                    assert_eq!(cpu.program_counter, 0x0602, "${:02x}", code);
+                }
+                // BRK, JSR, RTI, JMP, RTS
+                0x00 | 0x20 | 0x40 | 0x4c | 0x60 | 0x6c => {
+                    assert_eq!(cycles, op.cycles as usize, "${:02x}", code)
+                }
+                _ => {
+                    assert_eq!(cycles, op.cycles as usize, "${:02x} {}", code, op.mnemonic);
+                    let next = 0x0600 + op.len as u16;
+                    assert_eq!(cpu.program_counter, next, "${:02x} {}", code, op.mnemonic);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_step_cycle_read_modify_write() {
+        let mut cpu = CPU::new(Bus::new(test::test_rom()).unwrap());
+        cpu.load(vec![0xe6, 0x10]);
+        cpu.program_counter = 0x0600;
+        cpu.mem_write(0x10, 0x41);
+        cpu.bus.watchpoints.push(crate::bus::Watchpoint {
+            addr: 0x10,
+            access: crate::bus::Access::Write,
+        });
+
+        for _ in 0..4 {
+            cpu.step_cycle();
+        }
+        // the old value is written back first
+        assert_eq!(
+            cpu.bus.take_watchpoint_hit().map(|(_, data)| data),
+            Some(0x41)
+        );
+        assert_eq!(cpu.mem_read(0x10), 0x41);
+
+        cpu.step_cycle();
+        assert_eq!(cpu.mem_read(0x10), 0x42);
+        assert_eq!(cpu.bus.cycles(), 5);
+    }
+
+    #[test]
+    fn test_indexed_dummy_read_reaches_ppu() {
+        let mut cpu = CPU::new(Bus::new(test::test_rom()).unwrap());
+        cpu.mem_write(0x2006, 0x20);
+        cpu.mem_write(0x2006, 0x00);
+        // LDA $20f7,X reads $2007 on the wrong page before reading its mirror at $2107
+        cpu.load(vec![0xbd, 0xf7, 0x20]);
+        cpu.program_counter = 0x0600;
+        cpu.register_x = 0x10;
+        cpu.step();
+        assert_eq!(cpu.bus.ppu.loopy.addr(), 0x2002);
+
+        // without a page cross there is a single read
+        cpu.load(vec![0xbd, 0x00, 0x20]);
+        cpu.program_counter = 0x0600;
+        cpu.register_x = 0x07;
+        cpu.step();
+        assert_eq!(cpu.bus.ppu.loopy.addr(), 0x2003);
+    }
+
+    // NMI handler at $0700, IRQ/BRK handler at $0800, with NOPs from $0600
+    fn interrupt_test_cpu() -> CPU {
+        let mut prg_rom = vec![0; 0x4000];
+        prg_rom[0x3ffa..].copy_from_slice(&[0x00, 0x07, 0x00, 0x06, 0x00, 0x08]);
+        let rom = crate::rom::Rom {
+            prg_rom,
+            chr_rom: vec![0; 0x2000],
+            chr_ram: true,
+            trainer: None,
+            prg_ram_size: 0x2000,
+            battery: false,
+            mapper: 0,
+            screen_mirroring: crate::rom::Mirroring::Horizontal,
+            system: crate::rom::System::Nes,
+        };
+        let mut cpu = CPU::new(Bus::new(rom).unwrap());
+        cpu.load(vec![0xea; 8]);
+        cpu.program_counter = 0x0600;
+        cpu
+    }
+
+    #[test]
+    fn test_nmi_is_polled_before_the_last_cycle() {
+        let mut cpu = interrupt_test_cpu();
+        // detected during the first cycle of a NOP: taken right after it
+        cpu.step_cycle();
+        cpu.nmi_pending = true;
+        cpu.step();
+        cpu.step();
+        assert_eq!(cpu.program_counter, 0x0700);
+
+        // detected during the last cycle: one more instruction runs first
+        let mut cpu = interrupt_test_cpu();
+        cpu.step();
+        cpu.nmi_pending = true;
+        cpu.step();
+        assert_eq!(cpu.program_counter, 0x0602);
+        cpu.step();
+        assert_eq!(cpu.program_counter, 0x0700);
+        assert!(!cpu.nmi_pending);
+    }
+
+    #[test]
+    fn test_nmi_hijacks_brk() {
+        let mut cpu = interrupt_test_cpu();
+        cpu.halt_on_brk = false;
+        cpu.mem_write(0x0600, 0x00);
+        cpu.step();
+        assert_eq!(cpu.program_counter, 0x0800);
+        assert_eq!(cpu.mem_read(0x01fc), 0x02);
+
+        let mut cpu = interrupt_test_cpu();
+        cpu.halt_on_brk = false;
+        cpu.mem_write(0x0600, 0x00);
+        for _ in 0..4 {
+            cpu.step_cycle();
+        }
+        cpu.nmi_pending = true;
+        cpu.step();
+        assert_eq!(cpu.program_counter, 0x0700);
+        // the pushed flags still say BRK
+        assert_eq!(cpu.mem_read(0x01fb) & 0b0001_0000, 0b0001_0000);
+        assert_eq!(cpu.bus.cycles(), 7);
+    }
+
+    #[test]
+    fn test_interrupt_log() {
+        let mut cpu = interrupt_test_cpu();
+        cpu.bus.interrupt_log = Some(InterruptLog::new(None));
+        cpu.status.remove(CpuFlags::INTERRUPT_DISABLE);
+        cpu.bus.assert_irq(IrqSource::DMC);
+        cpu.step();
+        cpu.step();
+        assert_eq!(cpu.program_counter, 0x0800);
+        cpu.bus.acknowledge_irq(IrqSource::DMC);
+        cpu.program_counter = 0x0600;
+        cpu.nmi_pending = true;
+        cpu.step();
+        cpu.step();
+
+        let log = cpu.bus.interrupt_log.as_ref().unwrap().recent();
+        assert_eq!(log.len(), 2);
+        assert!(log[0].ends_with("0601  IRQ DMC"), "{}", log[0]);
+        assert!(log[1].ends_with("0601  NMI"), "{}", log[1]);
+    }
+
+    #[test]
+    fn test_kil_jams_until_reset() {
+        let mut cpu = interrupt_test_cpu();
+        cpu.mem_write(0x0601, 0x02);
+        assert_eq!(cpu.step(), CpuState::Running);
+        assert_eq!(cpu.step(), CpuState::Jammed);
+        assert_eq!(cpu.step(), CpuState::Jammed);
+        assert_eq!(cpu.program_counter, 0x0601);
+
+        // the rest of the console keeps going
+        let cycles = cpu.bus.cycles();
+        cpu.step_cycle();
+        assert_eq!(cpu.bus.cycles(), cycles + 1);
+
+        cpu.reset();
+        assert_eq!(cpu.step(), CpuState::Running);
+    }
+
+    #[test]
+    fn test_joypad_read_keeps_open_bus_bits() {
+        let mut cpu = interrupt_test_cpu();
+        cpu.bus
+            .joypad_mut(1)
+            .unwrap()
+            .set_button_pressed_status(JoypadButton::BUTTON_A, true);
+        cpu.mem_write(0x4016, 1);
+        // LDA $4016 leaves $40 on the bus from the address
+        cpu.load(vec![0xad, 0x16, 0x40, 0xad, 0x16, 0x40]);
+        cpu.program_counter = 0x0600;
+        cpu.step();
+        assert_eq!(cpu.register_a, 0x41);
+        cpu.step();
+        assert_eq!(cpu.register_a, 0x41);
+    }
+
+    #[test]
+    fn test_soft_reset_and_power_cycle() {
+        let mut cpu = interrupt_test_cpu();
+        cpu.step();
+        cpu.register_a = 0x42;
+        cpu.mem_write(0x0010, 0x99);
+        cpu.mem_write(0x2000, 0b1000_0000);
+        cpu.soft_reset();
+        assert_eq!(cpu.program_counter, 0x0600);
+        assert_eq!(cpu.register_a, 0x42);
+        assert_eq!(cpu.stack_pointer, STACK_RESET - 3);
+        assert!(cpu.status.contains(CpuFlags::INTERRUPT_DISABLE));
+        assert_eq!(cpu.mem_read(0x0010), 0x99);
+        assert!(!cpu.bus.ppu.ctrl.generate_vblank_nmi());
+
+        cpu.power_cycle();
+        assert_eq!(cpu.program_counter, 0x0600);
+        assert_eq!(cpu.register_a, 0);
+        assert_eq!(cpu.stack_pointer, STACK_RESET);
+        assert_eq!(cpu.mem_read(0x0010), 0);
+    }
 }
@@ -0,0 +1,397 @@
+use crate::bus::{Access, Watchpoint};
+use crate::cpu::{Mem, CPU};
+use crate::disasm::{address, disassemble};
+use crate::expr::Expr;
+use crate::trace::trace;
+use std::collections::BTreeMap;
+use std::io::{self, BufRead, Write};
+
+const JSR: u8 = 0x20;
+const HELP: &str = "Commands: c(ontinue), s(tep), n(ext), b <addr> [if <cond>], b if <cond>, d <addr>, d if <cond>, rw <addr>, ww <addr>, uw <addr>, l(ist), bt, i(nterrupts), r(egs), x <addr> [len], u [addr] [count], q(uit)";
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Resume {
+    Continue,
+    Step,
+    StepOver,
+    Stay,
+    Quit,
+}
+
+#[derive(Default)]
+pub struct Debugger {
+    // with the condition they stop on, if any
+    breakpoints: BTreeMap<u16, Option<Expr>>,
+    // stop wherever these hold
+    conditions: Vec<Expr>,
+    paused: bool,
+    step_over: Option<u16>,
+}
+
+impl Debugger {
+    pub fn new() -> Self {
+        Debugger {
+            breakpoints: BTreeMap::new(),
+            conditions: Vec::new(),
+            paused: false,
+            step_over: None,
+        }
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    pub fn pause(&mut self) {
+        self.paused = true;
+    }
+
+    pub fn add_breakpoint(&mut self, addr: u16) {
+        self.breakpoints.insert(addr, None);
+    }
+
+    /// Stops at `addr` when `condition` holds there, or anywhere it holds without an
+    /// address.
+    pub fn add_condition(&mut self, addr: Option<u16>, condition: Expr) {
+        match addr {
+            Some(addr) => {
+                self.breakpoints.insert(addr, Some(condition));
+            }
+            None => self.conditions.push(condition),
+        }
+    }
+
+    pub fn remove_breakpoint(&mut self, addr: u16) -> bool {
+        self.breakpoints.remove(&addr).is_some()
+    }
+
+    /// Called before every instruction. Decides whether execution should stop at the current PC.
+    pub fn should_break(&mut self, cpu: &mut CPU) -> bool {
+        if let Some((watchpoint, data)) = cpu.bus.take_watchpoint_hit() {
+            println!(
+                "Watchpoint: {:?} ${:04x} = {:02x}",
+                watchpoint.access, watchpoint.addr, data
+            );
+            self.paused = true;
+        }
+
+        let pc = cpu.program_counter;
+        let hit = match self.breakpoints.get(&pc) {
+            Some(None) => Some(format!("Breakpoint at {}", address(cpu, pc))),
+            Some(Some(condition)) if condition.is_true(cpu) => Some(format!(
+                "Breakpoint at {} with {}",
+                address(cpu, pc),
+                condition
+            )),
+            _ => self
+                .conditions
+                .iter()
+                .find(|condition| condition.is_true(cpu))
+                .map(|condition| format!("Stopped at {} with {}", address(cpu, pc), condition)),
+        };
+        if let Some(message) = hit {
+            println!("{}", message);
+            self.paused = true;
+        }
+
+        if self.step_over == Some(cpu.program_counter) {
+            self.step_over = None;
+            self.paused = true;
+        }
+
+        self.paused
+    }
+
+    /// Runs the interactive prompt on stdin while the debugger is paused. Returns whether
+    /// it stopped at this instruction.
+    pub fn on_instruction(&mut self, cpu: &mut CPU) -> bool {
+        if !self.should_break(cpu) {
+            return false;
+        }
+
+        println!("{}", trace(cpu));
+
+        let stdin = io::stdin();
+        loop {
+            print!("(debug) ");
+            io::stdout().flush().unwrap();
+
+            let mut line = String::new();
+            if stdin.lock().read_line(&mut line).unwrap_or(0) == 0 {
+                std::process::exit(0);
+            }
+
+            match self.execute(cpu, line.trim()) {
+                Resume::Stay => continue,
+                Resume::Quit => std::process::exit(0),
+                _ => return true,
+            }
+        }
+    }
+
+    pub fn execute(&mut self, cpu: &mut CPU, line: &str) -> Resume {
+        let (line, condition) = match line.split_once(" if ") {
+            Some((line, condition)) => (line, Some(condition)),
+            None => (line, None),
+        };
+        let mut parts = line.split_whitespace();
+        let command = parts.next().unwrap_or("s");
+        let args: Vec<u16> = parts
+            .filter_map(|arg| {
+                let mapper = cpu.bus.mapper.borrow();
+                cpu.bus
+                    .symbols
+                    .address_of(arg, &*mapper)
+                    .or_else(|| parse_addr(arg))
+            })
+            .collect();
+
+        if let Some(condition) = condition {
+            return self.execute_conditional(cpu, command, &args, condition);
+        }
+
+        match (command, args.as_slice()) {
+            ("c", _) | ("continue", _) => {
+                self.paused = false;
+                Resume::Continue
+            }
+            ("s", _) | ("step", _) => Resume::Step,
+            ("n", _) | ("next", _) => {
+                if cpu.mem_peek(cpu.program_counter) == JSR {
+                    self.step_over = Some(cpu.program_counter.wrapping_add(3));
+                    self.paused = false;
+                }
+                Resume::StepOver
+            }
+            ("b", [addr]) | ("break", [addr]) => {
+                self.add_breakpoint(*addr);
+                Resume::Stay
+            }
+            ("d", [addr]) | ("delete", [addr]) => {
+                if !self.remove_breakpoint(*addr) {
+                    println!("No breakpoint at {}", address(cpu, *addr));
+                }
+                Resume::Stay
+            }
+            ("rw", [addr]) | ("ww", [addr]) => {
+                let access = if command == "rw" {
+                    Access::Read
+                } else {
+                    Access::Write
+                };
+                cpu.bus.watchpoints.push(Watchpoint {
+                    addr: *addr,
+                    access,
+                });
+                Resume::Stay
+            }
+            ("uw", [addr]) => {
+                cpu.bus.watchpoints.retain(|w| w.addr != *addr);
+                Resume::Stay
+            }
+            ("l", _) | ("list", _) => {
+                for (addr, condition) in self.breakpoints.iter() {
+                    match condition {
+                        Some(condition) => {
+                            println!("break {} if {}", address(cpu, *addr), condition)
+                        }
+                        None => println!("break {}", address(cpu, *addr)),
+                    }
+                }
+                for condition in self.conditions.iter() {
+                    println!("break if {}", condition);
+                }
+                for watchpoint in cpu.bus.watchpoints.iter() {
+                    println!("watch ${:04x} {:?}", watchpoint.addr, watchpoint.access);
+                }
+                Resume::Stay
+            }
+            ("bt", _) | ("backtrace", _) => {
+                for line in cpu.call_stack.backtrace(&*cpu, cpu.program_counter) {
+                    println!("{}", line);
+                }
+                Resume::Stay
+            }
+            ("i", _) | ("interrupts", _) => {
+                match cpu.bus.interrupt_log.as_ref() {
+                    Some(log) => log.recent().iter().for_each(|line| println!("{}", line)),
+                    None => println!("Interrupts aren't being logged"),
+                }
+                Resume::Stay
+            }
+            ("r", _) | ("regs", _) => {
+                println!("{}", trace(cpu));
+                Resume::Stay
+            }
+            ("x", [addr]) | ("x", [addr, _]) => {
+                let len = args.get(1).copied().unwrap_or(16);
+                dump_memory(cpu, *addr, len);
+                Resume::Stay
+            }
+            ("u", _) => {
+                let addr = args.first().copied().unwrap_or(cpu.program_counter);
+                let count = args.get(1).copied().unwrap_or(10);
+                for line in disassemble(cpu, addr, count as usize) {
+                    if let Some(name) = cpu.symbol(line.addr) {
+                        println!("{}:", name);
+                    }
+                    println!("{}", line);
+                }
+                Resume::Stay
+            }
+            ("q", _) | ("quit", _) => Resume::Quit,
+            _ => {
+                println!("{}", HELP);
+                Resume::Stay
+            }
+        }
+    }
+
+    // Breaking and deleting with `if <cond>`
+    fn execute_conditional(
+        &mut self,
+        cpu: &mut CPU,
+        command: &str,
+        args: &[u16],
+        condition: &str,
+    ) -> Resume {
+        match (command, args) {
+            ("b" | "break", []) | ("b" | "break", [_]) => {
+                let parsed = Expr::parse(condition, |name| {
+                    let mapper = cpu.bus.mapper.borrow();
+                    cpu.bus.symbols.address_of(name, &*mapper)
+                });
+                match parsed {
+                    Ok(condition) => self.add_condition(args.first().copied(), condition),
+                    Err(e) => println!("{}", e),
+                }
+            }
+            ("d" | "delete", []) => {
+                let before = self.conditions.len();
+                self.conditions
+                    .retain(|c| c.to_string() != condition.trim());
+                if self.conditions.len() == before {
+                    println!("No breakpoint if {}", condition.trim());
+                }
+            }
+            _ => println!("{}", HELP),
+        }
+        Resume::Stay
+    }
+}
+
+fn parse_addr(arg: &str) -> Option<u16> {
+    let hex = arg.trim_start_matches('$').trim_start_matches("0x");
+    u16::from_str_radix(hex, 16).ok()
+}
+
+fn dump_memory(cpu: &CPU, start: u16, len: u16) {
+    for row in (0..len).step_by(16) {
+        let addr = start.wrapping_add(row);
+        let bytes = (0..16.min(len - row))
+            .map(|i| format!("{:02x}", cpu.mem_peek(addr.wrapping_add(i))))
+            .collect::<Vec<String>>()
+            .join(" ");
+        println!("{:04x}: {}", addr, bytes);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bus::Bus;
+    use crate::rom::test;
+    use crate::symbols::Symbols;
+
+    #[test]
+    fn test_breakpoint_pauses_execution() {
+        let mut cpu = CPU::new(Bus::new(test::test_rom()).unwrap());
+        let mut debugger = Debugger::new();
+        debugger.add_breakpoint(0x0602);
+
+        cpu.load(vec![0xa9, 0x01, 0xaa, 0x00]);
+        cpu.program_counter = 0x0600;
+
+        assert!(!debugger.should_break(&mut cpu));
+        cpu.step();
+        assert!(debugger.should_break(&mut cpu));
+        assert_eq!(debugger.execute(&mut cpu, "c"), Resume::Continue);
+        assert!(!debugger.is_paused());
+    }
+
+    #[test]
+    fn test_step_over_subroutine() {
+        let mut cpu = CPU::new(Bus::new(test::test_rom()).unwrap());
+        let mut debugger = Debugger::new();
+
+        // JSR $0606; BRK; ...; INX; RTS
+        cpu.load(vec![0x20, 0x06, 0x06, 0x00, 0x00, 0x00, 0xe8, 0x60]);
+        cpu.program_counter = 0x0600;
+        debugger.pause();
+
+        assert_eq!(debugger.execute(&mut cpu, "n"), Resume::StepOver);
+        assert!(!debugger.is_paused());
+
+        while !debugger.should_break(&mut cpu) {
+            cpu.step();
+        }
+        assert_eq!(cpu.program_counter, 0x0603);
+        assert_eq!(cpu.register_x, 1);
+    }
+
+    #[test]
+    fn test_breakpoint_at_label() {
+        let mut cpu = CPU::new(Bus::new(test::test_rom()).unwrap());
+        cpu.bus.symbols = Symbols::parse("al 000602 .add").unwrap();
+        let mut debugger = Debugger::new();
+        debugger.execute(&mut cpu, "b add");
+        debugger.execute(&mut cpu, "b $add");
+        assert_eq!(
+            debugger.breakpoints.keys().copied().collect::<Vec<_>>(),
+            vec![0x0602, 0x0add]
+        );
+    }
+
+    #[test]
+    fn test_conditional_breakpoints() {
+        let mut cpu = CPU::new(Bus::new(test::test_rom()).unwrap());
+        let mut debugger = Debugger::new();
+        // LDX #$00; loop: INX; JMP loop
+        cpu.load(vec![0xa2, 0x00, 0xe8, 0x4c, 0x02, 0x06]);
+        cpu.program_counter = 0x0600;
+
+        debugger.execute(&mut cpu, "b $0603 if X == 3");
+        debugger.execute(&mut cpu, "b if X == 5 && pc == $0602");
+        debugger.execute(&mut cpu, "b if X ==");
+        assert_eq!(debugger.conditions.len(), 1);
+
+        while !debugger.should_break(&mut cpu) {
+            cpu.step();
+        }
+        assert_eq!((cpu.program_counter, cpu.register_x), (0x0603, 3));
+        debugger.execute(&mut cpu, "c");
+        cpu.step();
+        while !debugger.should_break(&mut cpu) {
+            cpu.step();
+        }
+        assert_eq!((cpu.program_counter, cpu.register_x), (0x0602, 5));
+
+        debugger.execute(&mut cpu, "d if X == 5 && pc == $0602");
+        assert!(debugger.conditions.is_empty());
+    }
+
+    #[test]
+    fn test_watchpoint_command() {
+        let mut cpu = CPU::new(Bus::new(test::test_rom()).unwrap());
+        let mut debugger = Debugger::new();
+        debugger.execute(&mut cpu, "ww $10");
+
+        // LDA #$05; STA $10
+        cpu.load(vec![0xa9, 0x05, 0x85, 0x10, 0x00]);
+        cpu.program_counter = 0x0600;
+        cpu.step();
+        assert!(!debugger.should_break(&mut cpu));
+        cpu.step();
+        assert!(debugger.should_break(&mut cpu));
+    }
+}
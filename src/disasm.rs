@@ -0,0 +1,166 @@
+use crate::cpu::{AddressingMode, Mem};
+use crate::opcodes::OPCODES;
+use std::fmt;
+
+pub struct DisasmLine {
+    pub addr: u16,
+    pub bytes: Vec<u8>,
+    pub mnemonic: &'static str,
+    pub mode: Option<&'static AddressingMode>,
+    pub operand: String,
+}
+
+impl DisasmLine {
+    pub fn size(&self) -> u16 {
+        self.bytes.len() as u16
+    }
+
+    /// The raw operand bytes as a little-endian value (0 for implied instructions).
+    pub fn operand_value(&self) -> u16 {
+        match self.bytes.len() {
+            2 => self.bytes[1] as u16,
+            3 => u16::from_le_bytes([self.bytes[1], self.bytes[2]]),
+            _ => 0,
+        }
+    }
+}
+
+impl fmt::Display for DisasmLine {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let hex_str = self
+            .bytes
+            .iter()
+            .map(|x| format!("{:02X}", x))
+            .collect::<Vec<String>>()
+            .join(" ");
+
+        let asm_str = format!(
+            "{:04X}  {:8} {:>4} {}",
+            self.addr, hex_str, self.mnemonic, self.operand
+        );
+        write!(f, "{}", asm_str.trim())
+    }
+}
+
+/// `addr` by the name debug symbols give it, or in hex.
+pub fn address<M: Mem>(mem: &M, addr: u16) -> String {
+    match mem.symbol(addr) {
+        Some(name) => name.to_string(),
+        None => format!("${:04X}", addr),
+    }
+}
+
+fn zero_page<M: Mem>(mem: &M, addr: u16) -> String {
+    match mem.symbol(addr) {
+        Some(name) => name.to_string(),
+        None => format!("${:02X}", addr),
+    }
+}
+
+/// Decodes the single instruction located at `addr`. Memory is only peeked at, so
+/// disassembling never disturbs memory-mapped registers.
+pub fn decode<M: Mem>(mem: &M, addr: u16) -> DisasmLine {
+    let code = mem.mem_peek(addr);
+    let opcode = match OPCODES[code as usize] {
+        Some(opcode) => opcode,
+        None => {
+            return DisasmLine {
+                addr,
+                bytes: vec![code],
+                mnemonic: "???",
+                mode: None,
+                operand: String::from(""),
+            }
+        }
+    };
+
+    let bytes: Vec<u8> = (0..opcode.len as u16)
+        .map(|i| {
+            if i == 0 {
+                code
+            } else {
+                mem.mem_peek(addr.wrapping_add(i))
+            }
+        })
+        .collect();
+
+    let mut line = DisasmLine {
+        addr,
+        bytes,
+        mnemonic: opcode.mnemonic,
+        mode: Some(&opcode.mode),
+        operand: String::from(""),
+    };
+
+    let value = line.operand_value();
+    line.operand = match (opcode.len, &opcode.mode) {
+        (1, _) => match opcode.code {
+            0x0a | 0x4a | 0x2a | 0x6a => String::from("A"),
+            _ => String::from(""),
+        },
+        (_, AddressingMode::Immediate) => format!("#${:02X}", value),
+        (_, AddressingMode::ZeroPage) => zero_page(mem, value),
+        (_, AddressingMode::ZeroPage_X) => format!("{},X", zero_page(mem, value)),
+        (_, AddressingMode::ZeroPage_Y) => format!("{},Y", zero_page(mem, value)),
+        (_, AddressingMode::Indirect_X) => format!("({},X)", zero_page(mem, value)),
+        (_, AddressingMode::Indirect_Y) => format!("({}),Y", zero_page(mem, value)),
+        (_, AddressingMode::Absolute) => address(mem, value),
+        (_, AddressingMode::Absolute_X) => format!("{},X", address(mem, value)),
+        (_, AddressingMode::Absolute_Y) => format!("{},Y", address(mem, value)),
+        (2, AddressingMode::NoneAddressing) => {
+            // Local jumps
+            let target = addr
+                .wrapping_add(2)
+                .wrapping_add((value as u8 as i8) as u16);
+            address(mem, target)
+        }
+        (_, AddressingMode::NoneAddressing) => {
+            if opcode.code == 0x6c {
+                // JMP indirect
+                format!("({})", address(mem, value))
+            } else {
+                address(mem, value)
+            }
+        }
+    };
+
+    line
+}
+
+/// Disassembles `count` consecutive instructions starting at `start`.
+pub fn disassemble<M: Mem>(mem: &M, start: u16, count: usize) -> Vec<DisasmLine> {
+    let mut result = Vec::with_capacity(count);
+    let mut addr = start;
+    for _ in 0..count {
+        let line = decode(mem, addr);
+        addr = addr.wrapping_add(line.size());
+        result.push(line);
+    }
+    result
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bus::Bus;
+    use crate::rom::test::test_rom;
+
+    #[test]
+    fn test_disassemble_range() {
+        let mut bus = Bus::new(test_rom()).unwrap();
+        let program = [0xa9, 0x05, 0x9d, 0x00, 0x02, 0xd0, 0xf9, 0x6c, 0x34, 0x12];
+        for (i, byte) in program.iter().enumerate() {
+            bus.mem_write(0x0600 + i as u16, *byte);
+        }
+
+        let lines: Vec<String> = disassemble(&bus, 0x0600, 4)
+            .iter()
+            .map(|line| line.to_string())
+            .collect();
+
+        assert_eq!(lines[0], "0600  A9 05     LDA #$05");
+        assert_eq!(lines[1], "0602  9D 00 02  STA $0200,X");
+        assert_eq!(lines[2], "0605  D0 F9     BNE $0600");
+        assert_eq!(lines[3], "0607  6C 34 12  JMP ($1234)");
+    }
+}
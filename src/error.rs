@@ -0,0 +1,36 @@
+use crate::rom::RomError;
+use std::fmt;
+
+/// Things that stop the emulator, reported to the frontend instead of aborting.
+#[derive(Debug, Clone, PartialEq)]
+pub enum NesError {
+    Rom(RomError),
+    UnsupportedMapper(u8),
+    UnknownOpcode { opcode: u8, addr: u16 },
+    Jammed { opcode: u8, addr: u16 },
+}
+
+impl fmt::Display for NesError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            NesError::Rom(e) => write!(f, "{}", e),
+            NesError::UnsupportedMapper(id) => write!(f, "Mapper {} is not supported!", id),
+            NesError::UnknownOpcode { opcode, addr } => write!(
+                f,
+                "OpCode ${:02x} at ${:04x} is not recognised!",
+                opcode, addr
+            ),
+            NesError::Jammed { opcode, addr } => {
+                write!(f, "CPU jammed by opcode ${:02x} at ${:04x}", opcode, addr)
+            }
+        }
+    }
+}
+
+impl std::error::Error for NesError {}
+
+impl From<RomError> for NesError {
+    fn from(e: RomError) -> Self {
+        NesError::Rom(e)
+    }
+}
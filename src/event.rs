@@ -0,0 +1,68 @@
+use std::collections::VecDeque;
+
+/// Something that happened inside the console, for the frontend to act on between
+/// instructions with `Bus::poll_event`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Event {
+    /// The PPU finished a frame, which is ready to render.
+    FrameComplete,
+    /// The PPU started this scanline. Only raised with `Bus::scanline_break` set, for
+    /// stepping through a frame.
+    Scanline(u16),
+    /// The PPU raised an NMI.
+    Nmi,
+    /// The IRQ line went up.
+    Irq,
+    /// The APU sent this many samples to its output, as the frame ended.
+    AudioSamples(usize),
+}
+
+/// The events raised since the frontend last looked, oldest first. Only the last
+/// `CAPACITY` are kept, so a frontend that never looks doesn't pile them up.
+#[derive(Default)]
+pub struct EventQueue {
+    events: VecDeque<Event>,
+}
+
+impl EventQueue {
+    pub const CAPACITY: usize = 64;
+
+    pub fn new() -> Self {
+        EventQueue::default()
+    }
+
+    pub fn push(&mut self, event: Event) {
+        if self.events.len() == Self::CAPACITY {
+            self.events.pop_front();
+        }
+        self.events.push_back(event);
+    }
+
+    pub fn poll(&mut self) -> Option<Event> {
+        self.events.pop_front()
+    }
+
+    pub fn clear(&mut self) {
+        self.events.clear();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_keeps_the_latest_events() {
+        let mut events = EventQueue::new();
+        events.push(Event::Nmi);
+        events.push(Event::FrameComplete);
+        assert_eq!(events.poll(), Some(Event::Nmi));
+
+        for scanline in 0..EventQueue::CAPACITY as u16 + 1 {
+            events.push(Event::Scanline(scanline));
+        }
+        assert_eq!(events.poll(), Some(Event::Scanline(1)));
+        events.clear();
+        assert_eq!(events.poll(), None);
+    }
+}
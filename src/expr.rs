@@ -0,0 +1,338 @@
+use crate::cpu::{CpuFlags, Mem, CPU};
+use std::fmt;
+
+/// Something to look at in the console when an expression is worked out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Value {
+    A,
+    X,
+    Y,
+    P,
+    SP,
+    PC,
+    Flag(CpuFlags),
+    Scanline,
+    Dot,
+    Frame,
+    Cycles,
+    PpuCtrl,
+    PpuMask,
+    PpuStatus,
+}
+
+impl Value {
+    fn from_name(name: &str) -> Option<Value> {
+        Some(match name.to_ascii_lowercase().as_str() {
+            "a" => Value::A,
+            "x" => Value::X,
+            "y" => Value::Y,
+            "p" => Value::P,
+            "sp" => Value::SP,
+            "pc" => Value::PC,
+            "c" => Value::Flag(CpuFlags::CARRY),
+            "z" => Value::Flag(CpuFlags::ZERO),
+            "i" => Value::Flag(CpuFlags::INTERRUPT_DISABLE),
+            "d" => Value::Flag(CpuFlags::DECIMAL_MODE),
+            "v" => Value::Flag(CpuFlags::OVERFLOW),
+            "n" => Value::Flag(CpuFlags::NEGATIVE),
+            "scanline" => Value::Scanline,
+            "dot" => Value::Dot,
+            "frame" => Value::Frame,
+            "cycles" => Value::Cycles,
+            "ppuctrl" => Value::PpuCtrl,
+            "ppumask" => Value::PpuMask,
+            "ppustatus" => Value::PpuStatus,
+            _ => return None,
+        })
+    }
+
+    fn get(self, cpu: &CPU) -> i64 {
+        match self {
+            Value::A => cpu.register_a as i64,
+            Value::X => cpu.register_x as i64,
+            Value::Y => cpu.register_y as i64,
+            Value::P => cpu.status.bits() as i64,
+            Value::SP => cpu.stack_pointer as i64,
+            Value::PC => cpu.program_counter as i64,
+            Value::Flag(flag) => cpu.status.contains(flag) as i64,
+            Value::Scanline => cpu.bus.ppu.scanline as i64,
+            Value::Dot => cpu.bus.ppu.dot() as i64,
+            Value::Frame => cpu.bus.frame_count() as i64,
+            Value::Cycles => cpu.bus.cycles() as i64,
+            Value::PpuCtrl => cpu.bus.ppu.ctrl.bits() as i64,
+            Value::PpuMask => cpu.bus.ppu.mask.bits() as i64,
+            Value::PpuStatus => cpu.bus.ppu.status.bits() as i64,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Op {
+    Or,
+    And,
+    BitOr,
+    BitXor,
+    BitAnd,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Shl,
+    Shr,
+    Add,
+    Sub,
+    Mul,
+    Div,
+}
+
+// Operators from the loosest to the tightest binding
+const PRECEDENCE: &[&[(&str, Op)]] = &[
+    &[("||", Op::Or)],
+    &[("&&", Op::And)],
+    &[("|", Op::BitOr)],
+    &[("^", Op::BitXor)],
+    &[("&", Op::BitAnd)],
+    &[("==", Op::Eq), ("!=", Op::Ne)],
+    &[("<=", Op::Le), (">=", Op::Ge), ("<", Op::Lt), (">", Op::Gt)],
+    &[("<<", Op::Shl), (">>", Op::Shr)],
+    &[("+", Op::Add), ("-", Op::Sub)],
+    &[("*", Op::Mul), ("/", Op::Div)],
+];
+
+#[derive(Debug)]
+enum Node {
+    Number(i64),
+    Value(Value),
+    /// The byte at an address, peeked at so reading it changes nothing.
+    Peek(Box<Node>),
+    Not(Box<Node>),
+    Negate(Box<Node>),
+    Complement(Box<Node>),
+    Binary(Op, Box<Node>, Box<Node>),
+}
+
+impl Node {
+    fn eval(&self, cpu: &CPU) -> i64 {
+        match self {
+            Node::Number(n) => *n,
+            Node::Value(value) => value.get(cpu),
+            Node::Peek(addr) => cpu.mem_peek(addr.eval(cpu) as u16) as i64,
+            Node::Not(node) => (node.eval(cpu) == 0) as i64,
+            Node::Negate(node) => node.eval(cpu).wrapping_neg(),
+            Node::Complement(node) => !node.eval(cpu),
+            // both sides of || and && are always worked out, as nothing has side effects
+            Node::Binary(op, left, right) => {
+                let (l, r) = (left.eval(cpu), right.eval(cpu));
+                match op {
+                    Op::Or => (l != 0 || r != 0) as i64,
+                    Op::And => (l != 0 && r != 0) as i64,
+                    Op::BitOr => l | r,
+                    Op::BitXor => l ^ r,
+                    Op::BitAnd => l & r,
+                    Op::Eq => (l == r) as i64,
+                    Op::Ne => (l != r) as i64,
+                    Op::Lt => (l < r) as i64,
+                    Op::Le => (l <= r) as i64,
+                    Op::Gt => (l > r) as i64,
+                    Op::Ge => (l >= r) as i64,
+                    Op::Shl => l.wrapping_shl(r as u32),
+                    Op::Shr => l.wrapping_shr(r as u32),
+                    Op::Add => l.wrapping_add(r),
+                    Op::Sub => l.wrapping_sub(r),
+                    Op::Mul => l.wrapping_mul(r),
+                    Op::Div => l.checked_div(r).unwrap_or(0),
+                }
+            }
+        }
+    }
+}
+
+struct Parser<'a, F> {
+    rest: &'a str,
+    symbol: F,
+}
+
+impl<'a, F: Fn(&str) -> Option<u16>> Parser<'a, F> {
+    fn skip_space(&mut self) {
+        self.rest = self.rest.trim_start();
+    }
+
+    fn eat(&mut self, token: &str) -> bool {
+        self.skip_space();
+        match self.rest.strip_prefix(token) {
+            Some(rest) => {
+                self.rest = rest;
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn expect(&mut self, token: &str) -> Result<(), String> {
+        if self.eat(token) {
+            Ok(())
+        } else {
+            Err(format!("Expected {} at \"{}\"", token, self.rest))
+        }
+    }
+
+    fn binary(&mut self, level: usize) -> Result<Node, String> {
+        let ops = match PRECEDENCE.get(level) {
+            Some(ops) => ops,
+            None => return self.unary(),
+        };
+        let mut left = self.binary(level + 1)?;
+        'outer: loop {
+            self.skip_space();
+            for &(token, op) in ops.iter() {
+                // | and & aren't the start of || and &&, nor < of <<
+                let longer = self.rest[token.len().min(self.rest.len())..]
+                    .starts_with(['|', '&', '<', '>', '='].as_ref());
+                if self.rest.starts_with(token) && !(token.len() == 1 && longer) {
+                    self.rest = &self.rest[token.len()..];
+                    let right = self.binary(level + 1)?;
+                    left = Node::Binary(op, Box::new(left), Box::new(right));
+                    continue 'outer;
+                }
+            }
+            return Ok(left);
+        }
+    }
+
+    fn unary(&mut self) -> Result<Node, String> {
+        if self.eat("!") {
+            return Ok(Node::Not(Box::new(self.unary()?)));
+        }
+        if self.eat("-") {
+            return Ok(Node::Negate(Box::new(self.unary()?)));
+        }
+        if self.eat("~") {
+            return Ok(Node::Complement(Box::new(self.unary()?)));
+        }
+        if self.eat("(") {
+            let node = self.binary(0)?;
+            self.expect(")")?;
+            return Ok(node);
+        }
+        if self.eat("[") {
+            let node = self.binary(0)?;
+            self.expect("]")?;
+            return Ok(Node::Peek(Box::new(node)));
+        }
+
+        self.skip_space();
+        let len = self
+            .rest
+            .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_' || c == '$'))
+            .unwrap_or(self.rest.len());
+        let (word, rest) = self.rest.split_at(len);
+        if word.is_empty() {
+            return Err(format!("Expected a value at \"{}\"", self.rest));
+        }
+        self.rest = rest;
+
+        let number = if let Some(hex) = word.strip_prefix("0x").or(word.strip_prefix('$')) {
+            i64::from_str_radix(hex, 16).ok()
+        } else if word.starts_with(|c: char| c.is_ascii_digit()) {
+            word.parse().ok()
+        } else {
+            None
+        };
+        if let Some(number) = number {
+            return Ok(Node::Number(number));
+        }
+        if let Some(value) = Value::from_name(word) {
+            return Ok(Node::Value(value));
+        }
+        match (self.symbol)(word) {
+            Some(addr) => Ok(Node::Number(addr as i64)),
+            None => Err(format!("Don't know what {} is", word)),
+        }
+    }
+}
+
+/// A condition on the state of the console, for breakpoints, like
+/// `A == 0x3F && scanline > 200`. There are C's operators, numbers in decimal or in hex
+/// as `0x3F` or `$3F`, and `[addr]` for the byte at an address. The values it can look
+/// at are:
+///
+/// - the registers `A`, `X`, `Y`, `P`, `SP` and `PC`
+/// - the flags `C`, `Z`, `I`, `D`, `V` and `N`, as 0 or 1
+/// - `scanline` and `dot` for where the PPU is, and `frame` and `cycles` for how far in
+///   the game is
+/// - `ppuctrl`, `ppumask` and `ppustatus`, the PPU's registers
+///
+/// Any other name is looked up in the debug symbols, for its address.
+pub struct Expr {
+    text: String,
+    root: Node,
+}
+
+impl Expr {
+    /// `symbol` gives the address for a name from the debug symbols.
+    pub fn parse<F: Fn(&str) -> Option<u16>>(text: &str, symbol: F) -> Result<Expr, String> {
+        let mut parser = Parser { rest: text, symbol };
+        let root = parser.binary(0)?;
+        parser.skip_space();
+        if !parser.rest.is_empty() {
+            return Err(format!("Can't make sense of \"{}\"", parser.rest));
+        }
+        Ok(Expr {
+            text: text.trim().to_string(),
+            root,
+        })
+    }
+
+    pub fn eval(&self, cpu: &CPU) -> i64 {
+        self.root.eval(cpu)
+    }
+
+    pub fn is_true(&self, cpu: &CPU) -> bool {
+        self.eval(cpu) != 0
+    }
+}
+
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.text)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bus::Bus;
+    use crate::rom::test;
+
+    fn eval(cpu: &CPU, text: &str) -> i64 {
+        Expr::parse(text, |name| (name == "buffer").then_some(0x0300))
+            .unwrap()
+            .eval(cpu)
+    }
+
+    #[test]
+    fn test_expressions() {
+        let mut cpu = CPU::new(Bus::new(test::test_rom()).unwrap());
+        cpu.register_a = 0x3f;
+        cpu.register_x = 2;
+        cpu.status = CpuFlags::CARRY;
+        cpu.mem_write(0x0302, 0x80);
+
+        assert_eq!(eval(&cpu, "A == 0x3F && scanline > 200"), 0);
+        assert_eq!(eval(&cpu, "a == $3f && scanline < 200"), 1);
+        assert_eq!(eval(&cpu, "1 + 2 * 3 - -1"), 8);
+        assert_eq!(eval(&cpu, "(1 + 2) * 3 << 1 | 1"), 19);
+        // & binds looser than ==, like in C
+        assert_eq!(eval(&cpu, "[buffer + X] & 0x80 != 0"), 0);
+        assert_eq!(eval(&cpu, "([buffer + X] & 0x80) != 0"), 1);
+        assert_eq!(eval(&cpu, "C && !Z || 0"), 1);
+        assert_eq!(eval(&cpu, "5 / 0"), 0);
+        assert_eq!(eval(&cpu, "X <= 2 && X >= 2 && ~0 == -1"), 1);
+
+        for bad in &["", "A ==", "(A", "[1", "A B", "jump", "0xzz"] {
+            assert!(Expr::parse(bad, |_| None).is_err(), "{}", bad);
+        }
+    }
+}
@@ -0,0 +1,130 @@
+// Size of one disk side in a .fds image
+const SIDE_SIZE: usize = 65500;
+const FWNES_TAG: [u8; 4] = [0x46, 0x44, 0x53, 0x1a];
+const FWNES_HEADER_SIZE: usize = 16;
+const DISK_INFO_TAG: &[u8] = b"\x01*NINTENDO-HVC*";
+
+// Gaps the drive sees on a real disk, in bytes: 28300 bits before the first block and
+// 976 bits between blocks
+const LEAD_IN: usize = 3537;
+const GAP: usize = 122;
+
+/// A Famicom Disk System image in the .fds format, with or without the fwNES header.
+#[derive(Clone)]
+pub struct FdsImage {
+    /// Each side laid out the way the drive reads it: gaps, block start marks and CRCs.
+    pub sides: Vec<Vec<u8>>,
+}
+
+impl FdsImage {
+    pub fn new(raw: &[u8]) -> Result<FdsImage, String> {
+        let data = if raw.starts_with(&FWNES_TAG) {
+            &raw[FWNES_HEADER_SIZE.min(raw.len())..]
+        } else {
+            raw
+        };
+
+        if data.len() < SIDE_SIZE || !data.starts_with(DISK_INFO_TAG) {
+            return Err("Not a Famicom Disk System image".to_string());
+        }
+
+        let sides = data
+            .chunks(SIDE_SIZE)
+            .filter(|side| side.starts_with(DISK_INFO_TAG))
+            .map(add_gaps)
+            .collect();
+        Ok(FdsImage { sides })
+    }
+}
+
+/// "disk 1 side A" and so on.
+pub fn side_name(side: usize) -> String {
+    format!(
+        "disk {} side {}",
+        side / 2 + 1,
+        if side & 1 == 0 { 'A' } else { 'B' }
+    )
+}
+
+// .fds images only hold the block contents. The BIOS never sees a bad CRC from us, so
+// the CRC bytes are left as zeros.
+fn add_gaps(side: &[u8]) -> Vec<u8> {
+    let mut disk = vec![0; LEAD_IN];
+    let mut pos = 0;
+    let mut file_size = 0;
+
+    while pos < side.len() {
+        let len = match side[pos] {
+            1 => 56,
+            2 => 2,
+            3 if pos + 15 < side.len() => {
+                file_size = u16::from_le_bytes([side[pos + 13], side[pos + 14]]) as usize;
+                16
+            }
+            4 => 1 + file_size,
+            _ => break,
+        };
+        if pos + len > side.len() {
+            break;
+        }
+
+        disk.push(0x80);
+        disk.extend(&side[pos..pos + len]);
+        disk.extend(&[0, 0]);
+        disk.extend(&[0; GAP]);
+        pos += len;
+    }
+
+    // leave room behind the last file for games that save by appending one
+    disk.resize(disk.len().max(LEAD_IN + SIDE_SIZE), 0);
+    disk
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn test_side() -> Vec<u8> {
+        let mut side = DISK_INFO_TAG.to_vec();
+        side.resize(56, 0);
+        side.extend(&[2, 1]);
+        // a file header for 3 bytes of data, then the data block
+        let mut header = vec![3; 16];
+        header[13] = 3;
+        header[14] = 0;
+        side.extend(&header);
+        side.extend(&[4, 0xaa, 0xbb, 0xcc]);
+        side.resize(SIDE_SIZE, 0);
+        side
+    }
+
+    #[test]
+    fn test_load_with_header() {
+        let mut raw = FWNES_TAG.to_vec();
+        raw.resize(FWNES_HEADER_SIZE, 0);
+        raw.extend(test_side());
+        raw.extend(test_side());
+
+        let image = FdsImage::new(&raw).unwrap();
+        assert_eq!(image.sides.len(), 2);
+
+        let side = &image.sides[0];
+        assert!(side[..LEAD_IN].iter().all(|byte| *byte == 0));
+        assert_eq!(side[LEAD_IN], 0x80);
+        assert_eq!(&side[LEAD_IN + 1..LEAD_IN + 16], DISK_INFO_TAG);
+
+        // start mark, block, CRC and gap for the first three blocks
+        let data_block = LEAD_IN + (1 + 56 + 2 + GAP) + (1 + 2 + 2 + GAP) + (1 + 16 + 2 + GAP);
+        assert_eq!(side[data_block], 0x80);
+        assert_eq!(
+            &side[data_block + 1..data_block + 5],
+            &[4, 0xaa, 0xbb, 0xcc]
+        );
+    }
+
+    #[test]
+    fn test_rejects_other_files() {
+        assert!(FdsImage::new(&[0; SIDE_SIZE]).is_err());
+        assert_eq!(side_name(3), "disk 2 side B");
+    }
+}
@@ -0,0 +1,149 @@
+use crate::compat::{self, Controller, Region, GAMES};
+use crate::mapper::SUPPORTED_MAPPERS;
+use crate::rom::{self, Header, RomError, System};
+use std::fmt::Write;
+
+fn kib(bytes: usize) -> String {
+    if bytes & 0x3ff == 0 {
+        format!("{} KiB", bytes / 1024)
+    } else {
+        format!("{} bytes", bytes)
+    }
+}
+
+/// A description of an iNES file's header, its hashes and what the compatibility database
+/// knows about it, for the `info` command. Works on files the emulator can't run as well.
+pub fn describe(raw: &[u8]) -> Result<String, RomError> {
+    let header = Header::parse(raw)?;
+    let mut out = String::new();
+    let mut line = |name: &str, value: String| {
+        writeln!(out, "{:<11} {}", format!("{}:", name), value).unwrap();
+    };
+
+    line(
+        "Format",
+        if header.nes2 { "NES 2.0" } else { "iNES" }.to_string(),
+    );
+    let supported = if rom::check_format(raw).is_err() {
+        "can't be loaded, the format isn't supported yet"
+    } else if SUPPORTED_MAPPERS.contains(&header.mapper) {
+        "supported"
+    } else {
+        "not supported"
+    };
+    line("Mapper", format!("{} ({})", header.mapper, supported));
+    if let Some(submapper) = header.submapper {
+        line("Submapper", submapper.to_string());
+    }
+    line("PRG ROM", kib(header.prg_rom_size));
+    if header.chr_rom_size == 0 {
+        line("CHR RAM", "8 KiB".to_string());
+    } else {
+        line("CHR ROM", kib(header.chr_rom_size));
+    }
+    line(
+        "PRG RAM",
+        format!(
+            "{}{}",
+            kib(header.prg_ram_size),
+            if header.battery {
+                ", battery backed"
+            } else {
+                ""
+            }
+        ),
+    );
+    line("Mirroring", format!("{:?}", header.screen_mirroring));
+    line(
+        "Trainer",
+        if header.trainer { "yes" } else { "no" }.to_string(),
+    );
+    let system = match header.system {
+        System::Nes => "NES",
+        System::VsSystem => "VS System",
+        System::PlayChoice10 => "PlayChoice-10",
+    };
+    line("System", system.to_string());
+    let region = match header.region {
+        Region::Ntsc => "NTSC",
+        Region::Pal => "PAL",
+    };
+    line("Region", region.to_string());
+
+    // a truncated file is hashed as far as it goes
+    let prg_rom_start = header.prg_rom_start().min(raw.len());
+    let chr_rom_start = (prg_rom_start + header.prg_rom_size).min(raw.len());
+    let chr_rom_end = (chr_rom_start + header.chr_rom_size).min(raw.len());
+    let (prg_rom, chr_rom) = (
+        &raw[prg_rom_start..chr_rom_start],
+        &raw[chr_rom_start..chr_rom_end],
+    );
+    let crc32 = compat::crc32(prg_rom, chr_rom);
+    let sha1 = compat::sha1(prg_rom, chr_rom);
+    line("CRC32", format!("{:08x}", crc32));
+    line("SHA1", sha1.clone());
+    line("File CRC32", format!("{:08x}", compat::crc32(raw, &[])));
+    if chr_rom_end < header.prg_rom_start() + header.prg_rom_size + header.chr_rom_size {
+        line(
+            "Warning",
+            "the file is shorter than the header says".to_string(),
+        );
+    }
+
+    match compat::find(GAMES, crc32, &sha1) {
+        Some(game) => {
+            line("Database", game.name.to_string());
+            if let Some(mapper) = game.mapper.filter(|&mapper| mapper != header.mapper) {
+                line("", format!("mapper fixed to {}", mapper));
+            }
+            if let Some(mirroring) = game.mirroring {
+                if mirroring != header.screen_mirroring {
+                    line("", format!("mirroring fixed to {:?}", mirroring));
+                }
+            }
+            if game.region == Region::Pal && header.region != Region::Pal {
+                line("", "PAL game".to_string());
+            }
+            match game.controller {
+                Controller::Joypad => {}
+                Controller::Zapper => line("", "plays with the Zapper".to_string()),
+                Controller::FourScore => line("", "plays with the Four Score".to_string()),
+            }
+            if game.bad_dump {
+                line("", "known bad dump".to_string());
+            }
+        }
+        None => line("Database", "no match".to_string()),
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_describe() {
+        let mut raw = vec![0x4e, 0x45, 0x53, 0x1a, 0x01, 0x00, 0x33, 0x10];
+        raw.resize(16 + 0x4000, 0);
+        let info = describe(&raw).unwrap();
+        assert!(info.contains("Format:     iNES\n"));
+        assert!(info.contains("Mapper:     19 (not supported)\n"));
+        assert!(info.contains("PRG ROM:    16 KiB\n"));
+        assert!(info.contains("CHR RAM:    8 KiB\n"));
+        assert!(info.contains("PRG RAM:    8 KiB, battery backed\n"));
+        assert!(info.contains("Database:   no match\n"));
+        assert!(!info.contains("Warning"));
+
+        // NES 2.0 headers are read, but the game can't be loaded yet
+        raw[6] = 0x00;
+        raw[7] = 0x08;
+        let info = describe(&raw).unwrap();
+        assert!(info.contains("Format:     NES 2.0\n"));
+        assert!(info.contains("Mapper:     0 (can't be loaded, the format isn't supported yet)\n"));
+
+        raw.truncate(100);
+        assert!(describe(&raw).unwrap().contains("Warning"));
+        assert_eq!(describe(&raw[..10]), Err(RomError::TruncatedHeader));
+    }
+}
@@ -1,3 +1,5 @@
+use crate::savestate::{Savestate, StateReader, StateWriter};
+
 bitflags! {
     pub struct JoypadButton: u8 {
         const BUTTON_A = 0b0000_0001;
@@ -11,43 +13,298 @@ bitflags! {
     }
 }
 
+impl JoypadButton {
+    /// Looks a button up by name, like "A", "start" or "Left".
+    pub fn from_name(name: &str) -> Option<JoypadButton> {
+        match name.to_ascii_uppercase().as_str() {
+            "A" => Some(JoypadButton::BUTTON_A),
+            "B" => Some(JoypadButton::BUTTON_B),
+            "SELECT" => Some(JoypadButton::SELECT),
+            "START" => Some(JoypadButton::START),
+            "UP" => Some(JoypadButton::UP),
+            "DOWN" => Some(JoypadButton::DOWN),
+            "LEFT" => Some(JoypadButton::LEFT),
+            "RIGHT" => Some(JoypadButton::RIGHT),
+            _ => None,
+        }
+    }
+}
+
+/// How many frames turbo buttons stay down, then up, by default: 15 presses a second.
+pub const DEFAULT_TURBO_PERIOD: u8 = 2;
+
+/// Whether turbo buttons are down on `frame`, when they switch every `period` frames.
+pub fn turbo_down(frame: usize, period: u8) -> bool {
+    (frame / period.max(1) as usize) & 1 == 0
+}
+
+// Bits 16-23 of the Four Score report identify which port is being read: $10 and $20,
+// sent MSB first, so stored reversed here to shift out LSB first like the buttons
+const FOUR_SCORE_SIGNATURES: [u8; 2] = [0x08, 0x04];
+
+/// A standard controller. Its buttons are copied into an 8 bit shift register while the
+/// strobe is high, and read out one at a time from A to right after it goes low. Once all
+/// 8 are out, reads return 1.
+///
+/// Turbo buttons are pressed and released every few frames for as long as they're held.
 pub struct Joypad {
     strobe: bool,
-    button_index: u8,
+    shift: u8,
     button_status: JoypadButton,
+    turbo: JoypadButton,
+    turbo_period: u8,
+    turbo_down: bool,
 }
 
 impl Joypad {
     pub fn new() -> Self {
         Joypad {
             strobe: false,
-            button_index: 0,
+            shift: 0,
             button_status: JoypadButton::from_bits_truncate(0b0000_0000),
+            turbo: JoypadButton::empty(),
+            turbo_period: DEFAULT_TURBO_PERIOD,
+            turbo_down: true,
         }
     }
 
     pub fn write(&mut self, data: u8) {
-        self.strobe = data & 1 == 1;
-        if self.strobe {
-            self.button_index = 0;
+        // the buttons are latched as the strobe goes low
+        if self.strobe || data & 1 == 1 {
+            self.shift = self.buttons().bits;
         }
+        self.strobe = data & 1 == 1;
     }
 
+    /// The next button in bit 0. The bus fills in the rest from open bus.
     pub fn read(&mut self) -> u8 {
-        if self.button_index > 7 {
-            return 1;
+        let response = self.peek();
+        if !self.strobe {
+            self.shift = self.shift >> 1 | 0b1000_0000;
         }
+        response
+    }
 
-        let response = (self.button_status.bits & (1 << self.button_index)) >> self.button_index;
+    pub fn peek(&self) -> u8 {
+        // while strobed the register keeps reloading, so A is read over and over
+        if self.strobe {
+            self.buttons().bits & 1
+        } else {
+            self.shift & 1
+        }
+    }
 
-        if !self.strobe && self.button_index <= 7 {
-            self.button_index += 1;
+    /// The buttons the console sees, turbo included.
+    pub fn buttons(&self) -> JoypadButton {
+        if self.turbo_down {
+            self.button_status | self.turbo
+        } else {
+            self.button_status
         }
+    }
 
-        response
+    /// The buttons held down, not counting turbo.
+    pub fn held(&self) -> JoypadButton {
+        self.button_status
+    }
+
+    pub fn turbo_held(&self) -> JoypadButton {
+        self.turbo
     }
 
     pub fn set_button_pressed_status(&mut self, button: JoypadButton, pressed: bool) {
         self.button_status.set(button, pressed);
     }
+
+    pub fn set_turbo_pressed(&mut self, button: JoypadButton, pressed: bool) {
+        self.turbo.set(button, pressed);
+    }
+
+    /// How many frames turbo buttons stay down, then up.
+    pub fn set_turbo_period(&mut self, frames: u8) {
+        self.turbo_period = frames.max(1);
+    }
+
+    /// Presses or releases the turbo buttons for `frame`. Called by the bus as every frame
+    /// starts, so turbo follows the frame count rather than the clock.
+    pub fn clock_turbo(&mut self, frame: usize) {
+        self.turbo_down = turbo_down(frame, self.turbo_period);
+    }
+}
+
+impl Savestate for Joypad {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_bool(self.strobe);
+        state.write_u8(self.shift);
+        state.write_u8(self.button_status.bits);
+        state.write_u8(self.turbo.bits);
+        state.write_bool(self.turbo_down);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), String> {
+        self.strobe = state.read_bool()?;
+        self.shift = state.read_u8()?;
+        self.button_status = JoypadButton::from_bits_truncate(state.read_u8()?);
+        self.turbo = JoypadButton::from_bits_truncate(state.read_u8()?);
+        self.turbo_down = state.read_bool()?;
+        Ok(())
+    }
+}
+
+/// The Four Score adapter: each port reports a 24 bit stream made of two controllers
+/// followed by a signature byte, allowing four players. Like a controller, the stream is
+/// latched into a shift register by the strobe, and reads return 1 once it's all out.
+pub struct FourScore {
+    pub joypad3: Joypad,
+    pub joypad4: Joypad,
+    strobe: bool,
+    shift: [u32; 2],
+}
+
+impl FourScore {
+    pub fn new() -> Self {
+        FourScore {
+            joypad3: Joypad::new(),
+            joypad4: Joypad::new(),
+            strobe: false,
+            shift: [0; 2],
+        }
+    }
+
+    /// `first` holds the controllers plugged into ports 1 and 2 of the adapter.
+    pub fn write(&mut self, data: u8, first: [&Joypad; 2]) {
+        // the buttons are latched as the strobe goes low
+        if self.strobe || data & 1 == 1 {
+            self.shift = [0, 1].map(|port| self.report(port, first[port]));
+        }
+        self.strobe = data & 1 == 1;
+    }
+
+    fn report(&self, port: usize, first: &Joypad) -> u32 {
+        let second = if port == 0 {
+            &self.joypad3
+        } else {
+            &self.joypad4
+        };
+        first.buttons().bits as u32
+            | (second.buttons().bits as u32) << 8
+            | (FOUR_SCORE_SIGNATURES[port] as u32) << 16
+    }
+
+    /// Reads the next bit of `port` (0 for $4016, 1 for $4017); `first` is the controller
+    /// plugged into that port, which reports before joypad 3/4.
+    pub fn read(&mut self, port: usize, first: &Joypad) -> u8 {
+        let result = self.peek(port, first);
+        if !self.strobe {
+            self.shift[port] = self.shift[port] >> 1 | 1 << 23;
+        }
+        result
+    }
+
+    pub fn peek(&self, port: usize, first: &Joypad) -> u8 {
+        // while strobed the register keeps reloading, so A is read over and over
+        if self.strobe {
+            first.buttons().bits & 1
+        } else {
+            (self.shift[port] & 1) as u8
+        }
+    }
+}
+
+impl Default for FourScore {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Savestate for FourScore {
+    fn save_state(&self, state: &mut StateWriter) {
+        self.joypad3.save_state(state);
+        self.joypad4.save_state(state);
+        state.write_bool(self.strobe);
+        state.write_u32(self.shift[0]);
+        state.write_u32(self.shift[1]);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), String> {
+        self.joypad3.load_state(state)?;
+        self.joypad4.load_state(state)?;
+        self.strobe = state.read_bool()?;
+        self.shift = [state.read_u32()?, state.read_u32()?];
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_joypad_shift_register() {
+        let mut joypad = Joypad::new();
+        joypad.set_button_pressed_status(JoypadButton::BUTTON_A | JoypadButton::DOWN, true);
+
+        // strobed, A comes back every time
+        joypad.write(1);
+        assert_eq!([joypad.read(), joypad.read()], [1, 1]);
+        joypad.set_button_pressed_status(JoypadButton::BUTTON_A, false);
+        assert_eq!(joypad.read(), 0);
+
+        joypad.write(0);
+        // pressing buttons after the latch doesn't change the report
+        joypad.set_button_pressed_status(JoypadButton::BUTTON_A, true);
+        let bits: Vec<u8> = (0..10).map(|_| joypad.read()).collect();
+        assert_eq!(bits, vec![0, 0, 0, 0, 0, 1, 0, 0, 1, 1]);
+    }
+
+    #[test]
+    fn test_turbo() {
+        let mut joypad = Joypad::new();
+        joypad.set_turbo_pressed(JoypadButton::BUTTON_B, true);
+        let held: Vec<bool> = (0..8)
+            .map(|frame| {
+                joypad.clock_turbo(frame);
+                joypad.buttons().contains(JoypadButton::BUTTON_B)
+            })
+            .collect();
+        assert_eq!(
+            held,
+            vec![true, true, false, false, true, true, false, false]
+        );
+
+        // holding the button itself wins
+        joypad.set_button_pressed_status(JoypadButton::BUTTON_B, true);
+        assert!(joypad.buttons().contains(JoypadButton::BUTTON_B));
+    }
+
+    #[test]
+    fn test_four_score_report() {
+        let mut four_score = FourScore::new();
+        let mut joypad1 = Joypad::new();
+        joypad1.set_button_pressed_status(JoypadButton::START, true);
+        four_score
+            .joypad3
+            .set_button_pressed_status(JoypadButton::BUTTON_A, true);
+
+        four_score.write(1, [&joypad1, &Joypad::new()]);
+        four_score.write(0, [&joypad1, &Joypad::new()]);
+        // buttons pressed after the strobe aren't seen until the next one
+        joypad1.set_button_pressed_status(JoypadButton::BUTTON_A, true);
+        let bits: Vec<u8> = (0..25).map(|_| four_score.read(0, &joypad1)).collect();
+
+        assert_eq!(&bits[0..8], &[0, 0, 0, 1, 0, 0, 0, 0]);
+        assert_eq!(&bits[8..16], &[1, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(&bits[16..24], &[0, 0, 0, 1, 0, 0, 0, 0]);
+        assert_eq!(bits[24], 1);
+    }
+
+    #[test]
+    fn test_four_score_port_2_signature() {
+        let mut four_score = FourScore::new();
+        let joypad2 = Joypad::new();
+        four_score.write(1, [&Joypad::new(), &joypad2]);
+        four_score.write(0, [&Joypad::new(), &joypad2]);
+        let bits: Vec<u8> = (0..24).map(|_| four_score.read(1, &joypad2)).collect();
+        assert_eq!(&bits[16..24], &[0, 0, 1, 0, 0, 0, 0, 0]);
+    }
 }
@@ -0,0 +1,53 @@
+pub mod apu;
+pub mod archive;
+pub mod asm;
+pub mod audio;
+pub mod benchmark;
+#[cfg(test)]
+mod blargg;
+pub mod bus;
+pub mod callstack;
+pub mod cdl;
+pub mod cheats;
+pub mod clip;
+pub mod compat;
+pub mod cpu;
+pub mod debugger;
+pub mod disasm;
+pub mod error;
+pub mod event;
+pub mod expr;
+pub mod fds;
+pub mod info;
+pub mod joypad;
+pub mod mapper;
+pub mod netplay;
+pub mod nsf;
+pub mod opcodes;
+pub mod pacer;
+pub mod ppu;
+pub mod profiler;
+pub mod ram_init;
+pub mod recorder;
+pub mod render;
+pub mod rewind;
+pub mod rom;
+pub mod runahead;
+pub mod savestate;
+#[cfg(feature = "script")]
+pub mod script;
+pub mod server;
+pub mod state;
+pub mod symbols;
+pub mod trace;
+pub mod video;
+#[cfg(feature = "sdl")]
+pub mod viewer;
+pub mod vs;
+pub mod zapper;
+
+#[macro_use]
+extern crate lazy_static;
+
+#[macro_use]
+extern crate bitflags;
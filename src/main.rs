@@ -1,55 +1,791 @@
-pub mod bus;
-pub mod cpu;
-pub mod joypad;
-pub mod opcodes;
-pub mod ppu;
-pub mod render;
-pub mod rom;
-pub mod trace;
-
-use crate::bus::Bus;
-use crate::cpu::CPU;
-use crate::joypad::Joypad;
-use crate::ppu::NesPPU;
-use cpu::Mem;
-use render::frame::Frame;
-use rom::Rom;
+use rust_nes::apu::filter::{SampleBuffer, SAMPLE_RATE};
+use rust_nes::apu::monitor::ApuMonitor;
+use rust_nes::apu::Channel;
+#[cfg(feature = "cpal")]
+use rust_nes::audio::cpal::CpalOutput;
+use rust_nes::audio::sdl::SdlOutput;
+use rust_nes::audio::{AudioSink, DeviceBuffer};
+use rust_nes::bus::{Alignment, Bus};
+use rust_nes::cdl::CodeDataLog;
+use rust_nes::clip::ClipBuffer;
+use rust_nes::compat::{self, Controller, Game, Region};
+use rust_nes::cpu::Mem;
+use rust_nes::cpu::{CpuState, CPU};
+use rust_nes::debugger::Debugger;
+use rust_nes::error::NesError;
+use rust_nes::event::Event as ConsoleEvent;
+use rust_nes::fds::FdsImage;
+use rust_nes::joypad::{FourScore, JoypadButton};
+use rust_nes::mapper::fds::Fds;
+use rust_nes::mapper::prg_ram;
+use rust_nes::mapper::SharedMapper;
+use rust_nes::netplay::Netplay;
+use rust_nes::nsf::{Nsf, NsfPlayer};
+use rust_nes::pacer::{FramePacer, FrameSkip, Speed};
+use rust_nes::ppu::NesPPU;
+use rust_nes::profiler::Profiler;
+use rust_nes::ram_init::{RamInit, Rng};
+use rust_nes::recorder::Recorder;
+use rust_nes::render::filter::{self, PostFilter};
+use rust_nes::render::frame::Frame;
+use rust_nes::render::input;
+use rust_nes::render::osd::Osd;
+use rust_nes::rewind::Rewind;
+use rust_nes::rom::{Rom, System};
+use rust_nes::runahead::RunAhead;
+use rust_nes::savestate::{StateReader, StateWriter};
+use rust_nes::script::{Overlay, Script};
+use rust_nes::server::ControlServer;
+use rust_nes::symbols::Symbols;
+use rust_nes::trace::{AccessLog, InterruptLog, TraceSink, Tracer};
+use rust_nes::video::{FullscreenMode, ScaleMode, VideoConfig};
+use rust_nes::viewer::apu::ApuViewer;
+use rust_nes::viewer::memory::{Edit, MemoryViewer};
+use rust_nes::viewer::nametables::NametableViewer;
+use rust_nes::viewer::patterns::PatternViewer;
+use rust_nes::viewer::search::RamSearch;
+use rust_nes::viewer::{ConsoleState, DebugWindows};
+use rust_nes::zapper::{Zapper, ZapperState};
+use rust_nes::{
+    archive, benchmark, fds, info, joypad, mapper, pacer, render, savestate, trace, vs, zapper,
+};
+use std::cell::{Cell, RefCell};
 use std::collections::HashMap;
+use std::net::TcpListener;
+use std::path::Path;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, SyncSender};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
-use sdl2::event::Event;
-use sdl2::keyboard::Keycode;
-use sdl2::pixels::PixelFormatEnum;
+use sdl2::controller::Button;
+use sdl2::event::{Event, WindowEvent};
+use sdl2::keyboard::{Keycode, Mod};
+use sdl2::messagebox::{
+    show_message_box, show_simple_message_box, ButtonData, ClickedButton, MessageBoxButtonFlag,
+    MessageBoxFlag,
+};
+use sdl2::pixels::{Color, PixelFormatEnum};
+use sdl2::rect::Rect;
+use sdl2::video::FullscreenType;
 
-#[macro_use]
-extern crate lazy_static;
+/// Requests raised by hotkeys and debug windows that need access to the whole console,
+/// applied between instructions.
+enum Command {
+    ToggleCheats,
+    ToggleMute(Channel),
+    Solo(Channel),
+    UpdateZapper(ZapperState),
+    SwitchDiskSide,
+    ChangeTrack(isize),
+    Reset,
+    PowerCycle,
+    InsertCoin(usize),
+    SaveState(u8),
+    LoadState(u8),
+    /// An edit from the memory viewer.
+    Poke(Edit),
+    /// A cheat code from the RAM search, holding an address where it is.
+    Freeze(String),
+    /// Saves the state to carry on from next time, then exits.
+    Quit,
+    SetButton {
+        player: usize,
+        button: JoypadButton,
+        turbo: bool,
+        pressed: bool,
+    },
+}
+
+/// Reports an error that stops the emulator from starting, on the console and in a dialog.
+fn fatal(message: &str) -> ! {
+    eprintln!("{}", message);
+    let _ = show_simple_message_box(MessageBoxFlag::ERROR, "Rust NES", message, None);
+    std::process::exit(1);
+}
+
+fn read_file(path: &str) -> Vec<u8> {
+    match std::fs::read(path) {
+        Ok(bytes) => bytes,
+        Err(e) => fatal(&format!("Can't read {}: {}", path, e)),
+    }
+}
+
+/// Reads a game, which can also be the first file with the right extension in a ZIP.
+fn read_game(path: &str, extension: &str) -> Vec<u8> {
+    match archive::read_game(path, &[extension]) {
+        Ok(bytes) => bytes,
+        Err(e) => fatal(&format!("Can't read {}: {}", path, e)),
+    }
+}
+
+fn open_rom(path: &str) -> Rom {
+    match Rom::new(&read_game(path, "nes")) {
+        Ok(rom) => rom,
+        Err(e) => fatal(&format!("Can't load {}: {}", path, e)),
+    }
+}
+
+/// Loads an iNES file, with `prg_ram_size` in place of the size in the header if given,
+/// and looks it up in the compatibility database.
+fn load_cartridge(path: &str, prg_ram_size: Option<usize>) -> (Rom, Option<&'static Game>) {
+    let mut rom = open_rom(path);
+    if let Some(size) = prg_ram_size {
+        rom.prg_ram_size = size;
+    }
+    println!(
+        "Loaded {}: mapper {}, {} KiB PRG ROM, {} KiB CHR {}",
+        path,
+        rom.mapper,
+        rom.prg_rom.len() / 1024,
+        rom.chr_rom.len() / 1024,
+        if rom.chr_ram { "RAM" } else { "ROM" }
+    );
+    let game = compat::lookup(&rom);
+    if let Some(game) = game {
+        println!("Found {} in the compatibility database", game.name);
+        if game.bad_dump {
+            eprintln!("This is a known bad dump, and may not run right");
+        }
+        if game.region == Region::Pal {
+            eprintln!("This is a PAL game, and will run at NTSC speed");
+        }
+    }
+    (rom, game)
+}
+
+/// Loads a savestate file, with the file named in the error.
+fn load_state_file(cpu: &mut CPU, path: &Path, game_crc: u32) -> Result<(), String> {
+    let data = std::fs::read(path).map_err(|e| format!("Can't read {}: {}", path.display(), e))?;
+    savestate::load_file(cpu, &data, game_crc)
+        .map_err(|e| format!("Can't load {}: {}", path.display(), e))
+}
+
+/// Fills the cartridge RAM from a battery save, unless the game hasn't saved yet.
+fn load_battery(mapper: &SharedMapper, path: &Path) -> Result<(), String> {
+    let data = match std::fs::read(path) {
+        Ok(data) => data,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(format!("Can't read {}: {}", path.display(), e)),
+    };
+    match mapper.borrow_mut().prg_ram_mut() {
+        Some(ram) => ram
+            .load(&data)
+            .map_err(|e| format!("Can't load {}: {}", path.display(), e)),
+        None => Ok(()),
+    }
+}
+
+fn save_battery(mapper: &SharedMapper, path: &Path) -> Result<(), String> {
+    match mapper.borrow().prg_ram() {
+        Some(ram) => std::fs::write(path, ram.data())
+            .map_err(|e| format!("Can't save {}: {}", path.display(), e)),
+        None => Ok(()),
+    }
+}
+
+/// Asks whether to carry on from the state saved on exit last time, or start afresh.
+fn ask_to_resume() -> bool {
+    let buttons = [
+        ButtonData {
+            flags: MessageBoxButtonFlag::RETURNKEY_DEFAULT,
+            button_id: 1,
+            text: "Resume",
+        },
+        ButtonData {
+            flags: MessageBoxButtonFlag::ESCAPEKEY_DEFAULT,
+            button_id: 0,
+            text: "Start over",
+        },
+    ];
+    let clicked = show_message_box(
+        MessageBoxFlag::INFORMATION,
+        &buttons,
+        "Rust NES",
+        "Carry on from where you left off last time?",
+        None,
+        None,
+    );
+    matches!(clicked, Ok(ClickedButton::CustomButton(button)) if button.button_id == 1)
+}
+
+/// The first of screenshot-001.png, screenshot-002.png and so on that isn't taken yet.
+fn next_free_path(prefix: &str, extension: &str) -> String {
+    (1..)
+        .map(|n| format!("{}-{:03}.{}", prefix, n, extension))
+        .find(|path| !std::path::Path::new(path).exists())
+        .unwrap()
+}
+
+fn load_disk(path: &str, bios_path: &str) -> Software {
+    match FdsImage::new(&read_game(path, "fds")) {
+        Ok(image) => {
+            println!("Loaded {}: {} disk sides", path, image.sides.len());
+            Software::Disk(image, read_file(bios_path))
+        }
+        Err(e) => fatal(&format!("Can't load {}: {}", path, e)),
+    }
+}
+
+fn load_nsf(path: &str) -> Software {
+    match Nsf::new(&read_game(path, "nsf")) {
+        Ok(nsf) => {
+            println!(
+                "Loaded {}: \"{}\" by {}, {} songs",
+                path, nsf.title, nsf.artist, nsf.songs
+            );
+            Software::Music(nsf)
+        }
+        Err(e) => fatal(&format!("Can't load {}: {}", path, e)),
+    }
+}
+
+/// A game as read from disk, before it's plugged into a console. It's plain data, so it
+/// can be sent to the emulation thread, with a copy kept for the debug windows.
+#[derive(Clone)]
+enum Software {
+    Cartridge(Rom),
+    /// A disk, with the disk system's BIOS.
+    Disk(FdsImage, Vec<u8>),
+    Music(Nsf),
+}
+
+/// What a game is plugged into, with the disk drive or NSF player behind the mapper for
+/// the hotkeys that work them.
+struct Board {
+    mapper: SharedMapper,
+    disk_drive: Option<Rc<RefCell<Fds>>>,
+    nsf_player: Option<Rc<RefCell<NsfPlayer>>>,
+}
+
+impl Software {
+    fn plug_in(self) -> Result<Board, String> {
+        let board = match self {
+            Software::Cartridge(rom) => Board {
+                mapper: mapper::from_rom(rom).map_err(|e| e.to_string())?,
+                disk_drive: None,
+                nsf_player: None,
+            },
+            Software::Disk(image, bios) => {
+                let fds = Rc::new(RefCell::new(Fds::new(image, bios)?));
+                Board {
+                    mapper: fds.clone(),
+                    disk_drive: Some(fds),
+                    nsf_player: None,
+                }
+            }
+            Software::Music(nsf) => {
+                let player = Rc::new(RefCell::new(NsfPlayer::new(nsf)));
+                Board {
+                    mapper: player.clone(),
+                    disk_drive: None,
+                    nsf_player: Some(player),
+                }
+            }
+        };
+        Ok(board)
+    }
+}
 
-#[macro_use]
-extern crate bitflags;
+/// The game to play, with what the frontend needs to know about it.
+struct LoadedGame {
+    software: Software,
+    system: System,
+    game: Option<&'static Game>,
+    /// The CRC32 savestates are tied to.
+    crc: u32,
+    /// The file savestates are kept next to.
+    state_path: String,
+    /// The cartridge RAM is battery backed, and kept in a .sav file between runs.
+    battery: bool,
+}
+
+/// Loads the game at `path`, or the disk or NSF file given with `--fds` or `--nsf`.
+fn load_game(path: &str, args: &[String]) -> LoadedGame {
+    let flag_value = |flag: &str| {
+        args.windows(2)
+            .find(|pair| pair[0] == flag)
+            .map(|pair| pair[1].clone())
+    };
+    // disk system games boot from the BIOS, with the disk in the drive
+    let disk = flag_value("--fds").map(|path| {
+        let bios_path = flag_value("--fds-bios").unwrap_or_else(|| "disksys.rom".to_string());
+        (load_disk(&path, &bios_path), path)
+    });
+    // NSF files turn the console into a music player, with left and right picking the song
+    let nsf = || flag_value("--nsf").map(|path| (load_nsf(&path), path));
+    match disk.or_else(nsf) {
+        Some((software, state_path)) => LoadedGame {
+            software,
+            system: System::Nes,
+            game: None,
+            crc: compat::crc32(&read_file(&state_path), &[]),
+            state_path,
+            battery: false,
+        },
+        None => {
+            // --prg-ram 8, 16 or 32 overrides the KiB of cartridge RAM the header asks for
+            let prg_ram_size = flag_value("--prg-ram")
+                .map(|size| prg_ram::parse_size(&size).unwrap_or_else(|e| fatal(&e)));
+            let (rom, game) = load_cartridge(path, prg_ram_size);
+            LoadedGame {
+                system: rom.system,
+                game,
+                crc: rom.crc32(),
+                battery: rom.battery,
+                software: Software::Cartridge(rom),
+                state_path: path.to_string(),
+            }
+        }
+    }
+}
+
+/// Whether a Zapper and a Four Score are plugged in. Games that need one get it without
+/// asking.
+fn accessories(game: Option<&Game>, args: &[String]) -> (bool, bool) {
+    let controller = game.map_or(Controller::Joypad, |game| game.controller);
+    (
+        controller == Controller::Zapper || args.iter().any(|arg| arg == "--zapper"),
+        controller == Controller::FourScore || args.iter().any(|arg| arg == "--four-score"),
+    )
+}
+
+const USAGE: &str = "\
+Usage: rust-nes [run] [GAME] [OPTIONS]    plays GAME, pac-man.nes by default
+       rust-nes trace GAME [--start ADDR] [--steps N] [--out FILE]
+                                         logs every instruction the CPU runs
+       rust-nes bench GAME [FRAMES]      runs 600 frames as fast as it can
+       rust-nes hash GAME [FRAMES]       prints a hash of each of the first 600 frames,
+                                         for comparing runs
+       rust-nes info GAME                describes the header and database entry";
 
 fn main() {
+    let mut args: Vec<String> = std::env::args().collect();
+    let command = match args.get(1).map(String::as_str) {
+        Some("run" | "trace" | "bench" | "hash" | "info") => args.remove(1),
+        Some("help" | "--help" | "-h") => return println!("{}", USAGE),
+        _ => "run".to_string(),
+    };
+    let game = args.get(1).filter(|arg| !arg.starts_with('-')).cloned();
+    let need_game = || game.clone().unwrap_or_else(|| fatal(USAGE));
+    match command.as_str() {
+        "trace" => trace_game(&need_game(), &args),
+        "bench" => bench_game(&need_game(), args.get(2)),
+        "hash" => hash_game(&need_game(), args.get(2)),
+        "info" => describe_game(&need_game()),
+        _ => run(game.as_deref().unwrap_or("pac-man.nes"), &args),
+    }
+}
+
+/// Sets up tracing from `--trace-range $8000-$80FF`, which leaves out everything else,
+/// and `--trace-from`, which waits until the CPU gets to an address. Returns whether
+/// tracing waits for the debugger to stop instead, with `--trace-from break`.
+fn configure_tracer(tracer: &mut Tracer, args: &[String]) -> bool {
+    let flag_value = |flag: &str| {
+        args.windows(2)
+            .find(|pair| pair[0] == flag)
+            .map(|pair| pair[1].clone())
+    };
+    if let Some(range) = flag_value("--trace-range") {
+        tracer.only_in(trace::parse_range(&range).unwrap_or_else(|e| fatal(&e)));
+    }
+    match flag_value("--trace-from").as_deref() {
+        Some("break") => {
+            tracer.wait();
+            true
+        }
+        Some(addr) => {
+            let addr = u16::from_str_radix(addr.trim_start_matches('$'), 16)
+                .unwrap_or_else(|_| fatal(&format!("Bad address {}", addr)));
+            tracer.start_at(addr);
+            false
+        }
+        None => false,
+    }
+}
+
+/// `--access-log $2000-$2007,$4016` logs the CPU's reads and writes to those addresses, to
+/// stdout or to `--access-log-file`.
+fn access_log(args: &[String]) -> Option<AccessLog> {
+    let flag_value = |flag: &str| {
+        args.windows(2)
+            .find(|pair| pair[0] == flag)
+            .map(|pair| pair[1].clone())
+    };
+    let ranges = flag_value("--access-log")?;
+    let ranges = trace::parse_ranges(&ranges).unwrap_or_else(|e| fatal(&e));
+    let sink = match flag_value("--access-log-file") {
+        Some(path) => TraceSink::file(&path)
+            .unwrap_or_else(|e| fatal(&format!("Can't write to {}: {}", path, e))),
+        None => TraceSink::Stdout,
+    };
+    Some(AccessLog::new(sink, ranges))
+}
+
+/// `--interrupt-log FILE` logs every NMI and IRQ the CPU takes to FILE, or to stdout with
+/// -. With `--debug` the last few are kept anyway, for the debugger's `interrupts`.
+fn interrupt_log(args: &[String]) -> Option<InterruptLog> {
+    let path = args
+        .windows(2)
+        .find(|pair| pair[0] == "--interrupt-log")
+        .map(|pair| pair[1].clone());
+    let sink = match path.as_deref() {
+        Some("-") => TraceSink::Stdout,
+        Some(path) => TraceSink::file(path)
+            .unwrap_or_else(|e| fatal(&format!("Can't write to {}: {}", path, e))),
+        None if args.iter().any(|arg| arg == "--debug") => return Some(InterruptLog::new(None)),
+        None => return None,
+    };
+    Some(InterruptLog::new(Some(sink)))
+}
+
+/// A code/data log for the game in `path`, carrying on from the one saved in `file`.
+fn code_data_log(path: &str, file: &str) -> Rc<RefCell<CodeDataLog>> {
+    let rom = open_rom(path);
+    let chr_len = if rom.chr_ram { 0 } else { rom.chr_rom.len() };
+    match CodeDataLog::open(file, rom.prg_rom.len(), chr_len) {
+        Ok(log) => Rc::new(RefCell::new(log)),
+        Err(e) => fatal(&e),
+    }
+}
+
+fn save_code_data_log(log: &CodeDataLog, file: &str) {
+    match log.save(file) {
+        Ok(()) => eprintln!("Saved the code/data log to {}: {}", file, log.summary()),
+        Err(e) => eprintln!("Can't save the code/data log to {}: {}", file, e),
+    }
+}
+
+/// `--symbols FILE` names addresses in traces and the debugger, from an ld65 label file,
+/// ca65 debug info or a Mesen .mlb file.
+fn symbols(args: &[String]) -> Symbols {
+    match args.windows(2).find(|pair| pair[0] == "--symbols") {
+        Some(pair) => Symbols::load(&pair[1]).unwrap_or_else(|e| fatal(&e)),
+        None => Symbols::new(),
+    }
+}
+
+/// Prints a line for every instruction, in the format of the nestest log, until the CPU
+/// stops. `--start` jumps somewhere after the reset, like $C000 for nestest's automated
+/// mode, and `--steps` stops after that many instructions. `--out` writes to a file
+/// instead.
+fn trace_game(path: &str, args: &[String]) {
+    let flag_value = |flag: &str| {
+        args.windows(2)
+            .find(|pair| pair[0] == flag)
+            .map(|pair| pair[1].clone())
+    };
+    let sink = match flag_value("--out") {
+        Some(out) => TraceSink::file(&out)
+            .unwrap_or_else(|e| fatal(&format!("Can't write to {}: {}", out, e))),
+        None => TraceSink::Stdout,
+    };
+    let mut tracer = Tracer::new(sink);
+    configure_tracer(&mut tracer, args);
+    let start = flag_value("--start").map(|addr| {
+        u16::from_str_radix(addr.trim_start_matches('$'), 16)
+            .unwrap_or_else(|_| fatal(&format!("Bad address {}", addr)))
+    });
+    let steps = flag_value("--steps").map_or(usize::MAX, |steps| {
+        steps
+            .parse()
+            .unwrap_or_else(|_| fatal(&format!("Bad step count {}", steps)))
+    });
+
+    let mapper = mapper::from_rom(open_rom(path)).unwrap_or_else(|e| fatal(&e.to_string()));
+    let mut cpu = CPU::new(Bus::with_mapper(mapper));
+    cpu.bus.access_log = access_log(args);
+    cpu.bus.interrupt_log = interrupt_log(args);
+    cpu.bus.symbols = symbols(args);
+    let cdl = flag_value("--cdl").map(|file| (code_data_log(path, &file), file));
+    cpu.bus.cdl = cdl.as_ref().map(|(log, _)| log.clone());
+    cpu.reset();
+    if let Some(start) = start {
+        cpu.program_counter = start;
+    }
+    for _ in 0..steps {
+        if let Err(e) = tracer.on_instruction(&cpu) {
+            fatal(&format!("Can't write the trace: {}", e));
+        }
+        match cpu.step() {
+            CpuState::Running => {}
+            CpuState::Error(e) => fatal(&e.to_string()),
+            CpuState::Halted => break,
+            CpuState::Jammed => {
+                eprintln!("Jammed at ${:04X}, in:", cpu.program_counter);
+                for line in cpu.call_stack.backtrace(&cpu, cpu.program_counter) {
+                    eprintln!("{}", line);
+                }
+                break;
+            }
+        }
+    }
+    if let Err(e) = tracer.dump(&mut std::io::sink()) {
+        fatal(&format!("Can't write the trace: {}", e));
+    }
+    if let Some(Err(e)) = cpu
+        .bus
+        .access_log
+        .as_mut()
+        .map(|log| log.dump(&mut std::io::sink()))
+    {
+        fatal(&format!("Can't write the access log: {}", e));
+    }
+    if let Some(Err(e)) = cpu
+        .bus
+        .interrupt_log
+        .as_mut()
+        .map(|log| log.dump(&mut std::io::sink()))
+    {
+        fatal(&format!("Can't write the interrupt log: {}", e));
+    }
+    if let Some((log, file)) = &cdl {
+        save_code_data_log(&log.borrow(), file);
+    }
+}
+
+/// Runs the game without a window or sound, and says where the time went.
+fn bench_game(path: &str, frames: Option<&String>) {
+    let frames = frames.map_or(600, |frames| {
+        frames
+            .parse()
+            .unwrap_or_else(|_| fatal(&format!("Bad frame count {}", frames)))
+    });
+    let (rom, _) = load_cartridge(path, None);
+    let board = Software::Cartridge(rom)
+        .plug_in()
+        .unwrap_or_else(|e| fatal(&format!("Can't load {}: {}", path, e)));
+    match benchmark::run(board.mapper, frames) {
+        Ok(result) => print!("{}", result),
+        Err(e) => fatal(&e.to_string()),
+    }
+}
+
+/// Runs the game without a window or sound, and prints the hash of every frame.
+fn hash_game(path: &str, frames: Option<&String>) {
+    let frames = frames.map_or(600, |frames| {
+        frames
+            .parse()
+            .unwrap_or_else(|_| fatal(&format!("Bad frame count {}", frames)))
+    });
+    let (rom, _) = load_cartridge(path, None);
+    let board = Software::Cartridge(rom)
+        .plug_in()
+        .unwrap_or_else(|e| fatal(&format!("Can't load {}: {}", path, e)));
+    let print = |frame, hash| println!("{:>6} {:08x}", frame, hash);
+    if let Err(e) = benchmark::hash_frames(board.mapper, frames, print) {
+        fatal(&e.to_string());
+    }
+}
+
+/// Describes a game without running it.
+fn describe_game(path: &str) {
+    match info::describe(&read_game(path, "nes")) {
+        Ok(info) => print!("{}", info),
+        Err(e) => fatal(&format!("Can't read {}: {}", path, e)),
+    }
+}
+
+/// What the window sends the emulation thread, which picks it up as each frame ends.
+enum Message {
+    /// For the instruction callback, which has the whole console.
+    Command(Command),
+    /// The keys held for player 1, and the turbo keys.
+    Keys(JoypadButton, JoypadButton),
+    Pause(bool),
+    /// Runs one more frame while paused, or one more scanline.
+    Step {
+        scanline: bool,
+    },
+    Break,
+    ChangeSpeed(fn(Speed) -> Speed),
+    ShowFps(bool),
+    /// Whether any debug windows are open, which want a copy of the console every frame.
+    WatchConsole(bool),
+    Quit,
+}
+
+/// A frame from the emulation thread, with what goes over it.
+struct Shown {
+    frame: Frame,
+    /// The buttons held on both controllers as the frame ended, for the input display.
+    input: [JoypadButton; 2],
+    overlay: Option<Overlay>,
+    /// Where the console stopped, when stepping a scanline at a time.
+    scanline: Option<u16>,
+    console: Option<ConsoleCopy>,
+}
+
+/// What the debug windows look at, copied as the frame ends so they can look at it on the
+/// window's thread.
+struct ConsoleCopy {
+    ppu: Vec<u8>,
+    mapper: Vec<u8>,
+    line_scroll: [(u16, u8); 240],
+    ram: [u8; 2048],
+    apu: ApuMonitor,
+}
+
+impl ConsoleCopy {
+    fn new(ppu: &NesPPU, ram: &[u8; 2048], apu: &ApuMonitor) -> Self {
+        let mut mapper = StateWriter::new();
+        ppu.bus.mapper.borrow().save_state(&mut mapper);
+        ConsoleCopy {
+            ppu: savestate::save(ppu),
+            mapper: mapper.into_bytes(),
+            line_scroll: ppu.line_scroll,
+            ram: *ram,
+            apu: apu.clone(),
+        }
+    }
+
+    /// Loads the copy into the window's own PPU and cartridge.
+    fn load_into(&self, ppu: &mut NesPPU) -> Result<(), String> {
+        savestate::load(ppu, &self.ppu)?;
+        ppu.bus
+            .mapper
+            .borrow_mut()
+            .load_state(&mut StateReader::new(&self.mapper))?;
+        ppu.line_scroll = self.line_scroll;
+        Ok(())
+    }
+}
+
+/// The emulation thread's ends of the channels to and from the window, and what they share.
+struct Link {
+    messages: Receiver<Message>,
+    frames: SyncSender<Shown>,
+    osd: Arc<Mutex<Osd>>,
+    /// A new title for the window, when there is one.
+    window_title: Arc<Mutex<Option<String>>>,
+    rewinding: Arc<AtomicBool>,
+    audio: Option<DeviceBuffer>,
+}
+
+/// Opens the window and plays the game in it. The console runs on a thread of its own, so
+/// nothing the window does, like waiting for vsync, being dragged around or saving a
+/// screenshot, holds it up.
+fn run(path: &str, args: &[String]) {
+    let flag_value = |flag: &str| {
+        args.windows(2)
+            .find(|pair| pair[0] == flag)
+            .map(|pair| pair[1].clone())
+    };
+
+    let mut video_config = VideoConfig::default();
+    if let Some(mode) = flag_value("--scaling") {
+        video_config.scale_mode = ScaleMode::parse(&mode).unwrap_or_else(|e| fatal(&e));
+    }
+    if let Some(scale) = flag_value("--scale") {
+        video_config.window_scale = scale
+            .parse()
+            .unwrap_or_else(|_| fatal(&format!("Bad window scale {}", scale)));
+    }
+    video_config.aspect_correct = args.iter().any(|arg| arg == "--aspect-correct");
+    if let Some(mode) = flag_value("--fullscreen-mode") {
+        video_config.fullscreen_mode = FullscreenMode::parse(&mode).unwrap_or_else(|e| fatal(&e));
+    }
+
+    //load the game
+    let loaded = load_game(path, args);
+    // --serve PORT takes requests from test frameworks and bots on localhost instead of
+    // opening a window
+    if let Some(port) = flag_value("--serve") {
+        let port: u16 = port
+            .parse()
+            .unwrap_or_else(|_| fatal(&format!("Bad port {}", port)));
+        let board = loaded
+            .software
+            .plug_in()
+            .unwrap_or_else(|e| fatal(&format!("Can't load {}: {}", path, e)));
+        let listener = TcpListener::bind(("127.0.0.1", port))
+            .unwrap_or_else(|e| fatal(&format!("Can't listen on port {}: {}", port, e)));
+        println!("Listening on port {}", port);
+        ControlServer::with_mapper(board.mapper).serve(listener);
+        return;
+    }
+    // the debug windows look at a copy of the console, kept up to date with the one being
+    // played on the emulation thread
+    let viewer_board = loaded
+        .software
+        .clone()
+        .plug_in()
+        .unwrap_or_else(|e| fatal(&format!("Can't load {}: {}", path, e)));
+    let mut viewer_ppu = NesPPU::new(viewer_board.mapper);
+    let mut viewer_ram = [0; 2048];
+    let mut viewer_apu = ApuMonitor::new();
+    let nsf_mode = viewer_board.nsf_player.is_some();
+    let (zapper_connected, _) = accessories(loaded.game, args);
+
+    // the state saved on the way out last time can be carried on from
+    let netplay = flag_value("--host").is_some() || flag_value("--join").is_some();
+    let resume =
+        !netplay && savestate::autosave_path(&loaded.state_path).exists() && ask_to_resume();
+
     // init sdl2
     let sdl_context = sdl2::init().unwrap();
     let video_subsystem = sdl_context.video().unwrap();
+    let (window_width, window_height) = video_config.window_size();
     let window = video_subsystem
-        .window("Rust NES", (256.0 * 3.0) as u32, (240.0 * 3.0) as u32)
+        .window("Rust NES", window_width, window_height)
         .position_centered()
+        .resizable()
         .build()
         .unwrap();
 
-    let mut canvas = window.into_canvas().present_vsync().build().unwrap();
+    let mut canvas = window.into_canvas().build().unwrap();
     let mut event_pump = sdl_context.event_pump().unwrap();
-    canvas.set_scale(3.0, 3.0).unwrap();
+
+    // a tenth of a second of slack between the emulator and the sound card, which is
+    // played through SDL, or cpal with --audio cpal in builds with the cpal feature. The
+    // device stays on this thread, and the emulation thread fills its buffer
+    let max_samples = SAMPLE_RATE as usize / 10;
+    let audio_output: Result<(DeviceBuffer, Box<dyn AudioSink>), String> =
+        match flag_value("--audio").as_deref() {
+            None | Some("sdl") => sdl_context
+                .audio()
+                .and_then(|audio| SdlOutput::open(&audio, max_samples))
+                .map(|output| (output.buffer(), Box::new(output) as _)),
+            #[cfg(feature = "cpal")]
+            Some("cpal") => {
+                CpalOutput::open(max_samples).map(|output| (output.buffer(), Box::new(output) as _))
+            }
+            Some(backend) => fatal(&format!("No audio backend called {}", backend)),
+        };
+    let (audio_buffer, _audio_output) = audio_output
+        .map_err(|e| eprintln!("Audio disabled: {}", e))
+        .ok()
+        .unzip();
+
+    // gamepads drive players 2-4, in the order they were found
+    let controller_subsystem = sdl_context.game_controller().unwrap();
+    let controllers: Vec<_> = (0..controller_subsystem.num_joysticks().unwrap_or(0))
+        .filter(|i| controller_subsystem.is_game_controller(*i))
+        .filter_map(|i| controller_subsystem.open(i).ok())
+        .take(3)
+        .collect();
 
     let creator = canvas.texture_creator();
     let mut texture = creator
         .create_texture_target(PixelFormatEnum::RGB24, 256, 240)
         .unwrap();
+    let mut filtered_texture = creator
+        .create_texture_target(
+            PixelFormatEnum::RGB24,
+            filter::WIDTH as u32,
+            filter::HEIGHT as u32,
+        )
+        .unwrap();
+    let mut filtered = vec![0; filter::WIDTH * filter::HEIGHT * 3];
+    let mut post_filter = PostFilter::None;
 
-    //load the game
-    let bytes: Vec<u8> = std::fs::read("pac-man.nes").unwrap();
-    let rom = Rom::new(&bytes).unwrap();
-
-    let mut frame = Frame::new();
+    let window_title = Arc::new(Mutex::new(
+        viewer_board
+            .nsf_player
+            .as_ref()
+            .map(|player| player.borrow().track_title()),
+    ));
 
     let mut key_map = HashMap::new();
     key_map.insert(Keycode::Down, joypad::JoypadButton::DOWN);
@@ -60,39 +796,1133 @@ fn main() {
     key_map.insert(Keycode::Return, joypad::JoypadButton::START);
     key_map.insert(Keycode::A, joypad::JoypadButton::BUTTON_A);
     key_map.insert(Keycode::S, joypad::JoypadButton::BUTTON_B);
+    let mut turbo_key_map = HashMap::new();
+    turbo_key_map.insert(Keycode::Q, JoypadButton::BUTTON_A);
+    turbo_key_map.insert(Keycode::W, JoypadButton::BUTTON_B);
+
+    let mut channel_keys = HashMap::new();
+    channel_keys.insert(Keycode::Num1, Channel::Pulse1);
+    channel_keys.insert(Keycode::Num2, Channel::Pulse2);
+    channel_keys.insert(Keycode::Num3, Channel::Triangle);
+    channel_keys.insert(Keycode::Num4, Channel::Noise);
+    channel_keys.insert(Keycode::Num5, Channel::Dmc);
+
+    let slot_keys = [
+        Keycode::F1,
+        Keycode::F2,
+        Keycode::F3,
+        Keycode::F4,
+        Keycode::F5,
+        Keycode::F6,
+        Keycode::F7,
+        Keycode::F8,
+        Keycode::F9,
+        Keycode::F10,
+    ];
+
+    let mut button_map = HashMap::new();
+    button_map.insert(Button::DPadDown, JoypadButton::DOWN);
+    button_map.insert(Button::DPadUp, JoypadButton::UP);
+    button_map.insert(Button::DPadRight, JoypadButton::RIGHT);
+    button_map.insert(Button::DPadLeft, JoypadButton::LEFT);
+    button_map.insert(Button::Back, JoypadButton::SELECT);
+    button_map.insert(Button::Start, JoypadButton::START);
+    button_map.insert(Button::A, JoypadButton::BUTTON_A);
+    button_map.insert(Button::X, JoypadButton::BUTTON_B);
+    let mut turbo_button_map = HashMap::new();
+    turbo_button_map.insert(Button::B, JoypadButton::BUTTON_A);
+    turbo_button_map.insert(Button::Y, JoypadButton::BUTTON_B);
 
-    // the game cycle
-    let bus = Bus::new(rom, move |ppu: &NesPPU, joypad: &mut Joypad| {
-        render::render(ppu, &mut frame);
-        texture.update(None, &frame.data, 256 * 3).unwrap();
+    // the last few seconds of video, for F11
+    let mut clip = ClipBuffer::new(6);
 
-        canvas.copy(&texture, None, None).unwrap();
+    // messages stay up for two seconds, and are drawn over a copy of the frame so they
+    // don't end up in screenshots and recordings
+    let osd = Arc::new(Mutex::new(Osd::new(120)));
+    let mut display = Frame::new();
+    let mut show_fps = args.iter().any(|arg| arg == "--show-fps");
+
+    // I shows the buttons held on both controllers in the bottom right corner, as they
+    // were at the end of the last frame
+    let mut show_input = args.iter().any(|arg| arg == "--show-input");
+
+    // P pauses, N runs one more frame and L one more scanline
+    let mut paused = false;
+    // backspace rewinds for as long as it's held
+    let rewinding = Arc::new(AtomicBool::new(false));
+    let mut keyboard = JoypadButton::empty();
+    let mut turbo_keys = JoypadButton::empty();
+
+    // M opens the memory viewer, which shows the RAM as it was at the end of the last
+    // frame, T the nametables, K the pattern tables, U the sound channels and H the RAM
+    // search
+    let mut debug_windows = DebugWindows::new(video_subsystem);
+    let memory_viewer = Rc::new(RefCell::new(MemoryViewer::new()));
+    let nametable_viewer = Rc::new(RefCell::new(NametableViewer::new()));
+    let pattern_viewer = Rc::new(RefCell::new(PatternViewer::new()));
+    let apu_viewer = Rc::new(RefCell::new(ApuViewer::new()));
+    let ram_search = Rc::new(RefCell::new(RamSearch::new()));
+    let mut watching = false;
+
+    // the window doesn't wait for the console, and the console only waits for the window
+    // while paused. A couple of frames can be waiting to be shown, and any more are
+    // dropped
+    let (messages, inbox) = mpsc::channel();
+    let (frame_sender, frames) = mpsc::sync_channel(2);
+    let link = Link {
+        messages: inbox,
+        frames: frame_sender,
+        osd: osd.clone(),
+        window_title: window_title.clone(),
+        rewinding: rewinding.clone(),
+        audio: audio_buffer,
+    };
+    let emulation_path = path.to_string();
+    let emulation_args = args.to_vec();
+    std::thread::Builder::new()
+        .name("emulation".to_string())
+        .spawn(move || emulate(&emulation_path, &emulation_args, loaded, resume, link))
+        .unwrap();
+
+    let mut shown = Shown {
+        frame: Frame::new(),
+        input: [JoypadButton::empty(); 2],
+        overlay: None,
+        scanline: None,
+        console: None,
+    };
+    loop {
+        // a new frame is usually waiting by the time the last one is up, and while paused
+        // the picture is redrawn every so often anyway
+        let next = match frames.recv_timeout(Duration::from_millis(16)) {
+            Ok(next) => Some(next),
+            Err(RecvTimeoutError::Timeout) => None,
+            Err(RecvTimeoutError::Disconnected) => return,
+        };
+        for next in next.into_iter().chain(frames.try_iter()) {
+            if next.scanline.is_none() {
+                clip.push(&next.frame);
+            }
+            if let Some(copy) = &next.console {
+                if let Err(e) = copy.load_into(&mut viewer_ppu) {
+                    eprintln!("Can't update the debug windows: {}", e);
+                }
+                viewer_ram = copy.ram;
+                viewer_apu = copy.apu.clone();
+            }
+            shown = next;
+        }
 
+        {
+            let mut osd = osd.lock().unwrap();
+            if let Some(scanline) = shown.scanline {
+                osd.show_for(&format!("Scanline {}", scanline), 1);
+            } else if paused {
+                osd.show_for("Paused", 1);
+            }
+            if rewinding.load(Ordering::Relaxed) {
+                osd.show_for("Rewinding", 1);
+            }
+        }
+        let overlay_empty = shown
+            .overlay
+            .as_ref()
+            .is_none_or(|overlay| overlay.is_empty());
+        let shown_frame = if osd.lock().unwrap().is_empty() && overlay_empty && !show_input {
+            &shown.frame
+        } else {
+            display.data.copy_from_slice(&shown.frame.data);
+            if let Some(overlay) = &shown.overlay {
+                overlay.draw(&mut display);
+            }
+            if show_input {
+                input::draw_input_display(&mut display, &shown.input);
+            }
+            osd.lock().unwrap().draw(&mut display);
+            &display
+        };
+        let texture_shown = if post_filter == PostFilter::None {
+            texture.update(None, &shown_frame.data, 256 * 3).unwrap();
+            &texture
+        } else {
+            post_filter.apply(shown_frame, &mut filtered);
+            filtered_texture
+                .update(None, &filtered, filter::WIDTH * 3)
+                .unwrap();
+            &filtered_texture
+        };
+
+        // the picture is fitted to the window again every frame, so resizing just works
+        let (output_width, output_height) = canvas.output_size().unwrap();
+        let viewport = video_config.viewport(output_width, output_height);
+        canvas.set_draw_color(Color::BLACK);
+        canvas.clear();
+        canvas
+            .copy(
+                texture_shown,
+                None,
+                Rect::new(viewport.x, viewport.y, viewport.width, viewport.height),
+            )
+            .unwrap();
+
+        if let Some(title) = window_title.lock().unwrap().take() {
+            canvas.window_mut().set_title(&title).unwrap();
+        }
         canvas.present();
+        debug_windows.draw(&ConsoleState {
+            ppu: &viewer_ppu,
+            ram: &viewer_ram,
+            apu: &viewer_apu,
+        });
+
+        let send = |message| {
+            // the emulation thread only goes away when the emulator exits
+            let _ = messages.send(message);
+        };
+        let command = |command| send(Message::Command(command));
+        let held = (keyboard, turbo_keys);
+        let mut stepped = false;
         for event in event_pump.poll_iter() {
+            let console = ConsoleState {
+                ppu: &viewer_ppu,
+                ram: &viewer_ram,
+                apu: &viewer_apu,
+            };
+            if debug_windows.handle_event(&event, &console) {
+                continue;
+            }
+
             match event {
                 Event::Quit { .. }
+                | Event::Window {
+                    win_event: WindowEvent::Close,
+                    ..
+                }
                 | Event::KeyDown {
                     keycode: Some(Keycode::Escape),
                     ..
-                } => std::process::exit(0),
-                Event::KeyDown { keycode, .. } => {
-                    if let Some(key) = key_map.get(&keycode.unwrap_or(Keycode::Ampersand)) {
-                        joypad.set_button_pressed_status(*key, true);
+                } => send(Message::Quit),
+                Event::KeyDown {
+                    keycode: Some(Keycode::Backquote),
+                    ..
+                } => send(Message::Break),
+                Event::KeyDown {
+                    keycode: Some(Keycode::Tab),
+                    ..
+                } => send(Message::ChangeSpeed(|speed| match speed {
+                    Speed::Unlimited => Speed::Scaled(1.0),
+                    _ => Speed::Unlimited,
+                })),
+                Event::KeyDown {
+                    keycode: Some(Keycode::Equals),
+                    ..
+                } => send(Message::ChangeSpeed(Speed::faster)),
+                Event::KeyDown {
+                    keycode: Some(Keycode::Minus),
+                    ..
+                } => send(Message::ChangeSpeed(Speed::slower)),
+                Event::KeyDown {
+                    keycode: Some(Keycode::Num0),
+                    ..
+                } => send(Message::ChangeSpeed(|_| Speed::Scaled(1.0))),
+                Event::KeyDown {
+                    keycode: Some(Keycode::C),
+                    ..
+                } => command(Command::ToggleCheats),
+                Event::KeyDown {
+                    keycode: Some(Keycode::D),
+                    ..
+                } => command(Command::SwitchDiskSide),
+                Event::KeyDown {
+                    keycode: Some(Keycode::P),
+                    ..
+                } => {
+                    paused = !paused;
+                    send(Message::Pause(paused));
+                }
+                Event::KeyDown {
+                    keycode: Some(Keycode::N),
+                    ..
+                } => {
+                    paused = true;
+                    stepped = true;
+                    send(Message::Step { scanline: false });
+                }
+                Event::KeyDown {
+                    keycode: Some(Keycode::L),
+                    ..
+                } => {
+                    paused = true;
+                    stepped = true;
+                    send(Message::Step { scanline: true });
+                }
+                Event::KeyDown {
+                    keycode: Some(Keycode::F),
+                    ..
+                } => {
+                    show_fps = !show_fps;
+                    send(Message::ShowFps(show_fps));
+                }
+                Event::KeyDown {
+                    keycode: Some(Keycode::I),
+                    ..
+                } => show_input = !show_input,
+                Event::KeyDown {
+                    keycode: Some(Keycode::M),
+                    ..
+                } => debug_windows.toggle("Memory", memory_viewer.clone()),
+                Event::KeyDown {
+                    keycode: Some(Keycode::T),
+                    ..
+                } => debug_windows.toggle("Nametables", nametable_viewer.clone()),
+                Event::KeyDown {
+                    keycode: Some(Keycode::K),
+                    ..
+                } => debug_windows.toggle("Pattern tables", pattern_viewer.clone()),
+                Event::KeyDown {
+                    keycode: Some(Keycode::U),
+                    ..
+                } => debug_windows.toggle("APU", apu_viewer.clone()),
+                Event::KeyDown {
+                    keycode: Some(Keycode::H),
+                    ..
+                } => debug_windows.toggle("RAM search", ram_search.clone()),
+                Event::KeyDown {
+                    keycode: Some(Keycode::V),
+                    ..
+                } => {
+                    post_filter = post_filter.next();
+                    let message = format!("Filter: {:?}", post_filter);
+                    osd.lock().unwrap().show(&message);
+                }
+                // F1 to F10 load a slot, and with shift save to it
+                Event::KeyDown {
+                    keycode: Some(keycode),
+                    keymod,
+                    ..
+                } if slot_keys.contains(&keycode) => {
+                    let slot = slot_keys.iter().position(|&key| key == keycode).unwrap() as u8 + 1;
+                    command(if keymod.intersects(Mod::LSHIFTMOD | Mod::RSHIFTMOD) {
+                        Command::SaveState(slot)
+                    } else {
+                        Command::LoadState(slot)
+                    });
+                }
+                Event::KeyDown {
+                    keycode: Some(Keycode::F11),
+                    ..
+                } => {
+                    let path = next_free_path("clip", "gif");
+                    match clip.save_gif(&path) {
+                        Ok(()) => osd.lock().unwrap().show(&format!("Saved {}", path)),
+                        Err(e) => eprintln!("Can't save {}: {}", path, e),
                     }
                 }
-                Event::KeyUp { keycode, .. } => {
-                    if let Some(key) = key_map.get(&keycode.unwrap_or(Keycode::Ampersand)) {
-                        joypad.set_button_pressed_status(*key, false);
+                Event::KeyDown {
+                    keycode: Some(Keycode::F12),
+                    ..
+                } => {
+                    let path = next_free_path("screenshot", "png");
+                    match shown.frame.save_png(&path) {
+                        Ok(()) => osd.lock().unwrap().show(&format!("Saved {}", path)),
+                        Err(e) => eprintln!("Can't save {}: {}", path, e),
+                    }
+                }
+                Event::KeyDown {
+                    keycode: Some(Keycode::R),
+                    keymod,
+                    ..
+                } => command(if keymod.intersects(Mod::LSHIFTMOD | Mod::RSHIFTMOD) {
+                    Command::PowerCycle
+                } else {
+                    Command::Reset
+                }),
+                Event::KeyDown {
+                    keycode: Some(Keycode::O),
+                    keymod,
+                    ..
+                } => command(Command::InsertCoin(
+                    keymod.intersects(Mod::LSHIFTMOD | Mod::RSHIFTMOD) as usize,
+                )),
+                Event::KeyDown {
+                    keycode: Some(Keycode::Left),
+                    ..
+                } if nsf_mode => command(Command::ChangeTrack(-1)),
+                Event::KeyDown {
+                    keycode: Some(Keycode::Right),
+                    ..
+                } if nsf_mode => command(Command::ChangeTrack(1)),
+                // checked before the joypad, which has start on enter
+                Event::KeyDown {
+                    keycode: Some(Keycode::Return),
+                    keymod,
+                    ..
+                } if keymod.intersects(Mod::LALTMOD | Mod::RALTMOD) => {
+                    let window = canvas.window_mut();
+                    let fullscreen = match (window.fullscreen_state(), video_config.fullscreen_mode)
+                    {
+                        (FullscreenType::Off, FullscreenMode::Desktop) => FullscreenType::Desktop,
+                        (FullscreenType::Off, FullscreenMode::Exclusive) => FullscreenType::True,
+                        _ => FullscreenType::Off,
+                    };
+                    if let Err(e) = window.set_fullscreen(fullscreen) {
+                        eprintln!("Can't switch to fullscreen: {}", e);
+                    }
+                }
+                Event::KeyDown {
+                    keycode: Some(keycode),
+                    keymod,
+                    ..
+                } if channel_keys.contains_key(&keycode) => {
+                    let channel = channel_keys[&keycode];
+                    command(if keymod.intersects(Mod::LSHIFTMOD | Mod::RSHIFTMOD) {
+                        Command::Solo(channel)
+                    } else {
+                        Command::ToggleMute(channel)
+                    });
+                }
+                Event::KeyDown {
+                    keycode: Some(Keycode::Backspace),
+                    ..
+                } => rewinding.store(true, Ordering::Relaxed),
+                Event::KeyUp {
+                    keycode: Some(Keycode::Backspace),
+                    ..
+                } => rewinding.store(false, Ordering::Relaxed),
+                Event::KeyDown { keycode, .. } | Event::KeyUp { keycode, .. } => {
+                    let keycode = keycode.unwrap_or(Keycode::Ampersand);
+                    let pressed = matches!(event, Event::KeyDown { .. });
+                    if let Some(key) = key_map.get(&keycode) {
+                        keyboard.set(*key, pressed);
+                    } else if let Some(key) = turbo_key_map.get(&keycode) {
+                        turbo_keys.set(*key, pressed);
+                    }
+                }
+                Event::ControllerButtonDown { which, button, .. }
+                | Event::ControllerButtonUp { which, button, .. } => {
+                    let player = controllers.iter().position(|c| c.instance_id() == which);
+                    let mapped = match button_map.get(&button) {
+                        Some(mapped) => Some((*mapped, false)),
+                        None => turbo_button_map.get(&button).map(|mapped| (*mapped, true)),
+                    };
+                    if let (Some(player), Some((mapped, turbo))) = (player, mapped) {
+                        command(Command::SetButton {
+                            player: player + 2,
+                            button: mapped,
+                            turbo,
+                            pressed: matches!(event, Event::ControllerButtonDown { .. }),
+                        });
                     }
                 }
                 _ => { /* do nothing */ }
             }
         }
+        if (keyboard, turbo_keys) != held {
+            send(Message::Keys(keyboard, turbo_keys));
+        }
+        if debug_windows.any_open() != watching {
+            watching = !watching;
+            send(Message::WatchConsole(watching));
+        }
+        // while paused, edits wait in the memory viewer like they would for the console
+        if !paused || stepped {
+            for edit in memory_viewer.borrow_mut().take_edits() {
+                command(Command::Poke(edit));
+            }
+            for code in ram_search.borrow_mut().take_freezes() {
+                command(Command::Freeze(code));
+            }
+        }
+
+        if zapper_connected {
+            let mouse = event_pump.mouse_state();
+            let (output_width, output_height) = canvas.output_size().unwrap();
+            let viewport = video_config.viewport(output_width, output_height);
+            // pointing away from the picture is like pointing away from the TV
+            let aim = viewport.to_nes(mouse.x(), mouse.y());
+            let (x, y) = aim.unwrap_or((0, 0));
+            command(Command::UpdateZapper(ZapperState {
+                x,
+                y,
+                trigger: mouse.left(),
+                bright: aim.is_some() && zapper::is_bright(&shown.frame, x, y),
+            }));
+        }
+    }
+}
+
+/// Runs the console, on a thread of its own, sending the frames to the window and playing
+/// the sound.
+fn emulate(path: &str, args: &[String], loaded: LoadedGame, resume: bool, link: Link) {
+    let flag_value = |flag: &str| {
+        args.windows(2)
+            .find(|pair| pair[0] == flag)
+            .map(|pair| pair[1].clone())
+    };
+
+    // --ram-init picks what RAM holds at power on, zero by default
+    let ram_init = flag_value("--ram-init")
+        .map(|pattern| RamInit::parse(&pattern).unwrap_or_else(|e| fatal(&e)));
+    // --alignment 0, 1 or 2 starts the PPU that many dots ahead of the CPU, or picks one of
+    // them at random like the real console
+    let alignment = flag_value("--alignment")
+        .map(|alignment| Alignment::parse(&alignment).unwrap_or_else(|e| fatal(&e)));
+    // --turbo-rate sets how many times a second turbo buttons press, 15 by default
+    let turbo_period = flag_value("--turbo-rate").map_or(joypad::DEFAULT_TURBO_PERIOD, |rate| {
+        let rate: f64 = rate
+            .parse()
+            .ok()
+            .filter(|rate| *rate > 0.0)
+            .unwrap_or_else(|| fatal(&format!("Bad turbo rate {}", rate)));
+        (pacer::NTSC_FPS / rate / 2.0).round().clamp(1.0, 255.0) as u8
+    });
+    // --seed N makes anything random the same on every run, for reproducing bugs
+    let seed: Option<u64> = flag_value("--seed").map(|seed| {
+        seed.parse()
+            .unwrap_or_else(|_| fatal(&format!("Bad seed {}", seed)))
+    });
+
+    let LoadedGame {
+        software,
+        system,
+        game,
+        crc: game_crc,
+        state_path: state_game,
+        battery,
+    } = loaded;
+    let Board {
+        mapper,
+        disk_drive,
+        nsf_player,
+    } = software
+        .plug_in()
+        .unwrap_or_else(|e| fatal(&format!("Can't load {}: {}", path, e)));
+    // --script runs a Rhai script alongside the game, with hooks on every frame and on
+    // chosen addresses
+    let mut script = flag_value("--script").map(|path| {
+        let source = String::from_utf8_lossy(&read_file(&path)).into_owned();
+        Script::new(&source, mapper.clone())
+            .unwrap_or_else(|e| fatal(&format!("Can't load {}: {}", path, e)))
+    });
+    let script_overlay = script.as_ref().map(Script::overlay);
+    // --host PORT or --join HOST:PORT plays against another instance over the network,
+    // with the buttons held back --input-delay frames (2 by default) to hide the lag
+    let input_delay = flag_value("--input-delay").map_or(2, |delay| {
+        delay
+            .parse()
+            .unwrap_or_else(|_| fatal(&format!("Bad input delay {}", delay)))
     });
+    let netplay = match (flag_value("--host"), flag_value("--join")) {
+        (Some(port), _) => {
+            let port = port
+                .parse()
+                .unwrap_or_else(|_| fatal(&format!("Bad port {}", port)));
+            println!("Waiting for player 2 on port {}", port);
+            Some(Netplay::host(port, input_delay))
+        }
+        (None, Some(addr)) => Some(Netplay::join(&addr, input_delay)),
+        (None, None) => None,
+    };
+    let mut netplay = netplay
+        .map(|netplay| netplay.unwrap_or_else(|e| fatal(&format!("Can't start netplay: {}", e))));
+
+    let Link {
+        messages,
+        frames,
+        osd,
+        window_title,
+        rewinding,
+        audio,
+    } = link;
+    let frame_osd = osd.clone();
+    let error_window_title = window_title.clone();
+
+    let stopped = Rc::new(Cell::new(false));
+    let frame_stopped = stopped.clone();
+
+    let mut frame = Frame::new();
 
-    let mut cpu = CPU::new(bus);
+    let debugger = Rc::new(RefCell::new(Debugger::new()));
+    if args.iter().any(|arg| arg == "--debug") {
+        debugger.borrow_mut().pause();
+    }
+    let frame_debugger = debugger.clone();
 
+    let mut pacer = FramePacer::new(pacer::NTSC_FPS);
+    // --frame-skip N/M draws only M - N of every M frames, for slow machines
+    let mut frame_skip = flag_value("--frame-skip")
+        .map_or_else(|| FrameSkip::new(0, 1), |skip| FrameSkip::parse(&skip))
+        .unwrap_or_else(|e| fatal(&e));
+
+    let commands = Rc::new(RefCell::new(Vec::new()));
+    let frame_commands = commands.clone();
+
+    // the keys held for player 1, which go through netplay before reaching the console
+    let keyboard = Rc::new(Cell::new(JoypadButton::empty()));
+    let frame_keyboard = keyboard.clone();
+    let turbo_keys = Rc::new(Cell::new(JoypadButton::empty()));
+    let frame_turbo_keys = turbo_keys.clone();
+
+    // --profile counts where the CPU spends its time, and prints the busiest code on exit
+    let profiler = if args.iter().any(|arg| arg == "--profile") {
+        Some(Rc::new(RefCell::new(Profiler::new())))
+    } else {
+        None
+    };
+    let frame_profiler = profiler.clone();
+
+    // --trace FILE writes a line for every instruction to FILE, or to stdout with -, and
+    // --trace-ring N keeps only the last N, to print if the CPU jams
+    let trace_sink = match (flag_value("--trace"), flag_value("--trace-ring")) {
+        (Some(path), _) if path == "-" => Some(TraceSink::Stdout),
+        (Some(path), _) => Some(
+            TraceSink::file(&path)
+                .unwrap_or_else(|e| fatal(&format!("Can't write to {}: {}", path, e))),
+        ),
+        (None, Some(lines)) => {
+            Some(TraceSink::ring(lines.parse().unwrap_or_else(|_| {
+                fatal(&format!("Bad line count {}", lines))
+            })))
+        }
+        (None, None) => None,
+    };
+    let mut trace_from_break = false;
+    let tracer = trace_sink.map(|sink| {
+        let mut tracer = Tracer::new(sink);
+        trace_from_break = configure_tracer(&mut tracer, args);
+        Rc::new(RefCell::new(tracer))
+    });
+    let frame_tracer = tracer.clone();
+    let jam_tracer = tracer.clone();
+    // --cdl FILE logs which PRG ROM bytes are run and which are read, for disassemblers,
+    // adding to the log already in FILE
+    let cdl = flag_value("--cdl").map(|file| (code_data_log(path, &file), file));
+    let frame_cdl = cdl.clone();
+
+    let (zapper_connected, four_score_connected) = accessories(game, args);
+
+    // --record captures the video and sound as the game is played
+    let mut recorder = flag_value("--record").map(|path| match Recorder::new(&path) {
+        Ok(recorder) => recorder,
+        Err(e) => fatal(&format!("Can't record to {}: {}", path, e)),
+    });
+    let recording = recorder.is_some();
+    let record_buffer = Arc::new(Mutex::new(SampleBuffer::new(SAMPLE_RATE as usize)));
+    let frame_record_buffer = record_buffer.clone();
+
+    let mut show_fps = args.iter().any(|arg| arg == "--show-fps");
+    let input_display = Rc::new(Cell::new([JoypadButton::empty(); 2]));
+    let frame_input_display = input_display.clone();
+
+    let mut paused = false;
+    let scanline_step = Rc::new(Cell::new(false));
+    let frame_scanline_step = scanline_step.clone();
+
+    // the debug windows get a copy of the console every frame while they're open
+    let mut watching = false;
+    let apu_monitor = Rc::new(RefCell::new(ApuMonitor::new()));
+    let frame_apu_monitor = apu_monitor.clone();
+
+    // the game cycle, run as each frame ends, and with scanline stepping on as each
+    // scanline starts
+    let mut on_event = move |cpu: &mut CPU, event: ConsoleEvent| {
+        let frame_done = match event {
+            ConsoleEvent::FrameComplete => true,
+            ConsoleEvent::Scanline(_) if paused => false,
+            _ => return,
+        };
+        let ppu = &cpu.bus.ppu;
+
+        // skipped frames are run but not drawn, and the last one drawn stays up. The
+        // Zapper looks at every frame, so they're all drawn while it's plugged in
+        let drawn = !frame_done || paused || zapper_connected || frame_skip.next_drawn();
+        if drawn {
+            render::render(ppu, &mut frame);
+            if frame_stopped.get() {
+                frame.draw_error_overlay();
+            }
+        }
+        if frame_done {
+            let recorded = recorder.as_mut().map_or(Ok(()), |recorder| {
+                let mut buffer = frame_record_buffer.lock().unwrap();
+                let mut samples = vec![0.0; buffer.len()];
+                buffer.fill(&mut samples);
+                recorder.add_frame(&frame, &samples)
+            });
+            if let Err(e) = recorded {
+                eprintln!("Recording stopped: {}", e);
+                recorder = None;
+            }
+        }
+        if show_fps {
+            let counter = format!(
+                "{:.0} FPS {:.0}%",
+                pacer.measured_fps(),
+                pacer.measured_speed()
+            );
+            frame_osd.lock().unwrap().set_corner(Some(counter));
+        }
+        if drawn {
+            // a window that can't keep up misses frames, rather than holding up the console
+            let _ = frames.try_send(Shown {
+                frame: frame.clone(),
+                input: frame_input_display.get(),
+                overlay: script_overlay
+                    .as_ref()
+                    .map(|overlay| overlay.borrow().clone()),
+                scanline: (!frame_done).then_some(ppu.scanline),
+                console: watching
+                    .then(|| ConsoleCopy::new(ppu, cpu.bus.ram(), &frame_apu_monitor.borrow())),
+            });
+        }
+        if !paused {
+            pacer.wait();
+        }
+
+        // while paused, this waits for the window to say what to do next
+        let mut step = false;
+        loop {
+            let message = if paused && !step {
+                match messages.recv() {
+                    Ok(message) => message,
+                    Err(_) => return,
+                }
+            } else {
+                match messages.try_recv() {
+                    Ok(message) => message,
+                    Err(_) => break,
+                }
+            };
+            match message {
+                Message::Command(command) => frame_commands.borrow_mut().push(command),
+                Message::Keys(held, turbo) => {
+                    frame_keyboard.set(held);
+                    frame_turbo_keys.set(turbo);
+                    let joypad = cpu.bus.joypad_mut(1).unwrap();
+                    joypad.set_button_pressed_status(JoypadButton::all(), false);
+                    joypad.set_button_pressed_status(held, true);
+                    joypad.set_turbo_pressed(JoypadButton::all(), false);
+                    joypad.set_turbo_pressed(turbo, true);
+                }
+                Message::Pause(pause) => {
+                    paused = pause;
+                    frame_scanline_step.set(false);
+                }
+                Message::Step { scanline } => {
+                    paused = true;
+                    frame_scanline_step.set(scanline);
+                    step = true;
+                }
+                Message::Break => frame_debugger.borrow_mut().pause(),
+                Message::ChangeSpeed(change) => {
+                    pacer.set_speed(change(pacer.speed()));
+                    cpu.bus.apu.set_speed(pacer.speed().scale());
+                    let message = format!("Speed {}", pacer.speed());
+                    frame_osd.lock().unwrap().show(&message);
+                }
+                Message::ShowFps(show) => {
+                    show_fps = show;
+                    frame_osd.lock().unwrap().set_corner(None);
+                }
+                Message::WatchConsole(watch) => watching = watch,
+                Message::Quit => {
+                    if let Some(recorder) = recorder.take() {
+                        if let Err(e) = recorder.finish() {
+                            eprintln!("Can't finish the recording: {}", e);
+                        }
+                    }
+                    if let Some(profiler) = &frame_profiler {
+                        print!("{}", profiler.borrow().report());
+                    }
+                    if let Some(tracer) = &frame_tracer {
+                        if let Err(e) = tracer.borrow_mut().dump(&mut std::io::sink()) {
+                            eprintln!("Can't write the trace: {}", e);
+                        }
+                    }
+                    if let Some((log, file)) = &frame_cdl {
+                        save_code_data_log(&log.borrow(), file);
+                    }
+                    // a jammed game isn't worth carrying on from
+                    if frame_stopped.get() {
+                        std::process::exit(0)
+                    }
+                    frame_commands.borrow_mut().push(Command::Quit);
+                    paused = false;
+                }
+            }
+        }
+    };
+
+    let mut cpu = CPU::new(Bus::with_mapper(mapper));
+    cpu.halt_on_brk = false;
+    if let Some(buffer) = audio {
+        cpu.bus.apu.set_output(Box::new(buffer));
+    }
+    cpu.bus.access_log = access_log(args);
+    cpu.bus.interrupt_log = interrupt_log(args);
+    cpu.bus.symbols = symbols(args);
+    cpu.bus.cdl = cdl.map(|(log, _)| log);
+    cpu.bus.apu.set_monitor(apu_monitor);
+    if recording {
+        cpu.bus.apu.set_record_output(Box::new(record_buffer));
+    }
+
+    // both sides of a netplay game have to start out exactly the same
+    match seed.or_else(|| netplay.as_ref().map(|_| 0)) {
+        Some(seed) => cpu.bus.set_seed(seed),
+        None => {
+            cpu.bus.set_seed(Rng::clock_seed());
+            if ram_init == Some(RamInit::Random(None)) {
+                println!("Random RAM from --seed {}", cpu.bus.seed());
+            }
+        }
+    }
+    if let Some(init) = ram_init {
+        cpu.bus.set_ram_init(init);
+    }
+    if let Some(alignment) = alignment {
+        cpu.bus.set_alignment(alignment);
+    }
     cpu.reset();
-    cpu.run();
+
+    if zapper_connected {
+        cpu.bus.zapper = Some(Zapper::new());
+    }
+
+    if four_score_connected {
+        cpu.bus.four_score = Some(FourScore::new());
+    }
+
+    for player in 1..=4 {
+        if let Some(joypad) = cpu.bus.joypad_mut(player) {
+            joypad.set_turbo_period(turbo_period);
+        }
+    }
+
+    if args.iter().any(|arg| arg == "--no-sprite-limit") {
+        cpu.bus.ppu.sprite_limit = false;
+    }
+
+    // VS System games take coins with O, and Shift+O for the second slot. --dip-switches
+    // sets the switches, which are all off by default
+    if system == System::VsSystem {
+        let dip_switches = flag_value("--dip-switches").map_or(0, |switches| {
+            vs::parse_dip_switches(&switches).unwrap_or_else(|e| fatal(&e))
+        });
+        cpu.bus.attach_vs_system(dip_switches);
+    }
+
+    for pair in args.windows(2).filter(|pair| pair[0] == "--cheat") {
+        match cpu.bus.cheats.add(&pair[1]) {
+            Ok(cheat) => println!(
+                "Cheat {}: ${:04x} = {:02x}",
+                cheat.code, cheat.addr, cheat.value
+            ),
+            Err(e) => eprintln!("{}", e),
+        }
+    }
+
+    // games that save to battery backed RAM get it back from the .sav file, all of it
+    // whatever size the board has
+    let battery = battery && {
+        let has_ram = cpu.bus.mapper.borrow().prg_ram().is_some();
+        if !has_ram {
+            eprintln!("Battery saves aren't supported for this board yet");
+        }
+        has_ram
+    };
+    if battery {
+        if let Err(e) = load_battery(&cpu.bus.mapper, &savestate::battery_path(&state_game)) {
+            eprintln!("{}", e);
+        }
+    }
+
+    if resume {
+        let autosave = savestate::autosave_path(&state_game);
+        if let Err(e) = load_state_file(&mut cpu, &autosave, game_crc) {
+            eprintln!("{}", e);
+        }
+    }
+
+    // --run-ahead 1 or 2 runs that many frames ahead, so the game reacts to the buttons
+    // sooner. Netplay has its own way of hiding lag
+    let mut run_ahead = flag_value("--run-ahead").map(|frames| match frames.parse() {
+        Ok(frames @ 1..=2) if netplay.is_none() => RunAhead::new(frames),
+        Ok(1..=2) => fatal("Run-ahead can't be used with netplay"),
+        _ => fatal(&format!(
+            "Bad run-ahead {}, it can be 1 or 2 frames",
+            frames
+        )),
+    });
+
+    // keep 10 seconds of history, one snapshot every 2 frames
+    let mut rewind = Rewind::new(10, 2);
+    let mut last_frame = 0;
+    let mut netplay_frame = 0;
+
+    let frame_on_event = &mut on_event;
+    let state = cpu.run_with_callback(move |cpu| {
+        while let Some(event) = cpu.bus.poll_event() {
+            frame_on_event(cpu, event);
+        }
+        cpu.bus.scanline_break = scanline_step.get();
+        let new_frame = cpu.bus.frame_count() != last_frame;
+        if new_frame {
+            last_frame = cpu.bus.frame_count();
+            let held = |cpu: &mut CPU, player| {
+                cpu.bus
+                    .joypad_mut(player)
+                    .map_or(JoypadButton::empty(), |joypad| joypad.buttons())
+            };
+            input_display.set([held(cpu, 1), held(cpu, 2)]);
+            // the window can be closed at any time, so the access and interrupt logs are
+            // written out as each frame ends
+            if let Some(log) = cpu.bus.access_log.as_mut() {
+                if let Err(e) = log.dump(&mut std::io::sink()) {
+                    eprintln!("Can't write the access log: {}", e);
+                    cpu.bus.access_log = None;
+                }
+            }
+            if let Some(log) = cpu.bus.interrupt_log.as_mut() {
+                if let Err(e) = log.dump(&mut std::io::sink()) {
+                    eprintln!("Can't write the interrupt log: {}", e);
+                    cpu.bus.interrupt_log = None;
+                }
+            }
+            // going back on one side only would leave the two games out of step
+            if rewinding.load(Ordering::Relaxed) && netplay.is_none() {
+                rewind.step_back(cpu);
+                if let Some(run_ahead) = run_ahead.as_mut() {
+                    run_ahead.clear();
+                }
+            } else {
+                rewind.on_frame(cpu);
+            }
+        }
+
+        for command in commands.borrow_mut().drain(..) {
+            // anything besides the buttons changes the console under the frames run ahead
+            if !matches!(
+                command,
+                Command::SetButton { .. } | Command::UpdateZapper(_)
+            ) {
+                if let Some(run_ahead) = run_ahead.as_mut() {
+                    run_ahead.clear();
+                }
+            }
+            match command {
+                Command::ToggleCheats => {
+                    let enabled = !cpu.bus.cheats.any_enabled();
+                    cpu.bus.cheats.set_all_enabled(enabled);
+                    osd.lock()
+                        .unwrap()
+                        .show(if enabled { "Cheats on" } else { "Cheats off" });
+                }
+                Command::ToggleMute(channel) => {
+                    let muted = !cpu.bus.apu.is_muted(channel);
+                    cpu.bus.apu.set_muted(channel, muted);
+                    let state = if muted { "muted" } else { "on" };
+                    osd.lock()
+                        .unwrap()
+                        .show(&format!("{:?} {}", channel, state));
+                }
+                Command::Solo(channel) => {
+                    cpu.bus.apu.solo(channel);
+                    osd.lock().unwrap().show(&format!("{:?} solo", channel));
+                }
+                Command::UpdateZapper(state) => {
+                    if let Some(zapper) = cpu.bus.zapper.as_mut() {
+                        zapper.update(state);
+                    }
+                }
+                Command::SwitchDiskSide => {
+                    if let Some(fds) = &disk_drive {
+                        let side = fds.borrow_mut().switch_side();
+                        let message = format!("Inserted {}", fds::side_name(side));
+                        println!("{}", message);
+                        osd.lock().unwrap().show(&message);
+                    }
+                }
+                Command::ChangeTrack(delta) => {
+                    if let Some(player) = &nsf_player {
+                        let songs = player.borrow().nsf().songs as isize;
+                        let song = (player.borrow().song() as isize + delta).rem_euclid(songs);
+                        player.borrow_mut().select_song(song as u8);
+                        cpu.reset();
+                        let title = player.borrow().track_title();
+                        println!("Playing {}", title);
+                        *window_title.lock().unwrap() = Some(title);
+                    }
+                }
+                // the other side of a netplay game would carry on without us
+                Command::Reset | Command::PowerCycle if netplay.is_some() => {
+                    osd.lock().unwrap().show("Can't reset during netplay");
+                }
+                Command::Reset => {
+                    cpu.soft_reset();
+                    osd.lock().unwrap().show("Reset");
+                }
+                Command::PowerCycle => {
+                    cpu.power_cycle();
+                    osd.lock().unwrap().show("Power cycled");
+                }
+                Command::InsertCoin(_) if netplay.is_some() => {
+                    osd.lock()
+                        .unwrap()
+                        .show("Can't insert coins during netplay");
+                }
+                Command::InsertCoin(slot) => {
+                    if let Some(vs) = &mut cpu.bus.vs {
+                        vs.insert_coin(slot);
+                        osd.lock().unwrap().show(&format!("Coin {}", slot + 1));
+                    }
+                }
+                Command::SaveState(slot) => {
+                    let path = savestate::slot_path(&state_game, slot);
+                    match std::fs::write(&path, savestate::save_file(cpu, game_crc)) {
+                        Ok(()) => osd.lock().unwrap().show(&format!("Saved slot {}", slot)),
+                        Err(e) => eprintln!("Can't save {}: {}", path.display(), e),
+                    }
+                }
+                // the other side would be left in a different game
+                Command::LoadState(_) if netplay.is_some() => {
+                    osd.lock().unwrap().show("Can't load states during netplay");
+                }
+                Command::LoadState(slot) => {
+                    let path = savestate::slot_path(&state_game, slot);
+                    match load_state_file(cpu, &path, game_crc) {
+                        Ok(()) => osd.lock().unwrap().show(&format!("Loaded slot {}", slot)),
+                        Err(e) => {
+                            eprintln!("{}", e);
+                            osd.lock()
+                                .unwrap()
+                                .show(&format!("Can't load slot {}", slot));
+                        }
+                    }
+                }
+                Command::Quit => {
+                    let path = savestate::autosave_path(&state_game);
+                    if let Err(e) = std::fs::write(&path, savestate::save_file(cpu, game_crc)) {
+                        eprintln!("Can't save {}: {}", path.display(), e);
+                    }
+                    if battery {
+                        let path = savestate::battery_path(&state_game);
+                        if let Err(e) = save_battery(&cpu.bus.mapper, &path) {
+                            eprintln!("{}", e);
+                        }
+                    }
+                    std::process::exit(0)
+                }
+                Command::Poke(edit) => edit.space.poke(&mut cpu.bus, edit.addr, edit.value),
+                Command::Freeze(code) => {
+                    if let Ok(cheat) = cpu.bus.cheats.add(&code) {
+                        let message = format!("Frozen ${:04X} at {:02X}", cheat.addr, cheat.value);
+                        osd.lock().unwrap().show(&message);
+                    }
+                }
+                Command::SetButton {
+                    player,
+                    button,
+                    turbo,
+                    pressed,
+                } => {
+                    if let Some(joypad) = cpu.bus.joypad_mut(player) {
+                        if turbo {
+                            joypad.set_turbo_pressed(button, pressed);
+                        } else {
+                            joypad.set_button_pressed_status(button, pressed);
+                        }
+                    }
+                }
+            }
+        }
+
+        // after the commands, so the other side's buttons aren't overridden by a gamepad here
+        if cpu.bus.frame_count() >= netplay_frame {
+            if let Some(session) = netplay.as_mut() {
+                // turbo is worked out here and sent as plain presses
+                let mut held = keyboard.get();
+                if joypad::turbo_down(netplay_frame, turbo_period) {
+                    held |= turbo_keys.get();
+                }
+                match session.exchange(netplay_frame, held) {
+                    Ok(buttons) => {
+                        for (player, &held) in buttons.iter().enumerate() {
+                            let joypad = cpu.bus.joypad_mut(player + 1).unwrap();
+                            joypad.set_button_pressed_status(JoypadButton::all(), false);
+                            joypad.set_turbo_pressed(JoypadButton::all(), false);
+                            joypad.set_button_pressed_status(held, true);
+                        }
+                    }
+                    Err(e) => {
+                        eprintln!("{}", e);
+                        osd.lock().unwrap().show("Netplay stopped");
+                        netplay = None;
+                    }
+                }
+            }
+            netplay_frame += 1;
+        }
+
+        // the frames run ahead start from here, so last_frame is caught up after them
+        if let (true, Some(run_ahead)) = (new_frame, run_ahead.as_mut()) {
+            run_ahead.on_frame(cpu);
+            last_frame = cpu.bus.frame_count();
+        }
+
+        if let Some(profiler) = &profiler {
+            profiler.borrow_mut().on_instruction(cpu);
+        }
+        if let Some(Err(e)) = script.as_mut().map(|script| script.on_instruction(cpu)) {
+            eprintln!("{}", e);
+            osd.lock().unwrap().show("Script stopped");
+            script = None;
+        }
+        let stopped = debugger.borrow_mut().on_instruction(cpu);
+        if let Some(tracer) = &tracer {
+            let mut tracer = tracer.borrow_mut();
+            if stopped && trace_from_break {
+                tracer.start();
+            }
+            if let Err(e) = tracer.on_instruction(cpu) {
+                eprintln!("Can't write the trace: {}", e);
+            }
+        }
+    });
+
+    let error = match state {
+        CpuState::Error(e) => Some(e),
+        CpuState::Jammed => Some(NesError::Jammed {
+            opcode: cpu.mem_peek(cpu.program_counter),
+            addr: cpu.program_counter,
+        }),
+        _ => None,
+    };
+    if let Some(error) = error {
+        let message = error.to_string();
+        eprintln!("{}", message);
+        if let Some(tracer) = &jam_tracer {
+            let mut tracer = tracer.borrow_mut();
+            if !tracer.recent().is_empty() {
+                eprintln!("The last instructions before that were:");
+            }
+            if let Err(e) = tracer.dump(&mut std::io::stderr()) {
+                eprintln!("Can't write the trace: {}", e);
+            }
+        }
+        eprintln!("Backtrace:");
+        for line in cpu.call_stack.backtrace(&cpu, cpu.program_counter) {
+            eprintln!("{}", line);
+        }
+        *error_window_title.lock().unwrap() = Some(format!("Rust NES - {}", message));
+        stopped.set(true);
+
+        // like the real thing, the PPU and APU carry on without the CPU
+        loop {
+            cpu.step_cycle();
+            while let Some(event) = cpu.bus.poll_event() {
+                on_event(&mut cpu, event);
+            }
+        }
+    }
 }
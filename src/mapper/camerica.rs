@@ -0,0 +1,183 @@
+use crate::mapper::Mapper;
+use crate::rom::{Mirroring, Rom};
+use crate::savestate::{Savestate, StateReader, StateWriter};
+
+const PRG_BANK: usize = 0x4000;
+
+/// Mappers 71 (Camerica/Codemasters) and 232 (the Quattro multicarts). A 16 KiB PRG bank
+/// is switched in at $8000, and CHR is 8 KiB of RAM.
+///
+/// Mapper 71 selects the bank with writes to $C000-$FFFF and keeps the last bank at
+/// $C000. Fire Hawk also picks a one-screen nametable with bit 4 of writes to
+/// $9000-$9FFF.
+///
+/// Mapper 232 splits the ROM into 64 KiB blocks, picked with bits 3-4 of writes to
+/// $8000-$BFFF, and selects a bank inside the block with writes to $C000-$FFFF. The
+/// block's last bank sits at $C000.
+pub struct Camerica {
+    quattro: bool,
+    prg_rom: Vec<u8>,
+    chr: Vec<u8>,
+    chr_ram: bool,
+    block: u8,
+    prg_bank: u8,
+    mirroring: Mirroring,
+}
+
+impl Camerica {
+    pub fn new(rom: Rom) -> Self {
+        Camerica {
+            quattro: rom.mapper == 232,
+            prg_rom: rom.prg_rom,
+            chr: rom.chr_rom,
+            chr_ram: rom.chr_ram,
+            block: 0,
+            prg_bank: 0,
+            mirroring: rom.screen_mirroring,
+        }
+    }
+
+    fn prg_offset(&self, addr: u16) -> usize {
+        let addr = (addr - 0x8000) as usize;
+        let bank = match (self.quattro, addr / PRG_BANK) {
+            (false, 0) => self.prg_bank as usize,
+            (false, _) => self.prg_rom.len() / PRG_BANK - 1,
+            (true, 0) => self.block as usize * 4 + self.prg_bank as usize,
+            (true, _) => self.block as usize * 4 + 3,
+        };
+        (bank * PRG_BANK + addr % PRG_BANK) % self.prg_rom.len()
+    }
+}
+
+impl Mapper for Camerica {
+    fn read_prg(&self, addr: u16) -> u8 {
+        match addr {
+            0x8000..=0xffff => self.prg_rom[self.prg_offset(addr)],
+            _ => 0,
+        }
+    }
+
+    fn write_prg(&mut self, addr: u16, data: u8) {
+        match (self.quattro, addr) {
+            (false, 0x9000..=0x9fff) => {
+                self.mirroring = if data & 0b0001_0000 == 0 {
+                    Mirroring::SingleScreenLower
+                } else {
+                    Mirroring::SingleScreenUpper
+                }
+            }
+            (false, 0xc000..=0xffff) => self.prg_bank = data & 0b1111,
+            (true, 0x8000..=0xbfff) => self.block = (data >> 3) & 0b11,
+            (true, 0xc000..=0xffff) => self.prg_bank = data & 0b11,
+            _ => {}
+        }
+    }
+
+    fn read_chr(&self, addr: u16) -> u8 {
+        self.chr[addr as usize]
+    }
+
+    fn write_chr(&mut self, addr: u16, data: u8) {
+        if self.chr_ram {
+            self.chr[addr as usize] = data;
+        }
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+
+    fn prg_bank(&self, addr: u16) -> usize {
+        self.prg_offset(addr) / PRG_BANK
+    }
+
+    fn prg_rom_offset(&self, addr: u16) -> Option<usize> {
+        match addr {
+            0x8000..=0xffff => Some(self.prg_offset(addr)),
+            _ => None,
+        }
+    }
+
+    fn power_cycle(&mut self) {
+        self.block = 0;
+        self.prg_bank = 0;
+    }
+}
+
+impl Savestate for Camerica {
+    fn save_state(&self, state: &mut StateWriter) {
+        if self.chr_ram {
+            state.write_bytes(&self.chr);
+        }
+        state.write_u8(self.block);
+        state.write_u8(self.prg_bank);
+        state.write_u8(match self.mirroring {
+            Mirroring::Vertical => 0,
+            Mirroring::Horizontal => 1,
+            Mirroring::SingleScreenLower => 2,
+            Mirroring::SingleScreenUpper => 3,
+            Mirroring::FourScreen => 4,
+        });
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), String> {
+        if self.chr_ram {
+            state.read_bytes(&mut self.chr)?;
+        }
+        self.block = state.read_u8()? & 0b11;
+        self.prg_bank = state.read_u8()?;
+        self.mirroring = match state.read_u8()? {
+            0 => Mirroring::Vertical,
+            1 => Mirroring::Horizontal,
+            2 => Mirroring::SingleScreenLower,
+            3 => Mirroring::SingleScreenUpper,
+            _ => Mirroring::FourScreen,
+        };
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::rom::System;
+
+    fn test_board(mapper: u8) -> Camerica {
+        let prg_rom = (0..16)
+            .flat_map(|bank| vec![bank as u8; PRG_BANK])
+            .collect();
+        Camerica::new(Rom {
+            prg_rom,
+            chr_rom: vec![0; 0x2000],
+            chr_ram: true,
+            trainer: None,
+            prg_ram_size: 0x2000,
+            battery: false,
+            mapper,
+            screen_mirroring: Mirroring::Horizontal,
+            system: System::Nes,
+        })
+    }
+
+    #[test]
+    fn test_camerica() {
+        let mut board = test_board(71);
+        assert_eq!(board.read_prg(0xc000), 15);
+        board.write_prg(0xc000, 5);
+        assert_eq!(board.read_prg(0x8000), 5);
+        assert_eq!(board.read_prg(0xffff), 15);
+        assert_eq!(board.mirroring(), Mirroring::Horizontal);
+        board.write_prg(0x9000, 0x10);
+        assert_eq!(board.mirroring(), Mirroring::SingleScreenUpper);
+    }
+
+    #[test]
+    fn test_quattro() {
+        let mut board = test_board(232);
+        assert_eq!(board.read_prg(0xc000), 3);
+        board.write_prg(0x8000, 0b1_0000);
+        board.write_prg(0xc000, 1);
+        assert_eq!(board.read_prg(0x8000), 9);
+        assert_eq!(board.read_prg(0xc000), 11);
+    }
+}
@@ -0,0 +1,536 @@
+use crate::apu::fds::FdsAudio;
+use crate::apu::filter::CPU_CLOCK;
+use crate::fds::FdsImage;
+use crate::mapper::Mapper;
+use crate::rom::Mirroring;
+use crate::savestate::{Savestate, StateReader, StateWriter};
+
+pub const BIOS_SIZE: usize = 0x2000;
+
+// The drive moves one byte under the head roughly every 150 CPU cycles
+const BYTE_CYCLES: u32 = 150;
+const MOTOR_SPINUP_CYCLES: u32 = 50_000;
+// How long the drive stays empty while switching sides, so the BIOS notices the change
+const SIDE_SWAP_CYCLES: u32 = CPU_CLOCK as u32;
+
+/// The Famicom Disk System RAM adapter: 32 KiB of PRG RAM, 8 KiB of CHR RAM, the BIOS,
+/// a timer IRQ, the disk drive interface and the wavetable sound channel.
+pub struct Fds {
+    bios: Vec<u8>,
+    prg_ram: Vec<u8>,
+    chr_ram: Vec<u8>,
+    mirroring: Mirroring,
+    audio: FdsAudio,
+
+    disk_regs_enabled: bool,
+    sound_regs_enabled: bool,
+
+    irq_reload: u16,
+    irq_counter: u16,
+    irq_enabled: bool,
+    irq_repeat: bool,
+    timer_irq: bool,
+
+    sides: Vec<Vec<u8>>,
+    side: Option<usize>,
+    next_side: Option<usize>,
+    swap_delay: u32,
+
+    motor_on: bool,
+    reset_transfer: bool,
+    read_mode: bool,
+    crc_control: bool,
+    disk_ready: bool,
+    disk_irq_enabled: bool,
+    disk_irq: bool,
+    end_of_head: bool,
+    scanning: bool,
+    gap_ended: bool,
+    transfer_complete: bool,
+    position: usize,
+    delay: u32,
+    read_data: u8,
+    write_data: u8,
+}
+
+impl Fds {
+    pub fn new(image: FdsImage, bios: Vec<u8>) -> Result<Self, String> {
+        if bios.len() != BIOS_SIZE {
+            return Err(format!(
+                "The FDS BIOS should be {} bytes, not {}",
+                BIOS_SIZE,
+                bios.len()
+            ));
+        }
+
+        Ok(Fds {
+            bios,
+            prg_ram: vec![0; 0x8000],
+            chr_ram: vec![0; 0x2000],
+            mirroring: Mirroring::Horizontal,
+            audio: FdsAudio::new(),
+            disk_regs_enabled: true,
+            sound_regs_enabled: true,
+            irq_reload: 0,
+            irq_counter: 0,
+            irq_enabled: false,
+            irq_repeat: false,
+            timer_irq: false,
+            side: if image.sides.is_empty() {
+                None
+            } else {
+                Some(0)
+            },
+            sides: image.sides,
+            next_side: None,
+            swap_delay: 0,
+            motor_on: false,
+            reset_transfer: false,
+            read_mode: true,
+            crc_control: false,
+            disk_ready: false,
+            disk_irq_enabled: false,
+            disk_irq: false,
+            end_of_head: true,
+            scanning: false,
+            gap_ended: false,
+            transfer_complete: false,
+            position: 0,
+            delay: 0,
+            read_data: 0,
+            write_data: 0,
+        })
+    }
+
+    pub fn side_count(&self) -> usize {
+        self.sides.len()
+    }
+
+    /// Ejects the disk and, after a moment, inserts the next side. Returns that side.
+    pub fn switch_side(&mut self) -> usize {
+        let next = match self.side.or(self.next_side) {
+            Some(side) => (side + 1) % self.sides.len(),
+            None => 0,
+        };
+        self.side = None;
+        self.next_side = Some(next);
+        self.swap_delay = SIDE_SWAP_CYCLES;
+        next
+    }
+
+    fn clock_timer(&mut self) {
+        if !self.irq_enabled || !self.disk_regs_enabled {
+            return;
+        }
+        if self.irq_counter == 0 {
+            self.timer_irq = true;
+            self.irq_counter = self.irq_reload;
+            if !self.irq_repeat {
+                self.irq_enabled = false;
+            }
+        } else {
+            self.irq_counter -= 1;
+        }
+    }
+
+    fn clock_drive(&mut self) {
+        if self.next_side.is_some() {
+            self.swap_delay -= 1;
+            if self.swap_delay == 0 {
+                self.side = self.next_side.take();
+            }
+        }
+
+        let side = match self.side {
+            Some(side) if self.motor_on => side,
+            _ => {
+                self.end_of_head = true;
+                self.scanning = false;
+                return;
+            }
+        };
+        if self.reset_transfer && !self.scanning {
+            return;
+        }
+        if self.end_of_head {
+            // back to the start of the disk, waiting for the motor to get up to speed
+            self.end_of_head = false;
+            self.position = 0;
+            self.gap_ended = false;
+            self.delay = MOTOR_SPINUP_CYCLES;
+            return;
+        }
+        if self.delay > 0 {
+            self.delay -= 1;
+            return;
+        }
+
+        self.scanning = true;
+        let mut needs_irq = self.disk_irq_enabled;
+        if self.read_mode {
+            let data = self.sides[side][self.position];
+            if !self.disk_ready {
+                self.gap_ended = false;
+            } else if data != 0 && !self.gap_ended {
+                // the start mark of a block, which isn't handed to the CPU
+                self.gap_ended = true;
+                needs_irq = false;
+            }
+            if self.gap_ended {
+                self.transfer_complete = true;
+                self.read_data = data;
+                self.disk_irq |= needs_irq;
+            }
+        } else {
+            let mut data = 0;
+            if !self.crc_control {
+                self.transfer_complete = true;
+                data = self.write_data;
+                self.disk_irq |= needs_irq;
+            }
+            if !self.disk_ready {
+                data = 0;
+            }
+            self.sides[side][self.position] = data;
+            self.gap_ended = false;
+        }
+
+        self.position += 1;
+        if self.position >= self.sides[side].len() {
+            self.motor_on = false;
+            self.end_of_head = true;
+        } else {
+            self.delay = BYTE_CYCLES;
+        }
+    }
+
+    fn read_register(&mut self, addr: u16) -> Option<u8> {
+        let data = self.peek_register(addr)?;
+        match addr {
+            0x4030 => {
+                self.transfer_complete = false;
+                self.timer_irq = false;
+                self.disk_irq = false;
+            }
+            0x4031 => {
+                self.transfer_complete = false;
+                self.disk_irq = false;
+            }
+            _ => {}
+        }
+        Some(data)
+    }
+
+    fn peek_register(&self, addr: u16) -> Option<u8> {
+        let inserted = self.side.is_some();
+        match addr {
+            0x4030..=0x4033 if self.disk_regs_enabled => Some(match addr {
+                0x4030 => (self.timer_irq as u8) | (self.transfer_complete as u8) << 1,
+                0x4031 => self.read_data,
+                0x4032 => {
+                    0b0100_0000
+                        | (!inserted as u8)
+                        | ((!inserted || !self.scanning) as u8) << 1
+                        | (!inserted as u8) << 2
+                }
+                // the battery is fine
+                _ => 0b1000_0000,
+            }),
+            0x4040..=0x407f | 0x4090 | 0x4092 => Some(self.audio.read(addr)),
+            _ => None,
+        }
+    }
+
+    fn write_register(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x4020 => self.irq_reload = (self.irq_reload & 0xff00) | data as u16,
+            0x4021 => self.irq_reload = (self.irq_reload & 0x00ff) | (data as u16) << 8,
+            0x4022 => {
+                self.irq_repeat = data & 0b01 != 0;
+                self.irq_enabled = data & 0b10 != 0 && self.disk_regs_enabled;
+                if self.irq_enabled {
+                    self.irq_counter = self.irq_reload;
+                } else {
+                    self.timer_irq = false;
+                }
+            }
+            0x4023 => {
+                self.disk_regs_enabled = data & 0b01 != 0;
+                self.sound_regs_enabled = data & 0b10 != 0;
+                if !self.disk_regs_enabled {
+                    self.irq_enabled = false;
+                    self.timer_irq = false;
+                    self.disk_irq = false;
+                }
+            }
+            0x4024..=0x4026 if !self.disk_regs_enabled => {}
+            0x4024 => {
+                self.write_data = data;
+                self.transfer_complete = false;
+                self.disk_irq = false;
+            }
+            0x4025 => {
+                self.motor_on = data & 0b0000_0001 != 0;
+                self.reset_transfer = data & 0b0000_0010 != 0;
+                self.read_mode = data & 0b0000_0100 != 0;
+                self.mirroring = if data & 0b0000_1000 != 0 {
+                    Mirroring::Horizontal
+                } else {
+                    Mirroring::Vertical
+                };
+                self.crc_control = data & 0b0001_0000 != 0;
+                self.disk_ready = data & 0b0100_0000 != 0;
+                self.disk_irq_enabled = data & 0b1000_0000 != 0;
+                self.disk_irq = false;
+            }
+            0x4040..=0x408a if self.sound_regs_enabled => self.audio.write(addr, data),
+            _ => {}
+        }
+    }
+}
+
+impl Mapper for Fds {
+    fn read_prg(&self, addr: u16) -> u8 {
+        match addr {
+            0x6000..=0xdfff => self.prg_ram[(addr - 0x6000) as usize],
+            0xe000..=0xffff => self.bios[(addr - 0xe000) as usize],
+            _ => 0,
+        }
+    }
+
+    fn write_prg(&mut self, addr: u16, data: u8) {
+        if let 0x6000..=0xdfff = addr {
+            self.prg_ram[(addr - 0x6000) as usize] = data;
+        }
+    }
+
+    fn read_chr(&self, addr: u16) -> u8 {
+        self.chr_ram[addr as usize]
+    }
+
+    fn write_chr(&mut self, addr: u16, data: u8) {
+        self.chr_ram[addr as usize] = data;
+    }
+
+    fn read_expansion(&mut self, addr: u16) -> Option<u8> {
+        self.read_register(addr)
+    }
+
+    fn peek_expansion(&self, addr: u16) -> Option<u8> {
+        self.peek_register(addr)
+    }
+
+    fn write_expansion(&mut self, addr: u16, data: u8) {
+        self.write_register(addr, data);
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+
+    fn irq_pending(&self) -> bool {
+        self.timer_irq || self.disk_irq
+    }
+
+    fn clock(&mut self) {
+        self.clock_timer();
+        self.clock_drive();
+        self.audio.clock();
+    }
+
+    fn audio_output(&self) -> f32 {
+        if self.sound_regs_enabled {
+            self.audio.output()
+        } else {
+            0.0
+        }
+    }
+
+    // the disk stays in the drive, but the head goes back to the start
+    fn power_cycle(&mut self) {
+        self.mirroring = Mirroring::Horizontal;
+        self.audio = FdsAudio::new();
+        self.disk_regs_enabled = true;
+        self.sound_regs_enabled = true;
+        self.irq_reload = 0;
+        self.irq_counter = 0;
+        self.irq_enabled = false;
+        self.irq_repeat = false;
+        self.timer_irq = false;
+        self.motor_on = false;
+        self.reset_transfer = false;
+        self.read_mode = true;
+        self.crc_control = false;
+        self.disk_ready = false;
+        self.disk_irq_enabled = false;
+        self.disk_irq = false;
+        self.end_of_head = true;
+        self.scanning = false;
+        self.gap_ended = false;
+        self.transfer_complete = false;
+        self.position = 0;
+        self.delay = 0;
+        self.read_data = 0;
+        self.write_data = 0;
+    }
+}
+
+impl Savestate for Fds {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_bytes(&self.prg_ram);
+        state.write_bytes(&self.chr_ram);
+        state.write_bool(self.mirroring == Mirroring::Horizontal);
+        self.audio.save_state(state);
+        for flag in [
+            self.disk_regs_enabled,
+            self.sound_regs_enabled,
+            self.irq_enabled,
+            self.irq_repeat,
+            self.timer_irq,
+            self.motor_on,
+            self.reset_transfer,
+            self.read_mode,
+            self.crc_control,
+            self.disk_ready,
+            self.disk_irq_enabled,
+            self.disk_irq,
+            self.end_of_head,
+            self.scanning,
+            self.gap_ended,
+            self.transfer_complete,
+        ]
+        .iter()
+        {
+            state.write_bool(*flag);
+        }
+        state.write_u16(self.irq_reload);
+        state.write_u16(self.irq_counter);
+        // sides are stored off by one so that 0 means no disk
+        state.write_usize(self.side.map_or(0, |side| side + 1));
+        state.write_usize(self.next_side.map_or(0, |side| side + 1));
+        state.write_usize(self.swap_delay as usize);
+        state.write_usize(self.position);
+        state.write_usize(self.delay as usize);
+        state.write_u8(self.read_data);
+        state.write_u8(self.write_data);
+        // games save to the disk itself
+        for side in self.sides.iter() {
+            state.write_vec(side);
+        }
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), String> {
+        state.read_bytes(&mut self.prg_ram)?;
+        state.read_bytes(&mut self.chr_ram)?;
+        self.mirroring = if state.read_bool()? {
+            Mirroring::Horizontal
+        } else {
+            Mirroring::Vertical
+        };
+        self.audio.load_state(state)?;
+        for flag in [
+            &mut self.disk_regs_enabled,
+            &mut self.sound_regs_enabled,
+            &mut self.irq_enabled,
+            &mut self.irq_repeat,
+            &mut self.timer_irq,
+            &mut self.motor_on,
+            &mut self.reset_transfer,
+            &mut self.read_mode,
+            &mut self.crc_control,
+            &mut self.disk_ready,
+            &mut self.disk_irq_enabled,
+            &mut self.disk_irq,
+            &mut self.end_of_head,
+            &mut self.scanning,
+            &mut self.gap_ended,
+            &mut self.transfer_complete,
+        ]
+        .iter_mut()
+        {
+            **flag = state.read_bool()?;
+        }
+        self.irq_reload = state.read_u16()?;
+        self.irq_counter = state.read_u16()?;
+        self.side = state.read_usize()?.checked_sub(1);
+        self.next_side = state.read_usize()?.checked_sub(1);
+        self.swap_delay = state.read_usize()? as u32;
+        self.position = state.read_usize()?;
+        self.delay = state.read_usize()? as u32;
+        self.read_data = state.read_u8()?;
+        self.write_data = state.read_u8()?;
+        for side in self.sides.iter_mut() {
+            *side = state.read_vec()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn test_fds() -> Fds {
+        let mut side = vec![0; 100];
+        side[10] = 0x80;
+        side[11] = 0x01;
+        side[12] = 0x2a;
+        Fds::new(
+            FdsImage {
+                sides: vec![side.clone(), side],
+            },
+            vec![0; BIOS_SIZE],
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_timer_irq() {
+        let mut fds = test_fds();
+        fds.write_expansion(0x4020, 2);
+        fds.write_expansion(0x4021, 0);
+        fds.write_expansion(0x4022, 0b10);
+        fds.clock();
+        fds.clock();
+        assert!(!fds.irq_pending());
+        fds.clock();
+        assert!(fds.irq_pending());
+
+        // reading $4030 acknowledges it, and without repeat it doesn't fire again
+        assert_eq!(fds.read_expansion(0x4030).unwrap() & 1, 1);
+        assert!(!fds.irq_pending());
+        for _ in 0..10 {
+            fds.clock();
+        }
+        assert!(!fds.irq_pending());
+    }
+
+    #[test]
+    fn test_reads_blocks_after_the_gap() {
+        let mut fds = test_fds();
+        // motor on, read mode, ready, IRQ on every byte
+        fds.write_expansion(0x4025, 0b1100_0101);
+
+        let mut bytes = Vec::new();
+        for _ in 0..MOTOR_SPINUP_CYCLES + 20 * (BYTE_CYCLES + 1) {
+            fds.clock();
+            if fds.irq_pending() {
+                bytes.push(fds.read_expansion(0x4031).unwrap());
+            }
+        }
+        assert_eq!(&bytes[..2], &[0x01, 0x2a]);
+    }
+
+    #[test]
+    fn test_switch_side() {
+        let mut fds = test_fds();
+        assert_eq!(fds.peek_expansion(0x4032).unwrap() & 1, 0);
+        assert_eq!(fds.switch_side(), 1);
+        assert_eq!(fds.peek_expansion(0x4032).unwrap() & 1, 1);
+        for _ in 0..SIDE_SWAP_CYCLES {
+            fds.clock();
+        }
+        assert_eq!(fds.side, Some(1));
+        assert_eq!(fds.switch_side(), 0);
+    }
+}
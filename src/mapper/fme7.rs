@@ -0,0 +1,293 @@
+use crate::apu::sunsoft5b::Sunsoft5bAudio;
+use crate::mapper::prg_ram::PrgRam;
+use crate::mapper::Mapper;
+use crate::rom::{Mirroring, Rom};
+use crate::savestate::{Savestate, StateReader, StateWriter};
+
+const PRG_BANK: usize = 0x2000;
+const CHR_BANK: usize = 0x400;
+
+const MIRRORING: [Mirroring; 4] = [
+    Mirroring::Vertical,
+    Mirroring::Horizontal,
+    Mirroring::SingleScreenLower,
+    Mirroring::SingleScreenUpper,
+];
+
+/// Mapper 69: Sunsoft's FME-7, and the 5B that adds a sound chip to it. A command
+/// register at $8000 picks which of eight 1 KiB CHR banks, four 8 KiB PRG banks, the
+/// mirroring or the IRQ counter the next write to $A000 sets. $6000 can show PRG RAM or
+/// ROM, and the last PRG bank is fixed at $E000.
+pub struct Fme7 {
+    prg_rom: Vec<u8>,
+    prg_ram: PrgRam,
+    chr: Vec<u8>,
+    chr_ram: bool,
+    command: u8,
+    chr_banks: [u8; 8],
+    // $6000, $8000, $A000 and $C000
+    prg_banks: [u8; 4],
+    ram_selected: bool,
+    mirroring: Mirroring,
+    irq_enabled: bool,
+    counter_enabled: bool,
+    counter: u16,
+    irq: bool,
+    audio: Sunsoft5bAudio,
+}
+
+impl Fme7 {
+    pub fn new(rom: Rom) -> Self {
+        Fme7 {
+            prg_rom: rom.prg_rom,
+            prg_ram: PrgRam::new(rom.prg_ram_size),
+            chr: rom.chr_rom,
+            chr_ram: rom.chr_ram,
+            command: 0,
+            chr_banks: [0; 8],
+            prg_banks: [0; 4],
+            ram_selected: false,
+            mirroring: rom.screen_mirroring,
+            irq_enabled: false,
+            counter_enabled: false,
+            counter: 0,
+            irq: false,
+            audio: Sunsoft5bAudio::default(),
+        }
+    }
+
+    fn prg_offset(&self, addr: u16) -> usize {
+        let bank = match addr {
+            0x6000..=0xdfff => self.prg_banks[(addr as usize - 0x6000) / PRG_BANK] as usize,
+            _ => self.prg_rom.len() / PRG_BANK - 1,
+        };
+        (bank * PRG_BANK + addr as usize % PRG_BANK) % self.prg_rom.len()
+    }
+
+    fn chr_offset(&self, addr: u16) -> usize {
+        let bank = self.chr_banks[addr as usize / CHR_BANK] as usize;
+        (bank * CHR_BANK + addr as usize % CHR_BANK) % self.chr.len()
+    }
+
+    fn write_parameter(&mut self, data: u8) {
+        match self.command {
+            command @ 0..=7 => self.chr_banks[command as usize] = data,
+            8 => {
+                self.prg_banks[0] = data & 0b0011_1111;
+                self.ram_selected = data & 0b0100_0000 != 0;
+                self.prg_ram.set_bank(self.prg_banks[0] as usize);
+                self.prg_ram.set_write_protected(data & 0b1000_0000 == 0);
+            }
+            command @ 9..=0xb => self.prg_banks[command as usize - 8] = data & 0b0011_1111,
+            0xc => self.mirroring = MIRRORING[data as usize & 0b11],
+            0xd => {
+                self.irq_enabled = data & 0b0000_0001 != 0;
+                self.counter_enabled = data & 0b1000_0000 != 0;
+                self.irq = false;
+            }
+            0xe => self.counter = (self.counter & 0xff00) | data as u16,
+            _ => self.counter = (self.counter & 0x00ff) | (data as u16) << 8,
+        }
+    }
+}
+
+impl Mapper for Fme7 {
+    fn read_prg(&self, addr: u16) -> u8 {
+        match addr {
+            0x6000..=0x7fff if self.ram_selected => self.prg_ram.read(addr),
+            0x6000..=0xffff => self.prg_rom[self.prg_offset(addr)],
+            _ => 0,
+        }
+    }
+
+    fn write_prg(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x6000..=0x7fff if self.ram_selected => self.prg_ram.write(addr, data),
+            0x8000..=0x9fff => self.command = data & 0b1111,
+            0xa000..=0xbfff => self.write_parameter(data),
+            0xc000..=0xdfff => self.audio.write_address(data),
+            0xe000..=0xffff => self.audio.write_data(data),
+            _ => {}
+        }
+    }
+
+    fn read_chr(&self, addr: u16) -> u8 {
+        self.chr[self.chr_offset(addr)]
+    }
+
+    fn write_chr(&mut self, addr: u16, data: u8) {
+        if self.chr_ram {
+            let offset = self.chr_offset(addr);
+            self.chr[offset] = data;
+        }
+    }
+
+    fn chr_bank_key(&self) -> usize {
+        self.chr_banks
+            .iter()
+            .fold(0, |key: usize, &bank| key.rotate_left(8) ^ bank as usize)
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+
+    fn prg_ram(&self) -> Option<&PrgRam> {
+        Some(&self.prg_ram)
+    }
+
+    fn prg_ram_mut(&mut self) -> Option<&mut PrgRam> {
+        Some(&mut self.prg_ram)
+    }
+
+    fn prg_bank(&self, addr: u16) -> usize {
+        self.prg_offset(addr) / PRG_BANK
+    }
+
+    fn prg_rom_offset(&self, addr: u16) -> Option<usize> {
+        match addr {
+            0x6000..=0x7fff if self.ram_selected => None,
+            0x6000..=0xffff => Some(self.prg_offset(addr)),
+            _ => None,
+        }
+    }
+
+    fn irq_pending(&self) -> bool {
+        self.irq
+    }
+
+    fn clock(&mut self) {
+        if self.counter_enabled {
+            self.counter = self.counter.wrapping_sub(1);
+            if self.counter == 0xffff && self.irq_enabled {
+                self.irq = true;
+            }
+        }
+        self.audio.clock();
+    }
+
+    fn power_cycle(&mut self) {
+        self.command = 0;
+        self.chr_banks = [0; 8];
+        self.prg_banks = [0; 4];
+        self.ram_selected = false;
+        self.irq_enabled = false;
+        self.counter_enabled = false;
+        self.counter = 0;
+        self.irq = false;
+        self.audio = Sunsoft5bAudio::default();
+    }
+
+    fn audio_output(&self) -> f32 {
+        self.audio.output()
+    }
+}
+
+impl Savestate for Fme7 {
+    fn save_state(&self, state: &mut StateWriter) {
+        self.prg_ram.save_state(state);
+        if self.chr_ram {
+            state.write_bytes(&self.chr);
+        }
+        state.write_u8(self.command);
+        state.write_bytes(&self.chr_banks);
+        state.write_bytes(&self.prg_banks);
+        state.write_bool(self.ram_selected);
+        let mirroring = MIRRORING.iter().position(|m| *m == self.mirroring);
+        state.write_u8(mirroring.unwrap_or(0) as u8);
+        state.write_bool(self.irq_enabled);
+        state.write_bool(self.counter_enabled);
+        state.write_u16(self.counter);
+        state.write_bool(self.irq);
+        self.audio.save_state(state);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), String> {
+        self.prg_ram.load_state(state)?;
+        if self.chr_ram {
+            state.read_bytes(&mut self.chr)?;
+        }
+        self.command = state.read_u8()? & 0b1111;
+        state.read_bytes(&mut self.chr_banks)?;
+        state.read_bytes(&mut self.prg_banks)?;
+        self.ram_selected = state.read_bool()?;
+        self.mirroring = MIRRORING[state.read_u8()? as usize & 0b11];
+        self.irq_enabled = state.read_bool()?;
+        self.counter_enabled = state.read_bool()?;
+        self.counter = state.read_u16()?;
+        self.irq = state.read_bool()?;
+        self.audio.load_state(state)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::rom::System;
+
+    fn test_board() -> Fme7 {
+        let prg_rom = (0..16)
+            .flat_map(|bank| vec![bank as u8; PRG_BANK])
+            .collect();
+        let chr_rom = (0..64)
+            .flat_map(|bank| vec![bank as u8; CHR_BANK])
+            .collect();
+        Fme7::new(Rom {
+            prg_rom,
+            chr_rom,
+            chr_ram: false,
+            trainer: None,
+            prg_ram_size: 0x2000,
+            battery: false,
+            mapper: 69,
+            screen_mirroring: Mirroring::Vertical,
+            system: System::Nes,
+        })
+    }
+
+    fn command(board: &mut Fme7, command: u8, data: u8) {
+        board.write_prg(0x8000, command);
+        board.write_prg(0xa000, data);
+    }
+
+    #[test]
+    fn test_banking() {
+        let mut board = test_board();
+        assert_eq!(board.read_prg(0xe000), 15);
+        command(&mut board, 9, 3);
+        command(&mut board, 0xb, 7);
+        assert_eq!(board.read_prg(0x8000), 3);
+        assert_eq!(board.read_prg(0xdfff), 7);
+        command(&mut board, 5, 42);
+        assert_eq!(board.read_chr(0x1400), 42);
+
+        // ROM at $6000, then RAM, write-protected until enabled
+        command(&mut board, 8, 2);
+        assert_eq!(board.read_prg(0x6000), 2);
+        command(&mut board, 8, 0b0100_0000);
+        board.write_prg(0x6000, 9);
+        assert_eq!(board.read_prg(0x6000), 0);
+        command(&mut board, 8, 0b1100_0000);
+        board.write_prg(0x6000, 9);
+        assert_eq!(board.read_prg(0x6000), 9);
+
+        command(&mut board, 0xc, 3);
+        assert_eq!(board.mirroring(), Mirroring::SingleScreenUpper);
+    }
+
+    #[test]
+    fn test_irq_counter() {
+        let mut board = test_board();
+        command(&mut board, 0xe, 2);
+        command(&mut board, 0xf, 0);
+        command(&mut board, 0xd, 0b1000_0001);
+        for _ in 0..3 {
+            assert!(!board.irq_pending());
+            board.clock();
+        }
+        // fires when the counter wraps around from 0
+        assert!(board.irq_pending());
+        command(&mut board, 0xd, 0b1000_0001);
+        assert!(!board.irq_pending());
+    }
+}
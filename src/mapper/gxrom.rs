@@ -0,0 +1,171 @@
+use crate::mapper::Mapper;
+use crate::rom::{Mirroring, Rom};
+use crate::savestate::{Savestate, StateReader, StateWriter};
+
+const PRG_BANK: usize = 0x8000;
+const CHR_BANK: usize = 0x2000;
+
+/// Mappers 66 (GxROM) and 11 (Color Dreams). A single latch anywhere in $8000-$FFFF
+/// picks a 32 KiB PRG bank and an 8 KiB CHR bank at once; the two boards only differ in
+/// which bits go where. There's no PRG RAM, and the ROM drives the bus as well while
+/// the latch is written, so the value written is ANDed with the byte at that address.
+pub struct GxRom {
+    color_dreams: bool,
+    prg_rom: Vec<u8>,
+    chr: Vec<u8>,
+    chr_ram: bool,
+    prg_bank: u8,
+    chr_bank: u8,
+    mirroring: Mirroring,
+}
+
+impl GxRom {
+    pub fn new(rom: Rom) -> Self {
+        GxRom {
+            color_dreams: rom.mapper == 11,
+            prg_rom: rom.prg_rom,
+            chr: rom.chr_rom,
+            chr_ram: rom.chr_ram,
+            prg_bank: 0,
+            chr_bank: 0,
+            mirroring: rom.screen_mirroring,
+        }
+    }
+
+    fn prg_offset(&self, addr: u16) -> usize {
+        (self.prg_bank as usize * PRG_BANK + (addr - 0x8000) as usize) % self.prg_rom.len()
+    }
+
+    fn chr_offset(&self, addr: u16) -> usize {
+        (self.chr_bank as usize * CHR_BANK + addr as usize) % self.chr.len()
+    }
+}
+
+impl Mapper for GxRom {
+    fn read_prg(&self, addr: u16) -> u8 {
+        match addr {
+            0x8000..=0xffff => self.prg_rom[self.prg_offset(addr)],
+            _ => 0,
+        }
+    }
+
+    fn write_prg(&mut self, addr: u16, data: u8) {
+        if addr < 0x8000 {
+            return;
+        }
+        let data = data & self.read_prg(addr);
+        if self.color_dreams {
+            self.prg_bank = data & 0b0000_0011;
+            self.chr_bank = data >> 4;
+        } else {
+            self.prg_bank = (data >> 4) & 0b11;
+            self.chr_bank = data & 0b11;
+        }
+    }
+
+    fn read_chr(&self, addr: u16) -> u8 {
+        self.chr[self.chr_offset(addr)]
+    }
+
+    fn write_chr(&mut self, addr: u16, data: u8) {
+        if self.chr_ram {
+            let offset = self.chr_offset(addr);
+            self.chr[offset] = data;
+        }
+    }
+
+    fn chr_bank_key(&self) -> usize {
+        self.chr_bank as usize
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+
+    fn prg_bank(&self, addr: u16) -> usize {
+        self.prg_offset(addr) / PRG_BANK
+    }
+
+    fn prg_rom_offset(&self, addr: u16) -> Option<usize> {
+        match addr {
+            0x8000..=0xffff => Some(self.prg_offset(addr)),
+            _ => None,
+        }
+    }
+
+    fn power_cycle(&mut self) {
+        self.prg_bank = 0;
+        self.chr_bank = 0;
+    }
+}
+
+impl Savestate for GxRom {
+    fn save_state(&self, state: &mut StateWriter) {
+        if self.chr_ram {
+            state.write_bytes(&self.chr);
+        }
+        state.write_u8(self.prg_bank);
+        state.write_u8(self.chr_bank);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), String> {
+        if self.chr_ram {
+            state.read_bytes(&mut self.chr)?;
+        }
+        self.prg_bank = state.read_u8()?;
+        self.chr_bank = state.read_u8()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::rom::System;
+
+    fn test_board(mapper: u8) -> GxRom {
+        // every PRG byte is $FF, so writes aren't masked by bus conflicts, apart from
+        // the first byte of each bank which holds the bank number
+        let prg_rom = (0..4u8)
+            .flat_map(|bank| {
+                let mut data = vec![0xff; PRG_BANK];
+                data[0] = bank;
+                data
+            })
+            .collect();
+        let chr_rom = (0..16)
+            .flat_map(|bank| vec![bank as u8; CHR_BANK])
+            .collect();
+        GxRom::new(Rom {
+            prg_rom,
+            chr_rom,
+            chr_ram: false,
+            trainer: None,
+            prg_ram_size: 0x2000,
+            battery: false,
+            mapper,
+            screen_mirroring: Mirroring::Vertical,
+            system: System::Nes,
+        })
+    }
+
+    #[test]
+    fn test_gxrom() {
+        let mut board = test_board(66);
+        board.write_prg(0xc000, 0x21);
+        assert_eq!(board.read_prg(0x8000), 2);
+        assert_eq!(board.read_chr(0x1fff), 1);
+        // the bank number at $8000 is ANDed in
+        board.write_prg(0x8000, 0x33);
+        assert_eq!(board.read_prg(0x8000), 0);
+        assert_eq!(board.read_chr(0), 2);
+    }
+
+    #[test]
+    fn test_color_dreams() {
+        let mut board = test_board(11);
+        board.write_prg(0xffff, 0xa3);
+        assert_eq!(board.read_prg(0x8000), 3);
+        assert_eq!(board.read_chr(0), 10);
+    }
+}
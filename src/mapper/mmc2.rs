@@ -0,0 +1,250 @@
+use crate::mapper::prg_ram::PrgRam;
+use crate::mapper::Mapper;
+use crate::rom::{Mirroring, Rom};
+use crate::savestate::{Savestate, StateReader, StateWriter};
+
+const PRG_BANK_8K: usize = 0x2000;
+const PRG_BANK_16K: usize = 0x4000;
+const CHR_BANK: usize = 0x1000;
+
+// Latch values, named after the tiles that select them
+const FD: usize = 0;
+const FE: usize = 1;
+
+/// Mappers 9 (MMC2) and 10 (MMC4). Each 4 KiB pattern table has two CHR banks, and a
+/// latch picks between them whenever the PPU fetches tile $FD or $FE from that table.
+pub struct Mmc2 {
+    mmc4: bool,
+    prg_rom: Vec<u8>,
+    prg_ram: PrgRam,
+    chr: Vec<u8>,
+    prg_bank: u8,
+    // chr_banks[table][latch]
+    chr_banks: [[u8; 2]; 2],
+    latches: [usize; 2],
+    mirroring: Mirroring,
+}
+
+impl Mmc2 {
+    pub fn new(rom: Rom) -> Self {
+        Mmc2 {
+            mmc4: rom.mapper == 10,
+            prg_rom: rom.prg_rom,
+            prg_ram: PrgRam::new(rom.prg_ram_size),
+            chr: rom.chr_rom,
+            prg_bank: 0,
+            chr_banks: [[0; 2]; 2],
+            latches: [FE, FE],
+            mirroring: rom.screen_mirroring,
+        }
+    }
+
+    fn prg_offset(&self, addr: u16) -> usize {
+        let addr = (addr - 0x8000) as usize;
+        if self.mmc4 {
+            // MMC4: a switchable 16 KiB bank, then the last one
+            let last = self.prg_rom.len() / PRG_BANK_16K - 1;
+            let bank = match addr / PRG_BANK_16K {
+                0 => self.prg_bank as usize,
+                _ => last,
+            };
+            bank * PRG_BANK_16K + addr % PRG_BANK_16K
+        } else {
+            // MMC2: a switchable 8 KiB bank, then the last three
+            let banks = self.prg_rom.len() / PRG_BANK_8K;
+            let bank = match addr / PRG_BANK_8K {
+                0 => self.prg_bank as usize,
+                slot => banks - 4 + slot,
+            };
+            bank * PRG_BANK_8K + addr % PRG_BANK_8K
+        }
+    }
+}
+
+impl Mapper for Mmc2 {
+    fn read_prg(&self, addr: u16) -> u8 {
+        match addr {
+            0x6000..=0x7fff => self.prg_ram.read(addr),
+            0x8000..=0xffff => self.prg_rom[self.prg_offset(addr) % self.prg_rom.len()],
+            _ => 0,
+        }
+    }
+
+    fn prg_bank(&self, addr: u16) -> usize {
+        let bank_size = if self.mmc4 { PRG_BANK_16K } else { PRG_BANK_8K };
+        self.prg_offset(addr) / bank_size
+    }
+
+    fn prg_rom_offset(&self, addr: u16) -> Option<usize> {
+        match addr {
+            0x8000..=0xffff => Some(self.prg_offset(addr) % self.prg_rom.len()),
+            _ => None,
+        }
+    }
+
+    fn write_prg(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x6000..=0x7fff => self.prg_ram.write(addr, data),
+            0xa000..=0xafff => self.prg_bank = data & 0b1111,
+            0xb000..=0xbfff => self.chr_banks[0][FD] = data & 0b1_1111,
+            0xc000..=0xcfff => self.chr_banks[0][FE] = data & 0b1_1111,
+            0xd000..=0xdfff => self.chr_banks[1][FD] = data & 0b1_1111,
+            0xe000..=0xefff => self.chr_banks[1][FE] = data & 0b1_1111,
+            0xf000..=0xffff => {
+                self.mirroring = if data & 1 == 0 {
+                    Mirroring::Vertical
+                } else {
+                    Mirroring::Horizontal
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn read_chr(&self, addr: u16) -> u8 {
+        let table = addr as usize / CHR_BANK;
+        let bank = self.chr_banks[table][self.latches[table]] as usize;
+        self.chr[(bank * CHR_BANK + addr as usize % CHR_BANK) % self.chr.len()]
+    }
+
+    fn write_chr(&mut self, addr: u16, _data: u8) {
+        println!("Attempted to write to chr rom space {}", addr);
+    }
+
+    fn chr_bank_key(&self) -> usize {
+        let bank = |table: usize| self.chr_banks[table][self.latches[table]] as usize;
+        bank(0) | bank(1) << 8
+    }
+
+    fn notify_chr_fetch(&mut self, addr: u16) {
+        // MMC2 only watches the exact address of the first row on the left pattern table
+        let (table, latch) = match addr {
+            0x0fd8 => (0, FD),
+            0x0fe8 => (0, FE),
+            0x0fd9..=0x0fdf if self.mmc4 => (0, FD),
+            0x0fe9..=0x0fef if self.mmc4 => (0, FE),
+            0x1fd8..=0x1fdf => (1, FD),
+            0x1fe8..=0x1fef => (1, FE),
+            _ => return,
+        };
+        self.latches[table] = latch;
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+
+    fn prg_ram(&self) -> Option<&PrgRam> {
+        Some(&self.prg_ram)
+    }
+
+    fn prg_ram_mut(&mut self) -> Option<&mut PrgRam> {
+        Some(&mut self.prg_ram)
+    }
+
+    fn power_cycle(&mut self) {
+        self.prg_bank = 0;
+        self.chr_banks = [[0; 2]; 2];
+        self.latches = [FE, FE];
+    }
+}
+
+impl Savestate for Mmc2 {
+    fn save_state(&self, state: &mut StateWriter) {
+        self.prg_ram.save_state(state);
+        state.write_u8(self.prg_bank);
+        for banks in self.chr_banks.iter() {
+            state.write_bytes(banks);
+        }
+        for latch in self.latches.iter() {
+            state.write_u8(*latch as u8);
+        }
+        state.write_bool(self.mirroring == Mirroring::Horizontal);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), String> {
+        self.prg_ram.load_state(state)?;
+        self.prg_bank = state.read_u8()?;
+        for banks in self.chr_banks.iter_mut() {
+            state.read_bytes(banks)?;
+        }
+        for latch in self.latches.iter_mut() {
+            *latch = state.read_u8()? as usize & 1;
+        }
+        self.mirroring = if state.read_bool()? {
+            Mirroring::Horizontal
+        } else {
+            Mirroring::Vertical
+        };
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn test_board(mapper: u8) -> Mmc2 {
+        // every CHR bank is filled with its own number
+        let chr_rom = (0..32)
+            .flat_map(|bank| vec![bank as u8; CHR_BANK])
+            .collect();
+        let prg_rom = (0..16)
+            .flat_map(|bank| vec![bank as u8; PRG_BANK_8K])
+            .collect();
+        Mmc2::new(Rom {
+            prg_rom,
+            chr_rom,
+            chr_ram: false,
+            trainer: None,
+            prg_ram_size: 0x2000,
+            battery: false,
+            mapper,
+            screen_mirroring: Mirroring::Vertical,
+            system: crate::rom::System::Nes,
+        })
+    }
+
+    #[test]
+    fn test_prg_banking() {
+        let mut mmc2 = test_board(9);
+        mmc2.write_prg(0xa000, 5);
+        assert_eq!(mmc2.read_prg(0x8000), 5);
+        assert_eq!(mmc2.read_prg(0xa000), 13);
+        assert_eq!(mmc2.read_prg(0xffff), 15);
+        assert_eq!(mmc2.prg_bank(0x8000), 5);
+        assert_eq!(mmc2.prg_bank(0xffff), 15);
+
+        let mut mmc4 = test_board(10);
+        mmc4.write_prg(0xa000, 2);
+        assert_eq!(mmc4.read_prg(0xa000), 5);
+        assert_eq!(mmc4.read_prg(0xc000), 14);
+        assert_eq!(mmc4.prg_bank(0xc000), 7);
+    }
+
+    #[test]
+    fn test_chr_latches() {
+        let mut mmc2 = test_board(9);
+        mmc2.write_prg(0xb000, 1);
+        mmc2.write_prg(0xc000, 2);
+        mmc2.write_prg(0xd000, 3);
+        mmc2.write_prg(0xe000, 4);
+        assert_eq!(mmc2.read_chr(0x0000), 2);
+        assert_eq!(mmc2.read_chr(0x1000), 4);
+
+        let key = mmc2.chr_bank_key();
+        mmc2.notify_chr_fetch(0x0fd8);
+        mmc2.notify_chr_fetch(0x1fdb);
+        assert_ne!(mmc2.chr_bank_key(), key);
+        assert_eq!(mmc2.read_chr(0x0000), 1);
+        assert_eq!(mmc2.read_chr(0x1000), 3);
+
+        // only MMC4 latches on the other rows of the left table
+        mmc2.notify_chr_fetch(0x0fea);
+        assert_eq!(mmc2.read_chr(0x0000), 1);
+        let mut mmc4 = test_board(10);
+        mmc4.write_prg(0xb000, 1);
+        mmc4.notify_chr_fetch(0x0fda);
+        assert_eq!(mmc4.read_chr(0x0000), 1);
+    }
+}
@@ -0,0 +1,174 @@
+pub mod camerica;
+pub mod fds;
+pub mod fme7;
+pub mod gxrom;
+pub mod mmc2;
+pub mod nrom;
+pub mod prg_ram;
+pub mod vs;
+
+use crate::error::NesError;
+use crate::mapper::camerica::Camerica;
+use crate::mapper::fme7::Fme7;
+use crate::mapper::gxrom::GxRom;
+use crate::mapper::mmc2::Mmc2;
+use crate::mapper::nrom::Nrom;
+use crate::mapper::prg_ram::PrgRam;
+use crate::mapper::vs::VsBoard;
+use crate::rom::{Mirroring, Rom, System};
+use crate::savestate::Savestate;
+use std::cell::RefCell;
+use std::rc::Rc;
+
+pub const SUPPORTED_MAPPERS: [u8; 9] = [0, 9, 10, 11, 66, 69, 71, 99, 232];
+
+/// The cartridge board, seen from both the CPU and the PPU side.
+pub trait Mapper: Savestate {
+    /// CPU access to $6000-$FFFF.
+    fn read_prg(&self, addr: u16) -> u8;
+    fn write_prg(&mut self, addr: u16, data: u8);
+
+    /// PPU access to the pattern tables at $0000-$1FFF.
+    fn read_chr(&self, addr: u16) -> u8;
+    fn write_chr(&mut self, addr: u16, data: u8);
+
+    /// Changes whenever a bank switch changes the CHR the PPU sees, so decoded tiles can
+    /// be kept until then. Boards without CHR banking always return 0.
+    fn chr_bank_key(&self) -> usize {
+        0
+    }
+
+    /// Called after every pattern fetch the PPU makes, for boards that watch the PPU
+    /// address bus.
+    fn notify_chr_fetch(&mut self, _addr: u16) {}
+
+    /// CPU access to $4020-$5FFF, which most boards leave unconnected. `None` means
+    /// nothing answered and the bus keeps its open bus value.
+    fn read_expansion(&mut self, _addr: u16) -> Option<u8> {
+        None
+    }
+    fn peek_expansion(&self, _addr: u16) -> Option<u8> {
+        None
+    }
+    fn write_expansion(&mut self, _addr: u16, _data: u8) {}
+
+    /// Sees the writes to $4016 that strobe the controllers, which VS System boards also
+    /// switch banks with.
+    fn write_4016(&mut self, _data: u8) {}
+
+    fn mirroring(&self) -> Mirroring;
+
+    /// What the 1 KiB nametable `page` (0-3, from $2000) is wired to. Most boards only
+    /// pick a mirroring, which this follows unless the board overrides it.
+    fn nametable(&self, page: usize) -> Nametable {
+        Nametable::mirrored(self.mirroring(), page)
+    }
+
+    /// PPU access to the nametable pages a board answers itself, like MMC5's ExRAM.
+    /// `page` is the board's own number from `Nametable::Board`.
+    fn read_nametable(&self, _page: usize, _offset: u16) -> u8 {
+        0
+    }
+    fn write_nametable(&mut self, _page: usize, _offset: u16, _data: u8) {}
+
+    /// The cartridge RAM at $6000-$7FFF, on boards where it can be banked or
+    /// write-protected. Battery saves hold all of it, whatever size it is.
+    fn prg_ram(&self) -> Option<&PrgRam> {
+        None
+    }
+    fn prg_ram_mut(&mut self) -> Option<&mut PrgRam> {
+        None
+    }
+
+    /// The PRG ROM bank mapped in at `addr` ($8000-$FFFF), counted in the board's own bank
+    /// size. Boards without bank switching only have bank 0.
+    fn prg_bank(&self, _addr: u16) -> usize {
+        0
+    }
+
+    /// Where in PRG ROM the CPU reads `addr` from, for debug symbols. None where it
+    /// isn't ROM.
+    fn prg_rom_offset(&self, _addr: u16) -> Option<usize> {
+        None
+    }
+
+    /// Level of the board's IRQ line.
+    fn irq_pending(&self) -> bool {
+        false
+    }
+
+    /// Clocked every CPU cycle, for boards carrying their own timers or sound chip.
+    fn clock(&mut self) {}
+
+    /// Puts the board's registers back to their power on state. Cartridge RAM and disks
+    /// keep their contents.
+    fn power_cycle(&mut self) {}
+
+    /// Expansion audio level, on the same scale as the APU mixer output.
+    fn audio_output(&self) -> f32 {
+        0.0
+    }
+}
+
+/// Where one 1 KiB page of the nametables at $2000-$2FFF is read from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Nametable {
+    /// One of the two pages of the console's own nametable RAM.
+    Ciram(usize),
+    /// One of the two extra pages of VRAM on a four-screen board.
+    CartVram(usize),
+    /// Memory inside the board, read with `Mapper::read_nametable`.
+    Board(usize),
+}
+
+impl Nametable {
+    pub fn mirrored(mirroring: Mirroring, page: usize) -> Self {
+        match (mirroring, page) {
+            (Mirroring::Vertical, page) => Nametable::Ciram(page & 1),
+            (Mirroring::Horizontal, page) => Nametable::Ciram(page >> 1),
+            (Mirroring::SingleScreenLower, _) => Nametable::Ciram(0),
+            (Mirroring::SingleScreenUpper, _) => Nametable::Ciram(1),
+            (Mirroring::FourScreen, page @ 0..=1) => Nametable::Ciram(page),
+            (Mirroring::FourScreen, page) => Nametable::CartVram(page - 2),
+        }
+    }
+}
+
+/// The bus and the PPU both hold on to the cartridge.
+pub type SharedMapper = Rc<RefCell<dyn Mapper>>;
+
+/// A blank NROM board, for tests and tools that only need a PPU.
+pub fn blank(mirroring: Mirroring, chr_ram: bool) -> SharedMapper {
+    Rc::new(RefCell::new(Nrom::new(Rom {
+        prg_rom: vec![0; 0x4000],
+        chr_rom: vec![0; 0x2000],
+        chr_ram,
+        trainer: None,
+        prg_ram_size: 0x2000,
+        battery: false,
+        mapper: 0,
+        screen_mirroring: mirroring,
+        system: System::Nes,
+    })))
+}
+
+pub fn from_rom(mut rom: Rom) -> Result<SharedMapper, NesError> {
+    let trainer = rom.trainer.take();
+    let mapper: SharedMapper = match rom.mapper {
+        0 => Rc::new(RefCell::new(Nrom::new(rom))),
+        9 | 10 => Rc::new(RefCell::new(Mmc2::new(rom))),
+        11 | 66 => Rc::new(RefCell::new(GxRom::new(rom))),
+        69 => Rc::new(RefCell::new(Fme7::new(rom))),
+        71 | 232 => Rc::new(RefCell::new(Camerica::new(rom))),
+        99 => Rc::new(RefCell::new(VsBoard::new(rom))),
+        id => return Err(NesError::UnsupportedMapper(id)),
+    };
+    // the trainer is copied into PRG RAM, where the game expects to find it at power on
+    if let Some(trainer) = trainer {
+        let mut board = mapper.borrow_mut();
+        for (addr, &data) in (0x7000..).zip(trainer.iter()) {
+            board.write_prg(addr, data);
+        }
+    }
+    Ok(mapper)
+}
@@ -0,0 +1,93 @@
+use crate::mapper::prg_ram::PrgRam;
+use crate::mapper::Mapper;
+use crate::rom::{Mirroring, Rom};
+use crate::savestate::{Savestate, StateReader, StateWriter};
+
+/// Mapper 0: no bank switching, 16 or 32 KiB of PRG ROM and 8 KiB of CHR.
+pub struct Nrom {
+    prg_rom: Vec<u8>,
+    prg_ram: PrgRam,
+    chr: Vec<u8>,
+    chr_ram: bool,
+    mirroring: Mirroring,
+}
+
+impl Nrom {
+    pub fn new(rom: Rom) -> Self {
+        Nrom {
+            prg_rom: rom.prg_rom,
+            prg_ram: PrgRam::new(rom.prg_ram_size),
+            chr: rom.chr_rom,
+            chr_ram: rom.chr_ram,
+            mirroring: rom.screen_mirroring,
+        }
+    }
+}
+
+impl Mapper for Nrom {
+    fn read_prg(&self, addr: u16) -> u8 {
+        match addr {
+            0x6000..=0x7fff => self.prg_ram.read(addr),
+            0x8000..=0xffff => {
+                // 16 KiB carts are mirrored into $C000-$FFFF
+                let addr = (addr - 0x8000) as usize % self.prg_rom.len();
+                self.prg_rom[addr]
+            }
+            _ => 0,
+        }
+    }
+
+    fn write_prg(&mut self, addr: u16, data: u8) {
+        if let 0x6000..=0x7fff = addr {
+            self.prg_ram.write(addr, data);
+        }
+    }
+
+    fn read_chr(&self, addr: u16) -> u8 {
+        self.chr[addr as usize]
+    }
+
+    fn write_chr(&mut self, addr: u16, data: u8) {
+        if self.chr_ram {
+            self.chr[addr as usize] = data;
+        } else {
+            println!("Attempted to write to chr rom space {}", addr);
+        }
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+
+    fn prg_rom_offset(&self, addr: u16) -> Option<usize> {
+        match addr {
+            0x8000..=0xffff => Some((addr - 0x8000) as usize % self.prg_rom.len()),
+            _ => None,
+        }
+    }
+
+    fn prg_ram(&self) -> Option<&PrgRam> {
+        Some(&self.prg_ram)
+    }
+
+    fn prg_ram_mut(&mut self) -> Option<&mut PrgRam> {
+        Some(&mut self.prg_ram)
+    }
+}
+
+impl Savestate for Nrom {
+    fn save_state(&self, state: &mut StateWriter) {
+        self.prg_ram.save_state(state);
+        if self.chr_ram {
+            state.write_bytes(&self.chr);
+        }
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), String> {
+        self.prg_ram.load_state(state)?;
+        if self.chr_ram {
+            state.read_bytes(&mut self.chr)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,144 @@
+use crate::savestate::{Savestate, StateReader, StateWriter};
+
+const BANK_SIZE: usize = 0x2000;
+const MAX_SIZE: usize = 0x8000;
+
+/// Cartridge RAM at $6000-$7FFF: 8, 16 or 32 KiB of it, seen through an 8 KiB window.
+/// Boards with more than one bank pick which one shows, and some can write-protect it.
+pub struct PrgRam {
+    data: Vec<u8>,
+    bank: usize,
+    write_protected: bool,
+}
+
+impl PrgRam {
+    /// `size` is rounded up to whole banks, and kept between 8 and 32 KiB.
+    pub fn new(size: usize) -> Self {
+        let banks = size.div_ceil(BANK_SIZE).clamp(1, MAX_SIZE / BANK_SIZE);
+        PrgRam {
+            data: vec![0; banks * BANK_SIZE],
+            bank: 0,
+            write_protected: false,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    pub fn banks(&self) -> usize {
+        self.data.len() / BANK_SIZE
+    }
+
+    /// Shows `bank` at $6000, wrapping around past the last one.
+    pub fn set_bank(&mut self, bank: usize) {
+        self.bank = bank % self.banks();
+    }
+
+    pub fn set_write_protected(&mut self, write_protected: bool) {
+        self.write_protected = write_protected;
+    }
+
+    fn offset(&self, addr: u16) -> usize {
+        self.bank * BANK_SIZE + (addr as usize - 0x6000) % BANK_SIZE
+    }
+
+    pub fn read(&self, addr: u16) -> u8 {
+        self.data[self.offset(addr)]
+    }
+
+    pub fn write(&mut self, addr: u16, data: u8) {
+        if !self.write_protected {
+            self.poke(addr, data);
+        }
+    }
+
+    /// Writes even while the RAM is write-protected, for cheats and tools.
+    pub fn poke(&mut self, addr: u16, data: u8) {
+        let offset = self.offset(addr);
+        self.data[offset] = data;
+    }
+
+    /// Every bank, the way a battery save holds them.
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// Loads a battery save, which has to be the same size as the RAM.
+    pub fn load(&mut self, data: &[u8]) -> Result<(), String> {
+        if data.len() != self.data.len() {
+            return Err(format!(
+                "The save is {} KiB, but the cartridge has {} KiB of RAM",
+                data.len() / 1024,
+                self.data.len() / 1024
+            ));
+        }
+        self.data.copy_from_slice(data);
+        Ok(())
+    }
+}
+
+/// Reads a PRG RAM size in KiB, for dumps whose header gets it wrong.
+pub fn parse_size(name: &str) -> Result<usize, String> {
+    match name {
+        "8" | "16" | "32" => Ok(name.parse::<usize>().unwrap() * 1024),
+        _ => Err(format!(
+            "Bad PRG RAM size {}, it can be 8, 16 or 32 KiB",
+            name
+        )),
+    }
+}
+
+impl Savestate for PrgRam {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_u8(self.bank as u8);
+        state.write_bool(self.write_protected);
+        state.write_bytes(&self.data);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), String> {
+        self.set_bank(state.read_u8()? as usize);
+        self.write_protected = state.read_bool()?;
+        state.read_bytes(&mut self.data)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_sizes() {
+        assert_eq!(PrgRam::new(0).len(), 0x2000);
+        assert_eq!(PrgRam::new(0x3000).len(), 0x4000);
+        assert_eq!(PrgRam::new(0x10000).len(), 0x8000);
+        assert_eq!(parse_size("16"), Ok(0x4000));
+        assert!(parse_size("12").is_err());
+    }
+
+    #[test]
+    fn test_banks_and_write_protect() {
+        let mut ram = PrgRam::new(0x8000);
+        ram.write(0x6000, 1);
+        ram.set_bank(3);
+        assert_eq!(ram.read(0x6000), 0);
+        ram.write(0x7fff, 2);
+        assert_eq!(ram.data()[0x7fff], 2);
+
+        ram.set_write_protected(true);
+        ram.write(0x7fff, 3);
+        assert_eq!(ram.read(0x7fff), 2);
+        ram.poke(0x7fff, 4);
+        assert_eq!(ram.read(0x7fff), 4);
+
+        ram.set_bank(4);
+        assert_eq!(ram.read(0x6000), 1);
+        assert!(ram.load(&[0; 0x2000]).is_err());
+        assert!(ram.load(&[5; 0x8000]).is_ok());
+        assert_eq!(ram.read(0x6000), 5);
+    }
+}
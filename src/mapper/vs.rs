@@ -0,0 +1,153 @@
+use crate::mapper::Mapper;
+use crate::rom::{Mirroring, Rom};
+use crate::savestate::{Savestate, StateReader, StateWriter};
+
+const PRG_BANK: usize = 0x2000;
+const CHR_BANK: usize = 0x2000;
+
+/// Mapper 99: the VS System's own board. Bit 2 of writes to $4016 picks one of two 8 KiB
+/// CHR banks, and on 40 KiB games also one of two PRG banks at $8000. The 2 KiB of RAM at
+/// $6000 is mirrored up to $7FFF.
+pub struct VsBoard {
+    prg_rom: Vec<u8>,
+    ram: [u8; 0x800],
+    chr: Vec<u8>,
+    chr_ram: bool,
+    bank: usize,
+    mirroring: Mirroring,
+}
+
+impl VsBoard {
+    pub fn new(rom: Rom) -> Self {
+        VsBoard {
+            prg_rom: rom.prg_rom,
+            ram: [0; 0x800],
+            chr: rom.chr_rom,
+            chr_ram: rom.chr_ram,
+            bank: 0,
+            mirroring: rom.screen_mirroring,
+        }
+    }
+
+    fn chr_offset(&self, addr: u16) -> usize {
+        (self.bank * CHR_BANK + addr as usize) % self.chr.len()
+    }
+}
+
+impl Mapper for VsBoard {
+    fn read_prg(&self, addr: u16) -> u8 {
+        match addr {
+            0x6000..=0x7fff => self.ram[addr as usize & 0x7ff],
+            0x8000..=0x9fff if self.prg_rom.len() > 0x8000 => {
+                // the extra 8 KiB of a 40 KiB game come after the first 32
+                self.prg_rom[self.bank * 4 * PRG_BANK + (addr - 0x8000) as usize]
+            }
+            0x8000..=0xffff => self.prg_rom[(addr - 0x8000) as usize % self.prg_rom.len()],
+            _ => 0,
+        }
+    }
+
+    fn write_prg(&mut self, addr: u16, data: u8) {
+        if let 0x6000..=0x7fff = addr {
+            self.ram[addr as usize & 0x7ff] = data;
+        }
+    }
+
+    fn read_chr(&self, addr: u16) -> u8 {
+        self.chr[self.chr_offset(addr)]
+    }
+
+    fn write_chr(&mut self, addr: u16, data: u8) {
+        if self.chr_ram {
+            let offset = self.chr_offset(addr);
+            self.chr[offset] = data;
+        }
+    }
+
+    fn chr_bank_key(&self) -> usize {
+        self.bank
+    }
+
+    fn write_4016(&mut self, data: u8) {
+        self.bank = (data >> 2) as usize & 1;
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+
+    fn prg_bank(&self, addr: u16) -> usize {
+        match addr {
+            0x8000..=0x9fff if self.prg_rom.len() > 0x8000 => self.bank * 4,
+            _ => 0,
+        }
+    }
+
+    fn prg_rom_offset(&self, addr: u16) -> Option<usize> {
+        match addr {
+            0x8000..=0x9fff if self.prg_rom.len() > 0x8000 => {
+                Some(self.bank * 4 * PRG_BANK + (addr - 0x8000) as usize)
+            }
+            0x8000..=0xffff => Some((addr - 0x8000) as usize % self.prg_rom.len()),
+            _ => None,
+        }
+    }
+
+    fn power_cycle(&mut self) {
+        self.bank = 0;
+    }
+}
+
+impl Savestate for VsBoard {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_u8(self.bank as u8);
+        state.write_bytes(&self.ram);
+        if self.chr_ram {
+            state.write_bytes(&self.chr);
+        }
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), String> {
+        self.bank = state.read_u8()? as usize & 1;
+        state.read_bytes(&mut self.ram)?;
+        if self.chr_ram {
+            state.read_bytes(&mut self.chr)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::rom::System;
+
+    #[test]
+    fn test_banks_follow_4016() {
+        let prg_rom = (0..5).flat_map(|bank| vec![bank as u8; PRG_BANK]).collect();
+        let chr_rom = (0..2).flat_map(|bank| vec![bank as u8; CHR_BANK]).collect();
+        let mut board = VsBoard::new(Rom {
+            prg_rom,
+            chr_rom,
+            chr_ram: false,
+            trainer: None,
+            prg_ram_size: 0x2000,
+            battery: false,
+            mapper: 99,
+            screen_mirroring: Mirroring::FourScreen,
+            system: System::VsSystem,
+        });
+        assert_eq!(board.read_prg(0x8000), 0);
+        assert_eq!(board.read_prg(0xa000), 1);
+        assert_eq!(board.read_chr(0x1fff), 0);
+
+        board.write_4016(0b0000_0101);
+        assert_eq!(board.read_prg(0x8000), 4);
+        assert_eq!(board.read_prg(0xe000), 3);
+        assert_eq!(board.read_chr(0x0000), 1);
+        assert_eq!(board.chr_bank_key(), 1);
+
+        board.write_prg(0x6001, 7);
+        assert_eq!(board.read_prg(0x7801), 7);
+    }
+}
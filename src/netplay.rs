@@ -0,0 +1,196 @@
+use crate::joypad::JoypadButton;
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::time::{Duration, Instant};
+
+const MAGIC: &[u8; 2] = b"NP";
+// how long to wait for a packet before sending ours again
+const RESEND_INTERVAL: Duration = Duration::from_millis(10);
+const GIVE_UP_AFTER: Duration = Duration::from_secs(10);
+
+/// Two instances running the same game in lockstep over UDP, player 1 hosting and player 2
+/// joining. Each side's buttons are applied `delay` frames after they're pressed, so they
+/// have that long to reach the other side before it has to wait for them. Both sides have
+/// to start from the same power on state.
+///
+/// Every packet carries the buttons of the last few frames, so a lost packet is made up
+/// for by the next one.
+pub struct Netplay {
+    socket: UdpSocket,
+    peer: Option<SocketAddr>,
+    player: usize,
+    // the buttons for each frame so far on this side and the other, starting with
+    // `delay` frames of nothing pressed
+    local: Vec<JoypadButton>,
+    remote: Vec<JoypadButton>,
+    window: usize,
+}
+
+impl Netplay {
+    /// Player 1, waiting on `port` for player 2 to join.
+    pub fn host(port: u16, delay: usize) -> io::Result<Netplay> {
+        Netplay::new(UdpSocket::bind(("0.0.0.0", port))?, None, 1, delay)
+    }
+
+    /// Player 2, joining the game hosted at `addr`.
+    pub fn join(addr: &str, delay: usize) -> io::Result<Netplay> {
+        let peer = addr.to_socket_addrs()?.next().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::NotFound, format!("Can't find {}", addr))
+        })?;
+        let local = if peer.is_ipv4() {
+            "0.0.0.0:0"
+        } else {
+            "[::]:0"
+        };
+        Netplay::new(UdpSocket::bind(local)?, Some(peer), 2, delay)
+    }
+
+    fn new(
+        socket: UdpSocket,
+        peer: Option<SocketAddr>,
+        player: usize,
+        delay: usize,
+    ) -> io::Result<Netplay> {
+        if delay > 100 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "The input delay can be at most 100 frames",
+            ));
+        }
+        socket.set_read_timeout(Some(RESEND_INTERVAL))?;
+        Ok(Netplay {
+            socket,
+            peer,
+            player,
+            local: vec![JoypadButton::empty(); delay],
+            remote: vec![JoypadButton::empty(); delay],
+            // the two sides are never more than delay + 1 frames apart
+            window: 2 * delay + 8,
+        })
+    }
+
+    /// 1 for the host, 2 for the one who joined.
+    pub fn player(&self) -> usize {
+        self.player
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.peer.is_some()
+    }
+
+    /// Sends the buttons held on this side for `frame`, and waits until the other side's
+    /// buttons for it are in. Called once per frame, before it runs, with frames counting
+    /// up from 0. Returns the buttons for players 1 and 2.
+    pub fn exchange(
+        &mut self,
+        frame: usize,
+        buttons: JoypadButton,
+    ) -> Result<[JoypadButton; 2], String> {
+        self.local.push(buttons);
+
+        let mut last_heard = Instant::now();
+        let mut buffer = [0; 512];
+        loop {
+            self.send().map_err(|e| format!("Netplay: {}", e))?;
+            if frame < self.remote.len() {
+                break;
+            }
+
+            match self.socket.recv_from(&mut buffer) {
+                Ok((len, from)) => {
+                    if self.peer.unwrap_or(from) == from && self.receive(&buffer[..len]) {
+                        self.peer = Some(from);
+                        last_heard = Instant::now();
+                    }
+                }
+                Err(e)
+                    if e.kind() == io::ErrorKind::WouldBlock
+                        || e.kind() == io::ErrorKind::TimedOut => {}
+                // on some systems an unreachable peer shows up as a failed receive
+                Err(e) if e.kind() == io::ErrorKind::ConnectionReset => {}
+                Err(e) => return Err(format!("Netplay: {}", e)),
+            }
+            // the host waits as long as it takes for someone to join
+            if self.peer.is_some() && last_heard.elapsed() > GIVE_UP_AFTER {
+                return Err("Netplay: lost the connection to the other player".to_string());
+            }
+        }
+
+        let (local, remote) = (self.local[frame], self.remote[frame]);
+        Ok(if self.player == 1 {
+            [local, remote]
+        } else {
+            [remote, local]
+        })
+    }
+
+    fn send(&self) -> io::Result<()> {
+        let peer = match self.peer {
+            Some(peer) => peer,
+            None => return Ok(()),
+        };
+        let first = self.local.len().saturating_sub(self.window);
+        let mut packet = MAGIC.to_vec();
+        packet.extend(&(first as u32).to_le_bytes());
+        packet.extend(self.local[first..].iter().map(|buttons| buttons.bits()));
+        self.socket.send_to(&packet, peer).map(|_| ())
+    }
+
+    /// Adds any frames the packet fills in. Returns false for packets that aren't ours.
+    fn receive(&mut self, packet: &[u8]) -> bool {
+        if packet.len() < 6 || &packet[..2] != MAGIC {
+            return false;
+        }
+        let mut first = [0; 4];
+        first.copy_from_slice(&packet[2..6]);
+        let first = u32::from_le_bytes(first) as usize;
+        for (frame, &bits) in (first..).zip(packet[6..].iter()) {
+            if frame == self.remote.len() {
+                self.remote.push(JoypadButton::from_bits_truncate(bits));
+            }
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::thread;
+
+    fn buttons(player: usize, frame: usize) -> JoypadButton {
+        JoypadButton::from_bits_truncate((frame * 7 + player) as u8)
+    }
+
+    #[test]
+    fn test_lockstep() {
+        let mut host = Netplay::host(0, 2).unwrap();
+        let port = host.socket.local_addr().unwrap().port();
+        let guest = thread::spawn(move || {
+            let mut guest = Netplay::join(&format!("127.0.0.1:{}", port), 2).unwrap();
+            (0..30)
+                .map(|frame| guest.exchange(frame, buttons(2, frame)).unwrap())
+                .collect::<Vec<_>>()
+        });
+        let hosted: Vec<_> = (0..30)
+            .map(|frame| host.exchange(frame, buttons(1, frame)).unwrap())
+            .collect();
+        let joined = guest.join().unwrap();
+
+        assert_eq!(hosted, joined);
+        assert_eq!(hosted[0], [JoypadButton::empty(), JoypadButton::empty()]);
+        assert_eq!(hosted[12], [buttons(1, 10), buttons(2, 10)]);
+        assert!(host.is_connected());
+    }
+
+    #[test]
+    fn test_lost_and_stray_packets() {
+        let mut netplay = Netplay::host(0, 1).unwrap();
+        assert!(!netplay.receive(b"hello"));
+        // packets overlap, and only frames that follow on from the ones known are kept
+        assert!(netplay.receive(b"NP\x00\x00\x00\x00\x00\x01\x02"));
+        assert!(netplay.receive(b"NP\x02\x00\x00\x00\x02\x03"));
+        let remote: Vec<u8> = netplay.remote.iter().map(|b| b.bits()).collect();
+        assert_eq!(remote, vec![0, 1, 2, 3]);
+    }
+}
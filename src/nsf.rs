@@ -0,0 +1,399 @@
+use crate::apu::filter::CPU_CLOCK;
+use crate::apu::vrc6::Vrc6Audio;
+use crate::mapper::Mapper;
+use crate::rom::Mirroring;
+use crate::savestate::{Savestate, StateReader, StateWriter};
+
+const NSF_TAG: [u8; 5] = [0x4e, 0x45, 0x53, 0x4d, 0x1a];
+const HEADER_SIZE: usize = 0x80;
+const BANK_SIZE: usize = 0x1000;
+const VRC6_AUDIO: u8 = 0b0000_0001;
+
+// The driver code lives in otherwise unused address space, with the interrupt vectors
+// pointing into it
+const DRIVER: u16 = 0x4100;
+const DRIVER_END: u16 = 0x41ff;
+// Reading this acknowledges the play timer IRQ
+const IRQ_ACK: u16 = DRIVER_END;
+
+/// A parsed NES Sound Format file.
+#[derive(Clone)]
+pub struct Nsf {
+    pub songs: u8,
+    /// 1-based, like the track numbers shown to the user.
+    pub starting_song: u8,
+    pub load_addr: u16,
+    pub init_addr: u16,
+    pub play_addr: u16,
+    pub title: String,
+    pub artist: String,
+    pub copyright: String,
+    /// Microseconds between calls to the play routine.
+    pub play_speed: u16,
+    pub banks: [u8; 8],
+    pub expansion_audio: u8,
+    pub data: Vec<u8>,
+}
+
+fn header_string(bytes: &[u8]) -> String {
+    let end = bytes.iter().position(|b| *b == 0).unwrap_or(bytes.len());
+    String::from_utf8_lossy(&bytes[..end]).to_string()
+}
+
+impl Nsf {
+    pub fn new(raw: &[u8]) -> Result<Nsf, String> {
+        if !raw.starts_with(&NSF_TAG) {
+            return Err("File is not in NSF format".to_string());
+        }
+        if raw.len() <= HEADER_SIZE {
+            return Err("NSF file is truncated".to_string());
+        }
+
+        let word = |offset: usize| u16::from_le_bytes([raw[offset], raw[offset + 1]]);
+        let load_addr = word(0x08);
+        if load_addr < 0x8000 {
+            return Err(format!("Unsupported NSF load address ${:04x}", load_addr));
+        }
+
+        let mut banks = [0; 8];
+        banks.copy_from_slice(&raw[0x70..0x78]);
+        Ok(Nsf {
+            songs: raw[0x06].max(1),
+            starting_song: raw[0x07].max(1),
+            load_addr,
+            init_addr: word(0x0a),
+            play_addr: word(0x0c),
+            title: header_string(&raw[0x0e..0x2e]),
+            artist: header_string(&raw[0x2e..0x4e]),
+            copyright: header_string(&raw[0x4e..0x6e]),
+            play_speed: word(0x6e),
+            banks,
+            expansion_audio: raw[0x7b],
+            data: raw[HEADER_SIZE..].to_vec(),
+        })
+    }
+
+    pub fn is_bankswitched(&self) -> bool {
+        self.banks.iter().any(|bank| *bank != 0)
+    }
+}
+
+/// Turns the console into an NSF player: a board with the NSF banking scheme, and a tiny
+/// driver program that runs the init routine for the selected song on reset and then the
+/// play routine from a timer IRQ at the rate the file asks for.
+pub struct NsfPlayer {
+    nsf: Nsf,
+    // the data laid out in 4 KiB banks, padded so the load address lines up
+    rom: Vec<u8>,
+    banks: [u8; 8],
+    prg_ram: [u8; 0x2000],
+    chr_ram: [u8; 0x2000],
+    vrc6: Option<Vrc6Audio>,
+    song: u8,
+    driver: Vec<u8>,
+    play_period: u32,
+    play_timer: u32,
+    play_irq: bool,
+}
+
+impl NsfPlayer {
+    pub fn new(nsf: Nsf) -> Self {
+        let padding = if nsf.is_bankswitched() {
+            nsf.load_addr as usize % BANK_SIZE
+        } else {
+            nsf.load_addr as usize - 0x8000
+        };
+        let mut rom = vec![0; padding];
+        rom.extend(&nsf.data);
+
+        let play_period = (nsf.play_speed as f64 * CPU_CLOCK / 1_000_000.0) as u32;
+        let mut player = NsfPlayer {
+            rom,
+            banks: [0; 8],
+            prg_ram: [0; 0x2000],
+            chr_ram: [0; 0x2000],
+            vrc6: None,
+            song: 0,
+            driver: Vec::new(),
+            play_period,
+            play_timer: play_period,
+            play_irq: false,
+            nsf,
+        };
+        if player.nsf.expansion_audio & VRC6_AUDIO != 0 {
+            player.vrc6 = Some(Vrc6Audio::default());
+        }
+        player.select_song(player.nsf.starting_song - 1);
+        player
+    }
+
+    pub fn nsf(&self) -> &Nsf {
+        &self.nsf
+    }
+
+    /// 0-based.
+    pub fn song(&self) -> u8 {
+        self.song
+    }
+
+    /// "Title - Artist (track 2/12)", for the window title.
+    pub fn track_title(&self) -> String {
+        format!(
+            "{} - {} (track {}/{})",
+            self.nsf.title,
+            self.nsf.artist,
+            self.song + 1,
+            self.nsf.songs
+        )
+    }
+
+    /// Switches to another song, which starts playing once the CPU is reset.
+    pub fn select_song(&mut self, song: u8) {
+        self.song = song;
+        self.banks = if self.nsf.is_bankswitched() {
+            self.nsf.banks
+        } else {
+            [0, 1, 2, 3, 4, 5, 6, 7]
+        };
+        self.prg_ram = [0; 0x2000];
+        self.play_timer = self.play_period;
+        self.play_irq = false;
+        self.driver = self.assemble_driver();
+    }
+
+    fn assemble_driver(&self) -> Vec<u8> {
+        let [init_lo, init_hi] = self.nsf.init_addr.to_le_bytes();
+        let [play_lo, play_hi] = self.nsf.play_addr.to_le_bytes();
+        let [ack_lo, ack_hi] = IRQ_ACK.to_le_bytes();
+        let branch_to =
+            |code: &Vec<u8>, target: usize| (target as isize - code.len() as isize - 1) as u8;
+
+        // clear the internal RAM: LDA #0, TAX, STA $xx00,X for every page, INX, BNE
+        let mut code = vec![0xa9, 0x00, 0xaa];
+        let clear_ram = code.len();
+        for page in 0..8 {
+            code.extend(&[0x9d, 0x00, page]);
+        }
+        code.extend(&[0xe8, 0xd0]);
+        code.push(branch_to(&code, clear_ram));
+
+        // silence the APU: LDX #$13, STA $4000,X, DEX, BPL
+        code.extend(&[0xa2, 0x13]);
+        let clear_apu = code.len();
+        code.extend(&[0x9d, 0x00, 0x40, 0xca, 0x10]);
+        code.push(branch_to(&code, clear_apu));
+        // enable the channels and the 4-step frame counter without its IRQ
+        code.extend(&[0xa9, 0x0f, 0x8d, 0x15, 0x40, 0xa9, 0x40, 0x8d, 0x17, 0x40]);
+
+        // LDA song, LDX #0 (NTSC), JSR init, CLI, then JMP to itself until the next IRQ
+        code.extend(&[0xa9, self.song, 0xa2, 0x00, 0x20, init_lo, init_hi, 0x58]);
+        let [idle_lo, idle_hi] = (DRIVER + code.len() as u16).to_le_bytes();
+        code.extend(&[0x4c, idle_lo, idle_hi]);
+
+        // the play timer IRQ: BIT ack, JSR play, RTI
+        code.extend(&[0x2c, ack_lo, ack_hi, 0x20, play_lo, play_hi, 0x40]);
+        // NMIs are ignored: RTI
+        code.push(0x40);
+        code
+    }
+
+    fn irq_handler(&self) -> u16 {
+        DRIVER + self.driver.len() as u16 - 8
+    }
+
+    fn nmi_handler(&self) -> u16 {
+        DRIVER + self.driver.len() as u16 - 1
+    }
+}
+
+impl Mapper for NsfPlayer {
+    fn read_prg(&self, addr: u16) -> u8 {
+        match addr {
+            0x6000..=0x7fff => self.prg_ram[(addr - 0x6000) as usize],
+            0xfffa..=0xffff => {
+                let vector = match addr {
+                    0xfffa | 0xfffb => self.nmi_handler(),
+                    0xfffc | 0xfffd => DRIVER,
+                    _ => self.irq_handler(),
+                };
+                vector.to_le_bytes()[(addr & 1) as usize]
+            }
+            0x8000..=0xffff => {
+                let slot = (addr as usize - 0x8000) / BANK_SIZE;
+                let offset = self.banks[slot] as usize * BANK_SIZE + addr as usize % BANK_SIZE;
+                self.rom.get(offset).copied().unwrap_or(0)
+            }
+            _ => 0,
+        }
+    }
+
+    fn prg_bank(&self, addr: u16) -> usize {
+        self.banks[(addr as usize - 0x8000) / BANK_SIZE] as usize
+    }
+
+    fn write_prg(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x6000..=0x7fff => self.prg_ram[(addr - 0x6000) as usize] = data,
+            0x9000..=0xb002 => {
+                if let Some(vrc6) = &mut self.vrc6 {
+                    vrc6.write(addr, data);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn read_chr(&self, addr: u16) -> u8 {
+        self.chr_ram[addr as usize]
+    }
+
+    fn write_chr(&mut self, addr: u16, data: u8) {
+        self.chr_ram[addr as usize] = data;
+    }
+
+    fn read_expansion(&mut self, addr: u16) -> Option<u8> {
+        if addr == IRQ_ACK {
+            self.play_irq = false;
+        }
+        self.peek_expansion(addr)
+    }
+
+    fn peek_expansion(&self, addr: u16) -> Option<u8> {
+        match addr {
+            DRIVER..=DRIVER_END => Some(
+                self.driver
+                    .get((addr - DRIVER) as usize)
+                    .copied()
+                    .unwrap_or(0),
+            ),
+            _ => None,
+        }
+    }
+
+    fn write_expansion(&mut self, addr: u16, data: u8) {
+        if let 0x5ff8..=0x5fff = addr {
+            if self.nsf.is_bankswitched() {
+                self.banks[(addr - 0x5ff8) as usize] = data;
+            }
+        }
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        Mirroring::Horizontal
+    }
+
+    fn irq_pending(&self) -> bool {
+        self.play_irq
+    }
+
+    fn clock(&mut self) {
+        if self.play_timer == 0 {
+            self.play_irq = true;
+            self.play_timer = self.play_period;
+        } else {
+            self.play_timer -= 1;
+        }
+        if let Some(vrc6) = &mut self.vrc6 {
+            vrc6.clock();
+        }
+    }
+
+    fn audio_output(&self) -> f32 {
+        self.vrc6.as_ref().map_or(0.0, |vrc6| vrc6.output())
+    }
+}
+
+impl Savestate for NsfPlayer {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_bytes(&self.banks);
+        state.write_bytes(&self.prg_ram);
+        state.write_bytes(&self.chr_ram);
+        state.write_u8(self.song);
+        state.write_usize(self.play_timer as usize);
+        state.write_bool(self.play_irq);
+        if let Some(vrc6) = &self.vrc6 {
+            vrc6.save_state(state);
+        }
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), String> {
+        state.read_bytes(&mut self.banks)?;
+        state.read_bytes(&mut self.prg_ram)?;
+        state.read_bytes(&mut self.chr_ram)?;
+        self.song = state.read_u8()?;
+        self.driver = self.assemble_driver();
+        self.play_timer = state.read_usize()? as u32;
+        self.play_irq = state.read_bool()?;
+        if let Some(vrc6) = &mut self.vrc6 {
+            vrc6.load_state(state)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bus::Bus;
+    use crate::cpu::{Mem, CPU};
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    // init stores the song number at $00, play counts calls in $01
+    fn test_nsf(banks: [u8; 8]) -> Vec<u8> {
+        let mut raw = NSF_TAG.to_vec();
+        raw.resize(HEADER_SIZE, 0);
+        raw[0x06] = 3;
+        raw[0x07] = 2;
+        raw[0x08..0x0e].copy_from_slice(&[0x00, 0x80, 0x00, 0x80, 0x03, 0x80]);
+        raw[0x0e..0x13].copy_from_slice(b"Tune\0");
+        raw[0x6e..0x70].copy_from_slice(&16639u16.to_le_bytes());
+        raw[0x70..0x78].copy_from_slice(&banks);
+        // $8000: STA $00, RTS  $8003: INC $01, RTS
+        raw.extend(&[0x85, 0x00, 0x60, 0xe6, 0x01, 0x60]);
+        raw
+    }
+
+    #[test]
+    fn test_parse_header() {
+        let nsf = Nsf::new(&test_nsf([0; 8])).unwrap();
+        assert_eq!(nsf.songs, 3);
+        assert_eq!(nsf.starting_song, 2);
+        assert_eq!(nsf.play_addr, 0x8003);
+        assert_eq!(nsf.title, "Tune");
+        assert!(!nsf.is_bankswitched());
+        assert!(Nsf::new(b"NES\x1a").is_err());
+    }
+
+    #[test]
+    fn test_bankswitching() {
+        let mut raw = test_nsf([0, 1, 0, 0, 0, 0, 0, 0]);
+        raw.resize(HEADER_SIZE + 2 * BANK_SIZE, 0);
+        raw[HEADER_SIZE + BANK_SIZE] = 0x42;
+        let mut player = NsfPlayer::new(Nsf::new(&raw).unwrap());
+        assert_eq!(player.read_prg(0x9000), 0x42);
+
+        player.write_expansion(0x5ff8, 1);
+        assert_eq!(player.read_prg(0x8000), 0x42);
+    }
+
+    #[test]
+    fn test_driver_runs_init_and_play() {
+        let player = Rc::new(RefCell::new(NsfPlayer::new(
+            Nsf::new(&test_nsf([0; 8])).unwrap(),
+        )));
+        let bus = Bus::with_mapper(player.clone());
+        let mut cpu = CPU::new(bus);
+        cpu.reset();
+
+        // a little over two play periods
+        cpu.run_with_callback(|cpu| {
+            if cpu.bus.cycles() > 70_000 {
+                // the driver cleared RAM, so this stops at a BRK
+                cpu.program_counter = 0x0010;
+            }
+        });
+        assert_eq!(cpu.mem_read(0x00), 1);
+        assert_eq!(cpu.mem_read(0x01), 2);
+    }
+}
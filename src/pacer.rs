@@ -0,0 +1,265 @@
+use std::fmt;
+use std::time::{Duration, Instant};
+
+pub const NTSC_FPS: f64 = 60.0988;
+
+// How far behind schedule we allow ourselves to fall before giving up on catching up
+const MAX_LAG_FRAMES: u32 = 4;
+
+pub const SPEED_STEPS: [f64; 5] = [0.25, 0.5, 1.0, 2.0, 4.0];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Speed {
+    Scaled(f64),
+    Unlimited,
+}
+
+impl Speed {
+    /// How many times faster than real time, `None` when there's no limit.
+    pub fn scale(self) -> Option<f64> {
+        match self {
+            Speed::Scaled(speed) => Some(speed),
+            Speed::Unlimited => None,
+        }
+    }
+
+    pub fn faster(self) -> Speed {
+        match self {
+            Speed::Scaled(current) => SPEED_STEPS
+                .iter()
+                .find(|step| **step > current)
+                .map_or(Speed::Unlimited, |step| Speed::Scaled(*step)),
+            Speed::Unlimited => Speed::Unlimited,
+        }
+    }
+
+    pub fn slower(self) -> Speed {
+        let current = match self {
+            Speed::Scaled(current) => current,
+            Speed::Unlimited => f64::INFINITY,
+        };
+        SPEED_STEPS
+            .iter()
+            .rev()
+            .find(|step| **step < current)
+            .map_or(Speed::Scaled(SPEED_STEPS[0]), |step| Speed::Scaled(*step))
+    }
+}
+
+impl fmt::Display for Speed {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Speed::Scaled(speed) => write!(f, "{}%", (speed * 100.0).round()),
+            Speed::Unlimited => write!(f, "unlimited"),
+        }
+    }
+}
+
+pub struct FramePacer {
+    fps: f64,
+    frame_duration: Duration,
+    next_frame: Option<Instant>,
+    speed: Speed,
+
+    // frames shown since the start of the current measurement
+    measure_start: Option<Instant>,
+    measured_frames: u32,
+    measured_fps: f64,
+}
+
+impl FramePacer {
+    pub fn new(fps: f64) -> Self {
+        FramePacer {
+            fps,
+            frame_duration: Duration::from_secs_f64(1.0 / fps),
+            next_frame: None,
+            speed: Speed::Scaled(1.0),
+            measure_start: None,
+            measured_frames: 0,
+            measured_fps: 0.0,
+        }
+    }
+
+    pub fn speed(&self) -> Speed {
+        self.speed
+    }
+
+    pub fn set_speed(&mut self, speed: Speed) {
+        self.speed = speed;
+        self.next_frame = None;
+    }
+
+    /// Frames actually shown per second, measured over the last second.
+    pub fn measured_fps(&self) -> f64 {
+        self.measured_fps
+    }
+
+    /// How fast the emulation runs compared to a real NES, in percent.
+    pub fn measured_speed(&self) -> f64 {
+        self.measured_fps / self.fps * 100.0
+    }
+
+    fn measure(&mut self, now: Instant) {
+        let start = *self.measure_start.get_or_insert(now);
+        let elapsed = now.saturating_duration_since(start);
+        if elapsed >= Duration::from_secs(1) {
+            self.measured_fps = self.measured_frames as f64 / elapsed.as_secs_f64();
+            self.measure_start = Some(now);
+            self.measured_frames = 0;
+        }
+        self.measured_frames += 1;
+    }
+
+    /// Returns how long to wait at `now` before the next frame may start, and schedules it.
+    pub fn delay(&mut self, now: Instant) -> Duration {
+        self.measure(now);
+        let frame_duration = match self.speed {
+            Speed::Scaled(speed) => self.frame_duration.div_f64(speed),
+            Speed::Unlimited => return Duration::from_secs(0),
+        };
+
+        let deadline = match self.next_frame {
+            Some(deadline) if now <= deadline + frame_duration * MAX_LAG_FRAMES => deadline,
+            _ => now,
+        };
+
+        self.next_frame = Some(deadline + frame_duration);
+        deadline.saturating_duration_since(now)
+    }
+
+    /// Blocks until the next frame is due.
+    pub fn wait(&mut self) {
+        let delay = self.delay(Instant::now());
+        if delay > Duration::from_secs(0) {
+            std::thread::sleep(delay);
+        }
+    }
+}
+
+/// Draws only some of the frames, for machines too slow to draw them all. The console
+/// still runs every frame, so the game and its sound keep to time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameSkip {
+    skip: u32,
+    out_of: u32,
+    frame: u32,
+}
+
+impl FrameSkip {
+    /// Skips `skip` of every `out_of` frames, spread out as evenly as they can be.
+    pub fn new(skip: u32, out_of: u32) -> Result<Self, String> {
+        if out_of == 0 || skip >= out_of {
+            return Err(format!("Can't skip {} of every {} frames", skip, out_of));
+        }
+        Ok(FrameSkip {
+            skip,
+            out_of,
+            frame: 0,
+        })
+    }
+
+    /// Parses `N/M`, for skipping N of every M frames.
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let bad = || format!("Bad frame skip {}, it should be like 1/2", text);
+        let (skip, out_of) = text.split_once('/').ok_or_else(bad)?;
+        let skip = skip.trim().parse().map_err(|_| bad())?;
+        let out_of = out_of.trim().parse().map_err(|_| bad())?;
+        FrameSkip::new(skip, out_of)
+    }
+
+    /// Whether to draw the next frame.
+    pub fn next_drawn(&mut self) -> bool {
+        let drawn = self.out_of - self.skip;
+        let frame = self.frame;
+        self.frame = (self.frame + 1) % self.out_of;
+        (frame + 1) * drawn / self.out_of > frame * drawn / self.out_of
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_frames_are_spaced_evenly() {
+        let mut pacer = FramePacer::new(NTSC_FPS);
+        let start = Instant::now();
+        let frame = Duration::from_secs_f64(1.0 / NTSC_FPS);
+
+        assert_eq!(pacer.delay(start), Duration::from_secs(0));
+        assert_eq!(pacer.delay(start), frame);
+        assert_eq!(pacer.delay(start + frame), frame);
+    }
+
+    #[test]
+    fn test_resyncs_when_far_behind() {
+        let mut pacer = FramePacer::new(NTSC_FPS);
+        let start = Instant::now();
+        pacer.delay(start);
+
+        let late = start + Duration::from_secs(1);
+        assert_eq!(pacer.delay(late), Duration::from_secs(0));
+        assert_eq!(pacer.delay(late), Duration::from_secs_f64(1.0 / NTSC_FPS));
+    }
+
+    #[test]
+    fn test_measures_fps() {
+        let mut pacer = FramePacer::new(NTSC_FPS);
+        pacer.set_speed(Speed::Unlimited);
+        let start = Instant::now();
+        for frame in 0..=120 {
+            pacer.delay(start + Duration::from_millis(frame * 10));
+        }
+        assert!((pacer.measured_fps() - 100.0).abs() < 0.01);
+        assert!((pacer.measured_speed() - 166.4).abs() < 0.1);
+    }
+
+    #[test]
+    fn test_unlimited_never_waits() {
+        let mut pacer = FramePacer::new(NTSC_FPS);
+        pacer.set_speed(Speed::Unlimited);
+        let now = Instant::now();
+        pacer.delay(now);
+        assert_eq!(pacer.delay(now), Duration::from_secs(0));
+    }
+
+    #[test]
+    fn test_scaled_speed() {
+        let mut pacer = FramePacer::new(NTSC_FPS);
+        let frame = Duration::from_secs_f64(1.0 / NTSC_FPS);
+        let now = Instant::now();
+
+        pacer.set_speed(Speed::Scaled(2.0));
+        pacer.delay(now);
+        assert_eq!(pacer.delay(now), frame.div_f64(2.0));
+
+        pacer.set_speed(Speed::Scaled(0.5));
+        pacer.delay(now);
+        assert_eq!(pacer.delay(now), frame.div_f64(0.5));
+    }
+
+    #[test]
+    fn test_speed_steps() {
+        assert_eq!(Speed::Scaled(1.0).faster(), Speed::Scaled(2.0));
+        assert_eq!(Speed::Scaled(4.0).faster(), Speed::Unlimited);
+        assert_eq!(Speed::Unlimited.slower(), Speed::Scaled(4.0));
+        assert_eq!(Speed::Scaled(0.5).slower(), Speed::Scaled(0.25));
+        assert_eq!(Speed::Scaled(0.25).slower(), Speed::Scaled(0.25));
+    }
+
+    #[test]
+    fn test_frame_skip() {
+        let mut skip = FrameSkip::parse("1/4").unwrap();
+        let drawn: Vec<bool> = (0..8).map(|_| skip.next_drawn()).collect();
+        assert_eq!(drawn, [false, true, true, true, false, true, true, true]);
+
+        let mut skip = FrameSkip::new(2, 3).unwrap();
+        assert_eq!((0..30).filter(|_| skip.next_drawn()).count(), 10);
+        let mut skip = FrameSkip::new(0, 1).unwrap();
+        assert!((0..10).all(|_| skip.next_drawn()));
+
+        assert!(FrameSkip::parse("2/2").is_err());
+        assert!(FrameSkip::parse("1/0").is_err());
+        assert!(FrameSkip::parse("half").is_err());
+    }
+}
@@ -0,0 +1,116 @@
+use crate::mapper::{Nametable, SharedMapper};
+
+/// The PPU's side of the cartridge: the pattern tables at $0000-$1FFF, and the nametables
+/// at $2000-$3EFF wherever the board wires each 1 KiB page.
+pub struct PpuBus {
+    pub mapper: SharedMapper,
+    /// The console's 2 KiB of nametable RAM, then the 2 KiB four-screen boards add.
+    pub vram: [u8; 4096],
+}
+
+impl PpuBus {
+    pub fn new(mapper: SharedMapper) -> Self {
+        PpuBus {
+            mapper,
+            vram: [0; 4096],
+        }
+    }
+
+    /// The nametable page `addr` falls in, and where the board wired it.
+    pub fn nametable(&self, addr: u16) -> Nametable {
+        let page = (addr as usize & 0x0fff) / 0x400;
+        self.mapper.borrow().nametable(page)
+    }
+
+    /// Which page of `vram` `addr` is in, or `None` where the board answers itself.
+    pub fn vram_page(&self, addr: u16) -> Option<usize> {
+        match self.nametable(addr) {
+            Nametable::Ciram(page) => Some(page & 1),
+            Nametable::CartVram(page) => Some(2 + (page & 1)),
+            Nametable::Board(_) => None,
+        }
+    }
+
+    pub fn read_nametable(&self, addr: u16) -> u8 {
+        let offset = addr & 0x3ff;
+        match self.nametable(addr) {
+            Nametable::Board(page) => self.mapper.borrow().read_nametable(page, offset),
+            _ => self.vram[self.vram_index(addr)],
+        }
+    }
+
+    pub fn write_nametable(&mut self, addr: u16, data: u8) {
+        let offset = addr & 0x3ff;
+        match self.nametable(addr) {
+            Nametable::Board(page) => self.mapper.borrow_mut().write_nametable(page, offset, data),
+            _ => self.vram[self.vram_index(addr)] = data,
+        }
+    }
+
+    // only for pages in `vram`
+    fn vram_index(&self, addr: u16) -> usize {
+        self.vram_page(addr).unwrap_or(0) * 0x400 + (addr & 0x3ff) as usize
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::mapper::Mapper;
+    use crate::rom::Mirroring;
+    use crate::savestate::{Savestate, StateReader, StateWriter};
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    // Vertical mirroring, except $2C00 which is RAM on the board, like MMC5's ExRAM
+    struct ExRamBoard {
+        ram: [u8; 0x400],
+    }
+
+    impl Mapper for ExRamBoard {
+        fn read_prg(&self, _addr: u16) -> u8 {
+            0
+        }
+        fn write_prg(&mut self, _addr: u16, _data: u8) {}
+        fn read_chr(&self, _addr: u16) -> u8 {
+            0
+        }
+        fn write_chr(&mut self, _addr: u16, _data: u8) {}
+        fn mirroring(&self) -> Mirroring {
+            Mirroring::Vertical
+        }
+        fn nametable(&self, page: usize) -> Nametable {
+            match page {
+                3 => Nametable::Board(0),
+                page => Nametable::mirrored(Mirroring::Vertical, page),
+            }
+        }
+        fn read_nametable(&self, _page: usize, offset: u16) -> u8 {
+            self.ram[offset as usize]
+        }
+        fn write_nametable(&mut self, _page: usize, offset: u16, data: u8) {
+            self.ram[offset as usize] = data;
+        }
+    }
+
+    impl Savestate for ExRamBoard {
+        fn save_state(&self, _state: &mut StateWriter) {}
+        fn load_state(&mut self, _state: &mut StateReader) -> Result<(), String> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_board_nametable_page() {
+        let board = Rc::new(RefCell::new(ExRamBoard { ram: [0; 0x400] }));
+        let mut bus = PpuBus::new(board.clone());
+        bus.write_nametable(0x2c05, 0x66);
+        bus.write_nametable(0x2805, 0x77);
+
+        assert_eq!(board.borrow().ram[5], 0x66);
+        assert_eq!(bus.read_nametable(0x3c05), 0x66);
+        assert_eq!(bus.vram[0x0005], 0x77);
+        assert_eq!(bus.vram_page(0x2c00), None);
+        assert_eq!(bus.vram_page(0x2400), Some(1));
+    }
+}
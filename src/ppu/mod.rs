@@ -1,20 +1,40 @@
+pub mod bus;
 pub mod registers;
 
-use crate::ppu::registers::addr::AddrRegister;
+use crate::mapper::{self, SharedMapper};
+use crate::ppu::bus::PpuBus;
 use crate::ppu::registers::ctrl::CtrlRegister;
+use crate::ppu::registers::loopy::LoopyRegister;
 use crate::ppu::registers::mask::MaskRegister;
-use crate::ppu::registers::scroll::ScrollRegister;
 use crate::ppu::registers::status::StatusRegister;
+use crate::render::cache::BackgroundCache;
+use crate::render::palette::PaletteKind;
 use crate::rom::Mirroring;
+use crate::savestate::{Savestate, StateReader, StateWriter};
+use std::cell::RefCell;
+
+// The I/O latch holds its value for roughly 600ms before fading to 0
+const IO_LATCH_DECAY_FRAMES: u8 = 36;
+
+// Secondary OAM only has room for this many sprites per scanline
+const SPRITES_PER_SCANLINE: usize = 8;
+
+// $3F00-$3FFF repeats the 32 palette entries, and $3F10/$3F14/$3F18/$3F1C are mirrors of
+// $3F00/$3F04/$3F08/$3F0C
+fn palette_index(addr: u16) -> usize {
+    let index = addr as usize & 0x1f;
+    if index >= 16 && index & 0b11 == 0 {
+        index - 16
+    } else {
+        index
+    }
+}
 
 pub struct NesPPU {
-    pub chr_rom: Vec<u8>,
-    pub vram: [u8; 2048],
-    pub mirroring: Mirroring,
-    pub addr: AddrRegister,
+    pub bus: PpuBus,
+    pub loopy: LoopyRegister,
     pub ctrl: CtrlRegister,
     pub mask: MaskRegister,
-    pub scroll: ScrollRegister,
     pub status: StatusRegister,
 
     pub oam_addr: u8,
@@ -22,212 +42,485 @@ pub struct NesPPU {
     pub palette_table: [u8; 32],
 
     internal_data_buf: u8,
+    io_latch: u8,
+    io_latch_age: u8,
 
     pub scanline: u16,
     cycles: usize,
+    odd_frame: bool,
+    vblank_suppressed: bool,
     pub nmi_interrupt: Option<u8>,
+
+    /// The VRAM address and fine X scroll at the start of each visible scanline, which is
+    /// where the background is drawn from.
+    pub line_scroll: [(u16, u8); 240],
+    /// The background the renderer drew last frame.
+    pub background_cache: RefCell<BackgroundCache>,
+
+    /// Drop sprites past the 8th on a scanline, like the hardware does. Turning this off
+    /// removes flicker, but doesn't change the overflow flag.
+    pub sprite_limit: bool,
+    /// The colours of the PPU the game was made for.
+    pub palette: PaletteKind,
 }
 
 pub trait PPU {
     fn write_to_ctrl(&mut self, value: u8);
     fn write_to_mask(&mut self, value: u8);
     fn read_status(&mut self) -> u8;
+    fn peek_status(&self) -> u8;
     fn write_to_oam_addr(&mut self, value: u8);
     fn write_to_oam_data(&mut self, value: u8);
-    fn read_oam_data(&self) -> u8;
+    fn read_oam_data(&mut self) -> u8;
+    fn peek_oam_data(&self) -> u8;
     fn write_to_scroll(&mut self, value: u8);
     fn write_to_ppu_addr(&mut self, value: u8);
     fn write_to_data(&mut self, value: u8);
     fn read_data(&mut self) -> u8;
+    fn peek_data(&self) -> u8;
     fn write_oam_dma(&mut self, value: &[u8; 256]);
 }
 
 impl NesPPU {
     pub fn new_empty_rom() -> Self {
-        NesPPU::new(vec![0; 2048], Mirroring::Horizontal)
+        NesPPU::new(mapper::blank(Mirroring::Horizontal, false))
     }
 
-    pub fn new(chr_rom: Vec<u8>, mirroring: Mirroring) -> Self {
+    pub fn new(mapper: SharedMapper) -> Self {
         NesPPU {
-            chr_rom,
-            mirroring,
-            vram: [0; 2048],
+            bus: PpuBus::new(mapper),
             oam_addr: 0,
             oam_data: [0; 256],
             palette_table: [0; 32],
 
-            addr: AddrRegister::new(),
+            loopy: LoopyRegister::new(),
             ctrl: CtrlRegister::new(),
             mask: MaskRegister::new(),
-            scroll: ScrollRegister::new(),
             status: StatusRegister::new(),
 
             internal_data_buf: 0,
+            io_latch: 0,
+            io_latch_age: 0,
             scanline: 0,
             cycles: 0,
+            odd_frame: false,
+            vblank_suppressed: false,
             nmi_interrupt: None,
+            line_scroll: [(0, 0); 240],
+            background_cache: RefCell::new(BackgroundCache::new()),
+            sprite_limit: true,
+            palette: PaletteKind::Composite,
         }
     }
 
-    fn increment_vram_addr(&mut self) {
-        self.addr.increment(self.ctrl.vram_addr_increment());
-    }
-
-    pub fn mirror_vram_addr(&self, addr: u16) -> u16 {
-        let mirrored_vram = addr & 0b0010_1111_1111_1111; // mirror down 0x3000-0x3eff to 0x2000 - 0x2eff
-        let vram_index = mirrored_vram - 0x2000; // to vram vector
-        let name_table = vram_index / 0x400; // to the name table index
-        match (&self.mirroring, name_table) {
-            (Mirroring::Vertical, 2) | (Mirroring::Vertical, 3) => vram_index - 0x800,
-            (Mirroring::Horizontal, 2) => vram_index - 0x400,
-            (Mirroring::Horizontal, 1) => vram_index - 0x400,
-            (Mirroring::Horizontal, 3) => vram_index - 0x800,
-            _ => vram_index,
+    /// The reset button clears $2000, $2001, the scroll and the read buffer. VRAM, OAM and
+    /// the palette are kept.
+    pub fn reset(&mut self) {
+        self.ctrl = CtrlRegister::new();
+        self.mask = MaskRegister::new();
+        self.loopy.t = 0;
+        self.loopy.fine_x = 0;
+        self.loopy.reset_latch();
+        self.internal_data_buf = 0;
+    }
+
+    /// Starts over from the power on state, still showing the same cartridge.
+    pub fn power_cycle(&mut self) {
+        let (sprite_limit, palette) = (self.sprite_limit, self.palette);
+        *self = NesPPU::new(self.bus.mapper.clone());
+        self.sprite_limit = sprite_limit;
+        self.palette = palette;
+    }
+
+    /// Value left on the PPU data bus by the last register access, returned when reading
+    /// write-only registers and the unused bits of $2002.
+    pub fn io_latch(&self) -> u8 {
+        self.io_latch
+    }
+
+    pub fn refresh_io_latch(&mut self, value: u8) {
+        self.io_latch = value;
+        self.io_latch_age = 0;
+    }
+
+    // Palette entries are 6 bits wide, the top 2 bits come from the I/O latch
+    fn read_palette(&self, addr: u16) -> u8 {
+        (self.palette_table[palette_index(addr)] & 0b0011_1111) | (self.io_latch & 0b1100_0000)
+    }
+
+    /// The palette entry shown wherever nothing opaque is drawn. With rendering off and the
+    /// VRAM address pointing at the palette, that entry is shown instead of $3F00, which
+    /// some games and demos use to draw colours directly.
+    pub fn backdrop(&self) -> u8 {
+        let addr = self.loopy.addr();
+        if !self.rendering_enabled() && addr >= 0x3f00 {
+            self.palette_table[palette_index(addr)]
+        } else {
+            self.palette_table[0]
+        }
+    }
+
+    /// Reads the PPU address space without touching the read buffer or the I/O latch.
+    pub fn peek_vram(&self, addr: u16) -> u8 {
+        match addr & 0x3fff {
+            addr @ 0..=0x1fff => self.bus.mapper.borrow().read_chr(addr),
+            addr @ 0x2000..=0x3eff => self.bus.read_nametable(addr),
+            addr => self.palette_table[palette_index(addr)],
+        }
+    }
+
+    /// Writes the PPU address space without moving the VRAM address, for the memory editor.
+    pub fn poke_vram(&mut self, addr: u16, value: u8) {
+        match addr & 0x3fff {
+            addr @ 0..=0x1fff => self.write_chr(addr, value),
+            addr @ 0x2000..=0x3eff => self.bus.write_nametable(addr, value),
+            addr => self.palette_table[palette_index(addr)] = value,
         }
     }
 
+    fn write_chr(&mut self, addr: u16, value: u8) {
+        self.bus.mapper.borrow_mut().write_chr(addr, value);
+        self.background_cache.borrow_mut().chr_written(addr);
+    }
+
+    fn increment_vram_addr(&mut self) {
+        self.loopy.increment(self.ctrl.vram_addr_increment());
+    }
+
     fn is_sprite_0_hit(&self, cycle: usize) -> bool {
         let y = self.oam_data[0] as usize;
         let x = self.oam_data[3] as usize;
 
-        (y == self.scanline as usize) && x <= cycle && self.mask.show_sprites()
+        // no hit is possible in the leftmost 8 pixels while either layer is clipped there
+        let clipped =
+            x < 8 && !(self.mask.leftmost_8pxl_background() && self.mask.leftmost_8pxl_sprite());
+
+        (y == self.scanline as usize) && x <= cycle && self.mask.show_sprites() && !clipped
+    }
+
+    fn sprite_in_range(&self, y: u8, scanline: u16) -> bool {
+        let y = y as u16;
+        scanline >= y && scanline < y + self.ctrl.sprite_size() as u16
     }
 
+    /// OAM indexes of the sprites drawn on `scanline`, in priority order.
+    pub fn sprites_on_scanline(&self, scanline: u16) -> Vec<usize> {
+        let sprites = (0..64).filter(|n| self.sprite_in_range(self.oam_data[n * 4], scanline));
+        if self.sprite_limit {
+            sprites.take(SPRITES_PER_SCANLINE).collect()
+        } else {
+            sprites.collect()
+        }
+    }
+
+    // Sprite evaluation as the hardware does it: once secondary OAM is full, the PPU keeps
+    // looking for a 9th sprite but increments the byte offset along with the sprite index,
+    // so it compares tile numbers, attributes and X positions against the scanline.
+    fn sprite_overflow(&self, scanline: u16) -> bool {
+        let mut n = 0;
+        let mut found = 0;
+        while n < 64 && found < SPRITES_PER_SCANLINE {
+            if self.sprite_in_range(self.oam_data[n * 4], scanline) {
+                found += 1;
+            }
+            n += 1;
+        }
+
+        let mut m = 0;
+        while n < 64 {
+            if self.sprite_in_range(self.oam_data[n * 4 + m], scanline) {
+                return true;
+            }
+            n += 1;
+            m = (m + 1) & 0b11;
+        }
+        false
+    }
+
+    fn rendering_enabled(&self) -> bool {
+        self.mask.show_background() || self.mask.show_sprites()
+    }
+
+    // The scroll position a scanline is drawn with. With rendering off `v` stays put, so
+    // this falls back to what was last written to $2000/$2005.
+    fn record_line_scroll(&mut self) {
+        let v = if self.rendering_enabled() {
+            self.loopy.v
+        } else {
+            self.loopy.t
+        };
+        self.line_scroll[self.scanline as usize] = (v, self.loopy.fine_x);
+    }
+
+    /// The dot on the current scanline, 0-340.
+    pub fn dot(&self) -> usize {
+        self.cycles
+    }
+
+    // Every other frame the pre-render line skips its last dot while rendering is on,
+    // which keeps the picture from crawling on NTSC TVs
+    fn line_length(&self) -> usize {
+        if self.scanline == 261 && self.odd_frame && self.rendering_enabled() {
+            340
+        } else {
+            341
+        }
+    }
+
+    /// Runs the PPU for `cycles` dots. Returns true when a frame has just finished.
     pub fn tick(&mut self, cycles: u8) -> bool {
-        self.cycles += cycles as usize;
-        if self.cycles >= 341 {
+        let mut frame_done = false;
+        for _ in 0..cycles {
+            frame_done |= self.tick_dot();
+        }
+        frame_done
+    }
+
+    fn tick_dot(&mut self) -> bool {
+        self.cycles += 1;
+        if self.cycles == 1 {
+            self.update_vblank();
+        }
+
+        let line_length = self.line_length();
+        if self.cycles >= line_length {
             if self.is_sprite_0_hit(self.cycles) {
                 self.status.set_sprite_zero_hit(true);
             }
 
-            self.cycles = self.cycles - 341;
+            let rendering = self.rendering_enabled();
+            if rendering && self.scanline < 240 {
+                // dots 256 and 257
+                self.loopy.increment_y();
+                self.loopy.copy_horizontal();
+            } else if rendering && self.scanline == 261 {
+                // the pre-render line reloads the whole scroll position
+                self.loopy.copy_horizontal();
+                self.loopy.copy_vertical();
+            }
+
+            self.cycles -= line_length;
             self.scanline += 1;
+            if self.scanline < 240 {
+                self.record_line_scroll();
+            }
 
-            if self.scanline == 241 {
-                self.status.set_vblank_status(true);
-                self.status.set_sprite_zero_hit(false);
-                if self.ctrl.generate_vblank_nmi() {
-                    self.nmi_interrupt = Some(1);
-                }
+            if self.scanline < 240 && rendering && self.sprite_overflow(self.scanline) {
+                self.status.set_sprite_overflow(true);
             }
 
             if self.scanline >= 262 {
                 self.scanline = 0;
-                self.nmi_interrupt = None;
-                self.status.reset_vblank_status();
-                self.status.set_sprite_zero_hit(false);
+                self.odd_frame = !self.odd_frame;
+                self.io_latch_age = self.io_latch_age.saturating_add(1);
+                if self.io_latch_age >= IO_LATCH_DECAY_FRAMES {
+                    self.io_latch = 0;
+                }
+                self.record_line_scroll();
                 return true;
             }
         }
-        return false;
+        false
+    }
+
+    // Vblank starts on dot 1 of line 241, and the pre-render line clears the flags on its
+    // dot 1
+    fn update_vblank(&mut self) {
+        if self.scanline == 241 {
+            if !self.vblank_suppressed {
+                self.status.set_vblank_status(true);
+                if self.ctrl.generate_vblank_nmi() {
+                    self.nmi_interrupt = Some(1);
+                }
+            }
+            self.vblank_suppressed = false;
+        } else if self.scanline == 261 {
+            self.nmi_interrupt = None;
+            self.status.reset_vblank_status();
+            self.status.set_sprite_zero_hit(false);
+            self.status.set_sprite_overflow(false);
+        }
+    }
+
+    // The CPU only notices a new NMI a couple of dots after vblank starts, so reading $2002
+    // or turning NMIs off in that window cancels it
+    fn in_nmi_window(&self) -> bool {
+        self.scanline == 241 && (1..3).contains(&self.cycles)
     }
 
     pub fn poll_nmi_interrupt(&mut self) -> Option<u8> {
+        if self.in_nmi_window() {
+            return None;
+        }
         self.nmi_interrupt.take()
     }
 }
 
 impl PPU for NesPPU {
     fn write_to_oam_addr(&mut self, value: u8) {
+        self.refresh_io_latch(value);
         self.oam_addr = value;
     }
 
     fn write_to_oam_data(&mut self, value: u8) {
+        self.refresh_io_latch(value);
         self.oam_data[self.oam_addr as usize] = value;
-        self.write_to_oam_addr(self.oam_addr.wrapping_add(1));
+        self.oam_addr = self.oam_addr.wrapping_add(1);
+    }
+
+    fn read_oam_data(&mut self) -> u8 {
+        let data = self.peek_oam_data();
+        self.refresh_io_latch(data);
+        data
     }
 
-    fn read_oam_data(&self) -> u8 {
+    fn peek_oam_data(&self) -> u8 {
         self.oam_data[self.oam_addr as usize]
     }
 
     fn write_to_ppu_addr(&mut self, value: u8) {
-        self.addr.update(value);
+        self.refresh_io_latch(value);
+        self.loopy.write_addr(value);
     }
 
     fn write_to_ctrl(&mut self, value: u8) {
+        self.refresh_io_latch(value);
         let before_nmi_status = self.ctrl.generate_vblank_nmi();
         self.ctrl.update(value);
+        self.loopy.write_ctrl(value);
         if !before_nmi_status && self.ctrl.generate_vblank_nmi() && self.status.is_in_vblank() {
             self.nmi_interrupt = Some(1);
+        } else if !self.ctrl.generate_vblank_nmi() && self.in_nmi_window() {
+            self.nmi_interrupt = None;
         }
     }
 
     fn write_to_mask(&mut self, value: u8) {
+        self.refresh_io_latch(value);
         self.mask.update(value);
     }
 
     fn write_to_scroll(&mut self, value: u8) {
-        self.scroll.write(value);
+        self.refresh_io_latch(value);
+        self.loopy.write_scroll(value);
     }
 
     fn read_status(&mut self) -> u8 {
-        let data = self.status.snapshot();
+        // a dot before vblank starts, the read stops the flag and NMI for this frame
+        if self.scanline == 241 && self.cycles == 0 {
+            self.vblank_suppressed = true;
+        } else if self.in_nmi_window() {
+            self.nmi_interrupt = None;
+        }
+        let data = self.peek_status();
+        self.refresh_io_latch(data);
         self.status.reset_vblank_status();
-        self.addr.reset_latch();
-        self.scroll.reset_latch();
+        self.loopy.reset_latch();
         data
     }
 
+    fn peek_status(&self) -> u8 {
+        // only the top 3 bits are driven, the rest come from the I/O latch
+        (self.status.snapshot() & 0b1110_0000) | (self.io_latch & 0b0001_1111)
+    }
+
     fn write_oam_dma(&mut self, data: &[u8; 256]) {
         for x in data.iter() {
             self.oam_data[self.oam_addr as usize] = *x;
             self.oam_addr = self.oam_addr.wrapping_add(1);
         }
+        self.refresh_io_latch(data[255]);
     }
 
     fn write_to_data(&mut self, value: u8) {
-        let addr = self.addr.get();
+        self.refresh_io_latch(value);
+        let addr = self.loopy.addr();
         match addr {
-            0..=0x1fff => println!("Attempted to write to chr rom space {}", addr),
-            0x2000..=0x2fff => {
-                self.vram[self.mirror_vram_addr(addr) as usize] = value;
-            }
-            0x3000..=0x3eff => panic!(
-                "addr space 0x3000..0x3eff is not expected to be used, requested = {} ",
-                addr
-            ),
-            //Addresses $3f10/$3f14/$3f18/$3f1C are mirrors of $3f00/$3f04/$3f08/$3f0C
-            0x3f10 | 0x3f14 | 0x3f18 | 0x3f1c => {
-                let add_mirror = addr - 0x10;
-                self.palette_table[(add_mirror - 0x3f00) as usize] = value;
-            }
-            0x3f00..=0x3fff => self.palette_table[(addr - 0x3f00) as usize] = value,
-            _ => panic!("unexpected access to mirrored space {}", addr),
+            0..=0x1fff => self.write_chr(addr, value),
+            // $3000-$3EFF mirrors the nametables
+            0x2000..=0x3eff => self.bus.write_nametable(addr, value),
+            _ => self.palette_table[palette_index(addr)] = value,
         }
         self.increment_vram_addr();
     }
 
     fn read_data(&mut self) -> u8 {
-        let addr = self.addr.get();
+        let addr = self.loopy.addr();
         self.increment_vram_addr();
 
-        match addr {
+        let data = match addr {
             0..=0x1fff => {
                 let result = self.internal_data_buf;
-                self.internal_data_buf = self.chr_rom[addr as usize];
+                let mut mapper = self.bus.mapper.borrow_mut();
+                self.internal_data_buf = mapper.read_chr(addr);
+                mapper.notify_chr_fetch(addr);
                 result
             }
-            0x2000..=0x2fff => {
+            0x2000..=0x3eff => {
                 let result = self.internal_data_buf;
-                self.internal_data_buf = self.vram[self.mirror_vram_addr(addr) as usize];
+                self.internal_data_buf = self.bus.read_nametable(addr);
                 result
             }
-            0x3000..=0x3eff => panic!(
-                "addr space 0x3000..0x3eff is not expected to be used, requested = {} ",
-                addr
-            ),
-            //Addresses $3f10/$3f14/$3f18/$3f1C are mirrors of $3f00/$3f04/$3f08/$3f0C
-            0x3f10 | 0x3f14 | 0x3f18 | 0x3f1c => {
-                let add_mirror = addr - 0x10;
-                self.palette_table[(add_mirror - 0x3f00) as usize]
+            // palette reads skip the buffer, which gets the nametable byte underneath instead
+            _ => {
+                self.internal_data_buf = self.bus.read_nametable(addr - 0x1000);
+                self.read_palette(addr)
             }
-            0x3f00..=0x3fff => self.palette_table[(addr - 0x3f00) as usize],
-            _ => panic!("Unexpected access to mirrored space: {}", addr),
+        };
+        self.refresh_io_latch(data);
+        data
+    }
+    fn peek_data(&self) -> u8 {
+        match self.loopy.addr() {
+            0..=0x3eff => self.internal_data_buf,
+            addr => self.read_palette(addr),
         }
     }
 }
 
+impl Savestate for NesPPU {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_bytes(&self.bus.vram);
+        state.write_bytes(&self.oam_data);
+        state.write_bytes(&self.palette_table);
+        state.write_u8(self.oam_addr);
+        self.loopy.save_state(state);
+        state.write_u8(self.ctrl.bits());
+        state.write_u8(self.mask.bits());
+        state.write_u8(self.status.bits());
+        state.write_u8(self.internal_data_buf);
+        state.write_u8(self.io_latch);
+        state.write_u8(self.io_latch_age);
+        state.write_u16(self.scanline);
+        state.write_usize(self.cycles);
+        state.write_bool(self.nmi_interrupt.is_some());
+        state.write_bool(self.odd_frame);
+        state.write_bool(self.vblank_suppressed);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), String> {
+        state.read_bytes(&mut self.bus.vram)?;
+        state.read_bytes(&mut self.oam_data)?;
+        state.read_bytes(&mut self.palette_table)?;
+        self.oam_addr = state.read_u8()?;
+        self.loopy.load_state(state)?;
+        self.ctrl.update(state.read_u8()?);
+        self.mask.update(state.read_u8()?);
+        self.status = StatusRegister::from_bits_truncate(state.read_u8()?);
+        self.internal_data_buf = state.read_u8()?;
+        self.io_latch = state.read_u8()?;
+        self.io_latch_age = state.read_u8()?;
+        self.scanline = state.read_u16()?;
+        self.cycles = state.read_usize()?;
+        self.nmi_interrupt = if state.read_bool()? { Some(1) } else { None };
+        self.odd_frame = state.read_bool()?;
+        self.vblank_suppressed = state.read_bool()?;
+        // CHR RAM comes back with the cartridge
+        self.background_cache.borrow_mut().invalidate();
+        Ok(())
+    }
+}
+
 #[cfg(test)]
 pub mod test {
     use super::*;
@@ -239,20 +532,35 @@ pub mod test {
         ppu.write_to_ppu_addr(0x05);
         ppu.write_to_data(0x66);
 
-        assert_eq!(ppu.vram[0x0305], 0x66);
+        assert_eq!(ppu.bus.vram[0x0305], 0x66);
+    }
+
+    #[test]
+    fn test_ppu_chr_ram_writes() {
+        let mut ppu = NesPPU::new_empty_rom();
+        ppu.write_to_ppu_addr(0x01);
+        ppu.write_to_ppu_addr(0x05);
+        ppu.write_to_data(0x66);
+        assert_eq!(ppu.bus.mapper.borrow().read_chr(0x0105), 0);
+
+        let mut ppu = NesPPU::new(mapper::blank(Mirroring::Horizontal, true));
+        ppu.write_to_ppu_addr(0x01);
+        ppu.write_to_ppu_addr(0x05);
+        ppu.write_to_data(0x66);
+        assert_eq!(ppu.bus.mapper.borrow().read_chr(0x0105), 0x66);
     }
 
     #[test]
     fn test_ppu_vram_reads() {
         let mut ppu = NesPPU::new_empty_rom();
         ppu.write_to_ctrl(0);
-        ppu.vram[0x0305] = 0x66;
+        ppu.bus.vram[0x0305] = 0x66;
 
         ppu.write_to_ppu_addr(0x23);
         ppu.write_to_ppu_addr(0x05);
 
         ppu.read_data(); //load_into_buffer
-        assert_eq!(ppu.addr.get(), 0x2306);
+        assert_eq!(ppu.loopy.addr(), 0x2306);
         assert_eq!(ppu.read_data(), 0x66);
     }
 
@@ -260,8 +568,8 @@ pub mod test {
     fn test_ppu_vram_reads_cross_page() {
         let mut ppu = NesPPU::new_empty_rom();
         ppu.write_to_ctrl(0);
-        ppu.vram[0x01ff] = 0x66;
-        ppu.vram[0x0200] = 0x77;
+        ppu.bus.vram[0x01ff] = 0x66;
+        ppu.bus.vram[0x0200] = 0x77;
 
         ppu.write_to_ppu_addr(0x21);
         ppu.write_to_ppu_addr(0xff);
@@ -275,9 +583,9 @@ pub mod test {
     fn test_ppu_vram_reads_step_32() {
         let mut ppu = NesPPU::new_empty_rom();
         ppu.write_to_ctrl(0b100);
-        ppu.vram[0x01ff] = 0x66;
-        ppu.vram[0x01ff + 32] = 0x77;
-        ppu.vram[0x01ff + 64] = 0x88;
+        ppu.bus.vram[0x01ff] = 0x66;
+        ppu.bus.vram[0x01ff + 32] = 0x77;
+        ppu.bus.vram[0x01ff + 64] = 0x88;
 
         ppu.write_to_ppu_addr(0x21);
         ppu.write_to_ppu_addr(0xff);
@@ -317,12 +625,31 @@ pub mod test {
         assert_eq!(ppu.read_data(), 0x77); //read from b
     }
 
+    #[test]
+    fn test_single_screen_vram() {
+        let mut ppu = NesPPU::new(mapper::blank(Mirroring::SingleScreenUpper, false));
+        ppu.poke_vram(0x2c05, 0x66);
+        assert_eq!(ppu.bus.vram[0x0405], 0x66);
+        assert_eq!(ppu.peek_vram(0x2005), 0x66);
+    }
+
+    #[test]
+    fn test_four_screen_vram() {
+        let mut ppu = NesPPU::new(mapper::blank(Mirroring::FourScreen, false));
+        for (i, addr) in [0x2005u16, 0x2405, 0x2805, 0x2c05].iter().enumerate() {
+            ppu.poke_vram(*addr, i as u8 + 1);
+        }
+        assert_eq!(ppu.bus.vram[0x0c05], 4);
+        assert_eq!(ppu.peek_vram(0x2805), 3);
+        assert_eq!(ppu.peek_vram(0x3405), 2);
+    }
+
     // Vertical: https://wiki.nesdev.com/w/index.php/Mirroring
     //   [0x2000 A ] [0x2400 B ]
     //   [0x2800 a ] [0x2C00 b ]
     #[test]
     fn test_vram_vertical_mirror() {
-        let mut ppu = NesPPU::new(vec![0; 2048], Mirroring::Vertical);
+        let mut ppu = NesPPU::new(mapper::blank(Mirroring::Vertical, false));
 
         ppu.write_to_ppu_addr(0x20);
         ppu.write_to_ppu_addr(0x05);
@@ -350,7 +677,7 @@ pub mod test {
     #[test]
     fn test_read_status_resets_latch() {
         let mut ppu = NesPPU::new_empty_rom();
-        ppu.vram[0x0305] = 0x66;
+        ppu.bus.vram[0x0305] = 0x66;
 
         ppu.write_to_ppu_addr(0x21);
         ppu.write_to_ppu_addr(0x23);
@@ -372,14 +699,28 @@ pub mod test {
     fn test_ppu_vram_mirroring() {
         let mut ppu = NesPPU::new_empty_rom();
         ppu.write_to_ctrl(0);
-        ppu.vram[0x0305] = 0x66;
+        ppu.bus.vram[0x0305] = 0x66;
 
         ppu.write_to_ppu_addr(0x63); //0x6305 -> 0x2305
         ppu.write_to_ppu_addr(0x05);
 
         ppu.read_data(); //load into_buffer
         assert_eq!(ppu.read_data(), 0x66);
-        // assert_eq!(ppu.addr.read(), 0x0306)
+        // assert_eq!(ppu.loopy.addr(), 0x0306)
+    }
+
+    #[test]
+    fn test_ppu_nametable_mirror_at_3000() {
+        let mut ppu = NesPPU::new_empty_rom();
+        ppu.write_to_ppu_addr(0x33);
+        ppu.write_to_ppu_addr(0x05);
+        ppu.write_to_data(0x66);
+        assert_eq!(ppu.bus.vram[0x0305], 0x66);
+
+        ppu.write_to_ppu_addr(0x3f);
+        ppu.write_to_ppu_addr(0x31);
+        ppu.write_to_data(0x21);
+        assert_eq!(ppu.palette_table[0x11], 0x21);
     }
 
     #[test]
@@ -393,6 +734,118 @@ pub mod test {
         assert_eq!(ppu.status.snapshot() >> 7, 0);
     }
 
+    #[test]
+    fn test_peek_has_no_side_effects() {
+        let mut ppu = NesPPU::new_empty_rom();
+        ppu.status.set_vblank_status(true);
+        ppu.bus.vram[0x0305] = 0x66;
+        ppu.write_to_ppu_addr(0x23);
+        ppu.write_to_ppu_addr(0x05);
+
+        assert_eq!(ppu.peek_status() >> 7, 1);
+        assert!(ppu.status.is_in_vblank());
+
+        ppu.peek_data();
+        assert_eq!(ppu.loopy.addr(), 0x2305);
+        ppu.read_data(); //load_into_buffer
+        assert_eq!(ppu.peek_data(), 0x66);
+        assert_eq!(ppu.read_data(), 0x66);
+    }
+
+    #[test]
+    fn test_io_latch_fills_status_and_decays() {
+        let mut ppu = NesPPU::new_empty_rom();
+        ppu.status.set_vblank_status(true);
+        ppu.write_to_mask(0b0001_0110);
+        assert_eq!(ppu.read_status(), 0b1001_0110);
+        assert_eq!(ppu.io_latch(), 0b1001_0110);
+
+        for _ in 0..(IO_LATCH_DECAY_FRAMES as usize * 262) {
+            ppu.tick(114);
+            ppu.tick(227);
+        }
+        assert_eq!(ppu.io_latch(), 0);
+        assert_eq!(ppu.peek_status() & 0b0001_1111, 0);
+    }
+
+    #[test]
+    fn test_sprite_0_hit_clipped_in_left_column() {
+        let mut ppu = NesPPU::new_empty_rom();
+        ppu.oam_data[0] = 0;
+        ppu.oam_data[3] = 4;
+
+        ppu.write_to_mask(0b0001_1000);
+        assert!(!ppu.is_sprite_0_hit(341));
+
+        ppu.write_to_mask(0b0001_1110);
+        assert!(ppu.is_sprite_0_hit(341));
+    }
+
+    fn run_scanline(ppu: &mut NesPPU) {
+        ppu.tick(255);
+        ppu.tick(86);
+    }
+
+    #[test]
+    fn test_mid_frame_scroll_write_splits_screen() {
+        let mut ppu = NesPPU::new_empty_rom();
+        ppu.write_to_mask(0b0000_1000);
+        ppu.scanline = 261;
+        for _ in 0..=100 {
+            run_scanline(&mut ppu);
+        }
+        assert_eq!(ppu.scanline, 100);
+
+        ppu.read_status();
+        ppu.write_to_scroll(16);
+        ppu.write_to_scroll(0);
+        run_scanline(&mut ppu);
+
+        let (before, _) = ppu.line_scroll[100];
+        let (after, _) = ppu.line_scroll[101];
+        assert_eq!(before & 0b1_1111, 0);
+        assert_eq!(after & 0b1_1111, 2);
+        // the vertical position keeps counting down the screen
+        assert_eq!((after >> 5) & 0b1_1111, 101 / 8);
+        assert_eq!(after >> 12, 101 % 8);
+    }
+
+    #[test]
+    fn test_sprite_limit_and_overflow() {
+        let mut ppu = NesPPU::new_empty_rom();
+        for sprite in ppu.oam_data.chunks_mut(4) {
+            sprite[0] = 0xff;
+        }
+        for n in 0..9 {
+            ppu.oam_data[n * 4] = 10;
+        }
+        assert_eq!(ppu.sprites_on_scanline(12), (0..8).collect::<Vec<_>>());
+        assert!(ppu.sprite_overflow(12));
+        assert!(!ppu.sprite_overflow(20));
+
+        ppu.sprite_limit = false;
+        assert_eq!(ppu.sprites_on_scanline(12).len(), 9);
+    }
+
+    #[test]
+    fn test_sprite_overflow_evaluation_bug() {
+        let mut ppu = NesPPU::new_empty_rom();
+        for sprite in ppu.oam_data.chunks_mut(4) {
+            sprite[0] = 0xff;
+        }
+        for n in 0..8 {
+            ppu.oam_data[n * 4] = 10;
+        }
+        // the 9th sprite isn't in range, but the 10th sprite's tile number is read as a Y
+        ppu.oam_data[9 * 4 + 1] = 10;
+        assert!(ppu.sprite_overflow(12));
+
+        // while a real 9th sprite at the offset that gets skipped is missed
+        ppu.oam_data[9 * 4 + 1] = 0;
+        ppu.oam_data[9 * 4] = 10;
+        assert!(!ppu.sprite_overflow(12));
+    }
+
     #[test]
     fn test_oam_read_write() {
         let mut ppu = NesPPU::new_empty_rom();
@@ -426,4 +879,126 @@ pub mod test {
         ppu.write_to_oam_addr(0x11);
         ppu.write_to_oam_addr(0x66);
     }
+
+    #[test]
+    fn test_odd_frames_skip_a_dot_while_rendering() {
+        let frame_length = |ppu: &mut NesPPU| (1..).find(|_| ppu.tick(1)).unwrap();
+        let mut ppu = NesPPU::new_empty_rom();
+        assert_eq!(frame_length(&mut ppu), 341 * 262);
+        assert_eq!(frame_length(&mut ppu), 341 * 262);
+
+        ppu.write_to_mask(0b0000_1000);
+        assert_eq!(frame_length(&mut ppu), 341 * 262);
+        assert_eq!(frame_length(&mut ppu), 341 * 262 - 1);
+        assert_eq!(frame_length(&mut ppu), 341 * 262);
+    }
+
+    #[test]
+    fn test_palette_reads_fill_the_buffer_from_the_nametable() {
+        let mut ppu = NesPPU::new_empty_rom();
+        ppu.write_to_ppu_addr(0x2f);
+        ppu.write_to_ppu_addr(0x05);
+        ppu.write_to_data(0x66);
+        ppu.write_to_ppu_addr(0x3f);
+        ppu.write_to_ppu_addr(0x05);
+        ppu.write_to_data(0x12);
+
+        ppu.write_to_ppu_addr(0x3f);
+        ppu.write_to_ppu_addr(0x05);
+        assert_eq!(ppu.read_data(), 0x12);
+        // the buffer now holds $2F05, not the palette entry
+        ppu.write_to_ppu_addr(0x20);
+        ppu.write_to_ppu_addr(0x00);
+        assert_eq!(ppu.read_data(), 0x66);
+    }
+
+    fn run_to_vblank_start(ppu: &mut NesPPU) {
+        while !(ppu.scanline == 241 && ppu.cycles == 0) {
+            ppu.tick(1);
+        }
+    }
+
+    #[test]
+    fn test_status_read_races_vblank() {
+        let mut ppu = NesPPU::new_empty_rom();
+        ppu.write_to_ctrl(0b1000_0000);
+
+        // a dot early: no flag and no NMI this frame
+        run_to_vblank_start(&mut ppu);
+        assert_eq!(ppu.read_status() & 0x80, 0);
+        ppu.tick(10);
+        assert!(!ppu.status.is_in_vblank());
+        assert_eq!(ppu.poll_nmi_interrupt(), None);
+
+        // on the dot it's set: the flag is seen, the NMI is lost
+        ppu.tick(1);
+        run_to_vblank_start(&mut ppu);
+        ppu.tick(1);
+        assert_eq!(ppu.read_status() & 0x80, 0x80);
+        ppu.tick(10);
+        assert_eq!(ppu.poll_nmi_interrupt(), None);
+
+        // a few dots later both happen
+        ppu.tick(1);
+        run_to_vblank_start(&mut ppu);
+        ppu.tick(3);
+        assert_eq!(ppu.poll_nmi_interrupt(), Some(1));
+        assert_eq!(ppu.read_status() & 0x80, 0x80);
+    }
+
+    #[test]
+    fn test_disabling_nmi_as_vblank_starts_cancels_it() {
+        let mut ppu = NesPPU::new_empty_rom();
+        ppu.write_to_ctrl(0b1000_0000);
+        run_to_vblank_start(&mut ppu);
+        ppu.tick(2);
+        ppu.write_to_ctrl(0);
+        ppu.tick(10);
+        assert_eq!(ppu.poll_nmi_interrupt(), None);
+
+        // turning them back on while the flag is still up raises one late
+        ppu.write_to_ctrl(0b1000_0000);
+        assert_eq!(ppu.poll_nmi_interrupt(), Some(1));
+    }
+
+    #[test]
+    fn test_palette_mirrors() {
+        let mut ppu = NesPPU::new_empty_rom();
+        let write = |ppu: &mut NesPPU, addr: u16, value: u8| {
+            ppu.write_to_ppu_addr((addr >> 8) as u8);
+            ppu.write_to_ppu_addr(addr as u8);
+            ppu.write_to_data(value);
+        };
+        let read = |ppu: &mut NesPPU, addr: u16| {
+            ppu.write_to_ppu_addr((addr >> 8) as u8);
+            ppu.write_to_ppu_addr(addr as u8);
+            ppu.read_data()
+        };
+
+        for (i, addr) in [0x3f10, 0x3f14, 0x3f18, 0x3f1c].iter().enumerate() {
+            write(&mut ppu, *addr, 0x20 + i as u8);
+            assert_eq!(read(&mut ppu, *addr - 0x10), 0x20 + i as u8);
+            write(&mut ppu, *addr - 0x10, 0x30 + i as u8);
+            assert_eq!(read(&mut ppu, *addr), 0x30 + i as u8);
+        }
+        // the other sprite entries are their own
+        write(&mut ppu, 0x3f11, 0x01);
+        write(&mut ppu, 0x3f01, 0x02);
+        assert_eq!(read(&mut ppu, 0x3f11), 0x01);
+        // and the whole thing repeats every 32 bytes
+        assert_eq!(read(&mut ppu, 0x3f2c), 0x33);
+    }
+
+    #[test]
+    fn test_backdrop() {
+        let mut ppu = NesPPU::new_empty_rom();
+        ppu.palette_table[0] = 0x0f;
+        ppu.palette_table[5] = 0x16;
+        ppu.write_to_ppu_addr(0x3f);
+        ppu.write_to_ppu_addr(0x05);
+        assert_eq!(ppu.backdrop(), 0x16);
+
+        ppu.write_to_mask(0b0000_1000);
+        assert_eq!(ppu.backdrop(), 0x0f);
+    }
 }
@@ -17,13 +17,7 @@ impl CtrlRegister {
     }
 
     pub fn nametable_addr(&self) -> u16 {
-        match self.bits & 0b11 {
-            0 => 0x2000,
-            1 => 0x2400,
-            2 => 0x2800,
-            3 => 0x2c00,
-            _ => panic!("not possible"),
-        }
+        0x2000 | (self.bits as u16 & 0b11) << 10
     }
 
     pub fn vram_addr_increment(&self) -> u8 {
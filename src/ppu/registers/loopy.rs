@@ -0,0 +1,164 @@
+use crate::savestate::{Savestate, StateReader, StateWriter};
+
+const COARSE_X: u16 = 0x001f;
+const COARSE_Y: u16 = 0x03e0;
+const NAMETABLE_X: u16 = 0x0400;
+const NAMETABLE_Y: u16 = 0x0800;
+const FINE_Y: u16 = 0x7000;
+
+const HORIZONTAL: u16 = NAMETABLE_X | COARSE_X;
+const VERTICAL: u16 = FINE_Y | NAMETABLE_Y | COARSE_Y;
+
+/// The PPU's internal scroll and address registers, shared by $2000, $2005, $2006 and $2007.
+/// See https://wiki.nesdev.com/w/index.php/PPU_scrolling
+///
+/// `v` (the current VRAM address) and `t` (the temporary one) are both laid out as
+/// yyy NN YYYYY XXXXX: fine Y, nametable, coarse Y and coarse X.
+pub struct LoopyRegister {
+    pub v: u16,
+    pub t: u16,
+    pub fine_x: u8,
+    w: bool,
+}
+
+impl Default for LoopyRegister {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl LoopyRegister {
+    pub fn new() -> Self {
+        LoopyRegister {
+            v: 0,
+            t: 0,
+            fine_x: 0,
+            w: false,
+        }
+    }
+
+    /// $2000 selects the base nametable.
+    pub fn write_ctrl(&mut self, data: u8) {
+        self.t = (self.t & !(NAMETABLE_X | NAMETABLE_Y)) | ((data as u16 & 0b11) << 10);
+    }
+
+    pub fn write_scroll(&mut self, data: u8) {
+        let data = data as u16;
+        if !self.w {
+            self.t = (self.t & !COARSE_X) | (data >> 3);
+            self.fine_x = (data & 0b111) as u8;
+        } else {
+            self.t = (self.t & !(FINE_Y | COARSE_Y)) | ((data & 0b111) << 12) | ((data >> 3) << 5);
+        }
+        self.w = !self.w;
+    }
+
+    pub fn write_addr(&mut self, data: u8) {
+        if !self.w {
+            // the top bit of the 15-bit register is cleared by the first write
+            self.t = (self.t & 0x00ff) | ((data as u16 & 0b0011_1111) << 8);
+        } else {
+            self.t = (self.t & 0xff00) | data as u16;
+            self.v = self.t;
+        }
+        self.w = !self.w;
+    }
+
+    pub fn reset_latch(&mut self) {
+        self.w = false;
+    }
+
+    /// The VRAM address seen by $2007.
+    pub fn addr(&self) -> u16 {
+        self.v & 0x3fff
+    }
+
+    pub fn increment(&mut self, inc: u8) {
+        self.v = self.v.wrapping_add(inc as u16) & 0x7fff;
+    }
+
+    /// Done at the end of every visible scanline, moving `v` down one pixel row.
+    pub fn increment_y(&mut self) {
+        if self.v & FINE_Y != FINE_Y {
+            self.v += 1 << 12;
+            return;
+        }
+
+        self.v &= !FINE_Y;
+        let mut coarse_y = (self.v & COARSE_Y) >> 5;
+        if coarse_y == 29 {
+            coarse_y = 0;
+            self.v ^= NAMETABLE_Y;
+        } else if coarse_y == 31 {
+            // rows 30 and 31 hold the attribute table, wrapping there stays in the nametable
+            coarse_y = 0;
+        } else {
+            coarse_y += 1;
+        }
+        self.v = (self.v & !COARSE_Y) | (coarse_y << 5);
+    }
+
+    pub fn copy_horizontal(&mut self) {
+        self.v = (self.v & !HORIZONTAL) | (self.t & HORIZONTAL);
+    }
+
+    pub fn copy_vertical(&mut self) {
+        self.v = (self.v & !VERTICAL) | (self.t & VERTICAL);
+    }
+}
+
+impl Savestate for LoopyRegister {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_u16(self.v);
+        state.write_u16(self.t);
+        state.write_u8(self.fine_x);
+        state.write_bool(self.w);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), String> {
+        self.v = state.read_u16()?;
+        self.t = state.read_u16()?;
+        self.fine_x = state.read_u8()?;
+        self.w = state.read_bool()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_scroll_and_addr_share_latch() {
+        let mut loopy = LoopyRegister::new();
+        loopy.write_ctrl(0b10);
+        loopy.write_scroll(0b0111_1101);
+        loopy.write_scroll(0b0101_1110);
+        // fine Y 6, nametable 2, coarse Y 11, coarse X 15
+        assert_eq!(loopy.t, 0x696f);
+        assert_eq!(loopy.fine_x, 0b101);
+
+        // after a lone $2005 write, the next $2006 write is taken as the low byte
+        loopy.write_scroll(0);
+        loopy.write_addr(0x3d);
+        assert_eq!(loopy.v & 0xff, 0x3d);
+
+        loopy.reset_latch();
+        loopy.write_addr(0x3d);
+        loopy.write_addr(0xf0);
+        assert_eq!(loopy.v, 0x3df0);
+        assert_eq!(loopy.addr(), 0x3df0);
+    }
+
+    #[test]
+    fn test_increment_y_wraps_into_next_nametable() {
+        let mut loopy = LoopyRegister::new();
+        loopy.v = FINE_Y | (29 << 5);
+        loopy.increment_y();
+        assert_eq!(loopy.v, NAMETABLE_Y);
+
+        loopy.v = FINE_Y | (31 << 5);
+        loopy.increment_y();
+        assert_eq!(loopy.v, 0);
+    }
+}
@@ -1,5 +1,4 @@
-pub mod addr;
 pub mod ctrl;
+pub mod loopy;
 pub mod mask;
-pub mod scroll;
 pub mod status;
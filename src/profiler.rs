@@ -0,0 +1,176 @@
+use crate::cpu::{Mem, CPU};
+use crate::opcodes::OPCODES;
+use std::collections::HashMap;
+use std::fmt::Write;
+
+// lines of the report given to the busiest addresses
+const TOP_LOCATIONS: usize = 40;
+
+/// Where an instruction was fetched from. Code in the cartridge is told apart by the PRG
+/// bank it was in; code running from RAM has no bank.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Location {
+    pub bank: Option<usize>,
+    pub addr: u16,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Counts {
+    pub instructions: u64,
+    pub cycles: u64,
+}
+
+/// Counts the instructions run and the cycles spent at every address and on every
+/// mnemonic, for finding the hot loops in a game. Cycles stolen by DMA and spent taking an
+/// interrupt are charged to the instruction before.
+#[derive(Default)]
+pub struct Profiler {
+    locations: HashMap<Location, (&'static str, Counts)>,
+    mnemonics: HashMap<&'static str, Counts>,
+    // the instruction about to run, and the cycle count when it started
+    current: Option<(Location, &'static str, usize)>,
+}
+
+impl Profiler {
+    pub fn new() -> Self {
+        Profiler {
+            locations: HashMap::new(),
+            mnemonics: HashMap::new(),
+            current: None,
+        }
+    }
+
+    /// Called before every instruction.
+    pub fn on_instruction(&mut self, cpu: &CPU) {
+        let pc = cpu.program_counter;
+        let location = Location {
+            bank: match pc {
+                0x8000..=0xffff => Some(cpu.bus.ppu.bus.mapper.borrow().prg_bank(pc)),
+                _ => None,
+            },
+            addr: pc,
+        };
+        let mnemonic = OPCODES[cpu.mem_peek(pc) as usize].map_or("???", |op| op.mnemonic);
+        self.record(location, mnemonic, cpu.bus.cycles());
+    }
+
+    fn record(&mut self, location: Location, mnemonic: &'static str, cycles: usize) {
+        if let Some((previous, previous_mnemonic, start)) = self.current {
+            let spent = (cycles - start) as u64;
+            let (_, counts) = self
+                .locations
+                .entry(previous)
+                .or_insert((previous_mnemonic, Counts::default()));
+            counts.instructions += 1;
+            counts.cycles += spent;
+            let counts = self.mnemonics.entry(previous_mnemonic).or_default();
+            counts.instructions += 1;
+            counts.cycles += spent;
+        }
+        self.current = Some((location, mnemonic, cycles));
+    }
+
+    pub fn total(&self) -> Counts {
+        self.mnemonics
+            .values()
+            .fold(Counts::default(), |total, counts| Counts {
+                instructions: total.instructions + counts.instructions,
+                cycles: total.cycles + counts.cycles,
+            })
+    }
+
+    /// The busiest addresses and mnemonics, most cycles first.
+    pub fn report(&self) -> String {
+        let total = self.total();
+        let share = |cycles: u64| 100.0 * cycles as f64 / total.cycles.max(1) as f64;
+        let mut report = format!(
+            "Profile: {} instructions, {} cycles\n\n",
+            total.instructions, total.cycles
+        );
+
+        let mut locations: Vec<_> = self.locations.iter().collect();
+        locations.sort_by(|a, b| (b.1).1.cycles.cmp(&(a.1).1.cycles).then(a.0.cmp(b.0)));
+        report.push_str("BANK ADDR  OP         CYCLES      %     INSTRS\n");
+        for (location, (mnemonic, counts)) in locations.into_iter().take(TOP_LOCATIONS) {
+            let bank = location
+                .bank
+                .map_or("--".to_string(), |bank| format!("{:02X}", bank));
+            writeln!(
+                report,
+                "{:>4} {:04X}  {:<4} {:>12} {:>5.1}% {:>10}",
+                bank,
+                location.addr,
+                mnemonic,
+                counts.cycles,
+                share(counts.cycles),
+                counts.instructions
+            )
+            .unwrap();
+        }
+
+        let mut mnemonics: Vec<_> = self.mnemonics.iter().collect();
+        mnemonics.sort_by(|a, b| b.1.cycles.cmp(&a.1.cycles).then(a.0.cmp(b.0)));
+        report.push_str("\nOP         CYCLES      %     INSTRS\n");
+        for (mnemonic, counts) in mnemonics {
+            writeln!(
+                report,
+                "{:<4} {:>12} {:>5.1}% {:>10}",
+                mnemonic,
+                counts.cycles,
+                share(counts.cycles),
+                counts.instructions
+            )
+            .unwrap();
+        }
+        report
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bus::Bus;
+    use crate::cpu::CpuState;
+    use crate::rom::test;
+
+    #[test]
+    fn test_counts_a_loop() {
+        let mut cpu = CPU::new(Bus::new(test::test_rom()).unwrap());
+        // LDX #$03; loop: DEX; BNE loop; BRK
+        cpu.load(vec![0xa2, 0x03, 0xca, 0xd0, 0xfd, 0x00]);
+        cpu.reset();
+        cpu.program_counter = 0x0600;
+
+        let mut profiler = Profiler::new();
+        let state = cpu.run_with_callback(|cpu| profiler.on_instruction(cpu));
+        assert_eq!(state, CpuState::Halted);
+
+        let dex = profiler.locations[&Location {
+            bank: None,
+            addr: 0x0602,
+        }];
+        assert_eq!(
+            dex,
+            (
+                "DEX",
+                Counts {
+                    instructions: 3,
+                    cycles: 6
+                }
+            )
+        );
+        // two taken branches and one that falls through
+        assert_eq!(
+            profiler.mnemonics["BNE"],
+            Counts {
+                instructions: 3,
+                cycles: 3 + 3 + 2
+            }
+        );
+        assert_eq!(profiler.total().instructions, 7);
+
+        let report = profiler.report();
+        assert!(report.contains("Profile: 7 instructions, 16 cycles"));
+        assert!(report.contains("  -- 0602  DEX             6  37.5%          3"));
+    }
+}
@@ -0,0 +1,123 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// SplitMix64, small and good enough for filling memory. The same seed always gives the
+/// same numbers, on every machine.
+pub struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        Rng { state: seed }
+    }
+
+    /// A seed that's different every run.
+    pub fn clock_seed() -> u64 {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        now.as_nanos() as u64
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    pub fn next_u8(&mut self) -> u8 {
+        (self.next_u64() >> 56) as u8
+    }
+}
+
+/// What the internal RAM holds when the console is switched on. The real thing comes up
+/// with whatever the chips settle on, and some games (or their bugs) depend on it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RamInit {
+    #[default]
+    Zero,
+    Ff,
+    /// Four bytes of $00 then four of $FF, over and over, which is close to what a lot of
+    /// consoles show.
+    Alternating,
+    /// The same random bytes every time for a given seed. Without one, the bytes come from
+    /// the console's seed and change with every power cycle.
+    Random(Option<u64>),
+}
+
+impl RamInit {
+    /// `zero`, `ff`, `alternating`, `random` or `random:SEED`.
+    pub fn parse(name: &str) -> Result<RamInit, String> {
+        match name {
+            "zero" => Ok(RamInit::Zero),
+            "ff" => Ok(RamInit::Ff),
+            "alternating" => Ok(RamInit::Alternating),
+            "random" => Ok(RamInit::Random(None)),
+            _ => match name.strip_prefix("random:") {
+                Some(seed) => seed
+                    .parse()
+                    .map(|seed| RamInit::Random(Some(seed)))
+                    .map_err(|_| format!("Bad RAM seed {}", seed)),
+                None => Err(format!(
+                    "Unknown RAM pattern {}, expected zero, ff, alternating or random[:SEED]",
+                    name
+                )),
+            },
+        }
+    }
+
+    /// Fills `ram`, taking anything random from `rng` unless there's a seed of our own.
+    pub fn fill(&self, ram: &mut [u8], rng: &mut Rng) {
+        match self {
+            RamInit::Zero => ram.iter_mut().for_each(|byte| *byte = 0),
+            RamInit::Ff => ram.iter_mut().for_each(|byte| *byte = 0xff),
+            RamInit::Alternating => {
+                for (i, byte) in ram.iter_mut().enumerate() {
+                    *byte = if i & 4 == 0 { 0x00 } else { 0xff };
+                }
+            }
+            RamInit::Random(seed) => {
+                let mut rng = Rng::new(seed.unwrap_or_else(|| rng.next_u64()));
+                ram.iter_mut().for_each(|byte| *byte = rng.next_u8());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_patterns() {
+        let mut rng = Rng::new(0);
+        let mut ram = [0x55; 16];
+        RamInit::Alternating.fill(&mut ram, &mut rng);
+        assert_eq!(ram[..8], [0, 0, 0, 0, 0xff, 0xff, 0xff, 0xff]);
+        assert_eq!(ram[8..], ram[..8]);
+        RamInit::Ff.fill(&mut ram, &mut rng);
+        assert_eq!(ram, [0xff; 16]);
+
+        let mut again = [0; 16];
+        RamInit::parse("random:42")
+            .unwrap()
+            .fill(&mut ram, &mut rng);
+        RamInit::Random(Some(42)).fill(&mut again, &mut rng);
+        assert_eq!(ram, again);
+        RamInit::Random(Some(43)).fill(&mut again, &mut rng);
+        assert_ne!(ram, again);
+
+        // without a seed of its own, the same seeded rng gives the same bytes
+        RamInit::Random(None).fill(&mut ram, &mut Rng::new(7));
+        RamInit::Random(None).fill(&mut again, &mut Rng::new(7));
+        assert_eq!(ram, again);
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert!(RamInit::parse("random:x").is_err());
+        assert!(RamInit::parse("ones").is_err());
+    }
+}
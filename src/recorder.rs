@@ -0,0 +1,214 @@
+use crate::apu::filter::SAMPLE_RATE;
+use crate::render::frame::Frame;
+use std::fs::File;
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::process::{Child, Command, Stdio};
+
+const WIDTH: usize = 256;
+const HEIGHT: usize = 240;
+// NTSC runs at 39375000/655171 = 60.0988 frames per second
+const Y4M_HEADER: &str = "YUV4MPEG2 W256 H240 F39375000:655171 Ip A1:1 C444\n";
+const FFMPEG_RATE: &str = "39375000/655171";
+
+/// 16-bit mono PCM. The sizes in the header are filled in by `finish`.
+pub struct WavWriter<W: Write + Seek> {
+    out: W,
+    samples: u32,
+}
+
+impl<W: Write + Seek> WavWriter<W> {
+    pub fn new(mut out: W) -> io::Result<Self> {
+        let byte_rate = SAMPLE_RATE * 2;
+        out.write_all(b"RIFF\0\0\0\0WAVEfmt ")?;
+        out.write_all(&16u32.to_le_bytes())?;
+        // PCM, 1 channel
+        out.write_all(&1u16.to_le_bytes())?;
+        out.write_all(&1u16.to_le_bytes())?;
+        out.write_all(&SAMPLE_RATE.to_le_bytes())?;
+        out.write_all(&byte_rate.to_le_bytes())?;
+        // 2 bytes per frame, 16 bits per sample
+        out.write_all(&2u16.to_le_bytes())?;
+        out.write_all(&16u16.to_le_bytes())?;
+        out.write_all(b"data\0\0\0\0")?;
+        Ok(WavWriter { out, samples: 0 })
+    }
+
+    pub fn write_samples(&mut self, samples: &[f32]) -> io::Result<()> {
+        for sample in samples {
+            let pcm = (sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16;
+            self.out.write_all(&pcm.to_le_bytes())?;
+        }
+        self.samples += samples.len() as u32;
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> io::Result<W> {
+        let data_size = self.samples * 2;
+        self.out.seek(SeekFrom::Start(4))?;
+        self.out.write_all(&(36 + data_size).to_le_bytes())?;
+        self.out.seek(SeekFrom::Start(40))?;
+        self.out.write_all(&data_size.to_le_bytes())?;
+        self.out.flush()?;
+        Ok(self.out)
+    }
+}
+
+// BT.601 studio range, one chroma sample per pixel
+fn write_y4m_frame(out: &mut impl Write, frame: &Frame) -> io::Result<()> {
+    let mut planes = vec![0u8; WIDTH * HEIGHT * 3];
+    for (i, rgb) in frame.data.chunks(3).enumerate() {
+        let (r, g, b) = (rgb[0] as f32, rgb[1] as f32, rgb[2] as f32);
+        planes[i] = (16.0 + 0.257 * r + 0.504 * g + 0.098 * b).round() as u8;
+        planes[WIDTH * HEIGHT + i] = (128.0 - 0.148 * r - 0.291 * g + 0.439 * b).round() as u8;
+        planes[WIDTH * HEIGHT * 2 + i] = (128.0 + 0.439 * r - 0.368 * g - 0.071 * b).round() as u8;
+    }
+    out.write_all(b"FRAME\n")?;
+    out.write_all(&planes)
+}
+
+enum Video {
+    Y4m(BufWriter<File>),
+    // encodes into a temporary file, which gets the audio muxed in at the end
+    Ffmpeg { process: Child, path: String },
+}
+
+/// Captures gameplay for `--record`. A .y4m path is written directly, with the sound in a
+/// .wav file next to it. Anything else is encoded by `ffmpeg`, which has to be on the PATH.
+pub struct Recorder {
+    path: String,
+    video: Video,
+    audio: WavWriter<BufWriter<File>>,
+    audio_path: String,
+}
+
+impl Recorder {
+    pub fn new(path: &str) -> Result<Recorder, String> {
+        let stem = path.rsplit_once('.').map_or(path, |(stem, _)| stem);
+        let audio_path = format!("{}.wav", stem);
+
+        let video = if path.ends_with(".y4m") {
+            let mut out = BufWriter::new(File::create(path).map_err(|e| e.to_string())?);
+            out.write_all(Y4M_HEADER.as_bytes())
+                .map_err(|e| e.to_string())?;
+            Video::Y4m(out)
+        } else {
+            let extension = path.rsplit_once('.').map_or("mkv", |(_, ext)| ext);
+            let video_path = format!("{}.video.{}", stem, extension);
+            let process = Command::new("ffmpeg")
+                .args([
+                    "-y",
+                    "-loglevel",
+                    "error",
+                    "-f",
+                    "rawvideo",
+                    "-pix_fmt",
+                    "rgb24",
+                ])
+                .args(["-s", "256x240", "-framerate", FFMPEG_RATE, "-i", "-"])
+                .arg(&video_path)
+                .stdin(Stdio::piped())
+                .spawn()
+                .map_err(|e| format!("Can't start ffmpeg: {}", e))?;
+            Video::Ffmpeg {
+                process,
+                path: video_path,
+            }
+        };
+
+        let audio_file = File::create(&audio_path).map_err(|e| e.to_string())?;
+        let audio = WavWriter::new(BufWriter::new(audio_file)).map_err(|e| e.to_string())?;
+        Ok(Recorder {
+            path: path.to_string(),
+            video,
+            audio,
+            audio_path,
+        })
+    }
+
+    /// Adds one frame of video, and the sound generated along with it.
+    pub fn add_frame(&mut self, frame: &Frame, samples: &[f32]) -> Result<(), String> {
+        match &mut self.video {
+            Video::Y4m(out) => write_y4m_frame(out, frame),
+            Video::Ffmpeg { process, .. } => process.stdin.as_mut().unwrap().write_all(&frame.data),
+        }
+        .and_then(|_| self.audio.write_samples(samples))
+        .map_err(|e| e.to_string())
+    }
+
+    pub fn finish(self) -> Result<(), String> {
+        self.audio.finish().map_err(|e| e.to_string())?;
+        let (mut process, video_path) = match self.video {
+            Video::Y4m(mut out) => return out.flush().map_err(|e| e.to_string()),
+            Video::Ffmpeg { process, path } => (process, path),
+        };
+
+        // closing stdin lets ffmpeg finish the video
+        drop(process.stdin.take());
+        let encoded = process.wait().map_err(|e| e.to_string())?;
+        if !encoded.success() {
+            return Err(format!("ffmpeg failed with {}", encoded));
+        }
+        let muxed = Command::new("ffmpeg")
+            .args([
+                "-y",
+                "-loglevel",
+                "error",
+                "-i",
+                &video_path,
+                "-i",
+                &self.audio_path,
+            ])
+            .args(["-c:v", "copy", "-shortest", &self.path])
+            .status()
+            .map_err(|e| e.to_string())?;
+        if !muxed.success() {
+            return Err(format!("ffmpeg failed with {}", muxed));
+        }
+        let _ = std::fs::remove_file(&video_path);
+        let _ = std::fs::remove_file(&self.audio_path);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_wav_header() {
+        let mut wav = WavWriter::new(Cursor::new(Vec::new())).unwrap();
+        wav.write_samples(&[0.0, 1.0, -2.0]).unwrap();
+        let data = wav.finish().unwrap().into_inner();
+
+        assert_eq!(data.len(), 44 + 6);
+        assert_eq!(&data[0..4], b"RIFF");
+        assert_eq!(
+            u32::from_le_bytes([data[4], data[5], data[6], data[7]]),
+            36 + 6
+        );
+        assert_eq!(
+            u32::from_le_bytes([data[24], data[25], data[26], data[27]]),
+            SAMPLE_RATE
+        );
+        assert_eq!(
+            u32::from_le_bytes([data[40], data[41], data[42], data[43]]),
+            6
+        );
+        assert_eq!(&data[44..], &[0, 0, 0xff, 0x7f, 0x01, 0x80]);
+    }
+
+    #[test]
+    fn test_y4m_frame_planes() {
+        let mut frame = Frame::new();
+        frame.set_pixel(1, 0, (0xff, 0xff, 0xff));
+        let mut out = vec![];
+        write_y4m_frame(&mut out, &frame).unwrap();
+
+        let planes = &out[6..];
+        assert_eq!(planes.len(), WIDTH * HEIGHT * 3);
+        // black and white in studio range, without any colour
+        assert_eq!(&planes[0..2], &[16, 235]);
+        assert_eq!(&planes[WIDTH * HEIGHT..WIDTH * HEIGHT + 2], &[128, 128]);
+    }
+}
@@ -0,0 +1,134 @@
+use crate::ppu::NesPPU;
+
+const WIDTH: usize = 256;
+const HEIGHT: usize = 240;
+const CHR_TILES: usize = 512;
+
+/// Everything a background line is drawn from, other than the nametables and CHR.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LineKey {
+    pub v: u16,
+    pub fine_x: u8,
+    pub pattern_bank: u16,
+    pub chr_bank_key: usize,
+    /// The pages of VRAM under the left and right halves of the line. `None` for pages
+    /// the board answers itself, which aren't tracked and so are drawn every frame.
+    pub tables: [Option<usize>; 2],
+}
+
+impl LineKey {
+    fn tile_row(&self) -> usize {
+        ((self.v >> 5) & 0b1_1111) as usize
+    }
+}
+
+/// The background as it was drawn last frame, so lines whose nametable rows, CHR tiles,
+/// palette and scroll haven't changed since are copied instead of being drawn again.
+pub struct BackgroundCache {
+    pub pixels: Vec<u8>,
+    pub opaque: Vec<bool>,
+    lines: Vec<Option<LineKey>>,
+    // what the cached lines were drawn from
+    vram: [u8; 4096],
+    palette: [u8; 32],
+    mask: u8,
+    // rows of each physical nametable changed since the last frame, attribute bytes
+    // included
+    dirty_rows: [[bool; 32]; 4],
+    dirty_tiles: Vec<bool>,
+    any_dirty_tiles: bool,
+}
+
+impl Default for BackgroundCache {
+    fn default() -> Self {
+        BackgroundCache::new()
+    }
+}
+
+impl BackgroundCache {
+    pub fn new() -> Self {
+        BackgroundCache {
+            pixels: vec![0; WIDTH * HEIGHT * 3],
+            opaque: vec![false; WIDTH * HEIGHT],
+            lines: vec![None; HEIGHT],
+            vram: [0; 4096],
+            palette: [0; 32],
+            mask: 0,
+            dirty_rows: [[false; 32]; 4],
+            dirty_tiles: vec![false; CHR_TILES],
+            any_dirty_tiles: false,
+        }
+    }
+
+    /// Called for every write to the pattern tables at $0000-$1FFF.
+    pub fn chr_written(&mut self, addr: u16) {
+        self.dirty_tiles[addr as usize / 16 % CHR_TILES] = true;
+        self.any_dirty_tiles = true;
+    }
+
+    /// Forgets every line, for when the whole PPU has changed under the cache.
+    pub fn invalidate(&mut self) {
+        self.lines.iter_mut().for_each(|line| *line = None);
+    }
+
+    /// Works out what changed since the last frame. Called before any lines are looked up.
+    pub fn begin_frame(&mut self, ppu: &NesPPU) {
+        if self.palette != ppu.palette_table || self.mask != ppu.mask.bits() {
+            self.palette = ppu.palette_table;
+            self.mask = ppu.mask.bits();
+            self.invalidate();
+        }
+
+        self.dirty_rows = [[false; 32]; 4];
+        if self.vram[..] != ppu.bus.vram[..] {
+            for (table, rows) in self.dirty_rows.iter_mut().enumerate() {
+                let old = &self.vram[table * 0x400..(table + 1) * 0x400];
+                let new = &ppu.bus.vram[table * 0x400..(table + 1) * 0x400];
+                for (row, dirty) in rows.iter_mut().enumerate() {
+                    let tiles = row * 32..row * 32 + 32;
+                    let attributes = 0x3c0 + row / 4 * 8..0x3c0 + row / 4 * 8 + 8;
+                    *dirty = old[tiles.clone()] != new[tiles]
+                        || old[attributes.clone()] != new[attributes];
+                }
+            }
+            self.vram = ppu.bus.vram;
+        }
+    }
+
+    /// Whether line `y` can be copied from last frame.
+    pub fn is_fresh(&self, y: usize, key: &LineKey) -> bool {
+        if self.lines[y].as_ref() != Some(key) {
+            return false;
+        }
+        let row = key.tile_row();
+        key.tables.iter().all(|&table| match table {
+            Some(table) => {
+                !self.dirty_rows[table][row] && !self.uses_dirty_tiles(table, row, key.pattern_bank)
+            }
+            None => false,
+        })
+    }
+
+    fn uses_dirty_tiles(&self, table: usize, row: usize, pattern_bank: u16) -> bool {
+        if !self.any_dirty_tiles {
+            return false;
+        }
+        let first = pattern_bank as usize / 16;
+        self.vram[table * 0x400 + row * 32..][..32]
+            .iter()
+            .any(|&tile| self.dirty_tiles[first + tile as usize])
+    }
+
+    /// Records that line `y` has just been drawn into `pixels` and `opaque`.
+    pub fn store(&mut self, y: usize, key: LineKey) {
+        self.lines[y] = Some(key);
+    }
+
+    /// Called once every line has been drawn or copied.
+    pub fn end_frame(&mut self) {
+        if self.any_dirty_tiles {
+            self.dirty_tiles.iter_mut().for_each(|dirty| *dirty = false);
+            self.any_dirty_tiles = false;
+        }
+    }
+}
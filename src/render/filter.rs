@@ -0,0 +1,119 @@
+use crate::render::frame::Frame;
+
+/// Filtered pictures are drawn at three times the NES resolution.
+pub const SCALE: usize = 3;
+pub const WIDTH: usize = 256 * SCALE;
+pub const HEIGHT: usize = 240 * SCALE;
+
+// How bright the gap between two scanlines is, out of 256
+const SCANLINE_GAP: u32 = 128;
+// Each column of the aperture grille lets its own colour through, and this much of the rest
+const MASK_BLEED: u32 = 128;
+
+/// Software post-processing, applied between rendering and showing the frame.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PostFilter {
+    None,
+    Scanlines,
+    /// Scanlines, with each pixel glowing into its neighbours like a phosphor dot.
+    Phosphor,
+    /// Scanlines behind an aperture grille, without any curvature.
+    CrtMask,
+}
+
+impl PostFilter {
+    pub fn next(self) -> PostFilter {
+        match self {
+            PostFilter::None => PostFilter::Scanlines,
+            PostFilter::Scanlines => PostFilter::Phosphor,
+            PostFilter::Phosphor => PostFilter::CrtMask,
+            PostFilter::CrtMask => PostFilter::None,
+        }
+    }
+
+    /// Draws `frame` into `out`, a WIDTH x HEIGHT RGB picture. Does nothing for `None`,
+    /// which shows the frame as it is.
+    pub fn apply(self, frame: &Frame, out: &mut [u8]) {
+        if self == PostFilter::None {
+            return;
+        }
+        for y in 0..HEIGHT {
+            let gap = y % SCALE == SCALE - 1;
+            for x in 0..WIDTH {
+                let rgb = self.source_pixel(frame, x / SCALE, y / SCALE);
+                let mut channels = [rgb.0 as u32, rgb.1 as u32, rgb.2 as u32];
+                if self == PostFilter::CrtMask {
+                    for (channel, value) in channels.iter_mut().enumerate() {
+                        if channel != x % SCALE {
+                            *value = *value * MASK_BLEED / 256;
+                        }
+                    }
+                }
+                if gap {
+                    for value in channels.iter_mut() {
+                        *value = *value * SCANLINE_GAP / 256;
+                    }
+                }
+                let base = (y * WIDTH + x) * 3;
+                out[base] = channels[0] as u8;
+                out[base + 1] = channels[1] as u8;
+                out[base + 2] = channels[2] as u8;
+            }
+        }
+    }
+
+    fn source_pixel(self, frame: &Frame, x: usize, y: usize) -> (u8, u8, u8) {
+        let pixel = frame.get_pixel(x, y);
+        if self != PostFilter::Phosphor {
+            return pixel;
+        }
+        // a quarter of each neighbour bleeds in, but never darkens the pixel
+        let left = frame.get_pixel(x.saturating_sub(1), y);
+        let right = frame.get_pixel((x + 1).min(255), y);
+        let glow = |own: u8, left: u8, right: u8| {
+            let blurred = (own as u32 * 2 + left as u32 + right as u32) / 4;
+            own.max(blurred as u8)
+        };
+        (
+            glow(pixel.0, left.0, right.0),
+            glow(pixel.1, left.1, right.1),
+            glow(pixel.2, left.2, right.2),
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn pixel(out: &[u8], x: usize, y: usize) -> (u8, u8, u8) {
+        let base = (y * WIDTH + x) * 3;
+        (out[base], out[base + 1], out[base + 2])
+    }
+
+    #[test]
+    fn test_scanlines_and_mask() {
+        let mut frame = Frame::new();
+        frame.set_pixel(0, 0, (200, 200, 200));
+        let mut out = vec![0; WIDTH * HEIGHT * 3];
+
+        PostFilter::Scanlines.apply(&frame, &mut out);
+        assert_eq!(pixel(&out, 1, 1), (200, 200, 200));
+        assert_eq!(pixel(&out, 1, 2), (100, 100, 100));
+
+        PostFilter::CrtMask.apply(&frame, &mut out);
+        assert_eq!(pixel(&out, 0, 0), (200, 100, 100));
+        assert_eq!(pixel(&out, 2, 0), (100, 100, 200));
+    }
+
+    #[test]
+    fn test_phosphor_glow() {
+        let mut frame = Frame::new();
+        frame.set_pixel(1, 0, (200, 0, 0));
+        let mut out = vec![0; WIDTH * HEIGHT * 3];
+        PostFilter::Phosphor.apply(&frame, &mut out);
+        assert_eq!(pixel(&out, SCALE, 0), (200, 0, 0));
+        assert_eq!(pixel(&out, 0, 0), (50, 0, 0));
+        assert_eq!(pixel(&out, 2 * SCALE, 0), (50, 0, 0));
+    }
+}
@@ -1,5 +1,11 @@
+use std::fs::File;
+use std::io::BufWriter;
+
+#[derive(Clone)]
 pub struct Frame {
     pub data: Vec<u8>,
+    width: usize,
+    height: usize,
 }
 
 impl Frame {
@@ -7,17 +13,108 @@ impl Frame {
     const HEIGHT: usize = 240;
 
     pub fn new() -> Self {
+        Frame::with_size(Frame::WIDTH, Frame::HEIGHT)
+    }
+
+    /// A picture of any size, for the debug windows.
+    pub fn with_size(width: usize, height: usize) -> Self {
         Frame {
-            data: vec![0; Frame::WIDTH * Frame::HEIGHT * 3],
+            data: vec![0; width * height * 3],
+            width,
+            height,
         }
     }
 
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
     pub fn set_pixel(&mut self, x: usize, y: usize, rgb: (u8, u8, u8)) {
-        let base = y * 3 * Frame::WIDTH + x * 3;
-        if base + 2 < self.data.len() {
+        if x < self.width && y < self.height {
+            let base = y * 3 * self.width + x * 3;
             self.data[base] = rgb.0;
             self.data[base + 1] = rgb.1;
             self.data[base + 2] = rgb.2;
         }
     }
+
+    pub fn get_pixel(&self, x: usize, y: usize) -> (u8, u8, u8) {
+        if x < self.width && y < self.height {
+            let base = y * 3 * self.width + x * 3;
+            (self.data[base], self.data[base + 1], self.data[base + 2])
+        } else {
+            (0, 0, 0)
+        }
+    }
+
+    /// A CRC32 of the picture, to tell whether two runs drew the same thing without
+    /// keeping screenshots of either.
+    pub fn hash(&self) -> u32 {
+        crc32fast::hash(&self.data)
+    }
+
+    /// Writes the frame out as an RGB PNG.
+    pub fn save_png(&self, path: &str) -> Result<(), String> {
+        let file = File::create(path).map_err(|e| e.to_string())?;
+        let mut encoder =
+            png::Encoder::new(BufWriter::new(file), self.width as u32, self.height as u32);
+        encoder.set_color(png::ColorType::Rgb);
+        encoder.set_depth(png::BitDepth::Eight);
+        encoder
+            .write_header()
+            .and_then(|mut writer| writer.write_image_data(&self.data))
+            .map_err(|e| e.to_string())
+    }
+
+    /// Darkens the picture and draws a red band across the middle, to show emulation has
+    /// stopped with an error.
+    pub fn draw_error_overlay(&mut self) {
+        for y in 0..Frame::HEIGHT {
+            for x in 0..Frame::WIDTH {
+                let (r, g, b) = self.get_pixel(x, y);
+                let pixel = if (104..136).contains(&y) {
+                    (0xb0, 0x10, 0x10)
+                } else {
+                    (r / 3, g / 3, b / 3)
+                };
+                self.set_pixel(x, y, pixel);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_save_png_round_trip() {
+        let mut frame = Frame::new();
+        frame.set_pixel(0, 0, (0x11, 0x22, 0x33));
+        frame.set_pixel(255, 239, (0xff, 0x80, 0x01));
+        let path = std::env::temp_dir().join("rust-nes-test-frame.png");
+        let path = path.to_str().unwrap();
+        frame.save_png(path).unwrap();
+
+        let decoder = png::Decoder::new(File::open(path).unwrap());
+        let mut reader = decoder.read_info().unwrap();
+        let mut data = vec![0; reader.output_buffer_size()];
+        let info = reader.next_frame(&mut data).unwrap();
+        std::fs::remove_file(path).unwrap();
+        assert_eq!((info.width, info.height), (256, 240));
+        assert_eq!(data, frame.data);
+    }
+
+    #[test]
+    fn test_hash() {
+        let mut frame = Frame::new();
+        let blank = frame.hash();
+        assert_eq!(Frame::new().hash(), blank);
+        frame.set_pixel(10, 10, (0, 0, 1));
+        assert_ne!(frame.hash(), blank);
+    }
 }
@@ -0,0 +1,78 @@
+use crate::joypad::JoypadButton;
+use crate::render::frame::Frame;
+
+/// Size of the controller drawn by `draw_controller`, background included.
+pub const WIDTH: usize = 29;
+pub const HEIGHT: usize = 11;
+
+const PRESSED: (u8, u8, u8) = (0xff, 0xff, 0xff);
+const RELEASED: (u8, u8, u8) = (0x50, 0x50, 0x50);
+
+// Where each button goes, as x, y, width and height inside the background
+const LAYOUT: [(JoypadButton, usize, usize, usize, usize); 8] = [
+    (JoypadButton::UP, 4, 1, 3, 3),
+    (JoypadButton::LEFT, 1, 4, 3, 3),
+    (JoypadButton::RIGHT, 7, 4, 3, 3),
+    (JoypadButton::DOWN, 4, 7, 3, 3),
+    (JoypadButton::SELECT, 11, 6, 3, 2),
+    (JoypadButton::START, 15, 6, 3, 2),
+    (JoypadButton::BUTTON_B, 20, 4, 3, 3),
+    (JoypadButton::BUTTON_A, 25, 4, 3, 3),
+];
+
+fn fill(frame: &mut Frame, x: usize, y: usize, width: usize, height: usize, rgb: (u8, u8, u8)) {
+    for dy in 0..height {
+        for dx in 0..width {
+            frame.set_pixel(x + dx, y + dy, rgb);
+        }
+    }
+}
+
+/// Draws a small controller with its top left corner at `x`, `y`, with the held buttons
+/// lit up.
+pub fn draw_controller(frame: &mut Frame, x: usize, y: usize, buttons: JoypadButton) {
+    fill(frame, x, y, WIDTH, HEIGHT, (0, 0, 0));
+    // the middle of the d-pad
+    fill(frame, x + 4, y + 4, 3, 3, RELEASED);
+    for &(button, left, top, width, height) in LAYOUT.iter() {
+        let rgb = if buttons.contains(button) {
+            PRESSED
+        } else {
+            RELEASED
+        };
+        fill(frame, x + left, y + top, width, height, rgb);
+    }
+}
+
+/// Draws the controllers side by side in the bottom right corner, player 1 first.
+pub fn draw_input_display(frame: &mut Frame, players: &[JoypadButton]) {
+    let y = 240 - 2 - HEIGHT;
+    let left = 256 - 2 - players.len() * (WIDTH + 2) + 2;
+    for (i, &buttons) in players.iter().enumerate() {
+        draw_controller(frame, left + i * (WIDTH + 2), y, buttons);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_pressed_buttons_light_up() {
+        let mut frame = Frame::new();
+        draw_input_display(
+            &mut frame,
+            &[
+                JoypadButton::BUTTON_A,
+                JoypadButton::UP | JoypadButton::START,
+            ],
+        );
+        let (x1, x2, y) = (256 - 2 - 2 * WIDTH - 2, 256 - 2 - WIDTH, 240 - 2 - HEIGHT);
+        assert_eq!(frame.get_pixel(x1 + 26, y + 5), PRESSED);
+        assert_eq!(frame.get_pixel(x1 + 5, y + 2), RELEASED);
+        assert_eq!(frame.get_pixel(x2 + 5, y + 2), PRESSED);
+        assert_eq!(frame.get_pixel(x2 + 16, y + 6), PRESSED);
+        assert_eq!(frame.get_pixel(x2 + 26, y + 5), RELEASED);
+        assert_eq!(frame.get_pixel(x2, y), (0, 0, 0));
+    }
+}
@@ -1,37 +1,15 @@
+pub mod cache;
+pub mod filter;
 pub mod frame;
+pub mod input;
+pub mod osd;
 pub mod palette;
 
 use crate::ppu::NesPPU;
-use crate::rom::Mirroring;
+use cache::{BackgroundCache, LineKey};
 use frame::Frame;
 
-struct Rect {
-    x1: usize,
-    y1: usize,
-    x2: usize,
-    y2: usize,
-}
-
-impl Rect {
-    fn new(x1: usize, y1: usize, x2: usize, y2: usize) -> Self {
-        Rect {
-            x1: x1,
-            y1: y1,
-            x2: x2,
-            y2: y2,
-        }
-    }
-}
-
-fn bg_pallette(
-    ppu: &NesPPU,
-    attribute_table: &[u8],
-    tile_column: usize,
-    tile_row: usize,
-) -> [u8; 4] {
-    let attr_table_idx = tile_row / 4 * 8 + tile_column / 4;
-    let attr_byte = attribute_table[attr_table_idx];
-
+fn bg_pallette(ppu: &NesPPU, attr_byte: u8, tile_column: usize, tile_row: usize) -> [u8; 4] {
     let pallet_idx = match (tile_column % 4 / 2, tile_row % 4 / 2) {
         (0, 0) => attr_byte & 0b11,
         (1, 0) => (attr_byte >> 2) & 0b11,
@@ -59,108 +37,146 @@ fn sprite_palette(ppu: &NesPPU, pallete_idx: u8) -> [u8; 4] {
     ]
 }
 
-fn render_name_table(
+fn read_tile(ppu: &NesPPU, addr: u16) -> [u8; 16] {
+    let mut mapper = ppu.bus.mapper.borrow_mut();
+    let mut tile = [0; 16];
+    for (i, byte) in tile.iter_mut().enumerate() {
+        *byte = mapper.read_chr(addr + i as u16);
+        mapper.notify_chr_fetch(addr + i as u16);
+    }
+    tile
+}
+
+// Draws line `y` of the background into the cache from scroll position `v` and `fine_x`.
+// With `cache` left out, only the pattern fetches are made, for boards that watch them.
+fn background_line(
     ppu: &NesPPU,
-    frame: &mut Frame,
-    name_table: &[u8],
-    view_port: Rect,
-    shift_x: isize,
-    shift_y: isize,
+    mut cache: Option<&mut BackgroundCache>,
+    y: usize,
+    (v, fine_x): (u16, u8),
 ) {
     let bank = ppu.ctrl.bknd_pattern_addr();
-    let attribute_table = &name_table[0x3c0..0x400];
+    let fine_y = v >> 12;
+    let tile_row = ((v >> 5) & 0b1_1111) as usize;
+    let nametable_y = (v >> 11) & 1;
+    let scroll_x = ((v >> 10) & 1) * 256 + (v & 0b1_1111) * 8 + fine_x as u16;
 
-    for i in 0..0x3c0 {
-        let tile_column = i % 32;
-        let tile_row = i / 32;
-        let tile_idx = name_table[i] as u16;
-        let tile =
-            &ppu.chr_rom[(bank + tile_idx * 16) as usize..=(bank + tile_idx * 16 + 15) as usize];
-        let palette = bg_pallette(ppu, attribute_table, tile_column, tile_row);
+    let mut x = 0;
+    while x < 256 {
+        let world_x = (scroll_x + x as u16) % 512;
+        let tile_column = (world_x % 256 / 8) as usize;
+        let name_table = 0x2000 | nametable_y << 11 | (world_x / 256) << 10;
+        let tile_addr = name_table + (tile_row * 32 + tile_column) as u16;
+        let tile_idx = ppu.bus.read_nametable(tile_addr) as u16;
 
-        for y in 0..=7 {
-            let mut upper = tile[y];
-            let mut lower = tile[y + 8];
+        let row = bank + tile_idx * 16 + fine_y;
+        let cache = match cache.as_mut() {
+            Some(cache) => cache,
+            None => {
+                ppu.bus.mapper.borrow_mut().notify_chr_fetch(row + 8);
+                x += 8 - world_x as usize % 8;
+                continue;
+            }
+        };
+        let (low, high) = {
+            let mut mapper = ppu.bus.mapper.borrow_mut();
+            let pattern = (mapper.read_chr(row), mapper.read_chr(row + 8));
+            mapper.notify_chr_fetch(row + 8);
+            pattern
+        };
+        let attr_addr = name_table + 0x3c0 + (tile_row / 4 * 8 + tile_column / 4) as u16;
+        let attr_byte = ppu.bus.read_nametable(attr_addr);
+        let palette = bg_pallette(ppu, attr_byte, tile_column, tile_row);
+        let colours = palette.map(|entry| palette::lookup(ppu, entry));
 
-            for x in (0..=7).rev() {
-                let value = (1 & lower) << 1 | (1 & upper);
-                upper = upper >> 1;
-                lower = lower >> 1;
-                let rgb = match value {
-                    0 => palette::SYSTEM_PALLETE[palette[0] as usize],
-                    1 => palette::SYSTEM_PALLETE[palette[1] as usize],
-                    2 => palette::SYSTEM_PALLETE[palette[2] as usize],
-                    3 => palette::SYSTEM_PALLETE[palette[3] as usize],
-                    _ => panic!("Impossible"),
-                };
-                let pixel_x = tile_column * 8 + x;
-                let pixel_y = tile_row * 8 + y;
-
-                if pixel_x >= view_port.x1
-                    && pixel_x < view_port.x2
-                    && pixel_y >= view_port.y1
-                    && pixel_y < view_port.y2
-                {
-                    frame.set_pixel(
-                        (shift_x + pixel_x as isize) as usize,
-                        (shift_y + pixel_y as isize) as usize,
-                        rgb,
-                    );
-                }
+        for bit in (0..8 - world_x % 8).rev() {
+            let value = ((high >> bit) & 1) << 1 | ((low >> bit) & 1);
+            let pixel = y * 256 + x;
+            let (r, g, b) = colours[value as usize];
+            cache.pixels[pixel * 3..pixel * 3 + 3].copy_from_slice(&[r, g, b]);
+            cache.opaque[pixel] = value != 0;
+            x += 1;
+            if x == 256 {
+                break;
             }
         }
     }
 }
 
+// Draws the background a scanline at a time from the scroll position the PPU had at the
+// start of that line, so scroll changes made mid-frame split the screen like on hardware.
+// Lines that would come out the same as last frame are copied from the cache.
+fn render_background(ppu: &NesPPU, frame: &mut Frame, bg_opaque: &mut [bool]) {
+    let mut cache = ppu.background_cache.borrow_mut();
+    cache.begin_frame(ppu);
+
+    for (y, &(v, fine_x)) in ppu.line_scroll.iter().enumerate() {
+        let nametable = |x: u16| {
+            let addr = 0x2000 | ((v >> 11) & 1) << 11 | x << 10;
+            ppu.bus.vram_page(addr)
+        };
+        let left = (v >> 10) & 1;
+        let key = LineKey {
+            v,
+            fine_x,
+            pattern_bank: ppu.ctrl.bknd_pattern_addr(),
+            chr_bank_key: ppu.bus.mapper.borrow().chr_bank_key(),
+            tables: [nametable(left), nametable(left ^ 1)],
+        };
+        if cache.is_fresh(y, &key) {
+            // the board still sees the same fetches
+            background_line(ppu, None, y, (v, fine_x));
+        } else {
+            background_line(ppu, Some(&mut cache), y, (v, fine_x));
+            cache.store(y, key);
+        }
+    }
+    cache.end_frame();
+
+    frame.data.copy_from_slice(&cache.pixels);
+    bg_opaque.copy_from_slice(&cache.opaque);
+}
+
 pub fn render(ppu: &NesPPU, frame: &mut Frame) {
+    let backdrop = palette::lookup(ppu, ppu.backdrop());
+
     // DRAW BACKGROUND
-    let scroll_x = ppu.scroll.scroll_x as usize;
-    let scroll_y = ppu.scroll.scroll_y as usize;
-
-    let (main_nametable, second_nametable) = match (&ppu.mirroring, ppu.ctrl.nametable_addr()) {
-        (Mirroring::Vertical, 0x2000)
-        | (Mirroring::Vertical, 0x2800)
-        | (Mirroring::Horizontal, 0x2000)
-        | (Mirroring::Horizontal, 0x2400) => (&ppu.vram[0..0x400], &ppu.vram[0x400..0x800]),
-        (Mirroring::Vertical, 0x2400)
-        | (Mirroring::Vertical, 0x2C00)
-        | (Mirroring::Horizontal, 0x2800)
-        | (Mirroring::Horizontal, 0x2C00) => (&ppu.vram[0x400..0x800], &ppu.vram[0..0x400]),
-        (_, _) => {
-            panic!("Not supported mirroring type {:?}", ppu.mirroring);
+    // which background pixels are not transparent, for sprite priority
+    let mut bg_opaque = vec![false; 256 * 240];
+    if ppu.mask.show_background() {
+        render_background(ppu, frame, &mut bg_opaque);
+    } else {
+        for y in 0..240 {
+            for x in 0..256 {
+                frame.set_pixel(x, y, backdrop);
+            }
+            // the pattern fetches still happen while sprites are being drawn
+            if ppu.mask.show_sprites() {
+                background_line(ppu, None, y, ppu.line_scroll[y]);
+            }
         }
-    };
+    }
 
-    render_name_table(
-        ppu,
-        frame,
-        main_nametable,
-        Rect::new(scroll_x, scroll_y, 256, 240),
-        -(scroll_x as isize),
-        -(scroll_y as isize),
-    );
-    if scroll_x > 0 {
-        render_name_table(
-            ppu,
-            frame,
-            second_nametable,
-            Rect::new(0, 0, scroll_x, 240),
-            (256 - scroll_x) as isize,
-            0,
-        );
-    } else if scroll_y > 0 {
-        render_name_table(
-            ppu,
-            frame,
-            second_nametable,
-            Rect::new(0, 0, 256, scroll_y),
-            0,
-            (240 - scroll_y) as isize,
-        );
+    if !ppu.mask.leftmost_8pxl_background() {
+        for y in 0..240 {
+            for x in 0..8 {
+                frame.set_pixel(x, y, backdrop);
+                bg_opaque[y * 256 + x] = false;
+            }
+        }
     }
 
     // DRAW SPRITES
-    for i in (0..ppu.oam_data.len()).step_by(4).rev() {
+    // The first opaque sprite pixel at a position wins, lower OAM indexes first. A sprite
+    // behind the background still claims the pixel and hides any later sprite there.
+    let mut sprite_claimed = vec![false; 256 * 240];
+    let mut on_scanline = vec![0u64; 240];
+    for (scanline, sprites) in on_scanline.iter_mut().enumerate() {
+        for n in ppu.sprites_on_scanline(scanline as u16) {
+            *sprites |= 1 << n;
+        }
+    }
+    for i in (0..ppu.oam_data.len()).step_by(4) {
         let tile_idx = ppu.oam_data[i + 1] as u16;
         let tile_x = ppu.oam_data[i + 3] as usize;
         let tile_y = ppu.oam_data[i] as usize;
@@ -175,12 +191,15 @@ pub fn render(ppu: &NesPPU, frame: &mut Frame) {
         } else {
             false
         };
+        let behind_background = ppu.oam_data[i + 2] >> 5 & 1 == 1;
         let pallette_idx = ppu.oam_data[i + 2] & 0b11;
         let sprite_palette = sprite_palette(ppu, pallette_idx);
         let bank: u16 = ppu.ctrl.sprt_pattern_addr();
 
-        let tile =
-            &ppu.chr_rom[(bank + tile_idx * 16) as usize..=(bank + tile_idx * 16 + 15) as usize];
+        let tile = read_tile(ppu, bank + tile_idx * 16);
+        if !ppu.mask.show_sprites() {
+            continue;
+        }
 
         for y in 0..=7 {
             let mut upper = tile[y];
@@ -191,18 +210,132 @@ pub fn render(ppu: &NesPPU, frame: &mut Frame) {
                 lower = lower >> 1;
                 let rgb = match value {
                     0 => continue 'ololo, // skip coloring the pixel
-                    1 => palette::SYSTEM_PALLETE[sprite_palette[1] as usize],
-                    2 => palette::SYSTEM_PALLETE[sprite_palette[2] as usize],
-                    3 => palette::SYSTEM_PALLETE[sprite_palette[3] as usize],
+                    1 => palette::lookup(ppu, sprite_palette[1]),
+                    2 => palette::lookup(ppu, sprite_palette[2]),
+                    3 => palette::lookup(ppu, sprite_palette[3]),
                     _ => panic!("can't be"),
                 };
-                match (flip_horizontal, flip_vertical) {
-                    (false, false) => frame.set_pixel(tile_x + x, tile_y + y, rgb),
-                    (true, false) => frame.set_pixel(tile_x + 7 - x, tile_y + y, rgb),
-                    (false, true) => frame.set_pixel(tile_x + x, tile_y + 7 - y, rgb),
-                    (true, true) => frame.set_pixel(tile_x + 7 - x, tile_y + 7 - y, rgb),
+                let (pixel_x, pixel_y) = match (flip_horizontal, flip_vertical) {
+                    (false, false) => (tile_x + x, tile_y + y),
+                    (true, false) => (tile_x + 7 - x, tile_y + y),
+                    (false, true) => (tile_x + x, tile_y + 7 - y),
+                    (true, true) => (tile_x + 7 - x, tile_y + 7 - y),
+                };
+                if pixel_x >= 256 || pixel_y >= 240 {
+                    continue 'ololo;
+                }
+                if pixel_x < 8 && !ppu.mask.leftmost_8pxl_sprite() {
+                    continue 'ololo;
+                }
+                if on_scanline[pixel_y] >> (i / 4) & 1 == 0 {
+                    continue 'ololo;
+                }
+
+                let pixel = pixel_y * 256 + pixel_x;
+                if sprite_claimed[pixel] {
+                    continue 'ololo;
+                }
+                sprite_claimed[pixel] = true;
+                if !(behind_background && bg_opaque[pixel]) {
+                    frame.set_pixel(pixel_x, pixel_y, rgb);
                 }
             }
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::mapper;
+    use crate::ppu::PPU;
+    use crate::rom::Mirroring;
+
+    #[test]
+    fn test_sprite_priority() {
+        let mut ppu = NesPPU::new(mapper::blank(Mirroring::Horizontal, true));
+        ppu.write_to_mask(0b0001_1110);
+        // tile 1 is solid colour 1
+        for i in 0..8 {
+            ppu.bus.mapper.borrow_mut().write_chr(16 + i, 0xff);
+        }
+        ppu.palette_table[0] = 0x0f;
+        ppu.palette_table[1] = 0x01;
+        ppu.palette_table[0x11] = 0x02;
+        ppu.palette_table[0x15] = 0x03;
+        ppu.bus.vram[0] = 1;
+
+        // sprite 0 is behind the background, sprite 1 is in front and palette 1
+        ppu.oam_data[0..8].copy_from_slice(&[0, 1, 0b0010_0000, 0, 4, 1, 0b0000_0001, 4]);
+        for sprite in ppu.oam_data[8..].chunks_mut(4) {
+            sprite[0] = 0xff;
+        }
+
+        let mut frame = Frame::new();
+        render(&ppu, &mut frame);
+
+        let bg = palette::SYSTEM_PALLETE[0x01];
+        // background wins over sprite 0, which hides sprite 1 even there
+        assert_eq!(frame.get_pixel(5, 5), bg);
+        // sprite 1 shows where sprite 0 doesn't reach and the background is transparent
+        assert_eq!(frame.get_pixel(10, 10), palette::SYSTEM_PALLETE[0x03]);
+    }
+
+    #[test]
+    fn test_background_cache_follows_writes() {
+        let mut ppu = NesPPU::new(mapper::blank(Mirroring::Horizontal, true));
+        ppu.write_to_mask(0b0000_1010);
+        ppu.palette_table[0] = 0x0f;
+        ppu.palette_table[1] = 0x01;
+        let backdrop = palette::SYSTEM_PALLETE[0x0f];
+        let colour = palette::SYSTEM_PALLETE[0x01];
+        let mut frame = Frame::new();
+        render(&ppu, &mut frame);
+        assert_eq!(frame.get_pixel(9, 0), backdrop);
+
+        // tile 1 is solid colour 1, and goes in the second column
+        for i in 0..8 {
+            ppu.poke_vram(16 + i, 0xff);
+        }
+        ppu.bus.vram[1] = 1;
+        render(&ppu, &mut frame);
+        assert_eq!(frame.get_pixel(9, 0), colour);
+        assert_eq!(frame.get_pixel(17, 0), backdrop);
+
+        // CHR written behind the PPU's back stays cached, but not past a write through it
+        ppu.bus.mapper.borrow_mut().write_chr(16, 0);
+        render(&ppu, &mut frame);
+        assert_eq!(frame.get_pixel(9, 0), colour);
+        ppu.poke_vram(16, 0b1000_0000);
+        render(&ppu, &mut frame);
+        assert_eq!(frame.get_pixel(9, 0), backdrop);
+        assert_eq!(frame.get_pixel(8, 0), colour);
+    }
+
+    #[test]
+    fn test_hidden_layers_show_the_backdrop() {
+        let mut ppu = NesPPU::new(mapper::blank(Mirroring::Horizontal, true));
+        for i in 0..8 {
+            ppu.poke_vram(16 + i, 0xff);
+        }
+        ppu.bus.vram[0] = 1;
+        ppu.oam_data[0..4].copy_from_slice(&[20, 1, 0, 20]);
+        ppu.poke_vram(0x3f00, 0x0f);
+        ppu.poke_vram(0x3f01, 0x01);
+        // written through the mirror at $3F10
+        ppu.poke_vram(0x3f10, 0x0d);
+        ppu.poke_vram(0x3f11, 0x02);
+        let backdrop = palette::SYSTEM_PALLETE[0x0d];
+
+        let mut frame = Frame::new();
+        ppu.write_to_mask(0b0001_0110);
+        render(&ppu, &mut frame);
+        assert_eq!(frame.get_pixel(0, 0), backdrop);
+        assert_eq!(frame.get_pixel(21, 21), palette::SYSTEM_PALLETE[0x02]);
+
+        ppu.write_to_mask(0b0000_1110);
+        render(&ppu, &mut frame);
+        assert_eq!(frame.get_pixel(0, 0), palette::SYSTEM_PALLETE[0x01]);
+        assert_eq!(frame.get_pixel(21, 21), backdrop);
+    }
+}
@@ -0,0 +1,198 @@
+use crate::render::frame::Frame;
+
+const GLYPH_WIDTH: usize = 3;
+const GLYPH_HEIGHT: usize = 5;
+/// Height of the background `draw_text` fills, a pixel of padding above and below.
+pub const TEXT_HEIGHT: usize = GLYPH_HEIGHT + 2;
+// and a pixel between lines
+const LINE_HEIGHT: usize = TEXT_HEIGHT + 1;
+const MAX_MESSAGES: usize = 4;
+pub const TEXT_COLOUR: (u8, u8, u8) = (0xff, 0xff, 0xff);
+
+// 3x5 glyphs, a row per byte with the leftmost pixel in bit 2
+fn glyph(c: char) -> [u8; 5] {
+    match c.to_ascii_uppercase() {
+        ' ' => [0, 0, 0, 0, 0],
+        '0' => [0b111, 0b101, 0b101, 0b101, 0b111],
+        '1' => [0b010, 0b110, 0b010, 0b010, 0b111],
+        '2' => [0b111, 0b001, 0b111, 0b100, 0b111],
+        '3' => [0b111, 0b001, 0b111, 0b001, 0b111],
+        '4' => [0b101, 0b101, 0b111, 0b001, 0b001],
+        '5' => [0b111, 0b100, 0b111, 0b001, 0b111],
+        '6' => [0b111, 0b100, 0b111, 0b101, 0b111],
+        '7' => [0b111, 0b001, 0b001, 0b010, 0b010],
+        '8' => [0b111, 0b101, 0b111, 0b101, 0b111],
+        '9' => [0b111, 0b101, 0b111, 0b001, 0b111],
+        'A' => [0b010, 0b101, 0b111, 0b101, 0b101],
+        'B' => [0b110, 0b101, 0b110, 0b101, 0b110],
+        'C' => [0b011, 0b100, 0b100, 0b100, 0b011],
+        'D' => [0b110, 0b101, 0b101, 0b101, 0b110],
+        'E' => [0b111, 0b100, 0b110, 0b100, 0b111],
+        'F' => [0b111, 0b100, 0b110, 0b100, 0b100],
+        'G' => [0b011, 0b100, 0b101, 0b101, 0b011],
+        'H' => [0b101, 0b101, 0b111, 0b101, 0b101],
+        'I' => [0b111, 0b010, 0b010, 0b010, 0b111],
+        'J' => [0b001, 0b001, 0b001, 0b101, 0b010],
+        'K' => [0b101, 0b101, 0b110, 0b101, 0b101],
+        'L' => [0b100, 0b100, 0b100, 0b100, 0b111],
+        'M' => [0b101, 0b111, 0b111, 0b101, 0b101],
+        'N' => [0b110, 0b101, 0b101, 0b101, 0b101],
+        'O' => [0b010, 0b101, 0b101, 0b101, 0b010],
+        'P' => [0b110, 0b101, 0b110, 0b100, 0b100],
+        'Q' => [0b010, 0b101, 0b101, 0b110, 0b011],
+        'R' => [0b110, 0b101, 0b110, 0b101, 0b101],
+        'S' => [0b011, 0b100, 0b010, 0b001, 0b110],
+        'T' => [0b111, 0b010, 0b010, 0b010, 0b010],
+        'U' => [0b101, 0b101, 0b101, 0b101, 0b111],
+        'V' => [0b101, 0b101, 0b101, 0b101, 0b010],
+        'W' => [0b101, 0b101, 0b111, 0b111, 0b101],
+        'X' => [0b101, 0b101, 0b010, 0b101, 0b101],
+        'Y' => [0b101, 0b101, 0b010, 0b010, 0b010],
+        'Z' => [0b111, 0b001, 0b010, 0b100, 0b111],
+        '.' => [0, 0, 0, 0, 0b010],
+        ',' => [0, 0, 0, 0b010, 0b100],
+        ':' => [0, 0b010, 0, 0b010, 0],
+        '!' => [0b010, 0b010, 0b010, 0, 0b010],
+        '\'' => [0b010, 0b010, 0, 0, 0],
+        '-' => [0, 0, 0b111, 0, 0],
+        '+' => [0, 0b010, 0b111, 0b010, 0],
+        '/' => [0b001, 0b001, 0b010, 0b100, 0b100],
+        '%' => [0b101, 0b001, 0b010, 0b100, 0b101],
+        '(' => [0b001, 0b010, 0b010, 0b010, 0b001],
+        ')' => [0b100, 0b010, 0b010, 0b010, 0b100],
+        '$' => [0b011, 0b110, 0b010, 0b011, 0b110],
+        '#' => [0b101, 0b111, 0b101, 0b111, 0b101],
+        _ => [0b110, 0b001, 0b010, 0, 0b010],
+    }
+}
+
+/// Width in pixels of `text` drawn by `draw_text`, including its background.
+pub fn text_width(text: &str) -> usize {
+    text.chars().count() * (GLYPH_WIDTH + 1) + 1
+}
+
+/// Draws `text` on a black background, with its top left corner at `x`, `y`.
+pub fn draw_text(frame: &mut Frame, x: usize, y: usize, text: &str) {
+    draw_text_coloured(frame, x, y, text, TEXT_COLOUR, (0, 0, 0));
+}
+
+pub fn draw_text_coloured(
+    frame: &mut Frame,
+    x: usize,
+    y: usize,
+    text: &str,
+    colour: (u8, u8, u8),
+    background: (u8, u8, u8),
+) {
+    for dy in 0..TEXT_HEIGHT {
+        for dx in 0..text_width(text) {
+            frame.set_pixel(x + dx, y + dy, background);
+        }
+    }
+    for (i, c) in text.chars().enumerate() {
+        let left = x + 1 + i * (GLYPH_WIDTH + 1);
+        for (row, bits) in glyph(c).iter().enumerate() {
+            for column in 0..GLYPH_WIDTH {
+                if bits & (0b100 >> column) != 0 {
+                    frame.set_pixel(left + column, y + 1 + row, colour);
+                }
+            }
+        }
+    }
+}
+
+struct Message {
+    text: String,
+    frames_left: u32,
+}
+
+/// Short messages drawn over the picture for a while, like "Cheats on" or "Speed 200%".
+pub struct Osd {
+    messages: Vec<Message>,
+    duration: u32,
+    corner: Option<String>,
+}
+
+impl Osd {
+    /// `duration` is how many frames a message stays up for.
+    pub fn new(duration: u32) -> Self {
+        Osd {
+            messages: vec![],
+            duration,
+            corner: None,
+        }
+    }
+
+    pub fn show(&mut self, text: &str) {
+        self.show_for(text, self.duration);
+    }
+
+    /// Showing a message that is already up only restarts its timer.
+    pub fn show_for(&mut self, text: &str, frames: u32) {
+        self.messages.retain(|message| message.text != text);
+        self.messages.push(Message {
+            text: text.to_string(),
+            frames_left: frames,
+        });
+        if self.messages.len() > MAX_MESSAGES {
+            self.messages.remove(0);
+        }
+    }
+
+    /// Text that stays up in the top right corner until it's cleared, like the FPS counter.
+    pub fn set_corner(&mut self, text: Option<String>) {
+        self.corner = text;
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.messages.is_empty() && self.corner.is_none()
+    }
+
+    /// Draws the messages in the bottom left corner, newest at the bottom, and counts down
+    /// a frame on each of them.
+    pub fn draw(&mut self, frame: &mut Frame) {
+        if let Some(text) = &self.corner {
+            draw_text(frame, 254usize.saturating_sub(text_width(text)), 2, text);
+        }
+        let bottom = 240 - 2;
+        for (i, message) in self.messages.iter().rev().enumerate() {
+            let y = bottom - (i + 1) * LINE_HEIGHT;
+            draw_text(frame, 2, y, &message.text);
+        }
+        for message in self.messages.iter_mut() {
+            message.frames_left = message.frames_left.saturating_sub(1);
+        }
+        self.messages.retain(|message| message.frames_left > 0);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_draw_text() {
+        let mut frame = Frame::new();
+        frame.set_pixel(20, 20, (1, 2, 3));
+        draw_text(&mut frame, 10, 10, "1");
+        assert_eq!(text_width("1"), 5);
+        // the top row of a 1 is just its middle pixel
+        assert_eq!(frame.get_pixel(11, 11), (0, 0, 0));
+        assert_eq!(frame.get_pixel(12, 11), TEXT_COLOUR);
+        assert_eq!(frame.get_pixel(11, 12), TEXT_COLOUR);
+        assert_eq!(frame.get_pixel(20, 20), (1, 2, 3));
+    }
+
+    #[test]
+    fn test_messages_expire() {
+        let mut osd = Osd::new(2);
+        let mut frame = Frame::new();
+        osd.show("Cheats on");
+        osd.draw(&mut frame);
+        osd.show("Cheats on");
+        osd.draw(&mut frame);
+        assert!(!osd.is_empty());
+        osd.draw(&mut frame);
+        assert!(osd.is_empty());
+    }
+}
@@ -1,3 +1,5 @@
+use crate::ppu::NesPPU;
+
 #[rustfmt::skip]
 pub static SYSTEM_PALLETE: [(u8,u8,u8); 64] = [
    (0x80, 0x80, 0x80), (0x00, 0x3D, 0xA6), (0x00, 0x12, 0xB0), (0x44, 0x00, 0x96), (0xA1, 0x00, 0x5E),
@@ -14,3 +16,125 @@ pub static SYSTEM_PALLETE: [(u8,u8,u8); 64] = [
    (0xFF, 0xEF, 0xA6), (0xFF, 0xF7, 0x9C), (0xD7, 0xE8, 0x95), (0xA6, 0xED, 0xAF), (0xA2, 0xF2, 0xDA),
    (0x99, 0xFF, 0xFC), (0xDD, 0xDD, 0xDD), (0x11, 0x11, 0x11), (0x11, 0x11, 0x11)
 ];
+
+// The RGB PPU (RP2C03) in VS System cabinets, with 3 bits for each of red, green and blue
+#[rustfmt::skip]
+const RGB_LEVELS: [u16; 64] = [
+    0o333, 0o014, 0o006, 0o326, 0o403, 0o503, 0o510, 0o420,
+    0o320, 0o120, 0o031, 0o040, 0o022, 0o000, 0o000, 0o000,
+    0o555, 0o036, 0o027, 0o407, 0o507, 0o704, 0o700, 0o630,
+    0o430, 0o140, 0o040, 0o053, 0o044, 0o000, 0o000, 0o000,
+    0o777, 0o357, 0o447, 0o637, 0o707, 0o737, 0o740, 0o750,
+    0o660, 0o360, 0o070, 0o276, 0o077, 0o000, 0o000, 0o000,
+    0o777, 0o567, 0o657, 0o757, 0o747, 0o755, 0o764, 0o772,
+    0o773, 0o572, 0o473, 0o276, 0o467, 0o000, 0o000, 0o000,
+];
+
+/// Which PPU the colours come from.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum PaletteKind {
+    /// The NES's own PPU, which makes a composite video signal.
+    #[default]
+    Composite,
+    /// The RGB PPU in VS System cabinets, which games made for the 2C03 and 2C05 expect.
+    Rgb,
+}
+
+// Each emphasis bit darkens the two other colour channels
+const EMPHASIS_ATTENUATION: f32 = 0.816328;
+
+lazy_static! {
+    /// `SYSTEM_PALLETE` for every combination of the PPUMASK emphasis bits, indexed by
+    /// those bits (red = 1, green = 2, blue = 4).
+    pub static ref EMPHASIZED_PALETTES: [[(u8, u8, u8); 64]; 8] = {
+        let mut palettes = [[(0, 0, 0); 64]; 8];
+        for (emphasis, palette) in palettes.iter_mut().enumerate() {
+            for (index, colour) in palette.iter_mut().enumerate() {
+                *colour = emphasize(index, emphasis as u8);
+            }
+        }
+        palettes
+    };
+
+    /// The same for the RGB PPU, where each emphasis bit turns its own channel up full.
+    pub static ref RGB_PALETTES: [[(u8, u8, u8); 64]; 8] = {
+        let mut palettes = [[(0, 0, 0); 64]; 8];
+        for (emphasis, palette) in palettes.iter_mut().enumerate() {
+            for (index, colour) in palette.iter_mut().enumerate() {
+                let level = |channel: usize| {
+                    if emphasis & (1 << channel) != 0 {
+                        0xff
+                    } else {
+                        (((RGB_LEVELS[index] >> (6 - 3 * channel)) & 0b111) * 0xff / 7) as u8
+                    }
+                };
+                *colour = (level(0), level(1), level(2));
+            }
+        }
+        palettes
+    };
+}
+
+fn emphasize(index: usize, emphasis: u8) -> (u8, u8, u8) {
+    let (r, g, b) = SYSTEM_PALLETE[index];
+    // the blacks in columns $xE/$xF are not affected
+    if index & 0x0f >= 0x0e {
+        return (r, g, b);
+    }
+
+    let mut channels = [r as f32, g as f32, b as f32];
+    for (i, channel) in channels.iter_mut().enumerate() {
+        let others = (0..3)
+            .filter(|bit| *bit != i && emphasis & (1 << bit) != 0)
+            .count();
+        *channel *= EMPHASIS_ATTENUATION.powi(others as i32);
+    }
+    (channels[0] as u8, channels[1] as u8, channels[2] as u8)
+}
+
+/// Resolves a palette RAM entry to a colour, applying the greyscale and emphasis bits.
+pub fn lookup(ppu: &NesPPU, index: u8) -> (u8, u8, u8) {
+    let index = if ppu.mask.is_grayscale() {
+        index & 0x30
+    } else {
+        index & 0x3f
+    } as usize;
+    let emphasis = (ppu.mask.bits() >> 5) as usize;
+    match ppu.palette {
+        PaletteKind::Composite => EMPHASIZED_PALETTES[emphasis][index],
+        PaletteKind::Rgb => RGB_PALETTES[emphasis][index],
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_greyscale_uses_grey_column() {
+        let mut ppu = NesPPU::new_empty_rom();
+        ppu.mask.update(0b0000_0001);
+        assert_eq!(lookup(&ppu, 0x21), SYSTEM_PALLETE[0x20]);
+        assert_eq!(lookup(&ppu, 0x16), SYSTEM_PALLETE[0x10]);
+    }
+
+    #[test]
+    fn test_emphasis_attenuates_other_channels() {
+        let mut ppu = NesPPU::new_empty_rom();
+        ppu.mask.update(0b0010_0000);
+        // red emphasis on white leaves red alone and darkens green and blue
+        assert_eq!(lookup(&ppu, 0x30), (0xff, 0xd0, 0xd0));
+        assert_eq!(lookup(&ppu, 0x0f), SYSTEM_PALLETE[0x0f]);
+    }
+
+    #[test]
+    fn test_rgb_palette() {
+        let mut ppu = NesPPU::new_empty_rom();
+        ppu.palette = PaletteKind::Rgb;
+        assert_eq!(lookup(&ppu, 0x16), (0xff, 0, 0));
+        assert_eq!(lookup(&ppu, 0x01), (0, 0x24, 0x91));
+        // emphasis turns its channel up rather than the others down
+        ppu.mask.update(0b0100_0000);
+        assert_eq!(lookup(&ppu, 0x16), (0xff, 0xff, 0));
+    }
+}
@@ -0,0 +1,155 @@
+use crate::cpu::CPU;
+use crate::savestate;
+use std::collections::VecDeque;
+
+/// Keeps a rolling history of savestates so the console can be stepped backwards in time.
+///
+/// Only the newest snapshot is stored in full. Every older snapshot is kept as the XOR
+/// difference to its successor, which is mostly zeros between consecutive frames and
+/// compresses well with a simple zero-run encoding.
+pub struct Rewind {
+    interval: usize,
+    capacity: usize,
+    frames_since_snapshot: usize,
+    latest: Option<Vec<u8>>,
+    deltas: VecDeque<Vec<u8>>,
+}
+
+impl Rewind {
+    /// `interval` is the number of frames between snapshots.
+    pub fn new(seconds: usize, interval: usize) -> Self {
+        Rewind {
+            interval,
+            capacity: seconds * 60 / interval,
+            frames_since_snapshot: 0,
+            latest: None,
+            deltas: VecDeque::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.deltas.len() + self.latest.iter().count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.latest.is_none()
+    }
+
+    /// Call once per emulated frame; records a snapshot every `interval` frames.
+    pub fn on_frame(&mut self, cpu: &CPU) {
+        self.frames_since_snapshot += 1;
+        if self.frames_since_snapshot >= self.interval {
+            self.frames_since_snapshot = 0;
+            self.push(savestate::save(cpu));
+        }
+    }
+
+    /// Restores the most recent snapshot and drops it from the history. The oldest
+    /// snapshot is never dropped, so holding rewind parks the console there.
+    pub fn step_back(&mut self, cpu: &mut CPU) -> bool {
+        match self.pop() {
+            Some(state) => savestate::load(cpu, &state).is_ok(),
+            None => false,
+        }
+    }
+
+    pub fn push(&mut self, state: Vec<u8>) {
+        if let Some(previous) = self.latest.take() {
+            self.deltas.push_back(compress(&xor(&previous, &state)));
+            if self.deltas.len() >= self.capacity {
+                self.deltas.pop_front();
+            }
+        }
+        self.latest = Some(state);
+        self.frames_since_snapshot = 0;
+    }
+
+    pub fn pop(&mut self) -> Option<Vec<u8>> {
+        let latest = self.latest.take()?;
+        self.latest = match self.deltas.pop_back() {
+            Some(delta) => Some(xor(&decompress(&delta), &latest)),
+            None => Some(latest.clone()),
+        };
+        Some(latest)
+    }
+}
+
+// XORs `a` against `b`, treating `b` as zero-padded/truncated to the length of `a`.
+fn xor(a: &[u8], b: &[u8]) -> Vec<u8> {
+    a.iter()
+        .enumerate()
+        .map(|(i, x)| x ^ b.get(i).copied().unwrap_or(0))
+        .collect()
+}
+
+// Zero bytes are encoded as (0, run length); everything else is stored verbatim.
+fn compress(data: &[u8]) -> Vec<u8> {
+    let mut result = Vec::new();
+    let mut i = 0;
+    while i < data.len() {
+        if data[i] == 0 {
+            let run = data[i..].iter().take(255).take_while(|x| **x == 0).count();
+            result.push(0);
+            result.push(run as u8);
+            i += run;
+        } else {
+            result.push(data[i]);
+            i += 1;
+        }
+    }
+    result
+}
+
+fn decompress(data: &[u8]) -> Vec<u8> {
+    let mut result = Vec::new();
+    let mut i = 0;
+    while i < data.len() {
+        if data[i] == 0 {
+            result.resize(result.len() + data[i + 1] as usize, 0);
+            i += 2;
+        } else {
+            result.push(data[i]);
+            i += 1;
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_compression_round_trip() {
+        let mut data = vec![0; 1000];
+        data[3] = 7;
+        data[700] = 0xff;
+        let compressed = compress(&data);
+        assert!(compressed.len() < 20);
+        assert_eq!(decompress(&compressed), data);
+    }
+
+    #[test]
+    fn test_pops_states_newest_first() {
+        let mut rewind = Rewind::new(1, 2);
+        rewind.push(vec![1, 2, 3]);
+        rewind.push(vec![1, 2, 4]);
+        rewind.push(vec![9, 2, 4]);
+        assert_eq!(rewind.len(), 3);
+
+        assert_eq!(rewind.pop(), Some(vec![9, 2, 4]));
+        assert_eq!(rewind.pop(), Some(vec![1, 2, 4]));
+        assert_eq!(rewind.pop(), Some(vec![1, 2, 3]));
+        // the oldest state is kept around
+        assert_eq!(rewind.pop(), Some(vec![1, 2, 3]));
+    }
+
+    #[test]
+    fn test_history_is_bounded() {
+        let mut rewind = Rewind::new(1, 20);
+        for i in 0..10 {
+            rewind.push(vec![i; 4]);
+        }
+        assert_eq!(rewind.len(), 3);
+    }
+}
@@ -1,32 +1,120 @@
+use crate::compat::{self, Region};
+use crate::mapper::SUPPORTED_MAPPERS;
+use std::fmt;
+
 const NES_TAG: [u8; 4] = [0x4e, 0x45, 0x53, 0x1A];
 const PRG_ROM_PAGE_SIZE: usize = 16384;
 const CHR_ROM_PAGE_SIZE: usize = 8192;
+const CHR_RAM_SIZE: usize = 8192;
+const PRG_RAM_PAGE_SIZE: usize = 8192;
+const HEADER_SIZE: usize = 16;
+const TRAINER_SIZE: usize = 512;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum RomError {
+    BadMagic,
+    Nes2Unsupported,
+    UnsupportedMapper { id: u8 },
+    TruncatedHeader,
+    TruncatedTrainer,
+    TruncatedPrg { expected: usize, found: usize },
+    TruncatedChr { expected: usize, found: usize },
+}
 
-#[derive(Debug, PartialEq)]
+impl fmt::Display for RomError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RomError::BadMagic => write!(f, "File is not in the iNES format!"),
+            RomError::Nes2Unsupported => write!(f, "NES2.0 format is not supported!"),
+            RomError::UnsupportedMapper { id } => write!(f, "Mapper {} is not supported!", id),
+            RomError::TruncatedHeader => write!(f, "File is too short to hold an iNES header!"),
+            RomError::TruncatedTrainer => write!(f, "File ends inside the trainer!"),
+            RomError::TruncatedPrg { expected, found } => write!(
+                f,
+                "PRG ROM is truncated: expected {} bytes, found {}",
+                expected, found
+            ),
+            RomError::TruncatedChr { expected, found } => write!(
+                f,
+                "CHR ROM is truncated: expected {} bytes, found {}",
+                expected, found
+            ),
+        }
+    }
+}
+
+impl std::error::Error for RomError {}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum Mirroring {
     Vertical,
     Horizontal,
     FourScreen,
+    /// All four nametables show the first 1 KiB of VRAM, or the second.
+    SingleScreenLower,
+    SingleScreenUpper,
 }
 
+/// What the game was made for, from the iNES header.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum System {
+    #[default]
+    Nes,
+    /// Nintendo's VS System arcade cabinets, with coin slots, DIP switches and an RGB PPU.
+    VsSystem,
+    /// The PlayChoice-10 arcade machine, which runs the NES games as they are. The hint
+    /// screen ROM that follows the CHR in these dumps is left out.
+    PlayChoice10,
+}
+
+#[derive(Clone)]
 pub struct Rom {
     pub prg_rom: Vec<u8>,
     pub chr_rom: Vec<u8>,
+    /// Set when the cartridge has no CHR ROM; `chr_rom` is then 8 KiB of writable CHR RAM.
+    pub chr_ram: bool,
+    /// The 512 bytes some dumps carry for PRG RAM at $7000-$71FF, put there by
+    /// `mapper::from_rom` before the game starts.
+    pub trainer: Option<Vec<u8>>,
+    /// How much RAM the board has at $6000, from byte 8 of the header. Old dumps leave it
+    /// at 0, which means 8 KiB.
+    pub prg_ram_size: usize,
+    /// The RAM at $6000 is kept powered by a battery, so games save to it.
+    pub battery: bool,
     pub mapper: u8,
     pub screen_mirroring: Mirroring,
+    pub system: System,
 }
 
-impl Rom {
-    pub fn new(raw: &Vec<u8>) -> Result<Rom, String> {
-        if &raw[0..4] != NES_TAG {
-            return Err("File is not in the iNES format!".to_string());
-        }
+/// The 16 byte header in front of the game, read as it is without checking whether the
+/// emulator can run what it describes.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Header {
+    /// The header is in the NES 2.0 format rather than the original iNES one.
+    pub nes2: bool,
+    pub mapper: u8,
+    /// Only NES 2.0 headers have one.
+    pub submapper: Option<u8>,
+    pub prg_rom_size: usize,
+    pub chr_rom_size: usize,
+    pub prg_ram_size: usize,
+    pub battery: bool,
+    pub trainer: bool,
+    pub screen_mirroring: Mirroring,
+    pub system: System,
+    pub region: Region,
+}
 
-        let ines_ver = (raw[7] >> 2) & 0b11;
-        if ines_ver != 0 {
-            return Err("NES2.0 format is not supported!".to_string());
+impl Header {
+    pub fn parse(raw: &[u8]) -> Result<Header, RomError> {
+        if raw.len() < 4 || raw[0..4] != NES_TAG {
+            return Err(RomError::BadMagic);
+        }
+        if raw.len() < HEADER_SIZE {
+            return Err(RomError::TruncatedHeader);
         }
 
+        let nes2 = (raw[7] >> 2) & 0b11 == 0b10;
         let mapper = (raw[7] & 0b1111_0000) | (raw[6] >> 4);
 
         let four_screen = raw[6] & 0b1000 != 0;
@@ -37,21 +125,140 @@ impl Rom {
             (false, false) => Mirroring::Horizontal,
         };
 
-        let prg_rom_size = (raw[4] as usize) * PRG_ROM_PAGE_SIZE;
-        let chr_rom_size = (raw[5] as usize) * CHR_ROM_PAGE_SIZE;
-
-        let skip_trainer = raw[6] & 0b100 != 0;
+        let system = match raw[7] & 0b11 {
+            0b01 => System::VsSystem,
+            0b10 => System::PlayChoice10,
+            _ => System::Nes,
+        };
 
-        let prg_rom_start = 16 + if skip_trainer { 512 } else { 0 };
-        let chr_rom_start = prg_rom_start + prg_rom_size;
+        let (prg_pages, chr_pages, prg_ram_size, region) = if nes2 {
+            // the high bits of the ROM sizes are in byte 9, and RAM sizes are shifts of 64
+            // bytes, with battery backed RAM in the high nibble
+            let ram_size = |shift: u8| if shift == 0 { 0 } else { 64 << shift };
+            (
+                raw[4] as usize | (raw[9] as usize & 0x0f) << 8,
+                raw[5] as usize | (raw[9] as usize >> 4) << 8,
+                ram_size(raw[10] & 0x0f) + ram_size(raw[10] >> 4),
+                if raw[12] & 0b11 == 0b01 {
+                    Region::Pal
+                } else {
+                    Region::Ntsc
+                },
+            )
+        } else {
+            (
+                raw[4] as usize,
+                raw[5] as usize,
+                raw[8].max(1) as usize * PRG_RAM_PAGE_SIZE,
+                if raw[9] & 0b1 != 0 {
+                    Region::Pal
+                } else {
+                    Region::Ntsc
+                },
+            )
+        };
 
-        Ok(Rom {
-            prg_rom: raw[prg_rom_start..(prg_rom_start + prg_rom_size)].to_vec(),
-            chr_rom: raw[chr_rom_start..(chr_rom_start + chr_rom_size)].to_vec(),
+        Ok(Header {
+            nes2,
             mapper,
+            submapper: if nes2 { Some(raw[8] >> 4) } else { None },
+            prg_rom_size: prg_pages * PRG_ROM_PAGE_SIZE,
+            chr_rom_size: chr_pages * CHR_ROM_PAGE_SIZE,
+            prg_ram_size,
+            battery: raw[6] & 0b10 != 0,
+            trainer: raw[6] & 0b100 != 0,
             screen_mirroring,
+            system,
+            region,
         })
     }
+
+    /// Where the PRG ROM starts in the file, after the header and the trainer.
+    pub fn prg_rom_start(&self) -> usize {
+        HEADER_SIZE + if self.trainer { TRAINER_SIZE } else { 0 }
+    }
+}
+
+/// Only the original iNES format can be loaded so far, NES 2.0 headers are just read.
+/// Expects a whole header.
+pub fn check_format(raw: &[u8]) -> Result<(), RomError> {
+    let ines_ver = (raw[7] >> 2) & 0b11;
+    if ines_ver != 0 {
+        return Err(RomError::Nes2Unsupported);
+    }
+    Ok(())
+}
+
+impl Rom {
+    pub fn new(raw: &Vec<u8>) -> Result<Rom, RomError> {
+        let header = Header::parse(raw)?;
+        check_format(raw)?;
+
+        let prg_rom_size = header.prg_rom_size;
+        let chr_rom_size = header.chr_rom_size;
+        let has_trainer = header.trainer;
+
+        let prg_rom_start = header.prg_rom_start();
+        let chr_rom_start = prg_rom_start + prg_rom_size;
+
+        if raw.len() < prg_rom_start {
+            return Err(RomError::TruncatedTrainer);
+        }
+        if raw.len() < chr_rom_start {
+            return Err(RomError::TruncatedPrg {
+                expected: prg_rom_size,
+                found: raw.len() - prg_rom_start,
+            });
+        }
+        if raw.len() < chr_rom_start + chr_rom_size {
+            return Err(RomError::TruncatedChr {
+                expected: chr_rom_size,
+                found: raw.len() - chr_rom_start,
+            });
+        }
+
+        let chr_ram = chr_rom_size == 0;
+        let chr_rom = if chr_ram {
+            vec![0; CHR_RAM_SIZE]
+        } else {
+            raw[chr_rom_start..(chr_rom_start + chr_rom_size)].to_vec()
+        };
+
+        let mut rom = Rom {
+            prg_rom: raw[prg_rom_start..(prg_rom_start + prg_rom_size)].to_vec(),
+            chr_rom,
+            chr_ram,
+            trainer: if has_trainer {
+                Some(raw[HEADER_SIZE..prg_rom_start].to_vec())
+            } else {
+                None
+            },
+            prg_ram_size: header.prg_ram_size,
+            battery: header.battery,
+            mapper: header.mapper,
+            screen_mirroring: header.screen_mirroring,
+            system: header.system,
+        };
+        // games known to have a wrong header are put right before the mapper is checked
+        if let Some(game) = compat::lookup(&rom) {
+            game.fix(&mut rom);
+        }
+        if !SUPPORTED_MAPPERS.contains(&rom.mapper) {
+            return Err(RomError::UnsupportedMapper { id: rom.mapper });
+        }
+        Ok(rom)
+    }
+
+    /// The CRC32 of the PRG and CHR ROM, which the compatibility database is keyed by.
+    pub fn crc32(&self) -> u32 {
+        let chr_rom: &[u8] = if self.chr_ram { &[] } else { &self.chr_rom };
+        compat::crc32(&self.prg_rom, chr_rom)
+    }
+
+    pub fn sha1(&self) -> String {
+        let chr_rom: &[u8] = if self.chr_ram { &[] } else { &self.chr_rom };
+        compat::sha1(&self.prg_rom, chr_rom)
+    }
 }
 
 pub mod test {
@@ -85,7 +292,7 @@ pub mod test {
     pub fn test_rom() -> Rom {
         let test_rom = create_rom(TestRom {
             header: vec![
-                0x4E, 0x45, 0x53, 0x1A, 0x02, 0x01, 0x31, 00, 00, 00, 00, 00, 00, 00, 00, 00,
+                0x4E, 0x45, 0x53, 0x1A, 0x02, 0x01, 0x01, 00, 00, 00, 00, 00, 00, 00, 00, 00,
             ],
             trainer: None,
             pgp_rom: vec![1; 2 * PRG_ROM_PAGE_SIZE],
@@ -99,7 +306,7 @@ pub mod test {
     fn test() {
         let test_rom = create_rom(TestRom {
             header: vec![
-                0x4E, 0x45, 0x53, 0x1A, 0x02, 0x01, 0x31, 00, 00, 00, 00, 00, 00, 00, 00, 00,
+                0x4E, 0x45, 0x53, 0x1A, 0x02, 0x01, 0x01, 00, 00, 00, 00, 00, 00, 00, 00, 00,
             ],
             trainer: None,
             pgp_rom: vec![1; 2 * PRG_ROM_PAGE_SIZE],
@@ -110,7 +317,9 @@ pub mod test {
 
         assert_eq!(rom.chr_rom, vec!(2; 1 * CHR_ROM_PAGE_SIZE));
         assert_eq!(rom.prg_rom, vec!(1; 2 * PRG_ROM_PAGE_SIZE));
-        assert_eq!(rom.mapper, 3);
+        assert_eq!(rom.trainer, None);
+        assert_eq!(rom.mapper, 0);
+        assert_eq!(rom.system, System::Nes);
         assert_eq!(rom.screen_mirroring, Mirroring::Vertical);
     }
 
@@ -124,7 +333,7 @@ pub mod test {
                 0x1A,
                 0x02,
                 0x01,
-                0x31 | 0b100,
+                0x01 | 0b100,
                 00,
                 00,
                 00,
@@ -135,7 +344,7 @@ pub mod test {
                 00,
                 00,
             ],
-            trainer: Some(vec![0; 512]),
+            trainer: Some(vec![3; 512]),
             pgp_rom: vec![1; 2 * PRG_ROM_PAGE_SIZE],
             chr_rom: vec![2; 1 * CHR_ROM_PAGE_SIZE],
         });
@@ -144,15 +353,24 @@ pub mod test {
 
         assert_eq!(rom.chr_rom, vec!(2; 1 * CHR_ROM_PAGE_SIZE));
         assert_eq!(rom.prg_rom, vec!(1; 2 * PRG_ROM_PAGE_SIZE));
-        assert_eq!(rom.mapper, 3);
+        assert_eq!(rom.trainer, Some(vec![3; 512]));
+        assert_eq!(rom.mapper, 0);
         assert_eq!(rom.screen_mirroring, Mirroring::Vertical);
+
+        // loaded into PRG RAM at $7000
+        let mapper = crate::mapper::from_rom(rom).unwrap();
+        let mapper = mapper.borrow();
+        assert_eq!(mapper.read_prg(0x6fff), 0);
+        assert_eq!(mapper.read_prg(0x7000), 3);
+        assert_eq!(mapper.read_prg(0x71ff), 3);
+        assert_eq!(mapper.read_prg(0x7200), 0);
     }
 
     #[test]
     fn test_nes2_is_not_supported() {
         let test_rom = create_rom(TestRom {
             header: vec![
-                0x4E, 0x45, 0x53, 0x1A, 0x01, 0x01, 0x31, 0x8, 00, 00, 00, 00, 00, 00, 00, 00,
+                0x4E, 0x45, 0x53, 0x1A, 0x01, 0x01, 0x01, 0x8, 00, 00, 00, 00, 00, 00, 00, 00,
             ],
             trainer: None,
             pgp_rom: vec![1; 1 * PRG_ROM_PAGE_SIZE],
@@ -161,7 +379,74 @@ pub mod test {
         let rom = Rom::new(&test_rom);
         match rom {
             Result::Ok(_) => assert!(false, "should not load rom"),
-            Result::Err(str) => assert_eq!(str, "NES2.0 format is not supported!"),
+            Result::Err(err) => assert_eq!(err, RomError::Nes2Unsupported),
         }
     }
+
+    #[test]
+    fn test_chr_ram() {
+        let test_rom = create_rom(TestRom {
+            header: vec![
+                0x4E, 0x45, 0x53, 0x1A, 0x01, 0x00, 0x01, 00, 00, 00, 00, 00, 00, 00, 00, 00,
+            ],
+            trainer: None,
+            pgp_rom: vec![1; PRG_ROM_PAGE_SIZE],
+            chr_rom: vec![],
+        });
+
+        let rom = Rom::new(&test_rom).unwrap();
+        assert!(rom.chr_ram);
+        assert_eq!(rom.chr_rom, vec![0; CHR_RAM_SIZE]);
+    }
+
+    #[test]
+    fn test_vs_system() {
+        let test_rom = create_rom(TestRom {
+            header: vec![
+                0x4E, 0x45, 0x53, 0x1A, 0x02, 0x02, 0x38, 0x61, 0x02, 00, 00, 00, 00, 00, 00, 00,
+            ],
+            trainer: None,
+            pgp_rom: vec![1; 2 * PRG_ROM_PAGE_SIZE],
+            chr_rom: vec![2; 2 * CHR_ROM_PAGE_SIZE],
+        });
+        let rom = Rom::new(&test_rom).unwrap();
+        assert_eq!(rom.system, System::VsSystem);
+        assert_eq!(rom.prg_ram_size, 2 * PRG_RAM_PAGE_SIZE);
+        assert_eq!(rom.mapper, 99);
+        assert_eq!(rom.screen_mirroring, Mirroring::FourScreen);
+    }
+
+    #[test]
+    fn test_unsupported_mapper() {
+        let test_rom = create_rom(TestRom {
+            header: vec![
+                0x4E, 0x45, 0x53, 0x1A, 0x01, 0x01, 0x31, 0x10, 00, 00, 00, 00, 00, 00, 00, 00,
+            ],
+            trainer: None,
+            pgp_rom: vec![1; PRG_ROM_PAGE_SIZE],
+            chr_rom: vec![2; CHR_ROM_PAGE_SIZE],
+        });
+        let err = Rom::new(&test_rom).err().unwrap();
+        assert_eq!(err, RomError::UnsupportedMapper { id: 0x13 });
+        assert_eq!(err.to_string(), "Mapper 19 is not supported!");
+    }
+
+    #[test]
+    fn test_truncated_chr() {
+        let test_rom = create_rom(TestRom {
+            header: vec![
+                0x4E, 0x45, 0x53, 0x1A, 0x01, 0x01, 0x01, 00, 00, 00, 00, 00, 00, 00, 00, 00,
+            ],
+            trainer: None,
+            pgp_rom: vec![1; PRG_ROM_PAGE_SIZE],
+            chr_rom: vec![2; 100],
+        });
+        assert_eq!(
+            Rom::new(&test_rom).err(),
+            Some(RomError::TruncatedChr {
+                expected: CHR_ROM_PAGE_SIZE,
+                found: 100
+            })
+        );
+    }
 }
@@ -0,0 +1,180 @@
+use crate::cpu::{CpuState, CPU};
+use crate::joypad::JoypadButton;
+use crate::savestate;
+use std::collections::VecDeque;
+
+/// The buttons and turbo buttons held on each of the four controllers.
+type Input = [(JoypadButton, JoypadButton); 4];
+
+fn input(cpu: &mut CPU) -> Input {
+    let mut input = [(JoypadButton::empty(), JoypadButton::empty()); 4];
+    for (player, held) in input.iter_mut().enumerate() {
+        if let Some(joypad) = cpu.bus.joypad_mut(player + 1) {
+            *held = (joypad.held(), joypad.turbo_held());
+        }
+    }
+    input
+}
+
+fn set_input(cpu: &mut CPU, input: &Input) {
+    for (player, &(held, turbo)) in input.iter().enumerate() {
+        if let Some(joypad) = cpu.bus.joypad_mut(player + 1) {
+            joypad.set_button_pressed_status(JoypadButton::all(), false);
+            joypad.set_button_pressed_status(held, true);
+            joypad.set_turbo_pressed(JoypadButton::all(), false);
+            joypad.set_turbo_pressed(turbo, true);
+        }
+    }
+}
+
+/// Runs the console to the start of the next frame, without showing or playing it.
+fn run_unseen_frame(cpu: &mut CPU) {
+    cpu.bus.running_ahead = true;
+    let frame = cpu.bus.frame_count();
+    while cpu.bus.frame_count() == frame {
+        if let CpuState::Error(_) = cpu.step() {
+            break;
+        }
+    }
+    cpu.bus.running_ahead = false;
+}
+
+/// Keeps the console a few frames ahead of where it really is, so the picture and sound
+/// show the buttons pressed that many frames sooner. Most games take a frame or two to
+/// react to a button, and this takes that lag away.
+///
+/// The frames ahead are run with the buttons held now, guessing they'll stay held. While
+/// they do, nothing more is run than without running ahead, besides a savestate a frame.
+/// When the buttons change, the console goes back to the last frame it really got to
+/// and runs the frames ahead again, unseen.
+pub struct RunAhead {
+    frames: usize,
+    /// The states at the start of each frame run ahead, the first being where the console
+    /// really is, and the last where it is now.
+    states: VecDeque<Vec<u8>>,
+    predicted: Option<Input>,
+    rollbacks: usize,
+}
+
+impl RunAhead {
+    pub fn new(frames: usize) -> Self {
+        RunAhead {
+            frames,
+            states: VecDeque::new(),
+            predicted: None,
+            rollbacks: 0,
+        }
+    }
+
+    pub fn frames(&self) -> usize {
+        self.frames
+    }
+
+    /// How many times the buttons changed and the frames ahead were run again.
+    pub fn rollbacks(&self) -> usize {
+        self.rollbacks
+    }
+
+    /// Where the console would be without running ahead.
+    pub fn real_state(&self) -> Option<&[u8]> {
+        self.states.front().map(Vec::as_slice)
+    }
+
+    /// Forgets the frames run ahead, for when the console has been changed some other
+    /// way, like by loading a state. It runs ahead again from where it is next frame.
+    pub fn clear(&mut self) {
+        self.states.clear();
+        self.predicted = None;
+    }
+
+    /// Called as each frame starts, once the buttons for it are set.
+    pub fn on_frame(&mut self, cpu: &mut CPU) {
+        let input = input(cpu);
+        if self.predicted == Some(input) && self.states.len() > 1 {
+            // the next frame was run with the right buttons, so it's real now
+            self.states.pop_front();
+            self.states.push_back(savestate::save(cpu));
+            return;
+        }
+
+        if self.states.len() > 1 {
+            // the frame that was run with the guessed buttons and should have had these
+            if savestate::load(cpu, &self.states[1]).is_err() {
+                self.clear();
+                return;
+            }
+            set_input(cpu, &input);
+            self.rollbacks += 1;
+        }
+        self.states.clear();
+        for _ in 0..self.frames {
+            self.states.push_back(savestate::save(cpu));
+            run_unseen_frame(cpu);
+        }
+        self.states.push_back(savestate::save(cpu));
+        self.predicted = Some(input);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::asm;
+    use crate::bus::Bus;
+    use crate::cpu::Mem;
+    use crate::rom::test;
+
+    // copies the first controller into $00 and counts up in $01, forever
+    fn console() -> CPU {
+        let mut cpu = CPU::new(Bus::new(test::test_rom()).unwrap());
+        let program = asm::assemble(
+            "loop: LDA #$01
+             STA $4016
+             LDA #$00
+             STA $4016
+             LDA $4016
+             STA $00
+             INC $01
+             JMP loop",
+        )
+        .unwrap();
+        cpu.load(program);
+        cpu.program_counter = asm::LOAD_ADDRESS;
+        cpu
+    }
+
+    fn press(cpu: &mut CPU, pressed: bool) {
+        let joypad = cpu.bus.joypad_mut(1).unwrap();
+        joypad.set_button_pressed_status(JoypadButton::BUTTON_A, pressed);
+    }
+
+    #[test]
+    fn test_runs_ahead_like_the_real_thing() {
+        let mut ahead = console();
+        let mut real = console();
+        let mut run_ahead = RunAhead::new(2);
+
+        for (frame, &pressed) in [false, true, true, true, false, false, true]
+            .iter()
+            .enumerate()
+        {
+            press(&mut ahead, pressed);
+            press(&mut real, pressed);
+            run_ahead.on_frame(&mut ahead);
+            assert_eq!(
+                run_ahead.real_state(),
+                Some(savestate::save(&real).as_slice()),
+                "frame {}",
+                frame
+            );
+            // the button is seen two frames sooner
+            assert_eq!(ahead.bus.frame_count(), real.bus.frame_count() + 2);
+            assert_eq!(ahead.mem_read(0x00) & 1, pressed as u8);
+
+            run_unseen_frame(&mut ahead);
+            run_unseen_frame(&mut real);
+        }
+        // the first frame runs ahead without going back
+        assert_eq!(run_ahead.rollbacks(), 3);
+    }
+}
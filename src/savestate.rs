@@ -0,0 +1,247 @@
+use std::path::{Path, PathBuf};
+
+pub trait Savestate {
+    fn save_state(&self, state: &mut StateWriter);
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), String>;
+}
+
+#[derive(Default)]
+pub struct StateWriter {
+    data: Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn new() -> Self {
+        StateWriter { data: Vec::new() }
+    }
+
+    pub fn write_u8(&mut self, value: u8) {
+        self.data.push(value);
+    }
+
+    pub fn write_bool(&mut self, value: bool) {
+        self.data.push(value as u8);
+    }
+
+    pub fn write_u16(&mut self, value: u16) {
+        self.data.extend(&value.to_le_bytes());
+    }
+
+    pub fn write_u32(&mut self, value: u32) {
+        self.data.extend(&value.to_le_bytes());
+    }
+
+    pub fn write_usize(&mut self, value: usize) {
+        self.data.extend(&(value as u64).to_le_bytes());
+    }
+
+    /// Writes a fixed-size block; the reader must know its length.
+    pub fn write_bytes(&mut self, bytes: &[u8]) {
+        self.data.extend(bytes);
+    }
+
+    /// Writes a length-prefixed block.
+    pub fn write_vec(&mut self, bytes: &[u8]) {
+        self.write_usize(bytes.len());
+        self.write_bytes(bytes);
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.data
+    }
+}
+
+pub struct StateReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> StateReader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        StateReader { data, pos: 0 }
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], String> {
+        if self.pos + len > self.data.len() {
+            return Err("Savestate is truncated!".to_string());
+        }
+        let bytes = &self.data[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    pub fn read_u8(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn read_bool(&mut self) -> Result<bool, String> {
+        Ok(self.read_u8()? != 0)
+    }
+
+    pub fn read_u16(&mut self) -> Result<u16, String> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+
+    pub fn read_u32(&mut self) -> Result<u32, String> {
+        let mut bytes = [0; 4];
+        bytes.copy_from_slice(self.take(4)?);
+        Ok(u32::from_le_bytes(bytes))
+    }
+
+    pub fn read_usize(&mut self) -> Result<usize, String> {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(self.take(8)?);
+        Ok(u64::from_le_bytes(bytes) as usize)
+    }
+
+    pub fn read_bytes(&mut self, out: &mut [u8]) -> Result<(), String> {
+        out.copy_from_slice(self.take(out.len())?);
+        Ok(())
+    }
+
+    pub fn read_vec(&mut self) -> Result<Vec<u8>, String> {
+        let len = self.read_usize()?;
+        Ok(self.take(len)?.to_vec())
+    }
+}
+
+pub fn save<S: Savestate>(component: &S) -> Vec<u8> {
+    let mut writer = StateWriter::new();
+    component.save_state(&mut writer);
+    writer.into_bytes()
+}
+
+pub fn load<S: Savestate>(component: &mut S, data: &[u8]) -> Result<(), String> {
+    component.load_state(&mut StateReader::new(data))
+}
+
+const MAGIC: &[u8; 4] = b"NESS";
+
+/// Goes up whenever what any component saves changes, so states from other versions are
+/// turned away instead of loading as garbage.
+pub const FORMAT_VERSION: u16 = 8;
+
+/// Saves `component` for a file of its own, after a header with the format version and
+/// the CRC32 of the game, `game_crc`, so it can't be loaded into another game.
+pub fn save_file<S: Savestate>(component: &S, game_crc: u32) -> Vec<u8> {
+    let mut writer = StateWriter::new();
+    writer.write_bytes(MAGIC);
+    writer.write_u16(FORMAT_VERSION);
+    writer.write_u32(game_crc);
+    component.save_state(&mut writer);
+    writer.into_bytes()
+}
+
+/// Loads a state saved by `save_file`. The header is checked before anything is
+/// loaded, so a state for another game or version leaves `component` as it was.
+pub fn load_file<S: Savestate>(
+    component: &mut S,
+    data: &[u8],
+    game_crc: u32,
+) -> Result<(), String> {
+    let mut reader = StateReader::new(data);
+    let mut magic = [0; 4];
+    reader
+        .read_bytes(&mut magic)
+        .map_err(|_| "Not a savestate".to_string())?;
+    if &magic != MAGIC {
+        return Err("Not a savestate".to_string());
+    }
+    let version = reader.read_u16()?;
+    if version != FORMAT_VERSION {
+        return Err(format!(
+            "The savestate is from version {} of the format, and only version {} can be loaded",
+            version, FORMAT_VERSION
+        ));
+    }
+    let crc = reader.read_u32()?;
+    if crc != game_crc {
+        return Err(format!(
+            "The savestate is for another game, with CRC32 {:08X}",
+            crc
+        ));
+    }
+    component.load_state(&mut reader)
+}
+
+/// Where numbered slot `slot` of the game at `game_path` is kept: game.ss1 and so on,
+/// next to the game.
+pub fn slot_path(game_path: &str, slot: u8) -> PathBuf {
+    Path::new(game_path).with_extension(format!("ss{}", slot))
+}
+
+/// Where the state is kept on exit, for carrying on next time.
+pub fn autosave_path(game_path: &str) -> PathBuf {
+    Path::new(game_path).with_extension("auto.ss")
+}
+
+/// Where the battery backed RAM of games that have it is kept between runs.
+pub fn battery_path(game_path: &str) -> PathBuf {
+    Path::new(game_path).with_extension("sav")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bus::Bus;
+    use crate::cpu::{Mem, CPU};
+    use crate::rom::test::test_rom;
+
+    #[test]
+    fn test_cpu_state_round_trip() {
+        let mut cpu = CPU::new(Bus::new(test_rom()).unwrap());
+        cpu.register_a = 0x42;
+        cpu.program_counter = 0x8123;
+        cpu.mem_write(0x0200, 0x77);
+        cpu.bus.ppu.bus.vram[0x10] = 0x55;
+
+        let state = save(&cpu);
+
+        cpu.register_a = 0;
+        cpu.program_counter = 0;
+        cpu.mem_write(0x0200, 0);
+        cpu.bus.ppu.bus.vram[0x10] = 0;
+
+        load(&mut cpu, &state).unwrap();
+        assert_eq!(cpu.register_a, 0x42);
+        assert_eq!(cpu.program_counter, 0x8123);
+        assert_eq!(cpu.mem_read(0x0200), 0x77);
+        assert_eq!(cpu.bus.ppu.bus.vram[0x10], 0x55);
+    }
+
+    #[test]
+    fn test_truncated_state_is_rejected() {
+        let mut cpu = CPU::new(Bus::new(test_rom()).unwrap());
+        let state = save(&cpu);
+        assert!(load(&mut cpu, &state[..state.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn test_state_files() {
+        let mut cpu = CPU::new(Bus::new(test_rom()).unwrap());
+        cpu.register_a = 0x42;
+        let file = save_file(&cpu, 0x1234_5678);
+        cpu.register_a = 0;
+
+        let err = load_file(&mut cpu, &file, 0x8765_4321).unwrap_err();
+        assert_eq!(
+            err,
+            "The savestate is for another game, with CRC32 12345678"
+        );
+        let mut old = file.clone();
+        old[4] = 0;
+        assert!(load_file(&mut cpu, &old, 0x1234_5678).is_err());
+        let headless = save(&cpu);
+        assert!(load_file(&mut cpu, &headless, 0x1234_5678).is_err());
+        assert_eq!(cpu.register_a, 0);
+
+        load_file(&mut cpu, &file, 0x1234_5678).unwrap();
+        assert_eq!(cpu.register_a, 0x42);
+
+        assert_eq!(slot_path("games/smb.nes", 10), Path::new("games/smb.ss10"));
+        assert_eq!(autosave_path("smb.nes"), Path::new("smb.auto.ss"));
+        assert_eq!(battery_path("zelda.nes"), Path::new("zelda.sav"));
+    }
+}
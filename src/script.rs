@@ -0,0 +1,439 @@
+use crate::bus::{Access, Watchpoint};
+use crate::cpu::CPU;
+use crate::joypad::JoypadButton;
+use crate::mapper::SharedMapper;
+use crate::render::frame::Frame;
+use crate::render::osd;
+use crate::state::Snapshot;
+use rhai::{Dynamic, Engine, EvalAltResult, Scope, AST, INT};
+use serde_json::Value;
+use std::cell::RefCell;
+use std::rc::Rc;
+
+const PLAYERS: usize = 4;
+
+// Scripts see the console's state as nested object maps
+fn to_dynamic(value: &Value) -> Dynamic {
+    match value {
+        Value::Null => Dynamic::UNIT,
+        Value::Bool(b) => (*b).into(),
+        Value::Number(n) => (n.as_i64().unwrap_or(0) as INT).into(),
+        Value::String(s) => s.clone().into(),
+        Value::Array(values) => Dynamic::from_array(values.iter().map(to_dynamic).collect()),
+        Value::Object(fields) => Dynamic::from_map(
+            fields
+                .iter()
+                .map(|(name, value)| (name.as_str().into(), to_dynamic(value)))
+                .collect(),
+        ),
+    }
+}
+
+fn colour(rgb: INT) -> (u8, u8, u8) {
+    ((rgb >> 16) as u8, (rgb >> 8) as u8, rgb as u8)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Shape {
+    Pixel(INT, INT, INT),
+    Rect(INT, INT, INT, INT, INT),
+    Fill(INT, INT, INT, INT, INT),
+    Text(INT, INT, String, INT),
+}
+
+/// What the script has drawn since the last frame, shown over the picture but left out of
+/// screenshots and recordings like the on screen messages.
+#[derive(Debug, Default, Clone)]
+pub struct Overlay {
+    shapes: Vec<Shape>,
+}
+
+impl Overlay {
+    pub fn is_empty(&self) -> bool {
+        self.shapes.is_empty()
+    }
+
+    pub fn draw(&self, frame: &mut Frame) {
+        // anything off the left or top of the screen is clipped like the right and bottom
+        let at = |x: INT, y: INT| (x.max(-1) as usize, y.max(-1) as usize);
+        for shape in self.shapes.iter() {
+            match *shape {
+                Shape::Pixel(x, y, rgb) => {
+                    let (x, y) = at(x, y);
+                    frame.set_pixel(x, y, colour(rgb));
+                }
+                Shape::Rect(x, y, width, height, rgb) | Shape::Fill(x, y, width, height, rgb) => {
+                    let filled = matches!(shape, Shape::Fill(..));
+                    let (right, bottom) = (x.saturating_add(width), y.saturating_add(height));
+                    // only the part on screen is walked, however big the script made it
+                    let columns = x.max(0)..right.min(frame.width() as INT);
+                    for py in y.max(0)..bottom.min(frame.height() as INT) {
+                        for px in columns.clone() {
+                            let edge = px == x || py == y || px == right - 1 || py == bottom - 1;
+                            if filled || edge {
+                                frame.set_pixel(px as usize, py as usize, colour(rgb));
+                            }
+                        }
+                    }
+                }
+                Shape::Text(x, y, ref text, rgb) => {
+                    let (x, y) = (x.max(0) as usize, y.max(0) as usize);
+                    osd::draw_text_coloured(frame, x, y, text, colour(rgb), (0, 0, 0));
+                }
+            }
+        }
+    }
+}
+
+/// What the functions the script calls share with the emulator.
+struct Api {
+    // RAM as it was when the hook was called, with the script's own writes on top
+    ram: [u8; 2048],
+    ram_writes: Vec<(u16, u8)>,
+    mapper: SharedMapper,
+    frame: usize,
+    state: Option<Snapshot>,
+    pressed: [JoypadButton; PLAYERS],
+    overlay: Rc<RefCell<Overlay>>,
+    hooks: Vec<(Watchpoint, String)>,
+}
+
+impl Api {
+    fn read(&self, addr: u16) -> u8 {
+        match addr {
+            0x0000..=0x1fff => self.ram[addr as usize & 0x7ff],
+            0x4020..=0x5fff => self.mapper.borrow().peek_expansion(addr).unwrap_or(0),
+            0x6000..=0xffff => self.mapper.borrow().read_prg(addr),
+            // the PPU and APU registers change things when they're read
+            _ => 0,
+        }
+    }
+
+    fn write(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x0000..=0x1fff => {
+                self.ram[addr as usize & 0x7ff] = data;
+                self.ram_writes.push((addr, data));
+            }
+            0x6000..=0x7fff => self.mapper.borrow_mut().write_prg(addr, data),
+            _ => {}
+        }
+    }
+}
+
+/// A Rhai script run alongside the game, for bots, HUDs and automated tests. It can
+/// define `on_frame_start()`, called before each frame with the buttons still to be set,
+/// and `on_frame_end()`, called once the frame is done, and hook reads and writes of
+/// chosen addresses with `on_read(addr, "function")` and `on_write(addr, "function")`.
+/// The hooked function is called with the address and the value after the instruction
+/// that accessed it.
+///
+/// Scripts can call `read(addr)`, `write(addr, value)`, `frame()`, `state()` for the
+/// registers of the CPU, PPU and APU, like `state().cpu.a`, `press(player, button)` to
+/// hold a button down for the next frame, and `pixel(x, y, rgb)`,
+/// `rect(x, y, width, height, rgb)`, `fill(x, y, width, height, rgb)` and
+/// `text(x, y, string)` or `text(x, y, string, rgb)` to draw over the picture.
+pub struct Script {
+    engine: Engine,
+    ast: AST,
+    scope: Scope<'static>,
+    api: Rc<RefCell<Api>>,
+    has_frame_start: bool,
+    has_frame_end: bool,
+    installed_hooks: usize,
+    held: [JoypadButton; PLAYERS],
+    last_frame: Option<usize>,
+}
+
+impl Script {
+    /// Compiles the script and runs its top level, which usually sets up the hooks.
+    pub fn new(source: &str, mapper: SharedMapper) -> Result<Script, String> {
+        let api = Rc::new(RefCell::new(Api {
+            ram: [0; 2048],
+            ram_writes: Vec::new(),
+            mapper,
+            frame: 0,
+            state: None,
+            pressed: [JoypadButton::empty(); PLAYERS],
+            overlay: Rc::new(RefCell::new(Overlay::default())),
+            hooks: Vec::new(),
+        }));
+        let engine = Script::engine(&api);
+        let ast = engine.compile(source).map_err(|e| e.to_string())?;
+        let has_function = |name: &str| {
+            ast.iter_functions()
+                .any(|function| function.name == name && function.params.is_empty())
+        };
+        let (has_frame_start, has_frame_end) =
+            (has_function("on_frame_start"), has_function("on_frame_end"));
+
+        let mut scope = Scope::new();
+        engine
+            .run_ast_with_scope(&mut scope, &ast)
+            .map_err(|e| e.to_string())?;
+
+        Ok(Script {
+            engine,
+            ast,
+            scope,
+            api,
+            has_frame_start,
+            has_frame_end,
+            installed_hooks: 0,
+            held: [JoypadButton::empty(); PLAYERS],
+            last_frame: None,
+        })
+    }
+
+    fn engine(api: &Rc<RefCell<Api>>) -> Engine {
+        let mut engine = Engine::new();
+
+        let state = api.clone();
+        engine.register_fn("read", move |addr: INT| {
+            state.borrow().read(addr as u16) as INT
+        });
+        let state = api.clone();
+        engine.register_fn("write", move |addr: INT, data: INT| {
+            state.borrow_mut().write(addr as u16, data as u8)
+        });
+        let state = api.clone();
+        engine.register_fn("frame", move || state.borrow().frame as INT);
+        let state = api.clone();
+        engine.register_fn("state", move || match &state.borrow().state {
+            Some(snapshot) => {
+                serde_json::to_value(snapshot).map_or(Dynamic::UNIT, |v| to_dynamic(&v))
+            }
+            None => Dynamic::UNIT,
+        });
+
+        let state = api.clone();
+        engine.register_fn(
+            "press",
+            move |player: INT, name: &str| -> Result<(), Box<EvalAltResult>> {
+                let button =
+                    JoypadButton::from_name(name).ok_or(format!("No button called {}", name))?;
+                match state.borrow_mut().pressed.get_mut((player - 1) as usize) {
+                    Some(pressed) => *pressed |= button,
+                    None => return Err(format!("No player {}", player).into()),
+                }
+                Ok(())
+            },
+        );
+
+        for (name, access) in &[("on_read", Access::Read), ("on_write", Access::Write)] {
+            let (state, access) = (api.clone(), *access);
+            engine.register_fn(*name, move |addr: INT, function: &str| {
+                let watchpoint = Watchpoint {
+                    addr: addr as u16,
+                    access,
+                };
+                state
+                    .borrow_mut()
+                    .hooks
+                    .push((watchpoint, function.to_string()));
+            });
+        }
+
+        let draw = |api: &Rc<RefCell<Api>>| {
+            let overlay = api.borrow().overlay.clone();
+            move |shape| overlay.borrow_mut().shapes.push(shape)
+        };
+        let add = draw(api);
+        engine.register_fn("pixel", move |x: INT, y: INT, rgb: INT| {
+            add(Shape::Pixel(x, y, rgb))
+        });
+        let add = draw(api);
+        engine.register_fn("rect", move |x: INT, y: INT, w: INT, h: INT, rgb: INT| {
+            add(Shape::Rect(x, y, w, h, rgb))
+        });
+        let add = draw(api);
+        engine.register_fn("fill", move |x: INT, y: INT, w: INT, h: INT, rgb: INT| {
+            add(Shape::Fill(x, y, w, h, rgb))
+        });
+        let add = draw(api);
+        engine.register_fn("text", move |x: INT, y: INT, text: &str| {
+            add(Shape::Text(x, y, text.to_string(), 0xffffff))
+        });
+        let add = draw(api);
+        engine.register_fn("text", move |x: INT, y: INT, text: &str, rgb: INT| {
+            add(Shape::Text(x, y, text.to_string(), rgb))
+        });
+
+        engine
+    }
+
+    pub fn overlay(&self) -> Rc<RefCell<Overlay>> {
+        self.api.borrow().overlay.clone()
+    }
+
+    /// Called before every instruction, to run the frame and memory hooks that are due.
+    pub fn on_instruction(&mut self, cpu: &mut CPU) -> Result<(), String> {
+        self.install_hooks(cpu);
+
+        if let Some((watchpoint, data)) = cpu.bus.peek_watchpoint_hit() {
+            let function = self
+                .api
+                .borrow()
+                .hooks
+                .iter()
+                .find(|(hooked, _)| *hooked == watchpoint)
+                .map(|(_, function)| function.clone());
+            if let Some(function) = function {
+                cpu.bus.take_watchpoint_hit();
+                let args = (watchpoint.addr as INT, data as INT);
+                self.call(cpu, &function, args)?;
+            }
+        }
+
+        let frame = cpu.bus.frame_count();
+        if self.last_frame != Some(frame) {
+            self.last_frame = Some(frame);
+            self.api.borrow().overlay.borrow_mut().shapes.clear();
+            if self.has_frame_end && frame > 0 {
+                self.call(cpu, "on_frame_end", ())?;
+            }
+            if self.has_frame_start {
+                self.call(cpu, "on_frame_start", ())?;
+            }
+            self.press_buttons(cpu);
+        }
+        Ok(())
+    }
+
+    fn install_hooks(&mut self, cpu: &mut CPU) {
+        let api = self.api.borrow();
+        for (watchpoint, _) in api.hooks[self.installed_hooks..].iter() {
+            if !cpu.bus.watchpoints.contains(watchpoint) {
+                cpu.bus.watchpoints.push(*watchpoint);
+            }
+        }
+        self.installed_hooks = api.hooks.len();
+    }
+
+    fn call(
+        &mut self,
+        cpu: &mut CPU,
+        function: &str,
+        args: impl rhai::FuncArgs,
+    ) -> Result<(), String> {
+        {
+            let mut api = self.api.borrow_mut();
+            api.ram = *cpu.bus.ram();
+            api.frame = cpu.bus.frame_count();
+            api.state = Some(Snapshot::of(cpu));
+        }
+        let result = self
+            .engine
+            .call_fn::<rhai::Dynamic>(&mut self.scope, &self.ast, function, args)
+            .map(|_| ())
+            .map_err(|e| format!("Script error in {}: {}", function, e));
+        for (addr, data) in self.api.borrow_mut().ram_writes.drain(..) {
+            cpu.bus.poke(addr, data);
+        }
+        self.install_hooks(cpu);
+        result
+    }
+
+    // buttons the script pressed are let go again at the end of the next frame
+    fn press_buttons(&mut self, cpu: &mut CPU) {
+        let pressed = std::mem::replace(
+            &mut self.api.borrow_mut().pressed,
+            [JoypadButton::empty(); PLAYERS],
+        );
+        for (player, (&now, before)) in pressed.iter().zip(self.held.iter_mut()).enumerate() {
+            if let Some(joypad) = cpu.bus.joypad_mut(player + 1) {
+                joypad.set_button_pressed_status(*before - now, false);
+                joypad.set_button_pressed_status(now, true);
+            }
+            *before = now;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bus::Bus;
+    use crate::cpu::Mem;
+    use crate::mapper;
+    use crate::rom::test::test_rom;
+
+    fn load(source: &str) -> (Script, CPU) {
+        let mapper = mapper::from_rom(test_rom()).unwrap();
+        let script = Script::new(source, mapper.clone()).unwrap();
+        let mut cpu = CPU::new(Bus::with_mapper(mapper));
+        cpu.halt_on_brk = false;
+        (script, cpu)
+    }
+
+    #[test]
+    fn test_frame_hooks() {
+        let (mut script, mut cpu) = load(
+            r#"
+            fn on_frame_start() { press(1, "start"); }
+            fn on_frame_end() {
+                write(0x10, read(0x10) + 1);
+                write(0x11, state().frame);
+                text(0, 0, "frame " + frame());
+            }
+            "#,
+        );
+        while cpu.bus.frame_count() < 2 {
+            script.on_instruction(&mut cpu).unwrap();
+            cpu.step();
+        }
+        assert_eq!(cpu.mem_read(0x10), 1);
+        assert_eq!(cpu.mem_read(0x11), 1);
+        assert!(!script.overlay().borrow().is_empty());
+        let buttons = cpu.bus.joypad_mut(1).unwrap().buttons();
+        assert_eq!(buttons, JoypadButton::START);
+    }
+
+    #[test]
+    fn test_memory_hooks() {
+        let (mut script, mut cpu) = load(
+            r#"
+            on_write(0x300, "written");
+            fn written(addr, value) { write(addr + 1, value + 1); }
+            "#,
+        );
+        script.on_instruction(&mut cpu).unwrap();
+        cpu.mem_write(0x300, 5);
+        script.on_instruction(&mut cpu).unwrap();
+        assert_eq!(cpu.mem_read(0x301), 6);
+        assert_eq!(cpu.bus.take_watchpoint_hit(), None);
+    }
+
+    #[test]
+    fn test_errors() {
+        let mapper = mapper::from_rom(test_rom()).unwrap();
+        assert!(Script::new("fn on_frame_end( {", mapper.clone()).is_err());
+        let (mut script, mut cpu) = load(r#"fn on_frame_start() { press(5, "A"); }"#);
+        assert!(script.on_instruction(&mut cpu).is_err());
+    }
+
+    #[test]
+    fn test_overlay_clips() {
+        let mut overlay = Overlay::default();
+        overlay.shapes.push(Shape::Fill(-2, -2, 4, 4, 0x123456));
+        overlay.shapes.push(Shape::Rect(250, 230, 20, 20, 0xff0000));
+        let mut frame = Frame::new();
+        overlay.draw(&mut frame);
+        assert_eq!(frame.get_pixel(1, 1), (0x12, 0x34, 0x56));
+        assert_eq!(frame.get_pixel(2, 2), (0, 0, 0));
+        assert_eq!(frame.get_pixel(255, 230), (0xff, 0, 0));
+        assert_eq!(frame.get_pixel(251, 231), (0, 0, 0));
+
+        // a huge rectangle only costs the pixels on screen, and can't overflow
+        let mut overlay = Overlay::default();
+        overlay
+            .shapes
+            .push(Shape::Rect(0, 0, 1_000_000, 1_000_000, 0x00ff00));
+        overlay
+            .shapes
+            .push(Shape::Fill(INT::MAX - 1, 10, INT::MAX, 1, 0x00ff00));
+        overlay.draw(&mut frame);
+        assert_eq!(frame.get_pixel(0, 100), (0, 0xff, 0));
+        assert_eq!(frame.get_pixel(100, 100), (0, 0, 0));
+    }
+}
@@ -0,0 +1,333 @@
+use crate::archive;
+use crate::bus::Bus;
+use crate::cpu::{CpuState, Mem, CPU};
+use crate::error::NesError;
+use crate::event::Event;
+use crate::joypad::JoypadButton;
+use crate::mapper::{self, SharedMapper};
+use crate::render::{self, frame::Frame};
+use crate::rom::Rom;
+use crate::state::Snapshot;
+use serde_json::{json, Value};
+use std::convert::TryFrom;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+
+/// A console with nothing attached but a frame to render into.
+struct Machine {
+    cpu: CPU,
+    frame: Frame,
+}
+
+impl Machine {
+    fn new(mapper: SharedMapper) -> Machine {
+        let mut cpu = CPU::new(Bus::with_mapper(mapper));
+        cpu.halt_on_brk = false;
+        cpu.reset();
+        Machine {
+            cpu,
+            frame: Frame::new(),
+        }
+    }
+
+    fn run_frames(&mut self, frames: usize) -> Result<(), NesError> {
+        let end = self.cpu.bus.frame_count().saturating_add(frames);
+        while self.cpu.bus.frame_count() < end {
+            match self.cpu.step() {
+                CpuState::Running | CpuState::Halted => {}
+                CpuState::Jammed => {
+                    return Err(NesError::Jammed {
+                        opcode: self.cpu.mem_peek(self.cpu.program_counter),
+                        addr: self.cpu.program_counter,
+                    })
+                }
+                CpuState::Error(e) => return Err(e),
+            }
+            while let Some(event) = self.cpu.bus.poll_event() {
+                if event == Event::FrameComplete {
+                    render::render(&self.cpu.bus.ppu, &mut self.frame);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// 64 bit FNV-1a, which stays the same between runs and builds unlike the standard
+/// library's hasher.
+fn fnv1a(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &byte| {
+        (hash ^ byte as u64).wrapping_mul(0x0000_0100_0000_01b3)
+    })
+}
+
+fn number(request: &Value, key: &str, default: Option<u64>) -> Result<u64, String> {
+    match request.get(key) {
+        Some(value) => value
+            .as_u64()
+            .ok_or_else(|| format!("\"{}\" should be a number", key)),
+        None => default.ok_or_else(|| format!("Missing \"{}\"", key)),
+    }
+}
+
+fn address(request: &Value, key: &str) -> Result<u16, String> {
+    let addr = number(request, key, None)?;
+    u16::try_from(addr).map_err(|_| format!("\"{}\" should be at most $FFFF", key))
+}
+
+fn string<'a>(request: &'a Value, key: &str) -> Result<&'a str, String> {
+    request
+        .get(key)
+        .and_then(Value::as_str)
+        .ok_or_else(|| format!("Missing \"{}\"", key))
+}
+
+/// Lets test frameworks and bots drive the emulator without a window, over a TCP
+/// connection. Each request is a JSON object on a line of its own, with a `cmd` of:
+///
+/// - `load` with a `path` to an iNES file, or a ZIP with one in
+/// - `reset`, like pressing the reset button, or `power_cycle`
+/// - `run` with a number of `frames`, and optionally `buttons` held down by `player`
+///   (1 by default) all the while, like `["A", "right"]`
+/// - `read` with an `addr` and a `len` (1 by default), answered with `data`
+/// - `write` with an `addr` and the `data` to write to RAM or cartridge RAM
+/// - `state`, answered with the `state` of the CPU, PPU and APU
+/// - `hash`, answered with a `hash` of the last frame
+/// - `screenshot` with a `path` to save the last frame to as a PNG
+///
+/// Each gets a line back with `ok` and the frame count, or `ok` false and an `error`.
+#[derive(Default)]
+pub struct ControlServer {
+    machine: Option<Machine>,
+}
+
+impl ControlServer {
+    pub fn new() -> Self {
+        ControlServer { machine: None }
+    }
+
+    /// Starts out with a game already in.
+    pub fn with_mapper(mapper: SharedMapper) -> Self {
+        ControlServer {
+            machine: Some(Machine::new(mapper)),
+        }
+    }
+
+    /// Answers connections one after the other, forever. The console carries on from
+    /// where the last connection left it. A connection that goes wrong is dropped, and
+    /// the server moves on to the next.
+    pub fn serve(&mut self, listener: TcpListener) {
+        for stream in listener.incoming() {
+            if let Err(e) = stream.and_then(|stream| self.serve_connection(stream)) {
+                eprintln!("Dropped a connection: {}", e);
+            }
+        }
+    }
+
+    fn serve_connection(&mut self, stream: TcpStream) -> io::Result<()> {
+        let mut out = stream.try_clone()?;
+        for line in BufReader::new(stream).lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            writeln!(out, "{}", self.handle(&line))?;
+        }
+        Ok(())
+    }
+
+    /// Carries out one request and returns the response.
+    pub fn handle(&mut self, request: &str) -> Value {
+        let result = serde_json::from_str(request)
+            .map_err(|e| format!("Bad request: {}", e))
+            .and_then(|request| self.command(&request));
+        match result {
+            Ok(mut response) => {
+                response["ok"] = json!(true);
+                if let Some(machine) = &self.machine {
+                    response["frame"] = json!(machine.cpu.bus.frame_count());
+                }
+                response
+            }
+            Err(error) => json!({ "ok": false, "error": error }),
+        }
+    }
+
+    fn command(&mut self, request: &Value) -> Result<Value, String> {
+        let cmd = string(request, "cmd")?;
+        if cmd == "load" {
+            let path = string(request, "path")?;
+            let bytes = archive::read_game(path, &["nes"])
+                .map_err(|e| format!("Can't read {}: {}", path, e))?;
+            let rom = Rom::new(&bytes).map_err(|e| e.to_string())?;
+            let mapper = mapper::from_rom(rom).map_err(|e| e.to_string())?;
+            self.machine = Some(Machine::new(mapper));
+            return Ok(json!({}));
+        }
+
+        let machine = self
+            .machine
+            .as_mut()
+            .ok_or_else(|| "No game loaded".to_string())?;
+        match cmd {
+            "reset" => machine.cpu.soft_reset(),
+            "power_cycle" => machine.cpu.power_cycle(),
+            "run" => {
+                let frames = number(request, "frames", None)? as usize;
+                let player = number(request, "player", Some(1))? as usize;
+                let mut buttons = JoypadButton::empty();
+                for name in request
+                    .get("buttons")
+                    .and_then(Value::as_array)
+                    .unwrap_or(&vec![])
+                {
+                    let name = name.as_str().unwrap_or_default();
+                    buttons |= JoypadButton::from_name(name)
+                        .ok_or_else(|| format!("No button called {}", name))?;
+                }
+                let joypad = machine
+                    .cpu
+                    .bus
+                    .joypad_mut(player)
+                    .ok_or_else(|| format!("No player {}", player))?;
+                joypad.set_button_pressed_status(buttons, true);
+                let ran = machine.run_frames(frames);
+                if let Some(joypad) = machine.cpu.bus.joypad_mut(player) {
+                    joypad.set_button_pressed_status(buttons, false);
+                }
+                ran.map_err(|e| e.to_string())?;
+            }
+            "read" => {
+                let addr = address(request, "addr")? as u64;
+                let len = number(request, "len", Some(1))?.min(0x10000);
+                if addr + len > 0x10000 {
+                    return Err("Can't read past $FFFF".to_string());
+                }
+                let data: Vec<u8> = (addr..addr + len)
+                    .map(|addr| machine.cpu.mem_peek(addr as u16))
+                    .collect();
+                return Ok(json!({ "data": data }));
+            }
+            "write" => {
+                let addr = address(request, "addr")?;
+                let data = request
+                    .get("data")
+                    .and_then(Value::as_array)
+                    .ok_or_else(|| "Missing \"data\"".to_string())?;
+                for (i, value) in data.iter().enumerate() {
+                    let value = value.as_u64().ok_or("\"data\" should be numbers")?;
+                    let at = u16::try_from(i)
+                        .ok()
+                        .and_then(|i| addr.checked_add(i))
+                        .ok_or("Can't write past $FFFF")?;
+                    if !machine.cpu.bus.poke(at, value as u8) {
+                        return Err(format!("${:04X} isn't RAM", at));
+                    }
+                }
+            }
+            "state" => {
+                let state =
+                    serde_json::to_value(Snapshot::of(&machine.cpu)).map_err(|e| e.to_string())?;
+                return Ok(json!({ "state": state }));
+            }
+            "hash" => {
+                let hash = fnv1a(&machine.frame.data);
+                return Ok(json!({ "hash": format!("{:016x}", hash) }));
+            }
+            "screenshot" => machine.frame.save_png(string(request, "path")?)?,
+            _ => return Err(format!("Unknown command {}", cmd)),
+        }
+        Ok(json!({}))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::rom::Mirroring;
+    use std::thread;
+
+    fn server() -> ControlServer {
+        ControlServer::with_mapper(mapper::blank(Mirroring::Horizontal, false))
+    }
+
+    #[test]
+    fn test_commands() {
+        let mut server = server();
+        let response = server.handle(r#"{"cmd": "run", "frames": 3, "buttons": ["start"]}"#);
+        assert_eq!(response, json!({ "ok": true, "frame": 3 }));
+        // released again afterwards
+        assert_eq!(
+            server
+                .machine
+                .as_mut()
+                .unwrap()
+                .cpu
+                .bus
+                .joypad_mut(1)
+                .unwrap()
+                .buttons(),
+            JoypadButton::empty()
+        );
+
+        let response = server.handle(r#"{"cmd": "write", "addr": 16, "data": [1, 2, 255]}"#);
+        assert_eq!(response["ok"], json!(true));
+        let response = server.handle(r#"{"cmd": "read", "addr": 15, "len": 5}"#);
+        assert_eq!(response["data"], json!([0, 1, 2, 255, 0]));
+
+        // long reads stop at 64 KiB
+        let response = server.handle(r#"{"cmd": "read", "addr": 0, "len": 18446744073709551615}"#);
+        assert_eq!(response["data"].as_array().unwrap().len(), 0x10000);
+
+        let response = server.handle(r#"{"cmd": "hash"}"#);
+        assert_eq!(response["hash"].as_str().unwrap().len(), 16);
+
+        let response = server.handle(r#"{"cmd": "state"}"#);
+        assert_eq!(response["state"]["frame"], json!(3));
+        assert!(response["state"]["ppu"]["scanline"].is_number());
+    }
+
+    #[test]
+    fn test_errors() {
+        let mut server = ControlServer::new();
+        assert_eq!(
+            server.handle(r#"{"cmd": "hash"}"#),
+            json!({ "ok": false, "error": "No game loaded" })
+        );
+        let mut server = self::server();
+        for request in &[
+            "not json",
+            r#"{"cmd": "dance"}"#,
+            r#"{"cmd": "run"}"#,
+            r#"{"cmd": "run", "frames": 1, "buttons": ["turbo"]}"#,
+            r#"{"cmd": "write", "addr": 32768, "data": [1]}"#,
+            r#"{"cmd": "write", "addr": 65535, "data": [1, 2]}"#,
+            r#"{"cmd": "write", "addr": 65536, "data": [1]}"#,
+            r#"{"cmd": "read", "addr": 18446744073709551615}"#,
+            r#"{"cmd": "read", "addr": 65535, "len": 2}"#,
+            r#"{"cmd": "load", "path": "missing.nes"}"#,
+        ] {
+            let response = server.handle(request);
+            assert_eq!(response["ok"], json!(false), "{}", request);
+            assert!(response["error"].is_string());
+        }
+    }
+
+    #[test]
+    fn test_over_tcp() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || server().serve(listener));
+
+        // a line that isn't UTF-8 loses that connection, but not the server
+        let mut bad = TcpStream::connect(addr).unwrap();
+        bad.write_all(b"\xff\xfe\n").unwrap();
+        let mut line = String::new();
+        assert!(!matches!(BufReader::new(bad).read_line(&mut line), Ok(n) if n > 0));
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        writeln!(stream, r#"{{"cmd": "run", "frames": 1}}"#).unwrap();
+        BufReader::new(stream).read_line(&mut line).unwrap();
+        assert_eq!(line, "{\"frame\":1,\"ok\":true}\n");
+    }
+}
@@ -0,0 +1,141 @@
+use crate::apu::monitor::ChannelState;
+use crate::apu::{Apu, Channel};
+use crate::cpu::CPU;
+use crate::ppu::NesPPU;
+use serde::Serialize;
+
+/// The CPU's registers.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct CpuState {
+    pub a: u8,
+    pub x: u8,
+    pub y: u8,
+    pub status: u8,
+    pub stack_pointer: u8,
+    pub program_counter: u16,
+    /// CPU cycles since power on.
+    pub cycles: usize,
+    pub jammed: bool,
+}
+
+impl CpuState {
+    pub fn of(cpu: &CPU) -> Self {
+        CpuState {
+            a: cpu.register_a,
+            x: cpu.register_x,
+            y: cpu.register_y,
+            status: cpu.status.bits(),
+            stack_pointer: cpu.stack_pointer,
+            program_counter: cpu.program_counter,
+            cycles: cpu.bus.cycles(),
+            jammed: cpu.is_jammed(),
+        }
+    }
+}
+
+/// Where the PPU is in the frame, and its registers.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct PpuState {
+    pub scanline: u16,
+    pub dot: usize,
+    pub ctrl: u8,
+    pub mask: u8,
+    pub status: u8,
+    pub oam_addr: u8,
+    /// The VRAM address, `v`, and the one it's reloaded from, `t`.
+    pub vram_addr: u16,
+    pub temp_addr: u16,
+    pub fine_x: u8,
+    /// An NMI has been raised that the CPU hasn't taken yet.
+    pub nmi_pending: bool,
+    pub palette: Vec<u8>,
+}
+
+impl PpuState {
+    pub fn of(ppu: &NesPPU) -> Self {
+        PpuState {
+            scanline: ppu.scanline,
+            dot: ppu.dot(),
+            ctrl: ppu.ctrl.bits(),
+            mask: ppu.mask.bits(),
+            status: ppu.status.bits(),
+            oam_addr: ppu.oam_addr,
+            vram_addr: ppu.loopy.v,
+            temp_addr: ppu.loopy.t,
+            fine_x: ppu.loopy.fine_x,
+            nmi_pending: ppu.nmi_interrupt.is_some(),
+            palette: ppu.palette_table.to_vec(),
+        }
+    }
+}
+
+/// What each sound channel is playing.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ApuState {
+    /// As $4015 reads.
+    pub status: u8,
+    pub five_step_mode: bool,
+    pub pulse1: ChannelState,
+    pub pulse2: ChannelState,
+    pub triangle: ChannelState,
+    pub noise: ChannelState,
+}
+
+impl ApuState {
+    pub fn of(apu: &Apu) -> Self {
+        ApuState {
+            status: apu.status(),
+            five_step_mode: apu.five_step_mode(),
+            pulse1: apu.channel_state(Channel::Pulse1),
+            pulse2: apu.channel_state(Channel::Pulse2),
+            triangle: apu.channel_state(Channel::Triangle),
+            noise: apu.channel_state(Channel::Noise),
+        }
+    }
+}
+
+/// A copy of the state of the whole console, for frontends, scripts and the control
+/// server to look at. Changing it changes nothing in the console.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Snapshot {
+    pub frame: usize,
+    pub cpu: CpuState,
+    pub ppu: PpuState,
+    pub apu: ApuState,
+}
+
+impl Snapshot {
+    pub fn of(cpu: &CPU) -> Self {
+        Snapshot {
+            frame: cpu.bus.frame_count(),
+            cpu: CpuState::of(cpu),
+            ppu: PpuState::of(&cpu.bus.ppu),
+            apu: ApuState::of(&cpu.bus.apu),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bus::Bus;
+    use crate::rom::test;
+
+    #[test]
+    fn test_snapshot() {
+        let mut cpu = CPU::new(Bus::new(test::test_rom()).unwrap());
+        // LDA #$3F; STA $4015; LDX #$10; BRK
+        cpu.load_and_run(vec![0xa9, 0x3f, 0x8d, 0x15, 0x40, 0xa2, 0x10, 0x00]);
+
+        let snapshot = Snapshot::of(&cpu);
+        assert_eq!(snapshot.cpu.a, 0x3f);
+        assert_eq!(snapshot.cpu.x, 0x10);
+        assert_eq!(snapshot.cpu.program_counter, 0x0608);
+        assert!(!snapshot.apu.pulse1.active);
+
+        let json = serde_json::to_value(&snapshot).unwrap();
+        assert_eq!(json["cpu"]["a"], 0x3f);
+        assert_eq!(json["ppu"]["palette"].as_array().unwrap().len(), 32);
+        assert_eq!(json["apu"]["noise"]["muted"], false);
+    }
+}
@@ -0,0 +1,204 @@
+use crate::mapper::Mapper;
+use std::collections::HashMap;
+
+/// Names for addresses, from the debug files assemblers write out, to show in traces,
+/// the disassembly and the debugger. Three kinds of file are understood, and can be
+/// mixed:
+///
+/// - ld65 label files, from `-Ln`, with lines like `al 00C000 .reset`
+/// - ca65 debug info, from `--dbgfile`, where the `sym` lines of labels are used
+/// - Mesen label files (.mlb), like `P:0010:reset` for an offset into PRG ROM or
+///   `R:0300:buffer` for internal RAM, with the longer Mesen 2 names too
+///
+/// PRG ROM labels stay with their bytes as banks are switched. Labels at CPU
+/// addresses apply whatever is mapped in there.
+#[derive(Default)]
+pub struct Symbols {
+    cpu: HashMap<u16, String>,
+    prg: HashMap<usize, String>,
+}
+
+fn hex(text: &str) -> Option<u32> {
+    let text = text.trim();
+    let text = text
+        .strip_prefix("0x")
+        .or_else(|| text.strip_prefix('$'))
+        .unwrap_or(text);
+    u32::from_str_radix(text, 16).ok()
+}
+
+impl Symbols {
+    pub fn new() -> Self {
+        Symbols {
+            cpu: HashMap::new(),
+            prg: HashMap::new(),
+        }
+    }
+
+    pub fn load(path: &str) -> Result<Symbols, String> {
+        let text = std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+        Symbols::parse(&text).map_err(|e| format!("{}: {}", path, e))
+    }
+
+    pub fn parse(text: &str) -> Result<Symbols, String> {
+        let mut symbols = Symbols::new();
+        for (number, line) in text.lines().enumerate() {
+            symbols
+                .parse_line(line.trim())
+                .map_err(|e| format!("line {}: {}", number + 1, e))?;
+        }
+        Ok(symbols)
+    }
+
+    pub fn len(&self) -> usize {
+        self.cpu.len() + self.prg.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn parse_line(&mut self, line: &str) -> Result<(), String> {
+        if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+            return Ok(());
+        }
+        let mut words = line.split_whitespace();
+        match words.next() {
+            Some("al") => {
+                let addr = words.next().and_then(hex).ok_or("Bad address")?;
+                let name = words.next().ok_or("No name")?;
+                self.cpu
+                    .insert(addr as u16, name.trim_start_matches('.').to_string());
+                Ok(())
+            }
+            Some("sym") => self.parse_dbg_symbol(line),
+            // the other kinds of line in a ca65 debug file
+            Some(
+                "version" | "info" | "csym" | "file" | "lib" | "line" | "mod" | "scope" | "seg"
+                | "span" | "type",
+            ) => Ok(()),
+            _ => self.parse_mesen_label(line),
+        }
+    }
+
+    fn parse_dbg_symbol(&mut self, line: &str) -> Result<(), String> {
+        let mut name = None;
+        let mut value = None;
+        let mut label = false;
+        for field in line["sym".len()..].trim().split(',') {
+            match field.split_once('=') {
+                Some(("name", text)) => name = Some(text.trim_matches('"')),
+                Some(("val", text)) => value = hex(text),
+                Some(("type", "lab")) => label = true,
+                _ => {}
+            }
+        }
+        // equates are as likely to be constants as addresses
+        if let (true, Some(name), Some(value)) = (label, name, value) {
+            self.cpu.insert(value as u16, name.to_string());
+        }
+        Ok(())
+    }
+
+    fn parse_mesen_label(&mut self, line: &str) -> Result<(), String> {
+        let mut fields = line.splitn(4, ':');
+        let (kind, addr, name) = match (fields.next(), fields.next(), fields.next()) {
+            (Some(kind), Some(addr), Some(name)) => (kind, addr, name),
+            _ => return Err(format!("Can't make sense of \"{}\"", line)),
+        };
+        // a range is named by its first address
+        let addr = addr.split('-').next().and_then(hex).ok_or("Bad address")?;
+        // comments without a name
+        if name.is_empty() {
+            return Ok(());
+        }
+        let name = name.to_string();
+        match kind {
+            "P" | "NesPrgRom" => {
+                self.prg.insert(addr as usize, name);
+            }
+            "R" | "NesInternalRam" => {
+                self.cpu.insert(addr as u16 & 0x7ff, name);
+            }
+            "S" | "W" | "NesSaveRam" | "NesWorkRam" => {
+                self.cpu.insert(0x6000 + (addr as u16 & 0x1fff), name);
+            }
+            "G" | "NesMemory" => {
+                self.cpu.insert(addr as u16, name);
+            }
+            // CHR, palette and the like aren't seen by the CPU
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// The name for `addr` as the CPU sees it, with `mapper`'s banks as they are now.
+    pub fn name(&self, addr: u16, mapper: &dyn Mapper) -> Option<&str> {
+        if !self.prg.is_empty() {
+            if let Some(name) = mapper
+                .prg_rom_offset(addr)
+                .and_then(|offset| self.prg.get(&offset))
+            {
+                return Some(name);
+            }
+        }
+        let addr = if addr < 0x2000 { addr & 0x7ff } else { addr };
+        self.cpu.get(&addr).map(String::as_str)
+    }
+
+    /// Where `name` is to be found by the CPU. PRG ROM labels are only found if their
+    /// bank is mapped in.
+    pub fn address_of(&self, name: &str, mapper: &dyn Mapper) -> Option<u16> {
+        if let Some((&addr, _)) = self.cpu.iter().find(|(_, n)| *n == name) {
+            return Some(addr);
+        }
+        let (&offset, _) = self.prg.iter().find(|(_, n)| *n == name)?;
+        (0x6000..=0xffff).find(|&addr| mapper.prg_rom_offset(addr) == Some(offset))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::mapper;
+    use crate::rom::Mirroring;
+
+    #[test]
+    fn test_formats() {
+        let symbols = Symbols::parse(
+            "al 00C000 .reset
+             al 000010 .counter
+             version\tmajor=2,minor=0
+             sym\tid=0,name=\"nmi\",addrsize=absolute,scope=0,def=1,val=0xC100,seg=0,type=lab
+             sym\tid=1,name=\"SPEED\",addrsize=zeropage,scope=0,def=2,val=0x3,type=equ
+             P:0200:irq:the IRQ handler
+             R:0300-03FF:buffer
+             S:0000:save
+             G:2000:PPUCTRL
+             P:0210::just a comment",
+        )
+        .unwrap();
+        let mapper = mapper::blank(Mirroring::Horizontal, false);
+        let mapper = mapper.borrow();
+
+        assert_eq!(symbols.len(), 7);
+        assert_eq!(symbols.name(0xc000, &*mapper), Some("reset"));
+        // RAM is mirrored
+        assert_eq!(symbols.name(0x0810, &*mapper), Some("counter"));
+        assert_eq!(symbols.name(0xc100, &*mapper), Some("nmi"));
+        assert_eq!(symbols.name(0x0003, &*mapper), None);
+        assert_eq!(symbols.name(0x0300, &*mapper), Some("buffer"));
+        assert_eq!(symbols.name(0x6000, &*mapper), Some("save"));
+        assert_eq!(symbols.name(0x2000, &*mapper), Some("PPUCTRL"));
+        assert_eq!(symbols.address_of("reset", &*mapper), Some(0xc000));
+        // 16 KiB of PRG ROM is at $8000 and again at $C000
+        assert_eq!(symbols.name(0xc200, &*mapper), Some("irq"));
+        assert_eq!(symbols.address_of("irq", &*mapper), Some(0x8200));
+
+        assert!(Symbols::parse("al zz .oops").is_err());
+        assert_eq!(
+            Symbols::parse("\nnonsense").err(),
+            Some("line 2: Can't make sense of \"nonsense\"".to_string())
+        );
+    }
+}
@@ -1,130 +1,342 @@
+use crate::bus::Access;
 use crate::cpu::{AddressingMode, Mem, CPU};
-use crate::opcodes::{OpCode, OPCODES_MAP};
-use std::collections::HashMap;
-
-pub fn trace(cpu: &mut CPU) -> String {
-    let ref opcodes: HashMap<u8, &'static OpCode> = *OPCODES_MAP;
-
-    let code = cpu.mem_read(cpu.program_counter);
-    let opcode = opcodes.get(&code).unwrap();
+use crate::disasm::{decode, DisasmLine};
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::ops::RangeInclusive;
 
+pub fn trace(cpu: &CPU) -> String {
     let counter = cpu.program_counter;
+    let line = decode(cpu, counter);
 
-    let mut hex_dump = vec![];
-    hex_dump.push(code);
+    let mode = match line.mode {
+        Some(mode) => mode,
+        None => return format_registers(cpu, &line),
+    };
 
-    let (mem_addr, data) = match opcode.mode {
+    let (mem_addr, data) = match mode {
         AddressingMode::Immediate | AddressingMode::NoneAddressing => (0, 0),
         _ => {
-            let (addr, _) = cpu.get_absolute_address(&opcode.mode, counter + 1);
-            (addr, cpu.mem_read(addr))
+            let addr = cpu.peek_absolute_address(mode, counter + 1);
+            (addr, cpu.mem_peek(addr))
         }
     };
 
-    let tmp = match opcode.len {
-        1 => match opcode.code {
-            0x0a | 0x4a | 0x2a | 0x6a => format!("A "),
-            _ => String::from(""),
-        },
-        2 => {
-            let address: u8 = cpu.mem_read(counter + 1);
-            hex_dump.push(address);
-
-            match opcode.mode {
-                AddressingMode::Immediate => format!("#${:02x}", address),
-                AddressingMode::ZeroPage => format!("${:02x} = {:02x}", mem_addr, data),
-                AddressingMode::ZeroPage_X => {
-                    format!("${:02x},X @ {:02x} = {:02x}", address, mem_addr, data)
-                }
-                AddressingMode::ZeroPage_Y => {
-                    format!("${:02x},Y @ {:02x} = {:02x}", address, mem_addr, data)
-                }
-                AddressingMode::Indirect_X => format!(
-                    "(${:02x},X) @ {:02x} = {:04x} = {:02x}",
-                    address,
-                    (address.wrapping_add(cpu.register_x)),
-                    mem_addr,
-                    data
-                ),
-                AddressingMode::Indirect_Y => format!(
-                    "(${:02x}),Y = {:04x} @ {:04x} = {:02x}",
-                    address,
-                    (mem_addr.wrapping_sub(cpu.register_y as u16)),
-                    mem_addr,
-                    data
-                ),
-                AddressingMode::NoneAddressing => {
-                    // Local jumps
-                    let address: usize =
-                        (counter as usize + 2).wrapping_add((address as i8) as usize);
-                    format!("${:04x}", address)
-                }
+    let address = line.operand_value();
+    let annotation = match mode {
+        AddressingMode::ZeroPage | AddressingMode::Absolute => format!(" = {:02X}", data),
+        AddressingMode::ZeroPage_X | AddressingMode::ZeroPage_Y => {
+            format!(" @ {:02X} = {:02X}", mem_addr, data)
+        }
+        AddressingMode::Absolute_X | AddressingMode::Absolute_Y => {
+            format!(" @ {:04X} = {:02X}", mem_addr, data)
+        }
+        AddressingMode::Indirect_X => format!(
+            " @ {:02X} = {:04X} = {:02X}",
+            (address as u8).wrapping_add(cpu.register_x),
+            mem_addr,
+            data
+        ),
+        AddressingMode::Indirect_Y => format!(
+            " = {:04X} @ {:04X} = {:02X}",
+            mem_addr.wrapping_sub(cpu.register_y as u16),
+            mem_addr,
+            data
+        ),
+        AddressingMode::NoneAddressing if line.bytes[0] == 0x6c => {
+            // JMP indirect
+            let jmp_addr = if address & 0x00FF == 0x00FF {
+                let lo = cpu.mem_peek(address);
+                let hi = cpu.mem_peek(address & 0xFF00);
+                (hi as u16) << 8 | (lo as u16)
+            } else {
+                cpu.mem_peek_u16(address)
+            };
+            format!(" = {:04X}", jmp_addr)
+        }
+        AddressingMode::Immediate | AddressingMode::NoneAddressing => String::from(""),
+    };
 
-                _ => panic!(
-                    "unexpected addressing mode {:?} has ops-len 2. code {:02x}",
-                    opcode.mode, opcode.code
-                ),
-            }
+    let line = DisasmLine {
+        operand: line.operand.clone() + annotation.as_str(),
+        ..line
+    };
+    format_registers(cpu, &line)
+}
+
+// CYC counts every CPU cycle, so the ones DMA steals show up as a jump in it
+fn format_registers(cpu: &CPU, line: &DisasmLine) -> String {
+    format!(
+        "{:47} A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X} CYC:{}",
+        line.to_string(),
+        cpu.register_a,
+        cpu.register_x,
+        cpu.register_y,
+        cpu.status.bits(),
+        cpu.stack_pointer,
+        cpu.bus.cycles(),
+    )
+}
+
+/// Where trace lines go.
+pub enum TraceSink {
+    Stdout,
+    File(BufWriter<File>),
+    /// Only the last `capacity` lines are kept, to be dumped when something goes wrong.
+    Ring {
+        lines: VecDeque<String>,
+        capacity: usize,
+    },
+}
+
+impl TraceSink {
+    pub fn file(path: &str) -> io::Result<TraceSink> {
+        Ok(TraceSink::File(BufWriter::new(File::create(path)?)))
+    }
+
+    pub fn ring(capacity: usize) -> TraceSink {
+        TraceSink::Ring {
+            lines: VecDeque::with_capacity(capacity),
+            capacity,
         }
-        3 => {
-            let address_lo = cpu.mem_read(counter + 1);
-            let address_hi = cpu.mem_read(counter + 2);
-            hex_dump.push(address_lo);
-            hex_dump.push(address_hi);
-
-            let address = cpu.mem_read_u16(counter + 1);
-
-            match opcode.mode {
-                AddressingMode::NoneAddressing => {
-                    if opcode.code == 0x6c {
-                        // JMP indirect
-                        let jmp_addr = if address & 0x00FF == 0x00FF {
-                            let lo = cpu.mem_read(address);
-                            let hi = cpu.mem_read(address & 0xFF00);
-                            (hi as u16) << 8 | (lo as u16)
-                        } else {
-                            cpu.mem_read_u16(address)
-                        };
-
-                        format!("(${:04x}) = {:04x}", address, jmp_addr)
-                    } else {
-                        format!("${:04x}", address)
-                    }
-                }
-                AddressingMode::Absolute => format!("${:04x} = {:02x}", mem_addr, data),
-                AddressingMode::Absolute_X => {
-                    format!("${:04x},X @ {:04x} = {:02x}", address, mem_addr, data)
-                }
-                AddressingMode::Absolute_Y => {
-                    format!("${:04x},Y @ {:04x} = {:02x}", address, mem_addr, data)
+    }
+
+    fn write_line(&mut self, line: String) -> io::Result<()> {
+        match self {
+            TraceSink::Stdout => println!("{}", line),
+            TraceSink::File(file) => writeln!(file, "{}", line)?,
+            // a ring with no room keeps nothing
+            TraceSink::Ring { capacity: 0, .. } => {}
+            TraceSink::Ring { lines, capacity } => {
+                if lines.len() == *capacity {
+                    lines.pop_front();
                 }
-                _ => panic!(
-                    "unexpected addressing mode {:?} has ops-len 3. code {:02x}",
-                    opcode.mode, opcode.code
-                ),
+                lines.push_back(line);
             }
         }
-        _ => String::from(""),
-    };
+        Ok(())
+    }
 
-    let hex_str = hex_dump
-        .iter()
-        .map(|x| format!("{:02x}", x))
-        .collect::<Vec<String>>()
-        .join(" ");
+    fn recent(&self) -> Vec<&str> {
+        match self {
+            TraceSink::Ring { lines, .. } => lines.iter().map(String::as_str).collect(),
+            _ => vec![],
+        }
+    }
 
-    let asm_str = format!(
-        "{:04x}  {:8} {:>4} {}",
-        counter, hex_str, opcode.mnemonic, tmp
-    )
-    .trim()
-    .to_string();
+    fn dump(&mut self, out: &mut dyn Write) -> io::Result<()> {
+        match self {
+            TraceSink::Stdout => Ok(()),
+            TraceSink::File(file) => file.flush(),
+            TraceSink::Ring { lines, .. } => {
+                lines.iter().try_for_each(|line| writeln!(out, "{}", line))
+            }
+        }
+    }
+}
 
-    format!(
-        "{:47} A:{:02x} X:{:02x} Y:{:02x} P:{:02x} SP:{:02x}",
-        asm_str, cpu.register_a, cpu.register_x, cpu.register_y, cpu.status, cpu.stack_pointer,
-    )
-    .to_ascii_uppercase()
+/// Writes a trace line for every instruction, or only for the ones inside a range of
+/// addresses, and only once tracing has started.
+pub struct Tracer {
+    sink: TraceSink,
+    pc_range: Option<RangeInclusive<u16>>,
+    start_at: Option<u16>,
+    started: bool,
+}
+
+impl Tracer {
+    /// Traces everything from the next instruction on.
+    pub fn new(sink: TraceSink) -> Self {
+        Tracer {
+            sink,
+            pc_range: None,
+            start_at: None,
+            started: true,
+        }
+    }
+
+    /// Leaves out instructions outside `range`, like the NMI handler or a game's sound
+    /// driver.
+    pub fn only_in(&mut self, range: RangeInclusive<u16>) {
+        self.pc_range = Some(range);
+    }
+
+    /// Holds off until the CPU first reaches `addr`.
+    pub fn start_at(&mut self, addr: u16) {
+        self.start_at = Some(addr);
+        self.started = false;
+    }
+
+    /// Holds off until `start` is called, like when the debugger first stops.
+    pub fn wait(&mut self) {
+        self.started = false;
+    }
+
+    pub fn start(&mut self) {
+        self.started = true;
+    }
+
+    pub fn is_started(&self) -> bool {
+        self.started
+    }
+
+    /// Called before every instruction.
+    pub fn on_instruction(&mut self, cpu: &CPU) -> io::Result<()> {
+        if self.start_at == Some(cpu.program_counter) {
+            self.started = true;
+        }
+        let in_range = self
+            .pc_range
+            .as_ref()
+            .is_none_or(|range| range.contains(&cpu.program_counter));
+        if !self.started || !in_range {
+            return Ok(());
+        }
+
+        self.sink.write_line(trace(cpu))
+    }
+
+    /// The lines kept by a ring sink, oldest first.
+    pub fn recent(&self) -> Vec<&str> {
+        self.sink.recent()
+    }
+
+    /// Writes out what a ring sink kept, and makes sure everything sent to a file is in it.
+    pub fn dump(&mut self, out: &mut dyn Write) -> io::Result<()> {
+        self.sink.dump(out)
+    }
+}
+
+/// Logs every CPU read and write inside some ranges of addresses, like the PPU registers
+/// at $2000-$2007 or a mapper's registers, with the CPU cycle and the instruction that
+/// made it. Much shorter than a full trace for seeing how a game talks to the hardware.
+pub struct AccessLog {
+    ranges: Vec<RangeInclusive<u16>>,
+    sink: TraceSink,
+}
+
+impl AccessLog {
+    pub fn new(sink: TraceSink, ranges: Vec<RangeInclusive<u16>>) -> Self {
+        AccessLog { ranges, sink }
+    }
+
+    pub fn covers(&self, addr: u16) -> bool {
+        self.ranges.iter().any(|range| range.contains(&addr))
+    }
+
+    pub fn log(
+        &mut self,
+        cycle: usize,
+        pc: u16,
+        access: Access,
+        addr: u16,
+        data: u8,
+    ) -> io::Result<()> {
+        let access = match access {
+            Access::Read => "read ",
+            Access::Write => "write",
+        };
+        self.sink.write_line(format!(
+            "{:>10}  {:04X}  {} ${:04X} = {:02X}",
+            cycle, pc, access, addr, data
+        ))
+    }
+
+    /// The lines kept by a ring sink, oldest first.
+    pub fn recent(&self) -> Vec<&str> {
+        self.sink.recent()
+    }
+
+    /// Like `Tracer::dump`.
+    pub fn dump(&mut self, out: &mut dyn Write) -> io::Result<()> {
+        self.sink.dump(out)
+    }
+}
+
+/// Logs every NMI and IRQ the CPU takes, with the CPU cycle, where the PPU was and the PC
+/// that was interrupted, for games that hang waiting for an NMI or take an IRQ a line late.
+/// The last `RECENT` are kept for the debugger, and all of them go to the sink if there is
+/// one.
+pub struct InterruptLog {
+    sink: Option<TraceSink>,
+    recent: VecDeque<String>,
+}
+
+impl InterruptLog {
+    pub const RECENT: usize = 32;
+
+    pub fn new(sink: Option<TraceSink>) -> Self {
+        InterruptLog {
+            sink,
+            recent: VecDeque::with_capacity(Self::RECENT),
+        }
+    }
+
+    /// `source` is `NMI`, or `IRQ` with whatever held the line down.
+    pub fn log(
+        &mut self,
+        source: &str,
+        cycle: usize,
+        (scanline, dot): (u16, usize),
+        pc: u16,
+    ) -> io::Result<()> {
+        let line = format!(
+            "{:>10}  {:>3},{:>3}  {:04X}  {}",
+            cycle, scanline, dot, pc, source
+        );
+        if self.recent.len() == Self::RECENT {
+            self.recent.pop_front();
+        }
+        self.recent.push_back(line.clone());
+        match self.sink.as_mut() {
+            Some(sink) => sink.write_line(line),
+            None => Ok(()),
+        }
+    }
+
+    /// The last interrupts taken, oldest first.
+    pub fn recent(&self) -> Vec<&str> {
+        self.recent.iter().map(String::as_str).collect()
+    }
+
+    /// Like `Tracer::dump`.
+    pub fn dump(&mut self, out: &mut dyn Write) -> io::Result<()> {
+        match self.sink.as_mut() {
+            Some(sink) => sink.dump(out),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Parses a range of addresses like `$8000-$80FF`, for `Tracer::only_in`.
+pub fn parse_range(range: &str) -> Result<RangeInclusive<u16>, String> {
+    let addr = |addr: &str| {
+        u16::from_str_radix(addr.trim().trim_start_matches('$'), 16)
+            .map_err(|_| format!("Bad address {}", addr))
+    };
+    match range.split_once('-') {
+        Some((start, end)) => Ok(addr(start)?..=addr(end)?),
+        None => Err(format!(
+            "Bad address range {}, should be like $8000-$80FF",
+            range
+        )),
+    }
+}
+
+/// Parses a list of address ranges and single addresses like `$2000-$2007,$4016`, for
+/// `AccessLog`.
+pub fn parse_ranges(ranges: &str) -> Result<Vec<RangeInclusive<u16>>, String> {
+    ranges
+        .split(',')
+        .map(|range| {
+            if range.contains('-') {
+                parse_range(range)
+            } else {
+                parse_range(&format!("{}-{}", range, range))
+            }
+        })
+        .collect()
 }
 
 #[cfg(test)]
@@ -133,12 +345,10 @@ mod test {
     use crate::bus::Bus;
     use crate::cpu::Mem;
     use crate::rom::test::test_rom;
-    use crate::Joypad;
-    use crate::NesPPU;
 
     #[test]
     fn test_format_trace() {
-        let mut bus = Bus::new(test_rom(), |_: &NesPPU, _: &mut Joypad| {});
+        let mut bus = Bus::new(test_rom()).unwrap();
         bus.mem_write(100, 0xa2);
         bus.mem_write(101, 0x01);
         bus.mem_write(102, 0xca);
@@ -155,22 +365,54 @@ mod test {
             result.push(trace(cpu));
         });
         assert_eq!(
-            "0064  A2 01     LDX #$01                        A:01 X:02 Y:03 P:24 SP:FD",
+            "0064  A2 01     LDX #$01                        A:01 X:02 Y:03 P:24 SP:FD CYC:0",
             result[0]
         );
         assert_eq!(
-            "0066  CA        DEX                             A:01 X:01 Y:03 P:24 SP:FD",
+            "0066  CA        DEX                             A:01 X:01 Y:03 P:24 SP:FD CYC:2",
             result[1]
         );
         assert_eq!(
-            "0067  88        DEY                             A:01 X:00 Y:03 P:26 SP:FD",
+            "0067  88        DEY                             A:01 X:00 Y:03 P:26 SP:FD CYC:4",
             result[2]
         );
     }
 
+    #[test]
+    fn test_trace_shows_dma_cycles() {
+        let mut cpu = CPU::new(Bus::new(test_rom()).unwrap());
+        cpu.load(crate::asm::assemble("LDA #$02\n STA $4014\n NOP").unwrap());
+        cpu.program_counter = 0x0600;
+        let mut result: Vec<String> = vec![];
+        cpu.run_with_callback(|cpu| result.push(trace(cpu)));
+
+        // the store takes 4 cycles, and the DMA 514 more as its write lands on an odd one
+        assert!(result[1].ends_with("CYC:2"), "{}", result[1]);
+        assert!(result[2].ends_with("CYC:520"), "{}", result[2]);
+    }
+
+    #[test]
+    fn test_trace_does_not_consume_ppu_reads() {
+        let mut bus = Bus::new(test_rom()).unwrap();
+        // LDA $2002
+        bus.mem_write(100, 0xad);
+        bus.mem_write(101, 0x02);
+        bus.mem_write(102, 0x20);
+
+        let mut cpu = CPU::new(bus);
+        cpu.bus.ppu.status.set_vblank_status(true);
+        cpu.program_counter = 0x64;
+
+        assert_eq!(
+            "0064  AD 02 20  LDA $2002 = 80                  A:00 X:00 Y:00 P:24 SP:FD CYC:0",
+            trace(&cpu)
+        );
+        assert!(cpu.bus.ppu.status.is_in_vblank());
+    }
+
     #[test]
     fn test_format_mem_access() {
-        let mut bus = Bus::new(test_rom(), |_: &NesPPU, _: &mut Joypad| {});
+        let mut bus = Bus::new(test_rom()).unwrap();
         // ORA ($33), Y
         bus.mem_write(100, 0x11);
         bus.mem_write(101, 0x33);
@@ -190,8 +432,67 @@ mod test {
             result.push(trace(cpu));
         });
         assert_eq!(
-            "0064  11 33     ORA ($33),Y = 0400 @ 0400 = AA  A:00 X:00 Y:00 P:24 SP:FD",
+            "0064  11 33     ORA ($33),Y = 0400 @ 0400 = AA  A:00 X:00 Y:00 P:24 SP:FD CYC:0",
             result[0]
         );
     }
+
+    #[test]
+    fn test_tracer() {
+        let mut bus = Bus::new(test_rom()).unwrap();
+        // INX; INX; INX; INX; BRK
+        for addr in 100..104 {
+            bus.mem_write(addr, 0xe8);
+        }
+
+        let mut cpu = CPU::new(bus);
+        cpu.program_counter = 0x64;
+        let mut tracer = Tracer::new(TraceSink::ring(2));
+        tracer.only_in(parse_range("$0000-$0066").unwrap());
+        tracer.start_at(0x65);
+        cpu.run_with_callback(|cpu| tracer.on_instruction(cpu).unwrap());
+
+        // $0064 is before the start, and $0067 out of the range
+        let recent = tracer.recent();
+        assert_eq!(recent.len(), 2);
+        assert!(recent[0].starts_with("0065"));
+        assert!(recent[1].starts_with("0066"));
+
+        let mut out = Vec::new();
+        tracer.dump(&mut out).unwrap();
+        assert_eq!(out.iter().filter(|&&byte| byte == b'\n').count(), 2);
+        assert!(parse_range("$8000").is_err());
+
+        let mut empty = TraceSink::ring(0);
+        empty.write_line("0064".to_string()).unwrap();
+        assert!(empty.recent().is_empty());
+    }
+
+    #[test]
+    fn test_access_log() {
+        let mut bus = Bus::new(test_rom()).unwrap();
+        bus.access_log = Some(AccessLog::new(
+            TraceSink::ring(8),
+            parse_ranges("$2000-$2007,$10").unwrap(),
+        ));
+        let mut cpu = CPU::new(bus);
+        cpu.load(
+            crate::asm::assemble(
+                "LDA #$80
+                 STA $2000
+                 LDA $2002
+                 STA $10
+                 STA $11",
+            )
+            .unwrap(),
+        );
+        cpu.program_counter = 0x0600;
+        cpu.run();
+
+        let log = cpu.bus.access_log.as_ref().unwrap().recent();
+        assert_eq!(log.len(), 3);
+        assert!(log[0].ends_with("0602  write $2000 = 80"));
+        assert!(log[1].ends_with("0605  read  $2002 = 00"));
+        assert!(log[2].ends_with("0608  write $0010 = 00"));
+    }
 }
@@ -0,0 +1,173 @@
+const NES_WIDTH: f64 = 256.0;
+const NES_HEIGHT: f64 = 240.0;
+// NTSC pixels are a little wider than they are tall
+const PIXEL_ASPECT: f64 = 8.0 / 7.0;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ScaleMode {
+    /// Whole multiples of the NES resolution only, so every pixel is the same size.
+    Integer,
+    /// As large as the window allows, keeping the aspect ratio.
+    Fit,
+    /// Fills the whole window.
+    Stretch,
+}
+
+impl ScaleMode {
+    pub fn parse(name: &str) -> Result<ScaleMode, String> {
+        match name {
+            "integer" => Ok(ScaleMode::Integer),
+            "fit" => Ok(ScaleMode::Fit),
+            "stretch" => Ok(ScaleMode::Stretch),
+            _ => Err(format!(
+                "Unknown scaling {}, expected integer, fit or stretch",
+                name
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FullscreenMode {
+    /// A borderless window covering the desktop, without changing the display mode.
+    Desktop,
+    /// Takes over the display, switching it to the mode closest to the window size.
+    Exclusive,
+}
+
+impl FullscreenMode {
+    pub fn parse(name: &str) -> Result<FullscreenMode, String> {
+        match name {
+            "desktop" => Ok(FullscreenMode::Desktop),
+            "exclusive" => Ok(FullscreenMode::Exclusive),
+            _ => Err(format!(
+                "Unknown fullscreen mode {}, expected desktop or exclusive",
+                name
+            )),
+        }
+    }
+}
+
+/// Where the picture goes in the window. Anything around it is left black.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Viewport {
+    pub x: i32,
+    pub y: i32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Viewport {
+    /// Turns a position in the window into a pixel on the NES screen, if it's on the picture.
+    pub fn to_nes(&self, x: i32, y: i32) -> Option<(usize, usize)> {
+        let x = (x - self.x) as f64 * NES_WIDTH / self.width as f64;
+        let y = (y - self.y) as f64 * NES_HEIGHT / self.height as f64;
+        if x < 0.0 || y < 0.0 || x >= NES_WIDTH || y >= NES_HEIGHT {
+            return None;
+        }
+        Some((x as usize, y as usize))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct VideoConfig {
+    pub scale_mode: ScaleMode,
+    /// Draws pixels at 8:7 like a TV does, instead of square.
+    pub aspect_correct: bool,
+    /// Size of the window at start up, in multiples of the NES resolution.
+    pub window_scale: u32,
+    /// What alt+enter switches to.
+    pub fullscreen_mode: FullscreenMode,
+}
+
+impl Default for VideoConfig {
+    fn default() -> Self {
+        VideoConfig {
+            scale_mode: ScaleMode::Fit,
+            aspect_correct: false,
+            window_scale: 3,
+            fullscreen_mode: FullscreenMode::Desktop,
+        }
+    }
+}
+
+impl VideoConfig {
+    fn picture_width(&self) -> f64 {
+        if self.aspect_correct {
+            NES_WIDTH * PIXEL_ASPECT
+        } else {
+            NES_WIDTH
+        }
+    }
+
+    pub fn window_size(&self) -> (u32, u32) {
+        let scale = self.window_scale.max(1) as f64;
+        (
+            (self.picture_width() * scale).round() as u32,
+            (NES_HEIGHT * scale) as u32,
+        )
+    }
+
+    pub fn viewport(&self, window_width: u32, window_height: u32) -> Viewport {
+        let (window_width, window_height) = (window_width as f64, window_height as f64);
+        let fit = (window_width / self.picture_width()).min(window_height / NES_HEIGHT);
+        let (width, height) = match self.scale_mode {
+            ScaleMode::Stretch => (window_width, window_height),
+            ScaleMode::Fit => (self.picture_width() * fit, NES_HEIGHT * fit),
+            ScaleMode::Integer => {
+                let scale = fit.floor().max(1.0);
+                (self.picture_width() * scale, NES_HEIGHT * scale)
+            }
+        };
+        let (width, height) = (width.round(), height.round());
+        Viewport {
+            x: ((window_width - width) / 2.0) as i32,
+            y: ((window_height - height) / 2.0) as i32,
+            width: width as u32,
+            height: height as u32,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_fit_letterboxes() {
+        let config = VideoConfig::default();
+        assert_eq!(config.window_size(), (768, 720));
+        assert_eq!(
+            config.viewport(1024, 720),
+            Viewport {
+                x: 128,
+                y: 0,
+                width: 768,
+                height: 720
+            }
+        );
+        assert_eq!(config.viewport(1024, 720).to_nes(130, 5), Some((0, 1)));
+        assert_eq!(config.viewport(1024, 720).to_nes(100, 5), None);
+    }
+
+    #[test]
+    fn test_integer_scale_with_aspect_correction() {
+        let config = VideoConfig {
+            scale_mode: ScaleMode::Integer,
+            aspect_correct: true,
+            window_scale: 2,
+            fullscreen_mode: FullscreenMode::Desktop,
+        };
+        assert_eq!(config.window_size(), (585, 480));
+        // 2.5x fits, so 2x is used
+        assert_eq!(
+            config.viewport(800, 600),
+            Viewport {
+                x: 107,
+                y: 60,
+                width: 585,
+                height: 480
+            }
+        );
+    }
+}
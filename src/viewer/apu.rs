@@ -0,0 +1,132 @@
+use crate::apu::monitor::{ChannelState, WAVEFORM_LENGTH};
+use crate::apu::Channel;
+use crate::render::frame::Frame;
+use crate::render::osd::draw_text;
+use crate::viewer::{ConsoleState, DebugView};
+
+const WIDTH: usize = WAVEFORM_LENGTH;
+const HEIGHT: usize = 240;
+// a label and a waveform for each of the five channels
+const STRIP_HEIGHT: usize = 48;
+const WAVEFORM_TOP: usize = 12;
+const LEVEL_HEIGHT: usize = 2;
+const CPU_CLOCK: f64 = 1_789_773.0;
+const BASELINE_COLOUR: (u8, u8, u8) = (0x30, 0x30, 0x30);
+
+const CHANNELS: [(Channel, &str, (u8, u8, u8)); 5] = [
+    (Channel::Pulse1, "PULSE 1", (0xfc, 0x74, 0x60)),
+    (Channel::Pulse2, "PULSE 2", (0xfc, 0xbc, 0x3c)),
+    (Channel::Triangle, "TRIANGLE", (0x4c, 0xdc, 0x48)),
+    (Channel::Noise, "NOISE", (0x3c, 0xbc, 0xfc)),
+    (Channel::Dmc, "DMC", (0xa8, 0x88, 0xfc)),
+];
+
+/// Each channel's period, volume and recent output, a frame's worth wide.
+#[derive(Default)]
+pub struct ApuViewer {}
+
+impl ApuViewer {
+    pub fn new() -> Self {
+        ApuViewer {}
+    }
+}
+
+fn label(channel: Channel, name: &str, state: &ChannelState) -> String {
+    // the pulse timers count APU cycles, every other CPU cycle
+    let frequency = match channel {
+        Channel::Pulse1 | Channel::Pulse2 => CPU_CLOCK / (16.0 * (state.period as f64 + 1.0)),
+        Channel::Triangle => CPU_CLOCK / (32.0 * (state.period as f64 + 1.0)),
+        Channel::Noise | Channel::Dmc => 0.0,
+    };
+    let mut text = format!("{} ${:03X}", name, state.period);
+    if frequency > 0.0 {
+        text.push_str(&format!(" {:.0}HZ", frequency));
+    }
+    if channel == Channel::Triangle {
+        text.push_str(&format!(" LINEAR {}", state.volume));
+    } else {
+        text.push_str(&format!(" VOL {}", state.volume));
+    }
+    if !state.active {
+        text.push_str(" OFF");
+    }
+    if state.muted {
+        text.push_str(" MUTED");
+    }
+    text
+}
+
+impl DebugView for ApuViewer {
+    fn size(&self) -> (usize, usize) {
+        (WIDTH, HEIGHT)
+    }
+
+    fn render(&self, console: &ConsoleState) -> Frame {
+        let mut frame = Frame::with_size(WIDTH, HEIGHT);
+        for (i, (channel, name, colour)) in CHANNELS.iter().enumerate() {
+            let top = i * STRIP_HEIGHT;
+            let state = &console.apu.channels[*channel as usize];
+            draw_text(&mut frame, 1, top + 2, &label(*channel, name, state));
+
+            // level 0 at the bottom, 15 at the top, with the steps joined up
+            let bottom = top + WAVEFORM_TOP + 15 * LEVEL_HEIGHT;
+            for x in 0..WIDTH {
+                frame.set_pixel(x, bottom, BASELINE_COLOUR);
+            }
+            let waveform = console.apu.waveform(*channel as usize);
+            let mut previous = None;
+            for (x, level) in waveform.iter().enumerate() {
+                let y = bottom - *level as usize * LEVEL_HEIGHT;
+                let from = previous.unwrap_or(y);
+                for step in from.min(y)..=from.max(y) {
+                    frame.set_pixel(x, step, *colour);
+                }
+                previous = Some(y);
+            }
+        }
+        frame
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::apu::monitor::ApuMonitor;
+    use crate::ppu::NesPPU;
+
+    #[test]
+    fn test_label() {
+        let state = ChannelState {
+            period: 0xfd,
+            volume: 15,
+            active: true,
+            muted: true,
+        };
+        assert_eq!(
+            label(Channel::Pulse1, "PULSE 1", &state),
+            "PULSE 1 $0FD 440HZ VOL 15 MUTED"
+        );
+    }
+
+    #[test]
+    fn test_waveform_is_plotted() {
+        let mut monitor = ApuMonitor::new();
+        for _ in 0..WAVEFORM_LENGTH * 116 {
+            monitor.push_levels([15, 0, 0, 0, 0]);
+        }
+        let ppu = NesPPU::new_empty_rom();
+        let ram = [0; 2048];
+        let frame = ApuViewer::new().render(&ConsoleState {
+            ppu: &ppu,
+            ram: &ram,
+            apu: &monitor,
+        });
+
+        let colour = CHANNELS[0].2;
+        assert_eq!(frame.get_pixel(100, WAVEFORM_TOP), colour);
+        assert_eq!(
+            frame.get_pixel(100, STRIP_HEIGHT + WAVEFORM_TOP + 30),
+            CHANNELS[1].2
+        );
+    }
+}
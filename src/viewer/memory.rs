@@ -0,0 +1,310 @@
+use crate::bus::Bus;
+use crate::ppu::{NesPPU, PPU};
+use crate::render::frame::Frame;
+use crate::render::osd::{draw_text, draw_text_coloured, text_width, TEXT_COLOUR, TEXT_HEIGHT};
+use crate::viewer::{ConsoleState, DebugView};
+use sdl2::keyboard::Keycode;
+use std::convert::TryFrom;
+
+const WIDTH: usize = 224;
+const HEIGHT: usize = 240;
+const BYTES_PER_ROW: usize = 16;
+const ROWS: usize = 32;
+// the rows start under the header line
+const TOP: usize = 12;
+const UNREADABLE_COLOUR: (u8, u8, u8) = (0x74, 0x74, 0x74);
+// edits show up straight away, even while they wait for the console to pick them up
+const EDITED_COLOUR: (u8, u8, u8) = (0xfc, 0xd8, 0x58);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MemorySpace {
+    Cpu,
+    /// The whole PPU address space: pattern tables, nametables and palettes.
+    Vram,
+    Oam,
+    Palette,
+}
+
+impl MemorySpace {
+    fn next(self) -> MemorySpace {
+        match self {
+            MemorySpace::Cpu => MemorySpace::Vram,
+            MemorySpace::Vram => MemorySpace::Oam,
+            MemorySpace::Oam => MemorySpace::Palette,
+            MemorySpace::Palette => MemorySpace::Cpu,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            MemorySpace::Cpu => "CPU",
+            MemorySpace::Vram => "PPU",
+            MemorySpace::Oam => "OAM",
+            MemorySpace::Palette => "PALETTE",
+        }
+    }
+
+    pub fn size(self) -> usize {
+        match self {
+            MemorySpace::Cpu => 0x10000,
+            MemorySpace::Vram => 0x4000,
+            MemorySpace::Oam => 0x100,
+            MemorySpace::Palette => 0x20,
+        }
+    }
+
+    /// Reads a byte without side effects. `ram` stands in for the CPU's internal RAM, which
+    /// only the bus can see. `None` for the APU and I/O registers, which can't be peeked
+    /// from the PPU side.
+    pub fn peek(self, ppu: &NesPPU, ram: &[u8; 2048], addr: u16) -> Option<u8> {
+        match self {
+            MemorySpace::Cpu => match addr {
+                0..=0x1fff => Some(ram[(addr & 0x7ff) as usize]),
+                0x2000..=0x3fff => Some(match addr & 0x2007 {
+                    0x2002 => ppu.peek_status(),
+                    0x2004 => ppu.peek_oam_data(),
+                    0x2007 => ppu.peek_data(),
+                    _ => ppu.io_latch(),
+                }),
+                0x4020..=0x5fff => ppu.bus.mapper.borrow().peek_expansion(addr),
+                0x6000..=0xffff => Some(ppu.bus.mapper.borrow().read_prg(addr)),
+                _ => None,
+            },
+            MemorySpace::Vram => Some(ppu.peek_vram(addr)),
+            MemorySpace::Oam => Some(ppu.oam_data[addr as usize]),
+            MemorySpace::Palette => Some(ppu.peek_vram(0x3f00 | addr)),
+        }
+    }
+
+    /// Only RAM can be edited on the CPU side. Writes to CHR ROM are ignored by the board.
+    pub fn writable(self, addr: u16) -> bool {
+        match self {
+            MemorySpace::Cpu => matches!(addr, 0..=0x1fff | 0x6000..=0x7fff),
+            _ => true,
+        }
+    }
+
+    pub fn poke(self, bus: &mut Bus, addr: u16, value: u8) {
+        match self {
+            MemorySpace::Cpu => {
+                bus.poke(addr, value);
+            }
+            MemorySpace::Vram => bus.ppu.poke_vram(addr, value),
+            MemorySpace::Oam => bus.ppu.oam_data[addr as usize] = value,
+            MemorySpace::Palette => bus.ppu.poke_vram(0x3f00 | addr, value),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Edit {
+    pub space: MemorySpace,
+    pub addr: u16,
+    pub value: u8,
+}
+
+/// A live hex dump of one of the address spaces. The arrow keys and page up/down move the
+/// cursor, tab switches address space and typing two hex digits replaces the byte under the
+/// cursor.
+pub struct MemoryViewer {
+    space: MemorySpace,
+    cursor: usize,
+    top_row: usize,
+    // the first digit typed over the byte under the cursor
+    high_nibble: Option<u8>,
+    edits: Vec<Edit>,
+}
+
+impl Default for MemoryViewer {
+    fn default() -> Self {
+        MemoryViewer::new()
+    }
+}
+
+impl MemoryViewer {
+    pub fn new() -> Self {
+        MemoryViewer {
+            space: MemorySpace::Cpu,
+            cursor: 0,
+            top_row: 0,
+            high_nibble: None,
+            edits: vec![],
+        }
+    }
+
+    /// Edits made since the last call, to be written to the console.
+    pub fn take_edits(&mut self) -> Vec<Edit> {
+        std::mem::take(&mut self.edits)
+    }
+
+    fn move_cursor(&mut self, delta: isize) {
+        let last = self.space.size() as isize - 1;
+        self.cursor = (self.cursor as isize + delta).clamp(0, last) as usize;
+        self.high_nibble = None;
+
+        let row = self.cursor / BYTES_PER_ROW;
+        if row < self.top_row {
+            self.top_row = row;
+        } else if row >= self.top_row + ROWS {
+            self.top_row = row + 1 - ROWS;
+        }
+    }
+
+    fn type_digit(&mut self, digit: u8) {
+        if !self.space.writable(self.cursor as u16) {
+            return;
+        }
+        match self.high_nibble.take() {
+            None => self.high_nibble = Some(digit),
+            Some(high) => {
+                self.edits.push(Edit {
+                    space: self.space,
+                    addr: self.cursor as u16,
+                    value: high << 4 | digit,
+                });
+                self.move_cursor(1);
+            }
+        }
+    }
+
+    fn pending_edit(&self, addr: u16) -> Option<u8> {
+        self.edits
+            .iter()
+            .rev()
+            .find(|edit| edit.space == self.space && edit.addr == addr)
+            .map(|edit| edit.value)
+    }
+}
+
+impl DebugView for MemoryViewer {
+    fn size(&self) -> (usize, usize) {
+        (WIDTH, HEIGHT)
+    }
+
+    fn render(&self, console: &ConsoleState) -> Frame {
+        let mut frame = Frame::with_size(WIDTH, HEIGHT);
+        let mut header = format!("{} ${:04X}", self.space.name(), self.cursor);
+        if !self.space.writable(self.cursor as u16) {
+            header.push_str("  READ ONLY");
+        }
+        draw_text(&mut frame, 2, 2, &header);
+
+        let hex_x = 2 + text_width("0000: ") - 1;
+        let byte_width = text_width("00 ") - 1;
+        for row in 0..ROWS {
+            let start = (self.top_row + row) * BYTES_PER_ROW;
+            if start >= self.space.size() {
+                break;
+            }
+            let y = TOP + row * TEXT_HEIGHT;
+            draw_text(&mut frame, 2, y, &format!("{:04X}:", start));
+
+            for column in 0..BYTES_PER_ROW {
+                let addr = (start + column) as u16;
+                let x = hex_x + column * byte_width;
+                let edited = self.pending_edit(addr);
+                let (text, colour) = match (edited, self.space.peek(console.ppu, console.ram, addr))
+                {
+                    (Some(value), _) => (format!("{:02X}", value), EDITED_COLOUR),
+                    (None, Some(value)) => (format!("{:02X}", value), TEXT_COLOUR),
+                    (None, None) => ("--".to_string(), UNREADABLE_COLOUR),
+                };
+                if start + column == self.cursor {
+                    let text = match self.high_nibble {
+                        Some(high) => format!("{:X}-", high),
+                        None => text,
+                    };
+                    draw_text_coloured(&mut frame, x, y, &text, (0, 0, 0), colour);
+                } else {
+                    draw_text_coloured(&mut frame, x, y, &text, colour, (0, 0, 0));
+                }
+            }
+        }
+        frame
+    }
+
+    fn handle_key(&mut self, keycode: Keycode, _console: &ConsoleState) {
+        let page = (BYTES_PER_ROW * ROWS) as isize;
+        match keycode {
+            Keycode::Tab => {
+                self.space = self.space.next();
+                self.cursor = 0;
+                self.top_row = 0;
+                self.high_nibble = None;
+            }
+            Keycode::Up => self.move_cursor(-(BYTES_PER_ROW as isize)),
+            Keycode::Down => self.move_cursor(BYTES_PER_ROW as isize),
+            Keycode::Left => self.move_cursor(-1),
+            Keycode::Right => self.move_cursor(1),
+            Keycode::PageUp => self.move_cursor(-page),
+            Keycode::PageDown => self.move_cursor(page),
+            Keycode::Home => self.move_cursor(-(self.cursor as isize)),
+            Keycode::End => self.move_cursor(self.space.size() as isize),
+            Keycode::Escape => self.high_nibble = None,
+            _ => {
+                if let Some(digit) = hex_digit(keycode) {
+                    self.type_digit(digit);
+                }
+            }
+        }
+    }
+}
+
+// The number and letter keys have their ASCII codes as keycodes
+fn hex_digit(keycode: Keycode) -> Option<u8> {
+    let code = u8::try_from(keycode as i32).ok()?;
+    (code as char).to_digit(16).map(|digit| digit as u8)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::apu::monitor::ApuMonitor;
+
+    #[test]
+    fn test_typing_hex_digits_edits_the_byte() {
+        let ppu = NesPPU::new_empty_rom();
+        let ram = [0; 2048];
+        let apu = ApuMonitor::new();
+        let console = ConsoleState {
+            ppu: &ppu,
+            ram: &ram,
+            apu: &apu,
+        };
+        let mut viewer = MemoryViewer::new();
+        viewer.handle_key(Keycode::Down, &console);
+        viewer.handle_key(Keycode::Num4, &console);
+        viewer.handle_key(Keycode::A, &console);
+        assert_eq!(
+            viewer.take_edits(),
+            vec![Edit {
+                space: MemorySpace::Cpu,
+                addr: 0x10,
+                value: 0x4a
+            }]
+        );
+        assert_eq!(viewer.cursor, 0x11);
+
+        // ROM can't be edited
+        viewer.handle_key(Keycode::End, &console);
+        viewer.handle_key(Keycode::Num1, &console);
+        viewer.handle_key(Keycode::Num2, &console);
+        assert!(viewer.take_edits().is_empty());
+        assert_eq!(viewer.top_row, 0x1000 - ROWS);
+    }
+
+    #[test]
+    fn test_peek_address_spaces() {
+        let mut ppu = NesPPU::new_empty_rom();
+        ppu.oam_data[3] = 0x33;
+        ppu.palette_table[0] = 0x0f;
+        let mut ram = [0; 2048];
+        ram[0x10] = 0x42;
+
+        assert_eq!(MemorySpace::Cpu.peek(&ppu, &ram, 0x0810), Some(0x42));
+        assert_eq!(MemorySpace::Cpu.peek(&ppu, &ram, 0x4000), None);
+        assert_eq!(MemorySpace::Oam.peek(&ppu, &ram, 3), Some(0x33));
+        // $3F10 mirrors the backdrop colour
+        assert_eq!(MemorySpace::Palette.peek(&ppu, &ram, 0x10), Some(0x0f));
+    }
+}
@@ -0,0 +1,156 @@
+pub mod apu;
+pub mod memory;
+pub mod nametables;
+pub mod patterns;
+pub mod search;
+
+use crate::apu::monitor::ApuMonitor;
+use crate::ppu::NesPPU;
+use crate::render::frame::Frame;
+use sdl2::event::{Event, WindowEvent};
+use sdl2::keyboard::Keycode;
+use sdl2::pixels::PixelFormatEnum;
+use sdl2::render::Canvas;
+use sdl2::video::Window;
+use sdl2::VideoSubsystem;
+use std::cell::RefCell;
+use std::rc::Rc;
+
+// debug windows start out around as tall as the game window
+const WINDOW_HEIGHT: usize = 720;
+
+/// What the debug views are drawn from, as of the end of the last frame.
+pub struct ConsoleState<'a> {
+    pub ppu: &'a NesPPU,
+    pub ram: &'a [u8; 2048],
+    pub apu: &'a ApuMonitor,
+}
+
+pub trait DebugView {
+    /// Size of the pictures `render` draws.
+    fn size(&self) -> (usize, usize);
+
+    fn render(&self, console: &ConsoleState) -> Frame;
+
+    /// Keys pressed while the view's window has focus.
+    fn handle_key(&mut self, _keycode: Keycode, _console: &ConsoleState) {}
+}
+
+/// A second window for one of the debug views, next to the game.
+struct ViewerWindow {
+    canvas: Canvas<Window>,
+    view: Rc<RefCell<dyn DebugView>>,
+}
+
+impl ViewerWindow {
+    fn open(
+        video: &VideoSubsystem,
+        title: &str,
+        view: Rc<RefCell<dyn DebugView>>,
+    ) -> Result<ViewerWindow, String> {
+        let (width, height) = view.borrow().size();
+        let scale = (WINDOW_HEIGHT / height).max(1) as u32;
+        let window = video
+            .window(title, width as u32 * scale, height as u32 * scale)
+            .resizable()
+            .build()
+            .map_err(|e| e.to_string())?;
+        let canvas = window.into_canvas().build().map_err(|e| e.to_string())?;
+        Ok(ViewerWindow { canvas, view })
+    }
+
+    fn id(&self) -> u32 {
+        self.canvas.window().id()
+    }
+
+    // the picture is stretched over the whole window
+    fn draw(&mut self, console: &ConsoleState) -> Result<(), String> {
+        let frame = self.view.borrow().render(console);
+        let creator = self.canvas.texture_creator();
+        let mut texture = creator
+            .create_texture_streaming(
+                PixelFormatEnum::RGB24,
+                frame.width() as u32,
+                frame.height() as u32,
+            )
+            .map_err(|e| e.to_string())?;
+        texture
+            .update(None, &frame.data, frame.width() * 3)
+            .map_err(|e| e.to_string())?;
+        self.canvas.copy(&texture, None, None)?;
+        self.canvas.present();
+        Ok(())
+    }
+}
+
+/// The debug windows that are open, redrawn along with the game.
+pub struct DebugWindows {
+    video: VideoSubsystem,
+    windows: Vec<ViewerWindow>,
+}
+
+impl DebugWindows {
+    pub fn new(video: VideoSubsystem) -> Self {
+        DebugWindows {
+            video,
+            windows: vec![],
+        }
+    }
+
+    /// Opens a window titled `title` showing `view`, or closes it if it's already open.
+    pub fn toggle(&mut self, title: &str, view: Rc<RefCell<dyn DebugView>>) {
+        let open = self.windows.len();
+        self.windows
+            .retain(|window| window.canvas.window().title() != title);
+        if self.windows.len() < open {
+            return;
+        }
+        match ViewerWindow::open(&self.video, title, view) {
+            Ok(window) => self.windows.push(window),
+            Err(e) => eprintln!("Can't open the {} window: {}", title, e),
+        }
+    }
+
+    /// Passes events for the debug windows on to their views. Returns false for events that
+    /// don't belong to one of them.
+    pub fn handle_event(&mut self, event: &Event, console: &ConsoleState) -> bool {
+        let id = match event.get_window_id() {
+            Some(id) => id,
+            None => return false,
+        };
+        let index = match self.windows.iter().position(|window| window.id() == id) {
+            Some(index) => index,
+            None => return false,
+        };
+        match event {
+            Event::Window {
+                win_event: WindowEvent::Close,
+                ..
+            } => {
+                self.windows.remove(index);
+            }
+            Event::KeyDown {
+                keycode: Some(keycode),
+                ..
+            } => self.windows[index]
+                .view
+                .borrow_mut()
+                .handle_key(*keycode, console),
+            _ => {}
+        }
+        true
+    }
+
+    /// Whether any are open, and want the console kept up to date for them.
+    pub fn any_open(&self) -> bool {
+        !self.windows.is_empty()
+    }
+
+    pub fn draw(&mut self, console: &ConsoleState) {
+        for window in self.windows.iter_mut() {
+            if let Err(e) = window.draw(console) {
+                eprintln!("Can't draw a debug window: {}", e);
+            }
+        }
+    }
+}
@@ -0,0 +1,144 @@
+use crate::ppu::NesPPU;
+use crate::render::frame::Frame;
+use crate::render::palette;
+use crate::viewer::{ConsoleState, DebugView};
+use sdl2::keyboard::Keycode;
+
+// the four nametables side by side, $2000 top left and $2C00 bottom right
+const WIDTH: usize = 512;
+const HEIGHT: usize = 480;
+const SCROLL_COLOUR: (u8, u8, u8) = (0xff, 0x00, 0xff);
+// each attribute byte covers 32x32 pixels, split into four 16x16 palette areas
+const ATTRIBUTE_COLOUR: (u8, u8, u8) = (0x00, 0xc0, 0xff);
+const QUADRANT_COLOUR: (u8, u8, u8) = (0x00, 0x58, 0x80);
+
+/// All four nametables as the PPU sees them through the mirroring, with the part that's on
+/// screen outlined. G toggles a grid over the attribute areas.
+#[derive(Default)]
+pub struct NametableViewer {
+    attribute_grid: bool,
+}
+
+impl NametableViewer {
+    pub fn new() -> Self {
+        NametableViewer {
+            attribute_grid: false,
+        }
+    }
+}
+
+// A pixel of the 512x480 background plane. `ppu.peek_vram` takes care of the mirroring.
+fn background_pixel(ppu: &NesPPU, x: usize, y: usize) -> (u8, u8, u8) {
+    let name_table = 0x2000 + (y / 240 * 2 + x / 256) as u16 * 0x400;
+    let (column, row) = (x % 256 / 8, y % 240 / 8);
+    let tile_idx = ppu.peek_vram(name_table + (row * 32 + column) as u16) as u16;
+    let attr_byte = ppu.peek_vram(name_table + 0x3c0 + (row / 4 * 8 + column / 4) as u16);
+    let shift = (row % 4 / 2 * 4) + (column % 4 / 2 * 2);
+    let palette_idx = (attr_byte >> shift) & 0b11;
+
+    let addr = ppu.ctrl.bknd_pattern_addr() + tile_idx * 16 + (y % 8) as u16;
+    let mapper = ppu.bus.mapper.borrow();
+    let bit = 7 - x % 8;
+    let value =
+        ((mapper.read_chr(addr + 8) >> bit) & 1) << 1 | ((mapper.read_chr(addr) >> bit) & 1);
+    let entry = if value == 0 {
+        ppu.palette_table[0]
+    } else {
+        ppu.palette_table[palette_idx as usize * 4 + value as usize]
+    };
+    palette::lookup(ppu, entry)
+}
+
+// Where the top left pixel of a scanline comes from, in the 512x480 plane
+fn scroll_origin(v: u16, fine_x: u8) -> (usize, usize) {
+    let x = ((v >> 10) & 1) * 256 + (v & 0b1_1111) * 8 + fine_x as u16;
+    let y = ((v >> 11) & 1) * 240 + ((v >> 5) & 0b1_1111) * 8 + (v >> 12);
+    (x as usize, y as usize % HEIGHT)
+}
+
+impl DebugView for NametableViewer {
+    fn size(&self) -> (usize, usize) {
+        (WIDTH, HEIGHT)
+    }
+
+    fn render(&self, console: &ConsoleState) -> Frame {
+        let ppu = console.ppu;
+        let mut frame = Frame::with_size(WIDTH, HEIGHT);
+        for y in 0..HEIGHT {
+            for x in 0..WIDTH {
+                frame.set_pixel(x, y, background_pixel(ppu, x, y));
+            }
+        }
+
+        if self.attribute_grid {
+            for y in 0..HEIGHT {
+                for x in 0..WIDTH {
+                    let (ax, ay) = (x % 256, y % 240);
+                    if ax % 32 == 0 || ay % 32 == 0 {
+                        frame.set_pixel(x, y, ATTRIBUTE_COLOUR);
+                    } else if ax % 16 == 0 || ay % 16 == 0 {
+                        frame.set_pixel(x, y, QUADRANT_COLOUR);
+                    }
+                }
+            }
+        }
+
+        // Each scanline is outlined where it was scrolled to, so split screens show up as
+        // separate strips. Everything wraps around the edges of the plane.
+        for (line, &(v, fine_x)) in ppu.line_scroll.iter().enumerate() {
+            let (x, y) = scroll_origin(v, fine_x);
+            frame.set_pixel(x % WIDTH, y, SCROLL_COLOUR);
+            frame.set_pixel((x + 255) % WIDTH, y, SCROLL_COLOUR);
+            if line == 0 || line == 239 {
+                for dx in 0..256 {
+                    frame.set_pixel((x + dx) % WIDTH, y, SCROLL_COLOUR);
+                }
+            }
+        }
+        frame
+    }
+
+    fn handle_key(&mut self, keycode: Keycode, _console: &ConsoleState) {
+        if keycode == Keycode::G {
+            self.attribute_grid = !self.attribute_grid;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::apu::monitor::ApuMonitor;
+    use crate::mapper;
+    use crate::rom::Mirroring;
+
+    #[test]
+    fn test_scroll_outline_and_mirroring() {
+        // horizontal mirroring, so $2400 shows the same tiles as $2000
+        let mut ppu = NesPPU::new(mapper::blank(Mirroring::Horizontal, true));
+        // the top row of tile 0 is colour 1, drawn with the second palette in the top left
+        ppu.poke_vram(0x0000, 0xff);
+        ppu.poke_vram(0x23c0, 0b01);
+        ppu.palette_table[5] = 0x16;
+        let ram = [0; 2048];
+        let frame = NametableViewer::new().render(&ConsoleState {
+            ppu: &ppu,
+            ram: &ram,
+            apu: &ApuMonitor::new(),
+        });
+
+        assert_eq!(frame.get_pixel(100, 0), SCROLL_COLOUR);
+        assert_eq!(frame.get_pixel(255, 0), SCROLL_COLOUR);
+        assert_ne!(frame.get_pixel(256, 0), SCROLL_COLOUR);
+        assert_eq!(frame.get_pixel(10, 8), palette::SYSTEM_PALLETE[0x16]);
+        assert_eq!(frame.get_pixel(266, 8), palette::SYSTEM_PALLETE[0x16]);
+        assert_eq!(frame.get_pixel(10, 248), palette::SYSTEM_PALLETE[0]);
+    }
+
+    #[test]
+    fn test_scroll_origin_wraps_into_the_other_nametables() {
+        // nametable $2C00, coarse x 31, coarse y 29, fine y 7, fine x 7
+        let v = 7 << 12 | 0b11 << 10 | 29 << 5 | 31;
+        assert_eq!(scroll_origin(v, 7), (511, 479));
+    }
+}
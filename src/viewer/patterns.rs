@@ -0,0 +1,148 @@
+use crate::ppu::NesPPU;
+use crate::render::frame::Frame;
+use crate::render::palette;
+use crate::viewer::{ConsoleState, DebugView};
+use sdl2::keyboard::Keycode;
+
+// both pattern tables side by side, with the eight palettes underneath
+const WIDTH: usize = 256;
+const HEIGHT: usize = 176;
+const SWATCH_SIZE: usize = 14;
+const PALETTES_TOP: usize = 136;
+// a background palette and the sprite palette below it are this far apart
+const PALETTE_ROW_HEIGHT: usize = 20;
+const GROUP_SPACING: usize = 64;
+const SELECTED_COLOUR: (u8, u8, u8) = (0xff, 0xff, 0xff);
+
+/// The 512 tiles in CHR, drawn with one of the eight palettes, and the current colours of
+/// all eight. Left and right pick the palette the tiles are drawn with.
+#[derive(Default)]
+pub struct PatternViewer {
+    // 0-3 are the background palettes, 4-7 the sprite palettes
+    palette: usize,
+}
+
+impl PatternViewer {
+    pub fn new() -> Self {
+        PatternViewer { palette: 0 }
+    }
+}
+
+// Palette RAM entry for colour `value` of palette `group`. Colour 0 is always the backdrop.
+fn palette_entry(ppu: &NesPPU, group: usize, value: usize) -> u8 {
+    if value == 0 {
+        ppu.palette_table[0]
+    } else {
+        ppu.palette_table[group * 4 + value]
+    }
+}
+
+fn draw_tile(ppu: &NesPPU, frame: &mut Frame, table: usize, tile: usize, group: usize) {
+    let mapper = ppu.bus.mapper.borrow();
+    let addr = (table * 0x1000 + tile * 16) as u16;
+    let left = table * 128 + tile % 16 * 8;
+    let top = tile / 16 * 8;
+    for y in 0..8 {
+        let low = mapper.read_chr(addr + y as u16);
+        let high = mapper.read_chr(addr + y as u16 + 8);
+        for x in 0..8 {
+            let bit = 7 - x;
+            let value = ((high >> bit) & 1) << 1 | ((low >> bit) & 1);
+            let entry = palette_entry(ppu, group, value as usize);
+            frame.set_pixel(left + x, top + y, palette::lookup(ppu, entry));
+        }
+    }
+}
+
+// Top left corner of palette `group`'s swatches
+fn palette_position(group: usize) -> (usize, usize) {
+    (
+        4 + group % 4 * GROUP_SPACING,
+        PALETTES_TOP + group / 4 * PALETTE_ROW_HEIGHT,
+    )
+}
+
+impl DebugView for PatternViewer {
+    fn size(&self) -> (usize, usize) {
+        (WIDTH, HEIGHT)
+    }
+
+    fn render(&self, console: &ConsoleState) -> Frame {
+        let ppu = console.ppu;
+        let mut frame = Frame::with_size(WIDTH, HEIGHT);
+        for table in 0..2 {
+            for tile in 0..256 {
+                draw_tile(ppu, &mut frame, table, tile, self.palette);
+            }
+        }
+
+        for group in 0..8 {
+            let (left, top) = palette_position(group);
+            if group == self.palette {
+                let width = 4 * SWATCH_SIZE + 4;
+                for dx in 0..width {
+                    for dy in 0..SWATCH_SIZE + 4 {
+                        frame.set_pixel(left - 2 + dx, top - 2 + dy, SELECTED_COLOUR);
+                    }
+                }
+            }
+            for value in 0..4 {
+                let rgb = palette::lookup(ppu, palette_entry(ppu, group, value));
+                for dx in 0..SWATCH_SIZE {
+                    for dy in 0..SWATCH_SIZE {
+                        frame.set_pixel(left + value * SWATCH_SIZE + dx, top + dy, rgb);
+                    }
+                }
+            }
+        }
+        frame
+    }
+
+    fn handle_key(&mut self, keycode: Keycode, _console: &ConsoleState) {
+        match keycode {
+            Keycode::Left => self.palette = (self.palette + 7) % 8,
+            Keycode::Right => self.palette = (self.palette + 1) % 8,
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::apu::monitor::ApuMonitor;
+    use crate::mapper;
+    use crate::rom::Mirroring;
+
+    #[test]
+    fn test_tiles_use_the_selected_palette() {
+        let mut ppu = NesPPU::new(mapper::blank(Mirroring::Horizontal, true));
+        // the top row of tile 1 in the right table is colour 3
+        ppu.poke_vram(0x1010, 0xff);
+        ppu.poke_vram(0x1018, 0xff);
+        ppu.palette_table[0x13] = 0x2a;
+        let ram = [0; 2048];
+        let console = ConsoleState {
+            ppu: &ppu,
+            ram: &ram,
+            apu: &ApuMonitor::new(),
+        };
+
+        let mut viewer = PatternViewer::new();
+        viewer.handle_key(Keycode::Left, &console);
+        viewer.handle_key(Keycode::Left, &console);
+        viewer.handle_key(Keycode::Left, &console);
+        viewer.handle_key(Keycode::Left, &console);
+        let frame = viewer.render(&console);
+
+        assert_eq!(frame.get_pixel(128 + 8, 0), palette::SYSTEM_PALLETE[0x2a]);
+        assert_eq!(frame.get_pixel(128 + 8, 1), palette::SYSTEM_PALLETE[0]);
+        // colour 3 of the first sprite palette, and the outline around it
+        let (left, top) = palette_position(4);
+        assert_eq!(
+            frame.get_pixel(left + 3 * SWATCH_SIZE, top),
+            palette::SYSTEM_PALLETE[0x2a]
+        );
+        assert_eq!(frame.get_pixel(left - 1, top), SELECTED_COLOUR);
+    }
+}
@@ -0,0 +1,239 @@
+use crate::render::frame::Frame;
+use crate::render::osd::{draw_text, draw_text_coloured, TEXT_COLOUR, TEXT_HEIGHT};
+use crate::viewer::{ConsoleState, DebugView};
+use sdl2::keyboard::Keycode;
+use std::convert::TryFrom;
+
+const WIDTH: usize = 224;
+const HEIGHT: usize = 240;
+// the 2 KiB of work RAM, then the 8 KiB of PRG RAM at $6000
+const WORK_RAM_SIZE: usize = 0x800;
+const SEARCH_SIZE: usize = WORK_RAM_SIZE + 0x2000;
+const LIST_TOP: usize = 26;
+const LIST_ROWS: usize = 30;
+
+fn address(index: usize) -> u16 {
+    if index < WORK_RAM_SIZE {
+        index as u16
+    } else {
+        (0x6000 + index - WORK_RAM_SIZE) as u16
+    }
+}
+
+/// The current value of every searchable byte.
+fn read_values(console: &ConsoleState) -> Vec<u8> {
+    let mapper = console.ppu.bus.mapper.borrow();
+    (0..SEARCH_SIZE)
+        .map(|index| match index {
+            0..=0x7ff => console.ram[index],
+            _ => mapper.read_prg(address(index)),
+        })
+        .collect()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Comparison {
+    Equal,
+    Greater,
+    Less,
+    Changed,
+}
+
+/// Narrows down where a game keeps a value, by comparing snapshots of its RAM. Each
+/// comparison is against the number typed in, or against the last snapshot if there isn't
+/// one:
+///
+/// * E, G, L: equal to, greater than or less than
+/// * C: changed, or changed by exactly the number typed in (minus negates it)
+/// * R: starts again with every address
+/// * F: freezes the selected address at its current value with a cheat
+pub struct RamSearch {
+    previous: Vec<u8>,
+    candidates: Vec<usize>,
+    value: Option<i16>,
+    selected: usize,
+    freezes: Vec<String>,
+}
+
+impl Default for RamSearch {
+    fn default() -> Self {
+        RamSearch::new()
+    }
+}
+
+impl RamSearch {
+    pub fn new() -> Self {
+        RamSearch {
+            previous: vec![],
+            candidates: vec![],
+            value: None,
+            selected: 0,
+            freezes: vec![],
+        }
+    }
+
+    /// Cheat codes for the addresses frozen since the last call, to add to the console.
+    pub fn take_freezes(&mut self) -> Vec<String> {
+        std::mem::take(&mut self.freezes)
+    }
+
+    pub fn restart(&mut self, values: Vec<u8>) {
+        self.candidates = (0..values.len()).collect();
+        self.previous = values;
+        self.selected = 0;
+    }
+
+    /// Keeps the addresses that pass `comparison`, and makes `values` the new snapshot.
+    pub fn filter(&mut self, comparison: Comparison, values: Vec<u8>) {
+        if self.previous.is_empty() {
+            self.restart(values.clone());
+        }
+        let value = self.value;
+        let previous = &self.previous;
+        self.candidates.retain(|&index| {
+            let (now, before) = (values[index] as i16, previous[index] as i16);
+            match (comparison, value) {
+                (Comparison::Equal, Some(value)) => now == value,
+                (Comparison::Equal, None) => now == before,
+                (Comparison::Greater, Some(value)) => now > value,
+                (Comparison::Greater, None) => now > before,
+                (Comparison::Less, Some(value)) => now < value,
+                (Comparison::Less, None) => now < before,
+                (Comparison::Changed, Some(delta)) => now - before == delta,
+                (Comparison::Changed, None) => now != before,
+            }
+        });
+        self.previous = values;
+        self.selected = self.selected.min(self.candidates.len().saturating_sub(1));
+    }
+
+    fn type_digit(&mut self, digit: i16) {
+        let value = self.value.unwrap_or(0);
+        let typed = value.abs() * 10 + digit;
+        if typed <= 255 {
+            self.value = Some(if value < 0 { -typed } else { typed });
+        }
+    }
+}
+
+impl DebugView for RamSearch {
+    fn size(&self) -> (usize, usize) {
+        (WIDTH, HEIGHT)
+    }
+
+    fn render(&self, console: &ConsoleState) -> Frame {
+        let mut frame = Frame::with_size(WIDTH, HEIGHT);
+        let value = self
+            .value
+            .map_or("-".to_string(), |value| value.to_string());
+        let found = if self.previous.is_empty() {
+            "R TO START".to_string()
+        } else {
+            format!("{} FOUND", self.candidates.len())
+        };
+        draw_text(&mut frame, 2, 2, &format!("{}  VALUE {}", found, value));
+        draw_text(&mut frame, 2, 9, "E EQUAL G MORE L LESS C CHANGED");
+        draw_text(&mut frame, 2, 16, "R RESTART F FREEZE 0-9 VALUE");
+
+        let values = read_values(console);
+        let first = self.selected.saturating_sub(LIST_ROWS - 1);
+        for (row, &index) in self
+            .candidates
+            .iter()
+            .skip(first)
+            .take(LIST_ROWS)
+            .enumerate()
+        {
+            let text = format!(
+                "${:04X}  WAS {:02X}  NOW {:02X}",
+                address(index),
+                self.previous[index],
+                values[index]
+            );
+            let y = LIST_TOP + row * TEXT_HEIGHT;
+            if first + row == self.selected {
+                draw_text_coloured(&mut frame, 2, y, &text, (0, 0, 0), TEXT_COLOUR);
+            } else {
+                draw_text(&mut frame, 2, y, &text);
+            }
+        }
+        frame
+    }
+
+    fn handle_key(&mut self, keycode: Keycode, console: &ConsoleState) {
+        let comparison = match keycode {
+            Keycode::E => Some(Comparison::Equal),
+            Keycode::G => Some(Comparison::Greater),
+            Keycode::L => Some(Comparison::Less),
+            Keycode::C => Some(Comparison::Changed),
+            _ => None,
+        };
+        if let Some(comparison) = comparison {
+            self.filter(comparison, read_values(console));
+            return;
+        }
+
+        match keycode {
+            Keycode::R => self.restart(read_values(console)),
+            Keycode::Up => self.selected = self.selected.saturating_sub(1),
+            Keycode::Down => {
+                self.selected = (self.selected + 1).min(self.candidates.len().saturating_sub(1))
+            }
+            Keycode::Backspace => self.value = None,
+            Keycode::Minus => self.value = Some(-self.value.unwrap_or(0)),
+            Keycode::F => {
+                if let Some(&index) = self.candidates.get(self.selected) {
+                    let value = read_values(console)[index];
+                    self.freezes
+                        .push(format!("{:04X}:{:02X}", address(index), value));
+                }
+            }
+            _ => {
+                // the number keys have their ASCII codes as keycodes
+                let digit = u8::try_from(keycode as i32)
+                    .ok()
+                    .and_then(|code| (code as char).to_digit(10));
+                if let Some(digit) = digit {
+                    self.type_digit(digit as i16);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn values(changes: &[(usize, u8)]) -> Vec<u8> {
+        let mut values = vec![0; SEARCH_SIZE];
+        for &(index, value) in changes {
+            values[index] = value;
+        }
+        values
+    }
+
+    #[test]
+    fn test_narrow_down_a_counter() {
+        let mut search = RamSearch::new();
+        search.restart(values(&[(0x10, 3), (0x20, 3), (0x900, 3)]));
+        search.filter(
+            Comparison::Less,
+            values(&[(0x10, 2), (0x20, 3), (0x900, 2)]),
+        );
+        assert_eq!(search.candidates, vec![0x10, 0x900]);
+        assert_eq!(address(0x900), 0x6100);
+
+        // down by one again
+        search.value = Some(-1);
+        search.filter(
+            Comparison::Changed,
+            values(&[(0x10, 1), (0x20, 3), (0x900, 0)]),
+        );
+        assert_eq!(search.candidates, vec![0x10]);
+
+        search.value = Some(1);
+        search.filter(Comparison::Equal, values(&[(0x10, 1)]));
+        assert_eq!(search.candidates, vec![0x10]);
+    }
+}
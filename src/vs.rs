@@ -0,0 +1,90 @@
+// TODO: the rest of the VS System, for later:
+// - a DIP switch panel in the frontend, with the settings each game documents, instead
+//   of --dip-switches
+// - the RP2C04 PPUs, whose palettes are scrambled differently for each game, and the
+//   2C05's swapped $2000/$2001 and ID bits in $2002
+// - DualSystem cabinets, two consoles sharing the RAM at $6000
+
+// how long the coin acceptor holds the line down for each coin
+const COIN_FRAMES: u8 = 4;
+
+/// The cabinet side of a VS System: two coin slots, a service button and eight DIP
+/// switches, read through the unused bits of $4016 and $4017.
+#[derive(Debug, Clone, Default)]
+pub struct VsSystem {
+    /// Switch 1 is bit 0.
+    pub dip_switches: u8,
+    pub service: bool,
+    coins: [u8; 2],
+}
+
+impl VsSystem {
+    pub fn new(dip_switches: u8) -> Self {
+        VsSystem {
+            dip_switches,
+            ..VsSystem::default()
+        }
+    }
+
+    /// Drops a coin into `slot` 0 or 1.
+    pub fn insert_coin(&mut self, slot: usize) {
+        self.coins[slot] = COIN_FRAMES;
+    }
+
+    /// Called once a frame.
+    pub fn clock_frame(&mut self) {
+        for coin in self.coins.iter_mut() {
+            *coin = coin.saturating_sub(1);
+        }
+    }
+
+    /// Bits 2-6 of $4016: the service button, DIP switches 1 and 2 and the coin slots.
+    pub fn read_4016(&self) -> u8 {
+        (self.service as u8) << 2
+            | (self.dip_switches & 0b11) << 3
+            | ((self.coins[0] > 0) as u8) << 5
+            | ((self.coins[1] > 0) as u8) << 6
+    }
+
+    /// Bits 2-7 of $4017: DIP switches 3-8.
+    pub fn read_4017(&self) -> u8 {
+        self.dip_switches & 0b1111_1100
+    }
+}
+
+/// Reads DIP switch settings written the way they are on the board, switch 1 first, like
+/// `01000000`.
+pub fn parse_dip_switches(name: &str) -> Result<u8, String> {
+    if name.len() != 8 || !name.chars().all(|c| c == '0' || c == '1') {
+        return Err(format!(
+            "Bad DIP switches {}, they go like 01000000 with switch 1 first",
+            name
+        ));
+    }
+    Ok(name
+        .chars()
+        .enumerate()
+        .map(|(switch, c)| ((c == '1') as u8) << switch)
+        .sum())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_switches_and_coins() {
+        let mut vs = VsSystem::new(parse_dip_switches("10100001").unwrap());
+        assert_eq!(vs.dip_switches, 0b1000_0101);
+        assert_eq!(vs.read_4016(), 0b0000_1000);
+        assert_eq!(vs.read_4017(), 0b1000_0100);
+
+        vs.insert_coin(1);
+        for _ in 0..COIN_FRAMES {
+            assert_eq!(vs.read_4016() & 0b0110_0000, 0b0100_0000);
+            vs.clock_frame();
+        }
+        assert_eq!(vs.read_4016() & 0b0110_0000, 0);
+        assert!(parse_dip_switches("0100").is_err());
+    }
+}
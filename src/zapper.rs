@@ -0,0 +1,105 @@
+use crate::render::frame::Frame;
+
+// The photodiode keeps reporting light for roughly this many scanlines after the beam passes
+const LIGHT_SCANLINES: u16 = 26;
+const BRIGHTNESS_THRESHOLD: u32 = 180;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ZapperState {
+    pub x: usize,
+    pub y: usize,
+    pub trigger: bool,
+    pub bright: bool,
+}
+
+#[derive(Default)]
+pub struct Zapper {
+    state: ZapperState,
+}
+
+impl Zapper {
+    pub fn new() -> Self {
+        Zapper {
+            state: ZapperState::default(),
+        }
+    }
+
+    pub fn update(&mut self, state: ZapperState) {
+        self.state = state;
+    }
+
+    /// Returns the $4016/$4017 bits for the zapper: bit 3 is low while light is sensed,
+    /// bit 4 is high while the trigger is pulled.
+    pub fn read(&self, scanline: u16) -> u8 {
+        let y = self.state.y as u16;
+        let light = self.state.bright && scanline >= y && scanline < y + LIGHT_SCANLINES;
+
+        let mut result = 0;
+        if !light {
+            result |= 0b0000_1000;
+        }
+        if self.state.trigger {
+            result |= 0b0001_0000;
+        }
+        result
+    }
+}
+
+/// Whether the area around (x, y) of a rendered frame is bright enough to trigger the zapper.
+pub fn is_bright(frame: &Frame, x: usize, y: usize) -> bool {
+    let mut total = 0;
+    let mut samples = 0;
+    for py in y.saturating_sub(1)..=(y + 1).min(239) {
+        for px in x.saturating_sub(1)..=(x + 1).min(255) {
+            let (r, g, b) = frame.get_pixel(px, py);
+            total += (299 * r as u32 + 587 * g as u32 + 114 * b as u32) / 1000;
+            samples += 1;
+        }
+    }
+    total / samples >= BRIGHTNESS_THRESHOLD
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_light_sensed_only_near_beam() {
+        let mut zapper = Zapper::new();
+        zapper.update(ZapperState {
+            x: 100,
+            y: 50,
+            trigger: false,
+            bright: true,
+        });
+
+        assert_eq!(zapper.read(10) & 0b1000, 0b1000);
+        assert_eq!(zapper.read(50) & 0b1000, 0);
+        assert_eq!(zapper.read(70) & 0b1000, 0);
+        assert_eq!(zapper.read(80) & 0b1000, 0b1000);
+    }
+
+    #[test]
+    fn test_trigger_bit() {
+        let mut zapper = Zapper::new();
+        zapper.update(ZapperState {
+            trigger: true,
+            ..ZapperState::default()
+        });
+        assert_eq!(zapper.read(0), 0b1_1000);
+    }
+
+    #[test]
+    fn test_brightness_detection() {
+        let mut frame = Frame::new();
+        assert!(!is_bright(&frame, 10, 10));
+
+        for y in 0..20 {
+            for x in 0..20 {
+                frame.set_pixel(x, y, (236, 238, 236));
+            }
+        }
+        assert!(is_bright(&frame, 10, 10));
+        assert!(!is_bright(&frame, 30, 30));
+    }
+}
@@ -0,0 +1,221 @@
+use rust_nes::apu::filter::{SampleBuffer, SAMPLE_RATE};
+use rust_nes::bus::Bus;
+use rust_nes::cpu::{CpuState, Mem, CPU};
+use rust_nes::error::NesError;
+use rust_nes::event::Event;
+use rust_nes::joypad::JoypadButton;
+use rust_nes::render::{self, frame::Frame};
+use rust_nes::rom::Rom;
+use std::cell::Cell;
+use std::sync::{Arc, Mutex};
+use wasm_bindgen::prelude::*;
+use wasm_bindgen::Clamped;
+use web_sys::{AudioContext, CanvasRenderingContext2d, ImageData};
+
+const WIDTH: usize = 256;
+const HEIGHT: usize = 240;
+
+// how far ahead of the audio clock new sound is queued after falling behind
+const AUDIO_LEAD: f64 = 0.05;
+
+const BUTTONS: [JoypadButton; 8] = [
+    JoypadButton::UP,
+    JoypadButton::DOWN,
+    JoypadButton::LEFT,
+    JoypadButton::RIGHT,
+    JoypadButton::SELECT,
+    JoypadButton::START,
+    JoypadButton::BUTTON_A,
+    JoypadButton::BUTTON_B,
+];
+
+/// The button a `KeyboardEvent.code` presses, laid out like the desktop frontend.
+fn button(code: &str) -> Option<JoypadButton> {
+    match code {
+        "ArrowUp" => Some(JoypadButton::UP),
+        "ArrowDown" => Some(JoypadButton::DOWN),
+        "ArrowLeft" => Some(JoypadButton::LEFT),
+        "ArrowRight" => Some(JoypadButton::RIGHT),
+        "Space" => Some(JoypadButton::SELECT),
+        "Enter" => Some(JoypadButton::START),
+        "KeyA" => Some(JoypadButton::BUTTON_A),
+        "KeyS" => Some(JoypadButton::BUTTON_B),
+        _ => None,
+    }
+}
+
+fn to_js(error: NesError) -> JsValue {
+    JsValue::from_str(&error.to_string())
+}
+
+/// The console, driven a frame at a time by the page.
+#[wasm_bindgen]
+pub struct Emulator {
+    cpu: CPU,
+    frame: Frame,
+    buttons: Cell<JoypadButton>,
+    samples: Arc<Mutex<SampleBuffer>>,
+    audio: Option<AudioContext>,
+    // where on the audio clock the sound queued so far runs out
+    audio_end: f64,
+}
+
+#[wasm_bindgen]
+impl Emulator {
+    /// Loads an iNES file.
+    #[wasm_bindgen(constructor)]
+    pub fn new(rom: &[u8]) -> Result<Emulator, JsValue> {
+        let rom = Rom::new(&rom.to_vec()).map_err(|e| JsValue::from_str(&e.to_string()))?;
+
+        let samples = Arc::new(Mutex::new(SampleBuffer::new(SAMPLE_RATE as usize / 10)));
+        let mut cpu = CPU::new(Bus::new(rom).map_err(to_js)?);
+        cpu.halt_on_brk = false;
+        cpu.bus.apu.set_output(Box::new(samples.clone()));
+        cpu.reset();
+
+        Ok(Emulator {
+            cpu,
+            frame: Frame::new(),
+            buttons: Cell::new(JoypadButton::empty()),
+            samples,
+            audio: None,
+            audio_end: 0.0,
+        })
+    }
+
+    /// Runs the console to the end of the next frame.
+    pub fn run_frame(&mut self) -> Result<(), JsValue> {
+        let mut frame_done = false;
+        while !frame_done {
+            match self.cpu.step() {
+                CpuState::Running | CpuState::Halted => {}
+                CpuState::Jammed => {
+                    return Err(to_js(NesError::Jammed {
+                        opcode: self.cpu.mem_peek(self.cpu.program_counter),
+                        addr: self.cpu.program_counter,
+                    }))
+                }
+                CpuState::Error(e) => return Err(to_js(e)),
+            }
+            while let Some(event) = self.cpu.bus.poll_event() {
+                frame_done |= event == Event::FrameComplete;
+            }
+        }
+
+        render::render(&self.cpu.bus.ppu, &mut self.frame);
+        let joypad = self.cpu.bus.joypad_mut(1).unwrap();
+        for &button in BUTTONS.iter() {
+            joypad.set_button_pressed_status(button, self.buttons.get().contains(button));
+        }
+        Ok(())
+    }
+
+    /// Draws the last frame at the top left of a 2D canvas.
+    pub fn draw(&self, context: &CanvasRenderingContext2d) -> Result<(), JsValue> {
+        let mut rgba = Vec::with_capacity(WIDTH * HEIGHT * 4);
+        for pixel in self.frame.data.chunks(3) {
+            rgba.extend_from_slice(pixel);
+            rgba.push(0xff);
+        }
+        let image = ImageData::new_with_u8_clamped_array_and_sh(
+            Clamped(&rgba),
+            WIDTH as u32,
+            HEIGHT as u32,
+        )?;
+        context.put_image_data(&image, 0.0, 0.0)
+    }
+
+    /// Returns false for keys that don't play the game, which the page can leave alone.
+    pub fn key_down(&self, code: &str) -> bool {
+        self.set_key(code, true)
+    }
+
+    pub fn key_up(&self, code: &str) -> bool {
+        self.set_key(code, false)
+    }
+
+    fn set_key(&self, code: &str, pressed: bool) -> bool {
+        match button(code) {
+            Some(button) => {
+                let mut buttons = self.buttons.get();
+                buttons.set(button, pressed);
+                self.buttons.set(buttons);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Browsers only allow sound once the player has clicked or pressed something, so the
+    /// page calls this from an event handler.
+    pub fn enable_audio(&mut self) -> Result<(), JsValue> {
+        if self.audio.is_none() {
+            self.audio = Some(AudioContext::new()?);
+            self.audio_end = 0.0;
+        }
+        Ok(())
+    }
+
+    /// Queues the sound made since the last call behind what's already playing.
+    pub fn play_audio(&mut self) -> Result<(), JsValue> {
+        let samples = {
+            let mut buffer = self.samples.lock().unwrap();
+            let mut samples = vec![0.0; buffer.len()];
+            buffer.fill(&mut samples);
+            samples
+        };
+        let audio = match &self.audio {
+            Some(audio) if !samples.is_empty() => audio,
+            _ => return Ok(()),
+        };
+
+        let buffer = audio.create_buffer(1, samples.len() as u32, SAMPLE_RATE as f32)?;
+        buffer.copy_to_channel(&samples, 0)?;
+        let source = audio.create_buffer_source()?;
+        source.set_buffer(Some(&buffer));
+        source.connect_with_audio_node(&audio.destination())?;
+        let start = self.audio_end.max(audio.current_time() + AUDIO_LEAD);
+        source.start_with_when(start)?;
+        self.audio_end = start + samples.len() as f64 / SAMPLE_RATE as f64;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // an NROM cartridge whose program is JMP $8000
+    fn test_rom() -> Vec<u8> {
+        let mut rom = b"NES\x1a\x01\x01".to_vec();
+        rom.resize(16, 0);
+        let mut prg = vec![0; 0x4000];
+        prg[..3].copy_from_slice(&[0x4c, 0x00, 0x80]);
+        prg[0x3ffc..0x3ffe].copy_from_slice(&[0x00, 0x80]);
+        rom.extend(prg);
+        rom.extend(vec![0; 0x2000]);
+        rom
+    }
+
+    // the buttons come out of $4016 in the order A, B, Select, Start
+    fn start_pressed(emulator: &mut Emulator) -> bool {
+        emulator.cpu.mem_write(0x4016, 1);
+        emulator.cpu.mem_write(0x4016, 0);
+        let bits: Vec<u8> = (0..4).map(|_| emulator.cpu.mem_read(0x4016) & 1).collect();
+        bits[3] == 1
+    }
+
+    #[test]
+    fn test_keys_reach_the_joypad() {
+        let mut emulator = Emulator::new(&test_rom()).ok().unwrap();
+        assert!(emulator.key_down("Enter"));
+        assert!(!emulator.key_down("KeyQ"));
+        emulator.run_frame().ok().unwrap();
+        assert!(start_pressed(&mut emulator));
+
+        emulator.key_up("Enter");
+        emulator.run_frame().ok().unwrap();
+        assert!(!start_pressed(&mut emulator));
+        assert_eq!(emulator.cpu.bus.frame_count(), 2);
+    }
+}